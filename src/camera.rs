@@ -5,6 +5,9 @@ use std::f32::consts::FRAC_PI_2;
 use winit::event::*;
 use winit::keyboard::KeyCode;
 
+use crate::geodesy::LocalFrame;
+use crate::world_pos::WorldPos;
+
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
 
 #[repr(C)]
@@ -15,9 +18,16 @@ pub struct CameraUniform {
 }
 
 pub struct Camera {
-    pub position: Point3<f32>,
+    /// Split world position. Rendering is camera-relative, so the view matrix and everything
+    /// uploaded to the GPU is rebased around this each frame.
+    pub position: WorldPos,
     yaw: Rad<f32>,
     pitch: Rad<f32>,
+    /// Orient yaw, pitch and movement to the geodetic normal below the camera, treating
+    /// `position` as ECEF meters, instead of the world y axis.
+    pub geodetic_up: bool,
+    // Columns are the local east, up and south axes in world space
+    basis: Matrix3<f32>,
 
     aspect: f32,
     fovy: Rad<f32>,
@@ -38,7 +48,7 @@ pub struct Camera {
 }
 
 impl Camera {
-    pub fn new<V: Into<Point3<f32>>, Y: Into<Rad<f32>>>(
+    pub fn new<V: Into<WorldPos>, Y: Into<Rad<f32>>>(
         position: V,
         yaw: Y,
         pitch: Y,
@@ -51,6 +61,8 @@ impl Camera {
             position: position.into(),
             yaw: yaw.into(),
             pitch: pitch.into(),
+            geodetic_up: false,
+            basis: Matrix3::identity(),
 
             aspect: size.width as f32 / size.height as f32,
             fovy: fovy.into(),
@@ -82,6 +94,40 @@ impl Camera {
         }
     }
 
+    /// Camera-relative world direction through a cursor position in pixels, the same math as
+    /// `calculate_ray_direction` in raygen.wgsl.
+    pub fn ray_direction(&self, cursor: [f32; 2], resolution: [f32; 2]) -> Vector3<f32> {
+        let uniform = self.get_uniform();
+        let inverse_view = Matrix4::from(uniform.inverse_view);
+        let inverse_proj = Matrix4::from(uniform.inverse_proj);
+
+        let pixel = Vector2::new(cursor[0] / resolution[0], cursor[1] / resolution[1]);
+        let ndc = Vector2::new(2.0 * pixel.x - 1.0, 1.0 - 2.0 * pixel.y);
+        let target = inverse_proj * Vector4::new(ndc.x, ndc.y, 1.0, 1.0);
+        let direction = (target.truncate() / target.w).normalize();
+
+        (inverse_view * direction.extend(0.0))
+            .truncate()
+            .normalize()
+    }
+
+    /// Pixel position of a camera-relative world point, the inverse of `ray_direction`, or
+    /// `None` when it's behind the near plane.
+    pub fn project(&self, point: Vector3<f32>, resolution: [f32; 2]) -> Option<[f32; 2]> {
+        let clip = perspective(self.fovy, self.aspect, self.znear, self.zfar)
+            * self.get_matrix()
+            * point.extend(1.0);
+        if clip.w < self.znear {
+            return None;
+        }
+
+        let ndc = clip.truncate() / clip.w;
+        Some([
+            (ndc.x + 1.0) / 2.0 * resolution[0],
+            (1.0 - ndc.y) / 2.0 * resolution[1],
+        ])
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.aspect = new_size.width as f32 / new_size.height as f32;
     }
@@ -91,12 +137,26 @@ impl Camera {
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
 
         Matrix4::look_to_rh(
-            self.position,
-            Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize(),
-            Vector3::unit_y(),
+            Point3::origin(),
+            self.basis
+                * Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize(),
+            self.up(),
         )
     }
 
+    pub fn up(&self) -> Vector3<f32> {
+        self.basis.y
+    }
+
+    fn update_basis(&mut self) {
+        self.basis = if self.geodetic_up {
+            let frame = LocalFrame::at_ecef(self.position.to_f64());
+            frame.basis().cast().unwrap()
+        } else {
+            Matrix3::identity()
+        };
+    }
+
     pub fn process_keyboard(&mut self, key: KeyCode, state: ElementState) -> bool {
         let amount = if state == ElementState::Pressed {
             1.0
@@ -149,19 +209,25 @@ impl Camera {
     pub fn update_camera(&mut self, dt: std::time::Duration) {
         let dt = dt.as_secs_f32();
 
+        self.update_basis();
+
         let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
-        let forward = Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
-        let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        self.position += forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
-        self.position += right * (self.amount_right - self.amount_left) * self.speed * dt;
+        let forward = self.basis * Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
+        let right = self.basis * Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
+        self.position
+            .translate(forward * (self.amount_forward - self.amount_backward) * self.speed * dt);
+        self.position
+            .translate(right * (self.amount_right - self.amount_left) * self.speed * dt);
 
         let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
-        let scrollward =
-            Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        self.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
+        let scrollward = self.basis
+            * Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
+        self.position
+            .translate(scrollward * self.scroll * self.speed * self.sensitivity * dt);
         self.scroll = 0.0;
 
-        self.position.y += (self.amount_up - self.amount_down) * self.speed * dt;
+        self.position
+            .translate(self.up() * (self.amount_up - self.amount_down) * self.speed * dt);
 
         self.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
         self.pitch += Rad(-self.rotate_vertical) * self.sensitivity * dt;
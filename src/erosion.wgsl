@@ -0,0 +1,223 @@
+// Particle-based hydraulic erosion and thermal erosion on a heightmap. Mirrors
+// terrain/erosion.rs step for step. Height changes are accumulated as fixed-point integers
+// so the result doesn't depend on the order threads run in.
+
+const FIXED_POINT: f32 = 16777216.0;
+
+struct Params {
+    width: u32,
+    height: u32,
+    seed: u32,
+    first_droplet: u32,
+    droplet_count: u32,
+    lifetime: u32,
+    inertia: f32,
+    capacity: f32,
+    min_slope: f32,
+    deposition: f32,
+    erosion: f32,
+    evaporation: f32,
+    gravity: f32,
+    talus: f32,
+    thermal_rate: f32,
+    radius: i32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+// Heights normalized to the elevation range
+@group(0) @binding(1) var<storage, read_write> heights: array<f32>;
+@group(0) @binding(2) var<storage, read_write> deltas: array<atomic<i32>>;
+
+fn pcg(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn height_at(x: i32, y: i32) -> f32 {
+    let cx = clamp(x, 0, i32(params.width) - 1);
+    let cy = clamp(y, 0, i32(params.height) - 1);
+    return heights[u32(cy) * params.width + u32(cx)];
+}
+
+fn add_delta(x: i32, y: i32, amount: f32) {
+    atomicAdd(&deltas[u32(y) * params.width + u32(x)], i32(round(amount * FIXED_POINT)));
+}
+
+// Bilinear height and gradient, packed as (height, gradient x, gradient y)
+fn height_and_gradient(position: vec2<f32>) -> vec3<f32> {
+    let node = vec2<i32>(floor(position));
+    let f = position - floor(position);
+
+    let nw = height_at(node.x, node.y);
+    let ne = height_at(node.x + 1, node.y);
+    let sw = height_at(node.x, node.y + 1);
+    let se = height_at(node.x + 1, node.y + 1);
+
+    let gradient_x = (ne - nw) * (1.0 - f.y) + (se - sw) * f.y;
+    let gradient_y = (sw - nw) * (1.0 - f.x) + (se - ne) * f.x;
+    let height = nw * (1.0 - f.x) * (1.0 - f.y) + ne * f.x * (1.0 - f.y) + sw * (1.0 - f.x) * f.y
+        + se * f.x * f.y;
+
+    return vec3<f32>(height, gradient_x, gradient_y);
+}
+
+// Spreads a height change over the four corners of the cell `position` is in
+fn add_bilinear(position: vec2<f32>, amount: f32) {
+    let node = vec2<i32>(floor(position));
+    let f = position - floor(position);
+
+    add_delta(node.x, node.y, amount * (1.0 - f.x) * (1.0 - f.y));
+    add_delta(node.x + 1, node.y, amount * f.x * (1.0 - f.y));
+    add_delta(node.x, node.y + 1, amount * (1.0 - f.x) * f.y);
+    add_delta(node.x + 1, node.y + 1, amount * f.x * f.y);
+}
+
+fn brush_weight(center: vec2<i32>, offset: vec2<i32>, position: vec2<f32>) -> f32 {
+    let cell = center + offset;
+    if cell.x < 0 || cell.y < 0 || cell.x >= i32(params.width) || cell.y >= i32(params.height) {
+        return 0.0;
+    }
+    let distance = vec2<f32>(cell) - position;
+    return max(f32(params.radius) - sqrt(distance.x * distance.x + distance.y * distance.y), 0.0);
+}
+
+// Spreads a height change over the cells within the radius, weighted by closeness
+fn add_brush(position: vec2<f32>, amount: f32) {
+    let center = vec2<i32>(floor(position));
+
+    var total = 0.0;
+    for (var dy = -params.radius; dy <= params.radius; dy++) {
+        for (var dx = -params.radius; dx <= params.radius; dx++) {
+            total += brush_weight(center, vec2(dx, dy), position);
+        }
+    }
+    for (var dy = -params.radius; dy <= params.radius; dy++) {
+        for (var dx = -params.radius; dx <= params.radius; dx++) {
+            let weight = brush_weight(center, vec2(dx, dy), position);
+            if weight > 0.0 {
+                add_delta(center.x + dx, center.y + dy, amount * weight / total);
+            }
+        }
+    }
+}
+
+@compute @workgroup_size(64)
+fn droplets(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if global_id.x >= params.droplet_count {
+        return;
+    }
+
+    let hash = pcg(params.seed ^ pcg(params.first_droplet + global_id.x));
+    var position = vec2<f32>(
+        f32(hash & 0xffffu) / 65536.0 * f32(params.width - 1u),
+        f32(hash >> 16u) / 65536.0 * f32(params.height - 1u),
+    );
+    var direction = vec2<f32>(0.0);
+    var speed = 1.0;
+    var water = 1.0;
+    var sediment = 0.0;
+
+    for (var step = 0u; step < params.lifetime; step++) {
+        let sample = height_and_gradient(position);
+
+        direction = direction * params.inertia - sample.yz * (1.0 - params.inertia);
+        let length = sqrt(direction.x * direction.x + direction.y * direction.y);
+        if length == 0.0 {
+            break;
+        }
+        direction /= length;
+
+        let next = position + direction;
+        if next.x < 0.0 || next.y < 0.0 || next.x >= f32(params.width - 1u)
+            || next.y >= f32(params.height - 1u) {
+            break;
+        }
+
+        let height_difference = height_and_gradient(next).x - sample.x;
+        let capacity = max(-height_difference, params.min_slope) * speed * water * params.capacity;
+
+        if height_difference > 0.0 {
+            // Fill the pit it's climbing out of and stop there. It can't see its own deposit
+            // until the batch is applied, so staying would fill the same pit again
+            add_bilinear(position, min(height_difference, sediment));
+            break;
+        } else if sediment > capacity {
+            let deposit = (sediment - capacity) * params.deposition;
+            sediment -= deposit;
+            add_bilinear(position, deposit);
+        } else {
+            let eroded = min((capacity - sediment) * params.erosion, -height_difference);
+            sediment += eroded;
+            add_brush(position, -eroded);
+        }
+
+        speed = sqrt(max(speed * speed - height_difference * params.gravity, 0.0));
+        water *= 1.0 - params.evaporation;
+        position = next;
+    }
+}
+
+@compute @workgroup_size(64)
+fn thermal(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let index = global_id.x;
+    if index >= params.width * params.height {
+        return;
+    }
+
+    let x = i32(index % params.width);
+    let y = i32(index / params.width);
+    let center = heights[index];
+
+    var offsets = array<vec2<i32>, 8>(
+        vec2(-1, -1), vec2(0, -1), vec2(1, -1), vec2(-1, 0),
+        vec2(1, 0), vec2(-1, 1), vec2(0, 1), vec2(1, 1),
+    );
+    var excess = array<f32, 8>();
+    var total = 0.0;
+    var steepest = 0.0;
+
+    for (var k = 0; k < 8; k++) {
+        let neighbour = vec2<i32>(x, y) + offsets[k];
+        if neighbour.x < 0 || neighbour.y < 0 || neighbour.x >= i32(params.width)
+            || neighbour.y >= i32(params.height) {
+            continue;
+        }
+
+        var distance = 1.0;
+        if offsets[k].x != 0 && offsets[k].y != 0 {
+            distance = 1.4142135;
+        }
+        let difference = center - height_at(neighbour.x, neighbour.y) - params.talus * distance;
+        if difference > 0.0 {
+            excess[k] = difference;
+            total += difference;
+            steepest = max(steepest, difference);
+        }
+    }
+
+    if total <= 0.0 {
+        return;
+    }
+
+    // Half the steepest excess settles, spread over every neighbour past the talus angle
+    let moved = params.thermal_rate * steepest * 0.5;
+    for (var k = 0; k < 8; k++) {
+        if excess[k] > 0.0 {
+            let neighbour = vec2<i32>(x, y) + offsets[k];
+            let amount = moved * excess[k] / total;
+            add_delta(neighbour.x, neighbour.y, amount);
+            add_delta(x, y, -amount);
+        }
+    }
+}
+
+@compute @workgroup_size(64)
+fn apply(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let index = global_id.x;
+    if index >= params.width * params.height {
+        return;
+    }
+
+    heights[index] += f32(atomicExchange(&deltas[index], 0)) / FIXED_POINT;
+}
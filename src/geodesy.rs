@@ -0,0 +1,214 @@
+use cgmath::*;
+
+// WGS84 ellipsoid
+pub const SEMI_MAJOR_AXIS: f64 = 6_378_137.0;
+pub const FLATTENING: f64 = 1.0 / 298.257_223_563;
+pub const ECCENTRICITY_SQUARED: f64 = FLATTENING * (2.0 - FLATTENING);
+pub const SEMI_MINOR_AXIS: f64 = SEMI_MAJOR_AXIS * (1.0 - FLATTENING);
+
+/// Earth-centered, Earth-fixed position in meters of a geodetic coordinate in degrees and an
+/// ellipsoidal height in meters.
+pub fn geodetic_to_ecef(lat: f64, lon: f64, height: f64) -> Vector3<f64> {
+    let (sin_lat, cos_lat) = lat.to_radians().sin_cos();
+    let (sin_lon, cos_lon) = lon.to_radians().sin_cos();
+    let prime_vertical = SEMI_MAJOR_AXIS / (1.0 - ECCENTRICITY_SQUARED * sin_lat * sin_lat).sqrt();
+
+    Vector3::new(
+        (prime_vertical + height) * cos_lat * cos_lon,
+        (prime_vertical + height) * cos_lat * sin_lon,
+        (prime_vertical * (1.0 - ECCENTRICITY_SQUARED) + height) * sin_lat,
+    )
+}
+
+/// Geodetic `(lat, lon, height)` of an ECEF position, using Bowring's method which is accurate
+/// to well under a millimeter near the surface.
+pub fn ecef_to_geodetic(position: Vector3<f64>) -> (f64, f64, f64) {
+    let p = position.x.hypot(position.y);
+    let second_eccentricity_squared = (SEMI_MAJOR_AXIS * SEMI_MAJOR_AXIS
+        - SEMI_MINOR_AXIS * SEMI_MINOR_AXIS)
+        / (SEMI_MINOR_AXIS * SEMI_MINOR_AXIS);
+
+    let theta = (position.z * SEMI_MAJOR_AXIS).atan2(p * SEMI_MINOR_AXIS);
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    let lat = (position.z + second_eccentricity_squared * SEMI_MINOR_AXIS * sin_theta.powi(3))
+        .atan2(p - ECCENTRICITY_SQUARED * SEMI_MAJOR_AXIS * cos_theta.powi(3));
+    let lon = position.y.atan2(position.x);
+
+    let sin_lat = lat.sin();
+    let prime_vertical = SEMI_MAJOR_AXIS / (1.0 - ECCENTRICITY_SQUARED * sin_lat * sin_lat).sqrt();
+    let height = if lat.cos().abs() > 1e-9 {
+        p / lat.cos() - prime_vertical
+    } else {
+        position.z.abs() - SEMI_MINOR_AXIS
+    };
+
+    (lat.to_degrees(), lon.to_degrees(), height)
+}
+
+/// Geodetic `(lat, lon)` in degrees of a WGS84 UTM coordinate, using the series from Snyder's
+/// "Map Projections: A Working Manual", accurate to millimeters within a zone.
+pub fn utm_to_geodetic(zone: u8, north: bool, easting: f64, northing: f64) -> (f64, f64) {
+    const SCALE: f64 = 0.9996;
+    let second_eccentricity_squared = ECCENTRICITY_SQUARED / (1.0 - ECCENTRICITY_SQUARED);
+    let x = easting - 500_000.0;
+    let y = if north {
+        northing
+    } else {
+        northing - 10_000_000.0
+    };
+
+    // Footpoint latitude, where the meridian arc equals the northing
+    let e = ECCENTRICITY_SQUARED;
+    let mu = y
+        / SCALE
+        / (SEMI_MAJOR_AXIS * (1.0 - e / 4.0 - 3.0 * e * e / 64.0 - 5.0 * e * e * e / 256.0));
+    let e1 = (1.0 - (1.0 - e).sqrt()) / (1.0 + (1.0 - e).sqrt());
+    let phi = mu
+        + (3.0 * e1 / 2.0 - 27.0 * e1.powi(3) / 32.0) * (2.0 * mu).sin()
+        + (21.0 * e1 * e1 / 16.0 - 55.0 * e1.powi(4) / 32.0) * (4.0 * mu).sin()
+        + 151.0 * e1.powi(3) / 96.0 * (6.0 * mu).sin()
+        + 1097.0 * e1.powi(4) / 512.0 * (8.0 * mu).sin();
+
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let n = SEMI_MAJOR_AXIS / (1.0 - e * sin_phi * sin_phi).sqrt();
+    let r = SEMI_MAJOR_AXIS * (1.0 - e) / (1.0 - e * sin_phi * sin_phi).powf(1.5);
+    let t = phi.tan().powi(2);
+    let c = second_eccentricity_squared * cos_phi * cos_phi;
+    let d = x / (n * SCALE);
+
+    let lat = phi
+        - n * phi.tan() / r
+            * (d * d / 2.0
+                - (5.0 + 3.0 * t + 10.0 * c - 4.0 * c * c - 9.0 * second_eccentricity_squared)
+                    * d.powi(4)
+                    / 24.0
+                + (61.0 + 90.0 * t + 298.0 * c + 45.0 * t * t
+                    - 252.0 * second_eccentricity_squared
+                    - 3.0 * c * c)
+                    * d.powi(6)
+                    / 720.0);
+    let lon = (d - (1.0 + 2.0 * t + c) * d.powi(3) / 6.0
+        + (5.0 - 2.0 * c + 28.0 * t - 3.0 * c * c
+            + 8.0 * second_eccentricity_squared
+            + 24.0 * t * t)
+            * d.powi(5)
+            / 120.0)
+        / cos_phi;
+
+    let central_meridian = zone as f64 * 6.0 - 183.0;
+    (lat.to_degrees(), central_meridian + lon.to_degrees())
+}
+
+/// Unit normal of the ellipsoid at a geodetic coordinate, the local "up".
+pub fn geodetic_normal(lat: f64, lon: f64) -> Vector3<f64> {
+    let (sin_lat, cos_lat) = lat.to_radians().sin_cos();
+    let (sin_lon, cos_lon) = lon.to_radians().sin_cos();
+    Vector3::new(cos_lat * cos_lon, cos_lat * sin_lon, sin_lat)
+}
+
+/// Local tangent frame at a point on or above the ellipsoid. Axes follow the renderer's
+/// convention of x east, y up and z south, so voxel volumes can be placed in it unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalFrame {
+    pub origin: Vector3<f64>,
+    pub east: Vector3<f64>,
+    pub up: Vector3<f64>,
+    pub south: Vector3<f64>,
+}
+
+impl LocalFrame {
+    pub fn new(lat: f64, lon: f64, height: f64) -> Self {
+        let (sin_lon, cos_lon) = lon.to_radians().sin_cos();
+        let up = geodetic_normal(lat, lon);
+        let east = Vector3::new(-sin_lon, cos_lon, 0.0);
+
+        Self {
+            origin: geodetic_to_ecef(lat, lon, height),
+            east,
+            up,
+            south: east.cross(up),
+        }
+    }
+
+    /// Frame whose up is the geodetic normal below an arbitrary ECEF position.
+    pub fn at_ecef(position: Vector3<f64>) -> Self {
+        let (lat, lon, height) = ecef_to_geodetic(position);
+        Self::new(lat, lon, height)
+    }
+
+    /// Columns are the east, up and south axes, mapping local directions to ECEF.
+    pub fn basis(&self) -> Matrix3<f64> {
+        Matrix3::from_cols(self.east, self.up, self.south)
+    }
+
+    pub fn to_ecef(self, local: Vector3<f64>) -> Vector3<f64> {
+        self.origin + self.basis() * local
+    }
+
+    pub fn to_local(self, ecef: Vector3<f64>) -> Vector3<f64> {
+        self.basis().transpose() * (ecef - self.origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3<f64>, b: Vector3<f64>, tolerance: f64) {
+        assert!((a - b).magnitude() < tolerance, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn ecef_axes_meet_the_ellipsoid() {
+        let origin = geodetic_to_ecef(0.0, 0.0, 0.0);
+        assert_close(origin, Vector3::new(SEMI_MAJOR_AXIS, 0.0, 0.0), 1e-6);
+        let east = geodetic_to_ecef(0.0, 90.0, 100.0);
+        assert_close(east, Vector3::new(0.0, SEMI_MAJOR_AXIS + 100.0, 0.0), 1e-6);
+        let pole = geodetic_to_ecef(90.0, 0.0, 0.0);
+        assert_close(pole, Vector3::new(0.0, 0.0, SEMI_MINOR_AXIS), 1e-6);
+        let south_pole = geodetic_to_ecef(-90.0, 0.0, 0.0);
+        assert_close(south_pole, Vector3::new(0.0, 0.0, -SEMI_MINOR_AXIS), 1e-6);
+    }
+
+    #[test]
+    fn geodetic_round_trips() {
+        for lat in [-89.9, -60.0, -23.5, 0.0, 12.3, 45.0, 78.9, 90.0] {
+            for lon in [-179.0, -45.0, 0.0, 2.35, 120.0] {
+                for height in [-400.0, 0.0, 1_234.5, 8_848.0, 400_000.0] {
+                    // A single Bowring step drifts slightly at orbital heights
+                    let tolerance = if height > 10_000.0 { 1e-7 } else { 1e-9 };
+                    let (lat2, lon2, height2) =
+                        ecef_to_geodetic(geodetic_to_ecef(lat, lon, height));
+                    assert!((lat2 - lat).abs() < tolerance, "latitude {lat2} != {lat}");
+                    if lat.abs() < 90.0 {
+                        assert!((lon2 - lon).abs() < 1e-9, "longitude {lon2} != {lon}");
+                    }
+                    assert!(
+                        (height2 - height).abs() < tolerance * 1e6,
+                        "height {height2} != {height}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn local_frames_are_orthonormal() {
+        for (lat, lon) in [(0.0, 0.0), (46.95, 2.55), (-33.9, 151.2), (89.0, -120.0)] {
+            let frame = LocalFrame::new(lat, lon, 250.0);
+            for axis in [frame.east, frame.up, frame.south] {
+                assert!((axis.magnitude() - 1.0).abs() < 1e-12);
+            }
+            assert!(frame.east.dot(frame.up).abs() < 1e-12);
+            assert!(frame.up.dot(frame.south).abs() < 1e-12);
+            assert!(frame.south.dot(frame.east).abs() < 1e-12);
+            assert_close(frame.up, geodetic_normal(lat, lon), 1e-12);
+            // South points away from the north pole
+            assert!(frame.south.z < 0.0);
+
+            let local = Vector3::new(12.0, -3.5, 700.0);
+            assert_close(frame.to_local(frame.to_ecef(local)), local, 1e-6);
+            assert_close(LocalFrame::at_ecef(frame.origin).up, frame.up, 1e-9);
+        }
+    }
+}
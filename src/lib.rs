@@ -1,8 +1,38 @@
 mod camera;
-mod terrain;
+mod geodesy;
+mod picking;
+pub mod terrain;
+mod world_pos;
 
 use camera::Camera;
+use cgmath::{Matrix, Matrix3, SquareMatrix, Vector3, Zero};
+use geodesy::LocalFrame;
+use picking::{PickHit, PickWindow, PlacedVoxels};
+use terrain::density::DensityOptions;
+use terrain::editing::{Brush, Editor};
+use terrain::erosion::ErosionOptions;
+use terrain::generator::GeneratorOptions;
+use terrain::geoid::{Geoid, VerticalDatum};
+use terrain::landcover::{LandCover, LandCoverTable, PERMANENT_WATER};
+use terrain::lidar::{ClassTable, LidarPoints, PointCloud};
+use terrain::lod::{LodChain, LodSelection};
+use terrain::mesh::{self, MeshOptions};
+use terrain::osm::{OsmData, OsmFeatures, OsmOptions};
+use terrain::overlay::{DrapedLayer, OverlayLayer};
+use terrain::processor::{self, GeoBounds, Heightmap};
+use terrain::rules::{AttributeMap, RuleSet};
+use terrain::streaming::{ChunkStreamer, GeneratedSource, StreamingOptions, SLOT_VOXELS};
+use terrain::vegetation::{Template, TemplateLibrary, VegetationOptions, VegetationTable};
+use terrain::vox::VoxFile;
+use terrain::voxelizer::{
+    ChunkPlacement, GeoCoord, VoxelChunk, VoxelMaterial, AIR, HEIGHT, LENGTH, WIDTH,
+};
+use terrain::water::{WaterMap, WaterOptions};
+use world_pos::WorldPos;
+
+pub use terrain::downloader::download;
 
+use std::path::Path;
 use std::time::Instant;
 use std::{sync::Arc, u32};
 use wgpu::util::DeviceExt;
@@ -109,6 +139,24 @@ impl ApplicationHandler for App {
 
                     let now = Instant::now();
                     let dt = now - game_state.last_render_time;
+                    let mut globe_placement = game_state.volume_frame.is_some();
+                    let mut undo = false;
+                    let mut redo = false;
+                    let mut save = false;
+                    let mut export_vox = false;
+                    let mut export_glb = false;
+                    let mut export_obj = false;
+                    let mut tool = game_state.tool;
+                    let mut tool_prop = game_state.tool_prop;
+                    let mut tool_material = game_state
+                        .materials
+                        .iter()
+                        .position(|material| material.id == game_state.tool_material)
+                        .unwrap_or(0);
+                    let hover_geodetic = game_state
+                        .hover
+                        .and_then(|(slot, hit)| game_state.voxel_geodetic(slot, hit.voxel));
+                    let overlay_shapes = game_state.overlay_shapes(window.scale_factor() as f32);
 
                     if let Some(imgui) = &mut game_state.imgui {
                         imgui
@@ -151,8 +199,141 @@ impl ApplicationHandler for App {
                                 .position([400.0, 200.0], Condition::FirstUseEver)
                                 .build(|| {
                                     ui.text(format!("Frametime: {dt:?}"));
+                                    ui.checkbox("Place on WGS84 globe", &mut globe_placement);
+                                    ui.text(format!(
+                                        "Chunks: {} loaded, {} loading",
+                                        game_state.streamer.loaded(),
+                                        game_state.streamer.pending()
+                                    ));
                                 });
 
+                            let window = ui.window("Water");
+                            window
+                                .size([240.0, 80.0], Condition::FirstUseEver)
+                                .position([400.0, 520.0], Condition::FirstUseEver)
+                                .build(|| {
+                                    ui.checkbox("Waves", &mut game_state.waves);
+                                    ui.slider(
+                                        "Turbidity",
+                                        0.0,
+                                        20.0,
+                                        &mut game_state.water_turbidity,
+                                    );
+                                });
+
+                            let window = ui.window("Editing");
+                            window
+                                .size([200.0, 80.0], Condition::FirstUseEver)
+                                .position([400.0, 420.0], Condition::FirstUseEver)
+                                .build(|| {
+                                    undo = ui.button("Undo") && game_state.editor.can_undo();
+                                    ui.same_line();
+                                    redo = ui.button("Redo") && game_state.editor.can_redo();
+                                    ui.same_line();
+                                    save = ui.button("Save world");
+                                    export_vox = ui.button("Export .vox");
+                                    export_glb = ui.button("Export .glb");
+                                    ui.same_line();
+                                    export_obj = ui.button("Export .obj");
+                                });
+
+                            let window = ui.window("Picking");
+                            window
+                                .size([300.0, 250.0], Condition::FirstUseEver)
+                                .position([720.0, 200.0], Condition::FirstUseEver)
+                                .build(|| {
+                                    for (label, option) in [
+                                        ("Inspect", Tool::Inspect),
+                                        ("Place", Tool::Place),
+                                        ("Remove", Tool::Remove),
+                                        ("Stamp", Tool::Stamp),
+                                    ] {
+                                        if ui.radio_button_bool(label, tool == option) {
+                                            tool = option;
+                                        }
+                                        ui.same_line();
+                                    }
+                                    ui.text("");
+
+                                    let names: Vec<&str> = game_state
+                                        .materials
+                                        .iter()
+                                        .map(|material| material.name.as_str())
+                                        .collect();
+                                    ui.combo_simple_string("Material", &mut tool_material, &names);
+                                    if game_state.props.is_empty() {
+                                        ui.text(format!("No props, add .vox files to {PROP_DIR}/"));
+                                    } else {
+                                        let names: Vec<&str> = game_state
+                                            .props
+                                            .iter()
+                                            .map(|(name, _)| name.as_str())
+                                            .collect();
+                                        ui.combo_simple_string("Prop", &mut tool_prop, &names);
+                                    }
+                                    ui.separator();
+
+                                    match &game_state.hover {
+                                        Some((_, hit)) => {
+                                            let name = game_state
+                                                .materials
+                                                .iter()
+                                                .find(|material| material.id == hit.material)
+                                                .map_or("unknown", |material| &material.name);
+                                            let [x, y, z] = hit.voxel;
+                                            ui.text(format!("Voxel: ({x}, {y}, {z})"));
+                                            ui.text(format!("Material: {name}"));
+                                            ui.text(format!("Normal: {:?}", hit.normal));
+                                            ui.text(format!("Distance: {:.2}", hit.distance));
+                                            if let Some((lat, lon, height)) = hover_geodetic {
+                                                ui.text(format!(
+                                                    "Lat {lat:.6}, lon {lon:.6}, height {height:.1} m"
+                                                ));
+                                            }
+                                        }
+                                        None => ui.text("Nothing under the cursor"),
+                                    }
+                                });
+
+                            let window = ui.window("Layers");
+                            window
+                                .size([300.0, 150.0], Condition::FirstUseEver)
+                                .position([720.0, 440.0], Condition::FirstUseEver)
+                                .build(|| {
+                                    if game_state.overlays.is_empty() {
+                                        ui.text(format!(
+                                            "Put GPX or GeoJSON files in {OVERLAY_DIR}/"
+                                        ));
+                                    }
+                                    for (i, (layer, _)) in
+                                        game_state.overlays.iter_mut().enumerate()
+                                    {
+                                        ui.color_edit3(format!("##color{i}"), &mut layer.color);
+                                        ui.same_line();
+                                        ui.checkbox(
+                                            format!("{}##layer{i}", layer.name),
+                                            &mut layer.visible,
+                                        );
+                                    }
+                                });
+
+                            // Behind the windows, over the traced image
+                            let draw_list = ui.get_background_draw_list();
+                            for shapes in &overlay_shapes {
+                                for [a, b] in &shapes.lines {
+                                    draw_list
+                                        .add_line(*a, *b, shapes.color)
+                                        .thickness(2.0)
+                                        .build();
+                                }
+                                for point in &shapes.points {
+                                    draw_list
+                                        .add_circle(*point, 4.0, shapes.color)
+                                        .filled(true)
+                                        .build();
+                                }
+                            }
+
                             ui.show_demo_window(&mut imgui.demo_open);
                         }
 
@@ -162,6 +343,42 @@ impl ApplicationHandler for App {
                         }
                     }
 
+                    if globe_placement != game_state.volume_frame.is_some() {
+                        game_state.place_volume(globe_placement.then(|| game_state.globe_frame()));
+                    }
+
+                    game_state.tool = tool;
+                    game_state.tool_prop = tool_prop;
+                    if let Some(material) = game_state.materials.get(tool_material) {
+                        game_state.tool_material = material.id;
+                    }
+                    if undo {
+                        game_state.editor.undo();
+                    }
+                    if redo {
+                        game_state.editor.redo();
+                    }
+                    if save {
+                        match game_state.save_world() {
+                            Ok(()) => println!("Saved the world to {WORLD_FILE}"),
+                            Err(e) => eprintln!("couldn't save {WORLD_FILE}: {e}"),
+                        }
+                    }
+                    if export_vox {
+                        match game_state.export_vox() {
+                            Ok(()) => println!("Exported the edited chunk to {VOX_EXPORT}"),
+                            Err(e) => eprintln!("couldn't export {VOX_EXPORT}: {e}"),
+                        }
+                    }
+                    for (export, path) in [(export_glb, GLB_EXPORT), (export_obj, OBJ_EXPORT)] {
+                        if export {
+                            match game_state.export_mesh(Path::new(path)) {
+                                Ok(()) => println!("Exported the edited chunk to {path}"),
+                                Err(e) => eprintln!("couldn't export {path}: {e}"),
+                            }
+                        }
+                    }
+
                     game_state.last_render_time = now;
                     game_state.update(dt);
                     match game_state.render() {
@@ -190,10 +407,33 @@ impl ApplicationHandler for App {
                 WindowEvent::MouseWheel { delta, .. } => {
                     game_state.camera.process_scroll(&delta);
                 }
+                WindowEvent::CursorMoved { position, .. } => {
+                    game_state.cursor = Some([position.x as f32, position.y as f32]);
+                }
+                WindowEvent::CursorLeft { .. } => {
+                    game_state.cursor = None;
+                }
                 WindowEvent::MouseInput {
                     button: MouseButton::Left,
                     state,
                     ..
+                } => {
+                    let over_ui = game_state
+                        .imgui
+                        .as_ref()
+                        .is_some_and(|imgui| imgui.context.io().want_capture_mouse);
+
+                    // Editing tools take left clicks, the right button still turns the camera
+                    if game_state.tool == Tool::Inspect {
+                        game_state.mouse_pressed = state == ElementState::Pressed;
+                    } else if state == ElementState::Pressed && !over_ui {
+                        game_state.use_tool();
+                    }
+                }
+                WindowEvent::MouseInput {
+                    button: MouseButton::Right,
+                    state,
+                    ..
                 } => {
                     game_state.mouse_pressed = state == ElementState::Pressed;
                 }
@@ -233,6 +473,281 @@ struct SceneProps {
     voxel_count: u32,
     ray_offset: f32,
     ray_bounces: f32,
+    lod_bias: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct WaterProps {
+    absorption: [f32; 3],
+    wave_strength: f32,
+    time: f32,
+    _padding: [f32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PageTableInfo {
+    // Render units a chunk of the window spans east and south
+    chunk_length: [f32; 2],
+    side: i32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ChunkTransform {
+    pos: [f32; 3],
+    // Pool slot plus one, zero where the window has no chunk loaded
+    page: u32,
+    // Render units per voxel along the chunk's x, y and z
+    voxel_size: [f32; 3],
+    _padding: f32,
+    // Columns of the chunk's x, y and z axes in world space, padded like WGSL's mat3x3
+    axes: [[f32; 4]; 3],
+}
+
+const VOXEL_LENGTH: f32 = 0.1;
+
+// Chunks the generated heightmap is split into for streaming, east by south
+const WORLD_CHUNKS: [usize; 2] = [8, 8];
+
+// Absorption coefficients of clear water for red, green and blue light in 1/m, scaled by the
+// water turbidity
+const WATER_ABSORPTION: [f32; 3] = [0.35, 0.07, 0.04];
+// Tilt of the wave normals while waves are on
+const WAVE_STRENGTH: f32 = 0.15;
+
+// Where the world is saved, with the edited chunk in place
+const WORLD_FILE: &str = "world.pew";
+
+// Where the edited chunk is exported for MagicaVoxel
+const VOX_EXPORT: &str = "chunk.vox";
+
+// Where the edited chunk is exported as a mesh, with OBJ's material library next to it
+const GLB_EXPORT: &str = "chunk.glb";
+const OBJ_EXPORT: &str = "chunk.obj";
+
+// GPX and GeoJSON files in this directory are shown as overlay layers
+const OVERLAY_DIR: &str = "overlays";
+
+// MagicaVoxel models in this directory can be stamped into the edited chunk
+const PROP_DIR: &str = "props";
+
+// Elevation GeoTIFF voxelized instead of generated terrain when present, with bathymetry merged
+// into its sea when a bathymetry GeoTIFF sits next to it
+const DEM: &str = "dem.tif";
+const BATHYMETRY: &str = "bathymetry.tif";
+
+// Land-cover raster, such as a WorldCover tile, picking surface materials when present, with an
+// optional class table
+const LAND_COVER: &str = "landcover.tif";
+const LAND_COVER_CLASSES: &str = "landcover.toml";
+
+// Slope and altitude material rules, applied over the land cover when present
+const MATERIAL_RULES: &str = "rules.toml";
+
+// OpenStreetMap extract voxelized into the world when present, with optional settings
+const OSM_EXTRACT: &str = "map.osm.pbf";
+const OSM_OPTIONS: &str = "osm.toml";
+
+// LAS point cloud merged into the world when present, with an optional class table
+const LIDAR_CLOUD: &str = "lidar.las";
+const LIDAR_CLASSES: &str = "lidar.toml";
+
+/// What a left click in the viewport does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tool {
+    /// Turn the camera, and show what's under the cursor.
+    Inspect,
+    /// Add a voxel of the selected material against the face under the cursor.
+    Place,
+    /// Clear the voxel under the cursor.
+    Remove,
+    /// Stamp the selected prop against the face under the cursor.
+    Stamp,
+}
+
+// North-west corner of the generated terrain
+const GLOBE_ORIGIN: GeoCoord = GeoCoord {
+    lat: 47.0,
+    lon: 2.5,
+};
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct VolumeTransform {
+    pos: [f32; 3],
+    voxel_length: f32,
+    // Columns of the window's x, y and z axes in world space, padded like WGSL's mat3x3
+    axes: [[f32; 4]; 3],
+}
+
+/// An overlay layer projected to the screen, in imgui's logical pixels.
+struct OverlayShapes {
+    color: [f32; 4],
+    lines: Vec<[[f32; 2]; 2]>,
+    points: Vec<[f32; 2]>,
+}
+
+/// Columns of `m`, padded like WGSL's mat3x3.
+fn padded_columns(m: Matrix3<f64>) -> [[f32; 4]; 3] {
+    let column = |v: Vector3<f64>| [v.x as f32, v.y as f32, v.z as f32, 0.0];
+    [column(m.x), column(m.y), column(m.z)]
+}
+
+impl ChunkTransform {
+    /// The chunk's axes as matrix columns, mapping its directions to world space.
+    fn basis(&self) -> Matrix3<f32> {
+        let axis = |column: [f32; 4]| Vector3::new(column[0], column[1], column[2]);
+        let [x, y, z] = self.axes;
+        Matrix3::from_cols(axis(x), axis(y), axis(z))
+    }
+}
+
+/// Maps ECEF positions and directions into the camera-relative space the scene is rendered
+/// in. On the globe that's only the floating origin. On the flat grid the world is laid out
+/// in the edited chunk's frame and shrunk so a column is `VOXEL_LENGTH` across.
+struct RenderSpace {
+    /// ECEF point rendered at `pos`.
+    anchor: Vector3<f64>,
+    pos: Vector3<f64>,
+    /// Turns ECEF directions into render space.
+    rotation: Matrix3<f64>,
+    /// Render units per meter.
+    scale: f64,
+}
+
+impl RenderSpace {
+    fn position(&self, ecef: Vector3<f64>) -> Vector3<f32> {
+        (self.pos + self.rotation * (ecef - self.anchor) * self.scale).map(|v| v as f32)
+    }
+
+    /// ECEF position of the camera, which sits at the render space's origin.
+    fn camera(&self) -> Vector3<f64> {
+        self.anchor - self.rotation.transpose() * self.pos / self.scale
+    }
+
+    /// Camera-relative placement of a chunk in pool slot `page - 1`.
+    fn chunk(&self, placement: &ChunkPlacement, page: u32) -> ChunkTransform {
+        ChunkTransform {
+            pos: self.position(placement.frame.origin).into(),
+            page,
+            voxel_size: placement.voxel_size.map(|v| (v as f64 * self.scale) as f32),
+            _padding: 0.0,
+            axes: padded_columns(self.rotation * placement.frame.basis()),
+        }
+    }
+}
+
+// The shader indexes chunks with a single side length
+const _: () = assert!(LENGTH == HEIGHT && HEIGHT == WIDTH);
+
+/// Chunk pool layout for raygen.wgsl, prepended to it so the shader reads slots the way the
+/// streamer fills them.
+fn shader_constants() -> String {
+    format!(
+        "const CHUNK_SIZE = {}u;\nconst LOD_COUNT = {}u;\nconst SLOT_VOXELS = {}u;\n\n",
+        LENGTH,
+        LodChain::level_count([LENGTH, HEIGHT, WIDTH]),
+        SLOT_VOXELS,
+    )
+}
+
+// Undulation grids to convert heights from the `from` datum to `to`
+fn load_geoids(
+    from: VerticalDatum,
+    to: VerticalDatum,
+) -> Result<Vec<Geoid>, Box<dyn std::error::Error>> {
+    let mut geoids = Vec::new();
+    if from != to {
+        for datum in [from, to] {
+            if datum != VerticalDatum::Wgs84Ellipsoid {
+                geoids.push(Geoid::load(datum)?);
+            }
+        }
+    }
+    Ok(geoids)
+}
+
+// Loads an optional input from `path` when the file is there. One that fails to load is
+// reported and left out rather than stopping the app
+fn load_optional<T>(
+    path: &str,
+    load: impl FnOnce(&Path) -> Result<T, Box<dyn std::error::Error>>,
+) -> Option<T> {
+    let path = Path::new(path);
+    if !path.exists() {
+        return None;
+    }
+    load(path)
+        .map_err(|e| eprintln!("couldn't load {}: {e}", path.display()))
+        .ok()
+}
+
+// Settings next to an input, or the defaults when there's no such file
+fn load_or_default<T: Default>(
+    path: &str,
+    load: impl FnOnce(&Path) -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let path = Path::new(path);
+    if path.exists() {
+        load(path)
+    } else {
+        Ok(T::default())
+    }
+}
+
+// The DEM with the seafloor filled in from the bathymetry. Without bathymetry its voids take
+// the nearest elevation instead
+fn load_heightmap(path: &Path) -> Result<Heightmap, Box<dyn std::error::Error>> {
+    let land = processor::read_elevation_geotiff(path)?;
+    if !Path::new(BATHYMETRY).exists() {
+        return Ok(processor::fill_voids(&land));
+    }
+
+    let bathymetry = processor::read_elevation_geotiff(Path::new(BATHYMETRY))?;
+    let geoids = load_geoids(bathymetry.datum, land.datum)?;
+    let geoids: Vec<&Geoid> = geoids.iter().collect();
+    let bathymetry = processor::convert_datum(&bathymetry, land.datum, &geoids)?;
+    Ok(processor::merge_bathymetry(&land, &bathymetry))
+}
+
+// Land-cover classes and the materials they map to
+fn load_land_cover(path: &Path) -> Result<(LandCover, LandCoverTable), Box<dyn std::error::Error>> {
+    let table = load_or_default(LAND_COVER_CLASSES, LandCoverTable::load)?;
+    Ok((LandCover::from_geotiff(path)?, table))
+}
+
+// Material rules and the terrain attributes they match against
+fn load_rules(
+    path: &Path,
+    heightmap: &Heightmap,
+    water: &WaterMap,
+) -> Result<(RuleSet, AttributeMap), Box<dyn std::error::Error>> {
+    let rules = RuleSet::load(path)?;
+    let attributes = AttributeMap::compute(heightmap, Some(&water.mask()));
+    Ok((rules, attributes))
+}
+
+// Buildings, roads and water areas from an OpenStreetMap extract
+fn load_osm(path: &Path, heightmap: &Heightmap) -> Result<OsmFeatures, Box<dyn std::error::Error>> {
+    let options = load_or_default(OSM_OPTIONS, OsmOptions::load)?;
+    let data = OsmData::load(path)?;
+    Ok(OsmFeatures::from_data(&data, heightmap, &options))
+}
+
+// Points of a LiDAR cloud, in the heightmap's vertical datum
+fn load_lidar(
+    path: &Path,
+    heightmap: &Heightmap,
+) -> Result<(LidarPoints, ClassTable), Box<dyn std::error::Error>> {
+    let table = load_or_default(LIDAR_CLASSES, ClassTable::load)?;
+    let cloud = PointCloud::load(path)?;
+    let datum = cloud.vertical_datum.unwrap_or(heightmap.datum);
+    let geoids = load_geoids(datum, heightmap.datum)?;
+    let geoids: Vec<&Geoid> = geoids.iter().collect();
+    Ok((LidarPoints::from_cloud(&cloud, heightmap, &geoids)?, table))
 }
 
 fn create_render_pipeline(
@@ -308,16 +823,52 @@ struct State {
     camera_buffer: wgpu::Buffer,
     ray_origin_buffer: wgpu::Buffer,
 
+    volume_origin: WorldPos,
+    volume_frame: Option<LocalFrame>,
+    volume_transform_buffer: wgpu::Buffer,
+
     screen_res_buffer: wgpu::Buffer,
     pixels_texture: wgpu::Texture,
 
     render_bind_group: wgpu::BindGroup,
     compute_bind_group: wgpu::BindGroup,
 
+    voxels_buffer: wgpu::Buffer,
+    page_table_buffer: wgpu::Buffer,
+    pages_buffer: wgpu::Buffer,
+    water_buffer: wgpu::Buffer,
+    /// What the world's chunks are voxelized from, shared with the streamer's workers.
+    source: Arc<GeneratedSource>,
+    streamer: ChunkStreamer,
+    /// The streamed chunk in each pool slot, for where it's drawn and picking. The edited
+    /// chunk's slot is left empty, it's the editor's.
+    slot_chunks: Vec<Option<VoxelChunk>>,
+    /// Chunk pool slot of the edited chunk, chunk (0, 0), which is pinned rather than streamed.
+    editor_slot: usize,
+    editor: Editor,
+    lods: LodChain,
+
     compute_pipeline: wgpu::ComputePipeline,
     render_pipeline: wgpu::RenderPipeline,
 
     mouse_pressed: bool,
+    /// Cursor position in pixels, while it's over the window.
+    cursor: Option<[f32; 2]>,
+    materials: Vec<VoxelMaterial>,
+    tool: Tool,
+    tool_material: u32,
+    /// MagicaVoxel models loaded from `PROP_DIR`, by name.
+    props: Vec<(String, Template)>,
+    /// Index in `props` of the one the stamp tool places.
+    tool_prop: usize,
+    /// Voxel under the cursor, and the pool slot of the chunk it's in.
+    hover: Option<(usize, PickHit)>,
+    overlays: Vec<(OverlayLayer, DrapedLayer)>,
+    /// Animate the water surface with waves.
+    waves: bool,
+    /// Multiplier on `WATER_ABSORPTION`, higher for murkier water.
+    water_turbidity: f32,
+    started: Instant,
 }
 
 impl State {
@@ -405,7 +956,7 @@ impl State {
         });
 
         let camera = camera::Camera::new(
-            (0.0, 3.0, -7.0),
+            cgmath::Point3::new(0.0, 3.0, -7.0),
             cgmath::Deg(90.0),
             cgmath::Deg(-20.0),
             size,
@@ -518,19 +1069,126 @@ impl State {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             });
 
         let ray_origin_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Ray origin"),
             contents: bytemuck::cast_slice(&[RayOrigin {
-                pos: camera.position.into(),
+                pos: [0.0; 3],
                 _padding: 0.0,
             }]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let voxels = terrain::voxelizer::generate_volume();
+        let heightmap = load_optional(DEM, load_heightmap).unwrap_or_else(|| {
+            let heightmap = terrain::generator::generate(
+                &GeneratorOptions::default(),
+                256,
+                256,
+                GeoBounds {
+                    north: GLOBE_ORIGIN.lat,
+                    south: GLOBE_ORIGIN.lat - 0.25,
+                    east: GLOBE_ORIGIN.lon + 0.25,
+                    west: GLOBE_ORIGIN.lon,
+                },
+            );
+            terrain::erosion::erode(&heightmap, &ErosionOptions::default())
+        });
+        let land_cover = load_optional(LAND_COVER, load_land_cover);
+        // Permanent water in the land cover marks lakes the heightmap alone can't tell apart
+        // from dry depressions
+        let lakes = land_cover.as_ref().map(|(cover, _)| {
+            WaterMap::lake_mask(&heightmap, cover, |class| class == PERMANENT_WATER)
+        });
+        let water = WaterMap::compute(&heightmap, lakes.as_deref(), &WaterOptions::default());
+        let rules = load_optional(MATERIAL_RULES, |path| load_rules(path, &heightmap, &water));
+        let osm = load_optional(OSM_EXTRACT, |path| load_osm(path, &heightmap));
+        let lidar = load_optional(LIDAR_CLOUD, |path| load_lidar(path, &heightmap));
+        let source = GeneratedSource {
+            heightmap,
+            chunks: WORLD_CHUNKS,
+            land_cover,
+            rules,
+            water: Some(water),
+            density: Some(DensityOptions::default()),
+            lidar,
+            osm,
+            vegetation: Some((
+                VegetationTable::default(),
+                TemplateLibrary::default(),
+                VegetationOptions::default(),
+            )),
+        };
+
+        let materials = terrain::voxelizer::default_materials();
+        let props = VoxFile::load_templates(Path::new(PROP_DIR), &materials).unwrap_or_else(|e| {
+            eprintln!("couldn't load props: {e}");
+            Vec::new()
+        });
+
+        let overlays = OverlayLayer::load_dir(Path::new(OVERLAY_DIR))
+            .unwrap_or_else(|e| {
+                eprintln!("couldn't load overlays: {e}");
+                Vec::new()
+            })
+            .into_iter()
+            .map(|layer| {
+                let draped = layer.drape(&source.heightmap, WORLD_CHUNKS);
+                (layer, draped)
+            })
+            .collect();
+
+        // Chunk (0, 0) is built up front for editing, the rest stream in around the camera
+        let chunk = terrain::streaming::ChunkSource::load(&source, [0, 0])
+            .unwrap()
+            .expect("generated world has a first chunk");
+        let voxels = chunk.voxels.decode();
+        let lods = LodChain::build(&voxels, chunk.voxels.size(), LodSelection::Representative);
+
+        let source = Arc::new(source);
+        let mut streamer = ChunkStreamer::new(source.clone(), StreamingOptions::default()).unwrap();
+        let editor_slot = streamer.pin([0, 0]);
 
         let scene_props_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Scene props"),
@@ -540,6 +1198,7 @@ impl State {
                 voxel_count: voxels.len() as u32,
                 ray_offset: 0.0,
                 ray_bounces: 8.0,
+                lod_bias: 0.0,
             }]),
             usage: wgpu::BufferUsages::UNIFORM,
         });
@@ -550,10 +1209,54 @@ impl State {
             usage: wgpu::BufferUsages::UNIFORM,
         });
 
-        let voxels_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Voxels"),
-            contents: bytemuck::cast_slice(&voxels),
-            usage: wgpu::BufferUsages::STORAGE,
+        let voxels_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk pool"),
+            size: (streamer.capacity() * SLOT_VOXELS * std::mem::size_of::<u32>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut slot_voxels = lods.flatten();
+        slot_voxels.extend(chunk.surface_words());
+        queue.write_buffer(
+            &voxels_buffer,
+            (editor_slot * SLOT_VOXELS * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            bytemuck::cast_slice(&slot_voxels),
+        );
+
+        let slot_chunks = vec![None; streamer.capacity()];
+
+        // The window and its chunks are placed relative to the camera, so these are rewritten
+        // every frame
+        let page_table_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Page table"),
+            size: std::mem::size_of::<PageTableInfo>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let pages_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Pages"),
+            size: (streamer.side() * streamer.side() * std::mem::size_of::<ChunkTransform>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let editor = Editor::new(chunk);
+
+        // Rewritten every frame with the current time and settings
+        let water_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Water props"),
+            size: std::mem::size_of::<WaterProps>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let volume_origin = WorldPos::from_f64(Vector3::new(-1.6, -1.6, -1.6));
+        let volume_transform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Volume transform"),
+            size: std::mem::size_of::<VolumeTransform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
 
         let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -588,6 +1291,22 @@ impl State {
                     binding: 6,
                     resource: voxels_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: volume_transform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: page_table_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: pages_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: water_buffer.as_entire_binding(),
+                },
             ],
         });
 
@@ -600,7 +1319,9 @@ impl State {
 
         let compute_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Raygen"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("raygen.wgsl").into()),
+            source: wgpu::ShaderSource::Wgsl(
+                (shader_constants() + include_str!("raygen.wgsl")).into(),
+            ),
         });
 
         let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
@@ -695,13 +1416,39 @@ impl State {
             camera_buffer,
             ray_origin_buffer,
 
+            volume_origin,
+            volume_frame: None,
+            volume_transform_buffer,
+
             compute_bind_group,
             render_bind_group,
 
+            voxels_buffer,
+            page_table_buffer,
+            pages_buffer,
+            water_buffer,
+            source,
+            streamer,
+            slot_chunks,
+            editor_slot,
+            editor,
+            lods,
+
             compute_pipeline,
             render_pipeline,
 
             mouse_pressed: false,
+            cursor: None,
+            materials,
+            tool: Tool::Inspect,
+            tool_material: terrain::voxelizer::STONE,
+            props,
+            tool_prop: 0,
+            hover: None,
+            overlays,
+            waves: true,
+            water_turbidity: 1.0,
+            started: Instant::now(),
         }
     }
 
@@ -766,6 +1513,31 @@ impl State {
         }
     }
 
+    /// Tangent frame of the edited chunk, at the world's north-west corner, which the camera
+    /// starts over on the globe.
+    fn globe_frame(&self) -> LocalFrame {
+        self.editor.chunk().frame()
+    }
+
+    /// Places the world on the globe, each chunk in its own tangent frame, with the camera
+    /// flying above `frame`, or back on the flat grid for `None`.
+    fn place_volume(&mut self, frame: Option<LocalFrame>) {
+        match &frame {
+            Some(frame) => {
+                let extent = (LENGTH as f32 * self.editor.chunk().voxel_size[0]) as f64;
+                self.camera.position =
+                    WorldPos::from_f64(frame.to_ecef(Vector3::new(extent / 2.0, extent, -extent)));
+            }
+            None => {
+                self.volume_origin = WorldPos::from_f64(Vector3::new(-1.6, -1.6, -1.6));
+                self.camera.position = WorldPos::from_f64(Vector3::new(0.0, 3.0, -7.0));
+            }
+        }
+
+        self.camera.geodetic_up = frame.is_some();
+        self.volume_frame = frame;
+    }
+
     fn update(&mut self, dt: instant::Duration) {
         self.camera.update_camera(dt);
 
@@ -775,14 +1547,314 @@ impl State {
             bytemuck::cast_slice(&[self.camera.get_uniform()]),
         );
 
+        // Floating origin: the camera sits at the world origin and the scene is rebased around it
         self.queue.write_buffer(
             &self.ray_origin_buffer,
             0,
             bytemuck::cast_slice(&[RayOrigin {
-                pos: self.camera.position.into(),
+                pos: Vector3::zero().into(),
                 _padding: 0.0,
             }]),
         );
+
+        self.queue.write_buffer(
+            &self.water_buffer,
+            0,
+            bytemuck::cast_slice(&[WaterProps {
+                absorption: WATER_ABSORPTION.map(|a| a * self.water_turbidity),
+                wave_strength: if self.waves { WAVE_STRENGTH } else { 0.0 },
+                time: self.started.elapsed().as_secs_f32(),
+                _padding: [0.0; 3],
+            }]),
+        );
+
+        // Stream the chunks around the camera. Which chunk it's over is found on the grid of
+        // the edited chunk's tangent frame, which across the window strays from the chunks'
+        // own frames by a fraction of a chunk
+        let space = self.render_space();
+        let reference = self.editor.chunk().placement();
+        let chunk_length = [
+            LENGTH as f64 * reference.voxel_size[0] as f64,
+            WIDTH as f64 * reference.voxel_size[2] as f64,
+        ];
+        let camera_local = reference.frame.to_local(space.camera());
+        let center = [
+            (camera_local.x / chunk_length[0]).floor() as i32,
+            (camera_local.z / chunk_length[1]).floor() as i32,
+        ];
+        for upload in self.streamer.update(center) {
+            self.queue.write_buffer(
+                &self.voxels_buffer,
+                (upload.slot * SLOT_VOXELS * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+                bytemuck::cast_slice(&upload.voxels),
+            );
+            self.slot_chunks[upload.slot] = Some(upload.chunk);
+        }
+
+        // The window is walked in steps of a chunk from its north-west corner, and each of its
+        // chunks is traced in its own frame
+        let origin = self.streamer.origin();
+        let corner = reference.frame.to_ecef(Vector3::new(
+            origin[0] as f64 * chunk_length[0],
+            0.0,
+            origin[1] as f64 * chunk_length[1],
+        ));
+        let window = VolumeTransform {
+            pos: space.position(corner).into(),
+            voxel_length: (reference.voxel_size[0] as f64 * space.scale) as f32,
+            axes: padded_columns(space.rotation * reference.frame.basis()),
+        };
+        let page_table = PageTableInfo {
+            chunk_length: chunk_length.map(|length| (length * space.scale) as f32),
+            side: self.streamer.side() as i32,
+            _padding: 0,
+        };
+        let pages = self.streamer.page_table();
+        let transforms: Vec<ChunkTransform> = pages
+            .iter()
+            .map(|&page| {
+                let chunk = page
+                    .checked_sub(1)
+                    .and_then(|slot| self.slot_chunk(slot as usize));
+                chunk.map_or(bytemuck::Zeroable::zeroed(), |chunk| {
+                    space.chunk(&chunk.placement(), page)
+                })
+            })
+            .collect();
+        self.queue.write_buffer(
+            &self.volume_transform_buffer,
+            0,
+            bytemuck::cast_slice(&[window]),
+        );
+        self.queue.write_buffer(
+            &self.page_table_buffer,
+            0,
+            bytemuck::cast_slice(&[page_table]),
+        );
+        self.queue
+            .write_buffer(&self.pages_buffer, 0, bytemuck::cast_slice(&transforms));
+
+        // Pick through the same chunks, placed the same way, as the shader traces
+        let axis = |column: [f32; 4]| Vector3::new(column[0], column[1], column[2]);
+        let [east, up, south] = window.axes;
+        let pick_window = PickWindow {
+            pos: window.pos.into(),
+            axes: Matrix3::from_cols(axis(east), axis(up), axis(south)),
+            chunk_length: page_table.chunk_length,
+            side: self.streamer.side(),
+            chunks: pages
+                .iter()
+                .zip(&transforms)
+                .map(|(&page, transform)| {
+                    let chunk = self.slot_chunk(page.checked_sub(1)? as usize)?;
+                    Some(PlacedVoxels {
+                        pos: transform.pos.into(),
+                        axes: transform.basis(),
+                        voxel_size: transform.voxel_size.into(),
+                        voxels: &chunk.voxels,
+                    })
+                })
+                .collect(),
+        };
+        let hover = self.cursor.and_then(|cursor| {
+            let direction = self.camera.ray_direction(
+                cursor,
+                [self.config.width as f32, self.config.height as f32],
+            );
+            let (index, hit) =
+                picking::pick_window(Vector3::zero(), direction, &pick_window, f32::INFINITY)?;
+            Some((pages[index] as usize - 1, hit))
+        });
+        self.hover = hover;
+
+        // Re-upload only the slices of the full-resolution level that edits touched. The
+        // coarser levels are a seventh of its size, so they're rebuilt and sent whole
+        if let Some(region) = self.editor.take_dirty() {
+            let slot = self.editor_slot * SLOT_VOXELS;
+            let size = self.editor.chunk().voxels.size();
+            for span in region.spans(size) {
+                self.queue.write_buffer(
+                    &self.voxels_buffer,
+                    ((slot + span.start) * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+                    bytemuck::cast_slice(&self.editor.span_voxels(span)),
+                );
+            }
+
+            self.lods.update(&self.editor.chunk().voxels.decode());
+            self.queue.write_buffer(
+                &self.voxels_buffer,
+                ((slot + self.lods.level_offset(1)) * std::mem::size_of::<u32>())
+                    as wgpu::BufferAddress,
+                bytemuck::cast_slice(&self.lods.levels[1..].concat()),
+            );
+
+            // Carving or building on the terrain changes which columns keep a partly filled
+            // surface voxel. Recomputing them is cheap, only the touched columns are sent
+            let heightmap = &self.source.heightmap;
+            let chunk_region = terrain::jobs::chunk_region(
+                heightmap,
+                self.source.water.as_ref(),
+                self.source.chunks,
+                [0, 0],
+            );
+            self.editor.refresh_surface(heightmap, &chunk_region);
+            let surface = slot + SLOT_VOXELS - size[0] * size[2];
+            let words = self.editor.chunk().surface_words();
+            for columns in region.columns(size[2]) {
+                self.queue.write_buffer(
+                    &self.voxels_buffer,
+                    ((surface + columns.start) * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+                    bytemuck::cast_slice(&words[columns]),
+                );
+            }
+        }
+    }
+
+    /// The chunk in pool slot `slot`, the edited one included.
+    fn slot_chunk(&self, slot: usize) -> Option<&VoxelChunk> {
+        if slot == self.editor_slot {
+            Some(self.editor.chunk())
+        } else {
+            self.slot_chunks[slot].as_ref()
+        }
+    }
+
+    /// Camera-relative space the world is rendered in. On the flat grid the edited chunk's
+    /// corner sits at `volume_origin`.
+    fn render_space(&self) -> RenderSpace {
+        let reference = self.editor.chunk().placement();
+        let anchor = reference.frame.origin;
+        match self.volume_frame {
+            Some(_) => RenderSpace {
+                anchor,
+                pos: WorldPos::from_f64(anchor)
+                    .relative_to(&self.camera.position)
+                    .map(|v| v as f64),
+                rotation: Matrix3::identity(),
+                scale: 1.0,
+            },
+            None => RenderSpace {
+                anchor,
+                pos: self
+                    .volume_origin
+                    .relative_to(&self.camera.position)
+                    .map(|v| v as f64),
+                rotation: reference.frame.basis().transpose(),
+                scale: (VOXEL_LENGTH / reference.voxel_size[0]) as f64,
+            },
+        }
+    }
+
+    /// Projects the visible overlay layers to the screen. Segments with an end behind the
+    /// camera are left out rather than clipped.
+    fn overlay_shapes(&self, scale_factor: f32) -> Vec<OverlayShapes> {
+        let space = self.render_space();
+        let resolution = [self.config.width as f32, self.config.height as f32];
+        let screen = |&[lat, lon, height]: &[f64; 3]| {
+            let position = space.position(geodesy::geodetic_to_ecef(lat, lon, height));
+            let [x, y] = self.camera.project(position, resolution)?;
+            Some([x / scale_factor, y / scale_factor])
+        };
+
+        self.overlays
+            .iter()
+            .filter(|(layer, _)| layer.visible)
+            .map(|(layer, draped)| {
+                let lines = draped
+                    .lines
+                    .iter()
+                    .flat_map(|line| line.windows(2))
+                    .filter_map(|pair| Some([screen(&pair[0])?, screen(&pair[1])?]))
+                    .collect();
+                let [r, g, b] = layer.color;
+                OverlayShapes {
+                    color: [r, g, b, 1.0],
+                    lines,
+                    points: draped.points.iter().filter_map(screen).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Applies the current tool to the voxel under the cursor, when it's in the edited chunk.
+    fn use_tool(&mut self) {
+        let Some((slot, hit)) = self.hover else {
+            return;
+        };
+        if slot != self.editor_slot {
+            return;
+        }
+        match self.tool {
+            Tool::Inspect => {}
+            Tool::Place => {
+                self.editor.apply(
+                    &Brush::Set {
+                        position: hit.adjacent(),
+                    },
+                    self.tool_material,
+                );
+            }
+            Tool::Remove => {
+                self.editor.apply(
+                    &Brush::Set {
+                        position: hit.voxel,
+                    },
+                    AIR,
+                );
+            }
+            Tool::Stamp => {
+                if let Some((_, template)) = self.props.get(self.tool_prop) {
+                    self.editor.apply(
+                        &Brush::Stamp {
+                            origin: hit.adjacent(),
+                            template: template.clone(),
+                        },
+                        AIR,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Voxelizes every chunk of the world, swaps in the edited one and writes them all to
+    /// `WORLD_FILE`. Blocks until it's written.
+    fn save_world(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut world =
+            terrain::jobs::voxelize_chunks(&self.source.sources(), self.source.chunks, None)?;
+        world.insert(self.editor.chunk().clone());
+        world.save(Path::new(WORLD_FILE))
+    }
+
+    /// Writes the edited chunk to `VOX_EXPORT` as a single MagicaVoxel model.
+    fn export_vox(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let voxels = &self.editor.chunk().voxels;
+        VoxFile::from_region(voxels, [0; 3], voxels.size(), &self.materials)
+            .save(Path::new(VOX_EXPORT))
+    }
+
+    /// Meshes the edited chunk in meters at its voxel size and writes it as glTF or OBJ, chosen
+    /// by `path`'s extension.
+    fn export_mesh(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let chunk = self.editor.chunk();
+        let options = MeshOptions {
+            voxel_size: chunk.voxel_size,
+            ..MeshOptions::default()
+        };
+        let mesh = mesh::mesh_chunk(chunk, &options);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("glb") => mesh.save_glb(path, &self.materials),
+            _ => mesh.save_obj(path, &self.materials),
+        }
+    }
+
+    /// Geodetic `(lat, lon, height)` of the center of a voxel of the chunk in pool slot
+    /// `slot`, placed as it's drawn. `None` while the world is on the flat grid, where it isn't
+    /// drawn on the globe.
+    fn voxel_geodetic(&self, slot: usize, voxel: [i32; 3]) -> Option<(f64, f64, f64)> {
+        self.volume_frame?;
+        let center = voxel.map(|c| c as f64 + 0.5);
+        let placement = self.slot_chunk(slot)?.placement();
+        Some(geodesy::ecef_to_geodetic(placement.to_ecef(center)))
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -1,6 +1,14 @@
-use project_earth::run;
 use pollster;
+use project_earth::run;
 
 fn main() {
-    pollster::block_on(run());
+    match std::env::args().nth(1).as_deref() {
+        Some("download") => {
+            if let Err(e) = project_earth::download() {
+                eprintln!("Download failed: {e}");
+                std::process::exit(1);
+            }
+        }
+        _ => pollster::block_on(run()),
+    }
 }
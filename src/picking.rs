@@ -0,0 +1,416 @@
+use cgmath::*;
+
+use crate::terrain::palette::ChunkStorage;
+use crate::terrain::voxelizer::AIR;
+
+/// Voxel under a ray, as found by `pick`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PickHit {
+    pub voxel: [i32; 3],
+    /// Outward normal of the face the ray entered through, in voxel axes. When the ray starts
+    /// inside a solid voxel, the face back towards its origin along the axis it travels most.
+    pub normal: [i32; 3],
+    /// Distance along the ray to the face, in world units.
+    pub distance: f32,
+    pub material: u32,
+}
+
+/// Voxels placed like `pick`'s volume.
+pub struct PlacedVoxels<'a> {
+    pub pos: Vector3<f32>,
+    pub axes: Matrix3<f32>,
+    pub voxel_size: Vector3<f32>,
+    pub voxels: &'a ChunkStorage,
+}
+
+/// A square of `side` by `side` chunks, column-major like the page table, with its north-west
+/// corner at `pos` and `axes` as the world directions of its east, up and south. Each chunk
+/// spans `chunk_length` east and south, and has its own placement.
+pub struct PickWindow<'a> {
+    pub pos: Vector3<f32>,
+    pub axes: Matrix3<f32>,
+    pub chunk_length: [f32; 2],
+    pub side: usize,
+    pub chunks: Vec<Option<PlacedVoxels<'a>>>,
+}
+
+impl PickHit {
+    /// Empty voxel in front of the hit face, where a placed voxel goes.
+    pub fn adjacent(&self) -> [i32; 3] {
+        std::array::from_fn(|i| self.voxel[i] + self.normal[i])
+    }
+}
+
+/// Walks a world-space ray with a unit `direction` through a volume placed at `volume_pos`
+/// with `axes` as the world directions of its x, y and z and `voxel_size` world units per voxel
+/// along each, returning the first solid voxel within `max_distance`. Uses the same slab test
+/// and DDA as `trace_placed_chunk` in raygen.wgsl, so it picks what's on screen.
+pub fn pick(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    volume_pos: Vector3<f32>,
+    axes: Matrix3<f32>,
+    voxel_size: Vector3<f32>,
+    voxels: &ChunkStorage,
+    max_distance: f32,
+) -> Option<PickHit> {
+    let size = voxels.size().map(|n| n as i32);
+
+    // Axes are orthonormal, so the transpose takes world directions into the volume. The
+    // direction keeps its scale so distances along the ray stay in world units
+    let to_local = axes.transpose();
+    let local_origin = (to_local * (origin - volume_pos)).div_element_wise(voxel_size);
+    let local_direction = (to_local * direction).div_element_wise(voxel_size);
+
+    // Clip the ray to the volume's box
+    let mut t_min = 0.0f32;
+    let mut t_max = max_distance;
+    let mut entry_axis = None;
+    for i in 0..3 {
+        if local_direction[i].abs() < 1e-8 {
+            if local_origin[i] < 0.0 || local_origin[i] > size[i] as f32 {
+                return None;
+            }
+        } else {
+            let t1 = -local_origin[i] / local_direction[i];
+            let t2 = (size[i] as f32 - local_origin[i]) / local_direction[i];
+            if t1.min(t2) > t_min {
+                t_min = t1.min(t2);
+                entry_axis = Some(i);
+            }
+            t_max = t_max.min(t1.max(t2));
+        }
+    }
+    if t_min > t_max {
+        return None;
+    }
+
+    let start = local_origin + local_direction * t_min;
+    let mut voxel: [i32; 3] =
+        std::array::from_fn(|i| (start[i].floor() as i32).clamp(0, size[i] - 1));
+    let step: [i32; 3] = std::array::from_fn(|i| if local_direction[i] > 0.0 { 1 } else { -1 });
+    let delta: [f32; 3] = std::array::from_fn(|i| (1.0 / local_direction[i]).abs());
+    let mut next: [f32; 3] = std::array::from_fn(|i| {
+        if local_direction[i].abs() < 1e-8 {
+            return f32::INFINITY;
+        }
+        let boundary = if step[i] > 0 {
+            voxel[i] as f32 + 1.0
+        } else {
+            voxel[i] as f32
+        };
+        t_min + (boundary - start[i]) / local_direction[i]
+    });
+
+    // The face the ray came in through. Starting inside, the one it would have come in
+    // through along its main axis
+    let mut normal = [0; 3];
+    let mut t = t_min;
+    let axis = entry_axis.unwrap_or_else(|| {
+        (0..3)
+            .max_by(|&a, &b| {
+                local_direction[a]
+                    .abs()
+                    .total_cmp(&local_direction[b].abs())
+            })
+            .unwrap()
+    });
+    normal[axis] = -step[axis];
+
+    loop {
+        let material = voxels.get(voxel.map(|c| c as usize));
+        if material != AIR {
+            return Some(PickHit {
+                voxel,
+                normal,
+                distance: t,
+                material,
+            });
+        }
+
+        let axis = (0..3).min_by(|&a, &b| next[a].total_cmp(&next[b])).unwrap();
+        t = next[axis];
+        if t > t_max {
+            return None;
+        }
+        voxel[axis] += step[axis];
+        if voxel[axis] < 0 || voxel[axis] >= size[axis] {
+            return None;
+        }
+        next[axis] += delta[axis];
+        normal = [0; 3];
+        normal[axis] = -step[axis];
+    }
+}
+
+/// Walks a world-space ray with a unit `direction` through the chunks of `window` in the order
+/// `trace_ray` in raygen.wgsl visits them, returning the index in `window.chunks` of the first
+/// chunk with a solid voxel within `max_distance`, and the hit in that chunk.
+pub fn pick_window(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    window: &PickWindow,
+    max_distance: f32,
+) -> Option<(usize, PickHit)> {
+    let side = window.side as i32;
+
+    // In chunks from the window's corner, east and south
+    let to_window = window.axes.transpose();
+    let local_origin = to_window * (origin - window.pos);
+    let local_direction = to_window * direction;
+    let o = [
+        local_origin.x / window.chunk_length[0],
+        local_origin.z / window.chunk_length[1],
+    ];
+    let d = [
+        local_direction.x / window.chunk_length[0],
+        local_direction.z / window.chunk_length[1],
+    ];
+
+    let mut t_min = 0.0f32;
+    let mut t_max = max_distance;
+    for i in 0..2 {
+        if d[i].abs() < 1e-8 {
+            if o[i] < 0.0 || o[i] > side as f32 {
+                return None;
+            }
+        } else {
+            let t1 = -o[i] / d[i];
+            let t2 = (side as f32 - o[i]) / d[i];
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+        }
+    }
+    if t_min > t_max {
+        return None;
+    }
+
+    let start: [f32; 2] = std::array::from_fn(|i| o[i] + d[i] * t_min);
+    let mut chunk: [i32; 2] = std::array::from_fn(|i| (start[i].floor() as i32).clamp(0, side - 1));
+    let step: [i32; 2] = std::array::from_fn(|i| if d[i] > 0.0 { 1 } else { -1 });
+    let delta: [f32; 2] = std::array::from_fn(|i| (1.0 / d[i]).abs());
+    let mut next: [f32; 2] = std::array::from_fn(|i| {
+        if d[i].abs() < 1e-8 {
+            return f32::INFINITY;
+        }
+        let boundary = if step[i] > 0 { chunk[i] + 1 } else { chunk[i] } as f32;
+        t_min + (boundary - start[i]) / d[i]
+    });
+
+    loop {
+        let index = (chunk[0] * side + chunk[1]) as usize;
+        if let Some(placed) = &window.chunks[index] {
+            let hit = pick(
+                origin,
+                direction,
+                placed.pos,
+                placed.axes,
+                placed.voxel_size,
+                placed.voxels,
+                max_distance,
+            );
+            if let Some(hit) = hit {
+                return Some((index, hit));
+            }
+        }
+
+        let axis = if next[0] < next[1] { 0 } else { 1 };
+        if next[axis] >= t_max {
+            return None;
+        }
+        chunk[axis] += step[axis];
+        if chunk[axis] < 0 || chunk[axis] >= side {
+            return None;
+        }
+        next[axis] += delta[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::terrain::voxelizer::{GRASS, STONE};
+
+    fn ground() -> ChunkStorage {
+        let size = [8, 8, 8];
+        let mut voxels = vec![AIR; 8 * 8 * 8];
+        for x in 0..8 {
+            for z in 0..8 {
+                voxels[(x * 8) * 8 + z] = STONE;
+                voxels[(x * 8 + 1) * 8 + z] = GRASS;
+            }
+        }
+        ChunkStorage::encode(size, &voxels)
+    }
+
+    #[test]
+    fn looking_down_hits_the_top_face() {
+        let hit = pick(
+            Vector3::new(3.5, 10.0, 4.5),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::zero(),
+            Matrix3::identity(),
+            Vector3::new(1.0, 1.0, 1.0),
+            &ground(),
+            100.0,
+        )
+        .unwrap();
+
+        assert_eq!(hit.voxel, [3, 1, 4]);
+        assert_eq!(hit.normal, [0, 1, 0]);
+        assert_eq!(hit.material, GRASS);
+        assert!((hit.distance - 8.0).abs() < 1e-4);
+        assert_eq!(hit.adjacent(), [3, 2, 4]);
+    }
+
+    #[test]
+    fn follows_the_volume_placement() {
+        // Volume rotated a quarter turn about y, so its x axis points along world -z
+        let axes = Matrix3::from_cols(
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+        );
+        let hit = pick(
+            Vector3::new(100.45, 0.15, -2.0),
+            Vector3::unit_z(),
+            Vector3::new(100.0, 0.0, 0.0),
+            axes,
+            Vector3::new(0.1, 0.1, 0.1),
+            &ground(),
+            100.0,
+        )
+        .unwrap();
+
+        // Travels down the volume's x axis into the far side of the grass layer
+        assert_eq!(hit.voxel, [7, 1, 4]);
+        assert_eq!(hit.normal, [1, 0, 0]);
+        assert!((hit.distance - 1.2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn stretches_with_the_voxel_size() {
+        // Columns 10 wide east and 20 south, layers half a unit tall
+        let hit = pick(
+            Vector3::new(35.0, 10.0, 90.0),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::zero(),
+            Matrix3::identity(),
+            Vector3::new(10.0, 0.5, 20.0),
+            &ground(),
+            100.0,
+        )
+        .unwrap();
+
+        assert_eq!(hit.voxel, [3, 1, 4]);
+        assert_eq!(hit.normal, [0, 1, 0]);
+        assert!((hit.distance - 9.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn cursor_rays_match_the_view() {
+        let camera = Camera::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Deg(0.0),
+            Deg(0.0),
+            winit::dpi::PhysicalSize::new(800, 600),
+            Deg(90.0),
+            0.1,
+            100.0,
+        );
+
+        // Yaw 0 looks down +x, the screen's top edge sits 45 degrees above it
+        let center = camera.ray_direction([400.0, 300.0], [800.0, 600.0]);
+        assert!((center - Vector3::unit_x()).magnitude() < 1e-5);
+        let top = camera.ray_direction([400.0, 0.0], [800.0, 600.0]);
+        assert!((top - Vector3::new(1.0, 1.0, 0.0).normalize()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn starting_inside_faces_back_along_the_ray() {
+        let hit = pick(
+            Vector3::new(3.5, 0.5, 4.5),
+            Vector3::new(0.3, -1.0, 0.0).normalize(),
+            Vector3::zero(),
+            Matrix3::identity(),
+            Vector3::new(1.0, 1.0, 1.0),
+            &ground(),
+            100.0,
+        )
+        .unwrap();
+
+        assert_eq!(hit.voxel, [3, 0, 4]);
+        assert_eq!(hit.normal, [0, 1, 0]);
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn walks_the_window_chunks_in_order() {
+        let storage = ground();
+        let placed = |pos: Vector3<f32>| PlacedVoxels {
+            pos,
+            axes: Matrix3::identity(),
+            voxel_size: Vector3::new(1.0, 1.0, 1.0),
+            voxels: &storage,
+        };
+        let window = |chunks| PickWindow {
+            pos: Vector3::zero(),
+            axes: Matrix3::identity(),
+            chunk_length: [8.0, 8.0],
+            side: 2,
+            chunks,
+        };
+        let cast = |window: &PickWindow| {
+            pick_window(
+                Vector3::new(-2.0, 1.5, 4.5),
+                Vector3::unit_x(),
+                window,
+                100.0,
+            )
+        };
+
+        // Only the chunk east of the corner is loaded, so the ray crosses the gap to it
+        let east = window(vec![
+            None,
+            None,
+            Some(placed(Vector3::new(8.0, 0.0, 0.0))),
+            None,
+        ]);
+        let (index, hit) = cast(&east).unwrap();
+        assert_eq!(index, 2);
+        assert_eq!(hit.voxel, [0, 1, 4]);
+        assert_eq!(hit.normal, [-1, 0, 0]);
+        assert!((hit.distance - 10.0).abs() < 1e-4);
+
+        let both = window(vec![
+            Some(placed(Vector3::zero())),
+            None,
+            Some(placed(Vector3::new(8.0, 0.0, 0.0))),
+            None,
+        ]);
+        let (index, hit) = cast(&both).unwrap();
+        assert_eq!(index, 0);
+        assert!((hit.distance - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn misses_outside_and_beyond_range() {
+        let storage = ground();
+        let cast = |origin: Vector3<f32>, direction: Vector3<f32>, range: f32| {
+            pick(
+                origin,
+                direction,
+                Vector3::zero(),
+                Matrix3::identity(),
+                Vector3::new(1.0, 1.0, 1.0),
+                &storage,
+                range,
+            )
+        };
+
+        assert!(cast(Vector3::new(3.5, 10.0, 4.5), Vector3::unit_y(), 100.0).is_none());
+        assert!(cast(Vector3::new(3.5, 10.0, 4.5), -Vector3::unit_y(), 5.0).is_none());
+        assert!(cast(Vector3::new(-5.0, 4.0, 4.0), Vector3::unit_x(), 100.0).is_none());
+    }
+}
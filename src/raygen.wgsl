@@ -1,13 +1,12 @@
-const SAMPLES: u32 = 1;
+// CHUNK_SIZE, the voxels along each side of a chunk, LOD_COUNT and SLOT_VOXELS are prepended
+// from the Rust side, see `shader_constants`
 
-const MATERIAL_COUNT = 8;
+const SAMPLES: u32 = 1;
 
-const VOXEL_COUNT = 32 * 32 * 32;
+const MATERIAL_COUNT = 17;
 
 const gamma = 1 / 2.2;
 
-const voxel_length = 0.1; 
-
 struct Camera {
     inverse_view: mat4x4<f32>,
     inverse_proj: mat4x4<f32>,
@@ -31,12 +30,51 @@ struct Material {
 //     voxels: array<u32>
 // }
 
+// Placement of the window of streamed chunks: its north-west corner relative to the camera and
+// the tangent frame there, axes mapping east, up and south to world space. `voxel_length` is
+// the column spacing east
+struct VolumeTransform {
+    pos: vec3<f32>,
+    voxel_length: f32,
+    axes: mat3x3<f32>,
+}
+
+// Placement of one chunk of the window relative to the camera, axes map its local x, y and z to
+// world space
+struct ChunkTransform {
+    pos: vec3<f32>,
+    // Pool slot plus one, zero where no chunk is loaded
+    page: u32,
+    // Render units per voxel along x, y and z
+    voxel_size: vec3<f32>,
+    axes: mat3x3<f32>,
+}
+
 struct SceneProps {
     sunIntensity: f32,
     lightsIntensity: f32,
     voxel_count: f32,
     rayOffset: f32,
     rayBounces: f32,
+    // Added to every ray's LOD, negative values keep full detail further out
+    lodBias: f32,
+}
+
+// Water is traced as a medium rather than a solid voxel
+struct WaterProps {
+    // Beer-Lambert absorption coefficients of red, green and blue light in 1/m
+    absorption: vec3<f32>,
+    // Tilt of the animated wave normals, zero for a flat surface
+    wave_strength: f32,
+    // Seconds since startup, moves the waves
+    time: f32,
+}
+
+// The square of streamed chunks around the camera, `side` chunks across. Rays walk it in the
+// window's frame in steps of `chunk_length` east and south
+struct PageTable {
+    chunk_length: vec2<f32>,
+    side: i32,
 }
 
 @group(0) @binding(0) var pixels: texture_storage_2d<rgba32float, write>;
@@ -46,7 +84,14 @@ struct SceneProps {
 @group(0) @binding(4) var<uniform> sceneProps: SceneProps;
 @group(0) @binding(5) var<uniform> random_seed: f32;
 // @group(0) @binding(6) var<uniform> materials: array<Material, MATERIAL_COUNT>;
-@group(0) @binding(6) var<storage> voxels: array<array<array<u32, 32>, 32>, 32>;
+// Chunk pool, each slot holds every LOD level of one chunk back to back, see lod_offset, then
+// the surface height of each column, see surface_at
+@group(0) @binding(6) var<storage> voxels: array<u32>;
+@group(0) @binding(7) var<uniform> volume_transform: VolumeTransform;
+@group(0) @binding(8) var<uniform> page_table: PageTable;
+// Each chunk of the window, column-major from its north-west corner
+@group(0) @binding(9) var<storage> pages: array<ChunkTransform>;
+@group(0) @binding(10) var<uniform> water: WaterProps;
 
 struct Ray {
     o: vec3<f32>,
@@ -88,6 +133,8 @@ struct Intersection {
     intersects: bool,
     tmin: f32,
     tmax: f32,
+    // Outward normal of the face the ray enters through, zero when it starts inside
+    normal: vec3<f32>,
 }
 
 const SUN_DIRECTION: vec3<f32> = vec3<f32>(0.8, 1.0, 0.6);
@@ -97,7 +144,7 @@ const AMBIENT_LIGHT: vec3<f32> = vec3<f32>(0.1, 0.15, 0.2);
 fn calculate_lighting(normal: vec3<f32>, albedo: vec3<f32>) -> vec3<f32> {
     let NdotL = max(dot(normal, normalize(SUN_DIRECTION)), 0.0);
 
-    let up_dot = max(dot(normal, vec3<f32>(0.0, 1.0, 0.0)), 0.0);
+    let up_dot = max(dot(normal, volume_transform.axes[1]), 0.0);
     let ao = mix(0.5, 1.0, up_dot);
 
     return (SUN_COLOR * NdotL * sceneProps.sunIntensity + AMBIENT_LIGHT * ao) * albedo;
@@ -132,7 +179,11 @@ fn intersect_volume(ray: Ray, volume: Volume) -> Intersection {
                 t2 = temp_t1;
             }
 
-            tmin = max(t1, tmin);
+            if t1 > tmin {
+                tmin = t1;
+                intersection.normal = vec3<f32>(0.0);
+                intersection.normal[i] = -sign(ray.d[i]);
+            }
             tmax = min(t2, tmax);
 
             if tmin > tmax {
@@ -145,126 +196,458 @@ fn intersect_volume(ray: Ray, volume: Volume) -> Intersection {
         return intersection;
     }
 
+    if tmin < 0.0 {
+        intersection.normal = vec3<f32>(0.0);
+    }
     intersection.tmin = max(0.0, tmin);
     intersection.tmax = tmax;
     intersection.intersects = true;
     return intersection;
 }
 
-fn trace_ray(ray: Ray) -> RayPayload {
-    var rayPayload: RayPayload;
-    var volume: Volume;
-    volume.pos = vec3<f32>(-1.6, -1.6, -1.6);
-    volume.size = vec3<f32>(32.0, 32.0, 32.0);
-    volume.voxel_length = 0.1;
-
-    let intersect_result: Intersection = intersect_volume(ray, volume);
-
-    if intersect_result.intersects {
-        var found_voxel: u32 = 0u;
-        var normal: vec3<f32>;
-
-        let world_to_voxel = (ray.o - volume.pos) / volume.voxel_length;
-        var voxel_pos = world_to_voxel + intersect_result.tmin * ray.d / volume.voxel_length;
-
-        var x = i32(floor(voxel_pos.x));
-        var y = i32(floor(voxel_pos.y));
-        var z = i32(floor(voxel_pos.z));
-
-        let x_step: i32 = select(-1, 1, ray.d.x > 0.0);
-        let y_step: i32 = select(-1, 1, ray.d.y > 0.0);
-        let z_step: i32 = select(-1, 1, ray.d.z > 0.0);
-
-        var dt_x = abs(volume.voxel_length / ray.d.x);
-        var dt_y = abs(volume.voxel_length / ray.d.y);
-        var dt_z = abs(volume.voxel_length / ray.d.z);
-
-        var t_max_x = select(
-            (floor(voxel_pos.x) + 1.0 - voxel_pos.x),
-            (voxel_pos.x - floor(voxel_pos.x)),
-            ray.d.x < 0.0
-        ) * abs(volume.voxel_length / ray.d.x);
-
-        var t_max_y = select(
-            (floor(voxel_pos.y) + 1.0 - voxel_pos.y),
-            (voxel_pos.y - floor(voxel_pos.y)),
-            ray.d.y < 0.0
-        ) * abs(volume.voxel_length / ray.d.y);
-
-        var t_max_z = select(
-            (floor(voxel_pos.z) + 1.0 - voxel_pos.z),
-            (voxel_pos.z - floor(voxel_pos.z)),
-            ray.d.z < 0.0
-        ) * abs(volume.voxel_length / ray.d.z);
-
-        let x_out = select(-1, 32, x_step > 0);
-        let y_out = select(-1, 32, y_step > 0);
-        let z_out = select(-1, 32, z_step > 0);
-
-        var iters = 0u;
-        while found_voxel == 0u && iters < 1000u {
-            if x >= 0 && x < 32 && y >= 0 && y < 32 && z >= 0 && z < 32 {
-                found_voxel = voxels[x][y][z];
+// Each chunk pool slot holds SLOT_VOXELS words: the chunk's LOD_COUNT voxel levels, from the
+// full volume halving down to a single voxel, then its surface heights
+fn lod_offset(level: u32) -> u32 {
+    var offset = 0u;
+    for (var k = 0u; k < level; k++) {
+        let n = CHUNK_SIZE >> k;
+        offset += n * n * n;
+    }
+    return offset;
+}
+
+fn voxel_at(slot: u32, level: u32, cell: vec3<i32>) -> u32 {
+    let n = CHUNK_SIZE >> level;
+    let c = vec3<u32>(cell);
+    return voxels[slot * SLOT_VOXELS + lod_offset(level) + (c.x * n + c.y) * n + c.z];
+}
+
+// Packed surface height of a column, stored after the LOD chain: the layer of its partly filled
+// top voxel in the low 16 bits and the fill in 65535ths in the high ones, zero when it has none
+fn surface_at(slot: u32, column: vec2<i32>) -> u32 {
+    let c = vec2<u32>(column);
+    return voxels[slot * SLOT_VOXELS + lod_offset(LOD_COUNT) + c.x * CHUNK_SIZE + c.y];
+}
+
+// Terrain height of a column in voxels, or `fallback` where its top voxel is full
+fn column_height(slot: u32, column: vec2<i32>, fallback: f32) -> f32 {
+    let surface = surface_at(slot, column);
+    if surface == 0u {
+        return fallback;
+    }
+    return f32(surface & 0xffffu) + f32(surface >> 16u) / 65535.0;
+}
+
+// Normal of the bilinear surface through the heights at the column centres around `position`,
+// in voxels. Columns without a height take `height`, so buildings and water don't tilt it
+fn surface_normal(slot: u32, position: vec2<f32>, height: f32) -> vec3<f32> {
+    // Keep the four columns inside the chunk, extrapolating the slope at its edges
+    let corner = clamp(vec2<i32>(floor(position - 0.5)), vec2<i32>(0), vec2<i32>(i32(CHUNK_SIZE) - 2));
+    let f = position - 0.5 - vec2<f32>(corner);
+    let h00 = column_height(slot, corner, height);
+    let h10 = column_height(slot, corner + vec2<i32>(1, 0), height);
+    let h01 = column_height(slot, corner + vec2<i32>(0, 1), height);
+    let h11 = column_height(slot, corner + vec2<i32>(1, 1), height);
+    let slope = vec2<f32>(mix(h10 - h00, h11 - h01, f.y), mix(h01 - h00, h11 - h10, f.x));
+    return normalize(vec3<f32>(-slope.x, 1.0, -slope.y));
+}
+
+struct SurfaceHit {
+    hit: bool,
+    // Whether the voxel is a partly filled surface voxel, hit exactly at `t`
+    surface: bool,
+    t: f32,
+    normal: vec3<f32>,
+}
+
+// Hit on a solid full-resolution voxel the ray enters at `t_enter` through `entry_normal` and
+// leaves at `t_exit`, along a ray in the chunk's voxels. A column's surface voxel is only
+// filled up to its terrain height, so the ray can pass over it, and anywhere it's hit takes the
+// normal of the smooth terrain so the steps between columns don't show
+fn intersect_voxel(ray: Ray, slot: u32, cell: vec3<i32>, t_enter: f32, t_exit: f32, entry_normal: vec3<f32>) -> SurfaceHit {
+    var result: SurfaceHit;
+    result.hit = true;
+    result.t = t_enter;
+    result.normal = entry_normal;
+
+    // Edits may have built on top of the surface since it was voxelized
+    let surface = surface_at(slot, cell.xz);
+    if surface == 0u || i32(surface & 0xffffu) != cell.y
+        || (cell.y < i32(CHUNK_SIZE) - 1 && voxel_at(slot, 0u, cell + vec3<i32>(0, 1, 0)) != 0u) {
+        return result;
+    }
+
+    let height = f32(cell.y) + f32(surface >> 16u) / 65535.0;
+    if ray.o.y + ray.d.y * t_enter >= height {
+        let t_top = (height - ray.o.y) / ray.d.y;
+        if ray.d.y >= 0.0 || t_top > t_exit {
+            result.hit = false;
+            return result;
+        }
+        result.t = t_top;
+    }
+
+    result.surface = true;
+    let position = ray.o.xz + ray.d.xz * result.t;
+    result.normal = surface_normal(slot, position, height);
+    return result;
+}
+
+// Coarsest level whose voxels, `voxel_length` across at full resolution, are still smaller than
+// the pixel cone at `distance` from the camera, so each ray reads about one voxel per pixel
+fn lod_for_distance(distance: f32, voxel_length: f32) -> u32 {
+    let pixel_angle = 2.0 * camera.inverse_proj[1][1] / screenResolution.y;
+    let footprint = distance * pixel_angle / voxel_length;
+    let level = floor(log2(max(footprint, 1.0)) + sceneProps.lodBias);
+    return u32(clamp(level, 0.0, f32(LOD_COUNT - 1u)));
+}
+
+struct March {
+    hit: bool,
+    // The ray got far enough to want a coarser level, continue from `t` with it
+    coarser: bool,
+    material: u32,
+    level: u32,
+    cell: vec3<i32>,
+    normal: vec3<f32>,
+    t: f32,
+    // The hit is on a partly filled surface voxel, exactly at `t`
+    surface: bool,
+}
+
+// DDA through one LOD level of the chunk in `slot`, from `t_start` to `t_end` along a ray in
+// the chunk's voxels, with `t` still measuring render units along the world ray. `view` is that
+// world ray relative to the camera, for the cone footprint of voxels `voxel_length` across.
+// With `through_water` water voxels count as empty.
+fn march(ray: Ray, view: Ray, slot: u32, voxel_length: f32, level: u32, t_start: f32, t_end: f32, entry_normal: vec3<f32>, through_water: bool) -> March {
+    var result: March;
+    result.level = level;
+
+    let n = i32(CHUNK_SIZE >> level);
+    let cell_length = f32(1u << level);
+
+    // Nudge past the boundary the ray stopped on so it lands in the cell beyond
+    let position = (ray.o + ray.d * t_start) / cell_length + normalize(ray.d) * 0.0001;
+    var cell = clamp(vec3<i32>(floor(position)), vec3<i32>(0), vec3<i32>(n - 1));
+    let step = select(vec3<i32>(-1), vec3<i32>(1), ray.d > vec3<f32>(0.0));
+    let dt = abs(cell_length / ray.d);
+    var t_next = t_start + select(
+        vec3<f32>(cell + 1) - position,
+        position - vec3<f32>(cell),
+        ray.d < vec3<f32>(0.0)
+    ) * dt;
+
+    var t = t_start;
+    var normal = entry_normal;
+    for (var iters = 0; iters < 3 * n + 3; iters++) {
+        let material = voxel_at(slot, level, cell);
+        if material != 0u && !(through_water && material == WATER) {
+            var hit: SurfaceHit;
+            hit.hit = true;
+            hit.t = t;
+            hit.normal = normal;
+            // Coarser levels have no surface heights, they're too far away to show them
+            if level == 0u {
+                let t_exit = min(min(t_next.x, t_next.y), t_next.z);
+                hit = intersect_voxel(ray, slot, cell, t, t_exit, normal);
             }
 
-            if found_voxel != 0u {
-                break;
+            if hit.hit {
+                result.hit = true;
+                result.material = material;
+                result.cell = cell;
+                result.normal = hit.normal;
+                result.t = hit.t;
+                result.surface = hit.surface;
+                return result;
             }
+        }
+
+        if level + 1u < LOD_COUNT && lod_for_distance(length(view.o + view.d * t), voxel_length) > level {
+            result.coarser = true;
+            result.normal = normal;
+            result.t = t;
+            return result;
+        }
+
+        if t_next.x < t_next.y && t_next.x < t_next.z {
+            cell.x += step.x;
+            t = t_next.x;
+            t_next.x += dt.x;
+            normal = vec3<f32>(-f32(step.x), 0.0, 0.0);
+        } else if t_next.y < t_next.z {
+            cell.y += step.y;
+            t = t_next.y;
+            t_next.y += dt.y;
+            normal = vec3<f32>(0.0, -f32(step.y), 0.0);
+        } else {
+            cell.z += step.z;
+            t = t_next.z;
+            t_next.z += dt.z;
+            normal = vec3<f32>(0.0, 0.0, -f32(step.z));
+        }
+
+        if any(cell < vec3<i32>(0)) || any(cell >= vec3<i32>(n)) || t > t_end {
+            break;
+        }
+    }
+
+    return result;
+}
+
+// Marches a chunk from `t_start` to `t_end`, starting at the level the entry point calls for
+// and stepping to coarser ones as the ray goes on
+fn trace_chunk(ray: Ray, view: Ray, slot: u32, voxel_length: f32, t_start: f32, t_end: f32, entry_normal: vec3<f32>, through_water: bool) -> March {
+    var level = lod_for_distance(length(view.o + view.d * t_start), voxel_length);
+    var t = t_start;
+    var normal = entry_normal;
+    var result: March;
+    for (var restarts = 0u; restarts < LOD_COUNT; restarts++) {
+        result = march(ray, view, slot, voxel_length, level, t, t_end, normal, through_water);
+        if result.hit || !result.coarser {
+            break;
+        }
+
+        t = result.t;
+        normal = result.normal;
+        level = max(lod_for_distance(length(view.o + view.d * t), voxel_length), level + 1u);
+    }
+    return result;
+}
+
+// Traces the chunk `chunk` places along a camera-relative world ray, from where the ray enters
+// its box
+fn trace_placed_chunk(world_ray: Ray, view: Ray, chunk: ChunkTransform, through_water: bool) -> March {
+    // Into the chunk's voxels. The direction isn't renormalized, so distances along the ray
+    // stay in render units. The axes are orthonormal so the inverse is the transpose
+    let to_local = transpose(chunk.axes);
+    var ray: Ray;
+    ray.o = to_local * (world_ray.o - chunk.pos) / chunk.voxel_size;
+    ray.d = to_local * world_ray.d / chunk.voxel_size;
 
-            if t_max_x < t_max_y {
-                if t_max_x < t_max_z {
-                    x += x_step;
-                    if x == x_out { break; }
-                    t_max_x += dt_x;
-                    normal = vec3<f32>(-f32(x_step), 0.0, 0.0);
-                } else {
-                    z += z_step;
-                    if z == z_out { break; }
-                    t_max_z += dt_z;
-                    normal = vec3<f32>(0.0, 0.0, -f32(z_step));
-                }
-            } else {
-                if t_max_y < t_max_z {
-                    y += y_step;
-                    if y == y_out { break; }
-                    t_max_y += dt_y;
-                    normal = vec3<f32>(0.0, -f32(y_step), 0.0);
-                } else {
-                    z += z_step;
-                    if z == z_out { break; }
-                    t_max_z += dt_z;
-                    normal = vec3<f32>(0.0, 0.0, -f32(z_step));
-                }
+    var volume: Volume;
+    volume.pos = vec3<f32>(0.0);
+    volume.size = vec3<f32>(f32(CHUNK_SIZE));
+    volume.voxel_length = 1.0;
+    let box = intersect_volume(ray, volume);
+    if !box.intersects {
+        var result: March;
+        return result;
+    }
+
+    let voxel_length = max(chunk.voxel_size.x, max(chunk.voxel_size.y, chunk.voxel_size.z));
+    return trace_chunk(ray, view, chunk.page - 1u, voxel_length, box.tmin, box.tmax, box.normal, through_water);
+}
+
+// First voxel along a ray, passing through water voxels when `through_water` is set. Chunks
+// are visited in the order the ray crosses the window east-west and north-south, and each is
+// traced in its own frame
+fn trace_ray(world_ray: Ray, through_water: bool) -> RayPayload {
+    let side = page_table.side;
+
+    // DDA over the window's chunks, in chunks from its corner along its frame's east and south
+    let to_window = transpose(volume_transform.axes);
+    let o = (to_window * (world_ray.o - volume_transform.pos)).xz / page_table.chunk_length;
+    let d = (to_window * world_ray.d).xz / page_table.chunk_length;
+
+    var t_min = 0.0;
+    var t_max = 10000000000.0;
+    for (var i = 0; i < 2; i++) {
+        if abs(d[i]) < 0.00000001 {
+            if o[i] < 0.0 || o[i] > f32(side) {
+                return miss(world_ray);
             }
+        } else {
+            let t1 = -o[i] / d[i];
+            let t2 = (f32(side) - o[i]) / d[i];
+            t_min = max(t_min, min(t1, t2));
+            t_max = min(t_max, max(t1, t2));
+        }
+    }
+    if t_min > t_max {
+        return miss(world_ray);
+    }
 
-            iters++;
+    let start = o + d * t_min;
+    var chunk = clamp(vec2<i32>(floor(start)), vec2<i32>(0), vec2<i32>(side - 1));
+    let step = select(vec2<i32>(-1), vec2<i32>(1), d > vec2<f32>(0.0));
+    let dt = abs(1.0 / d);
+    var t_next = t_min + select(
+        vec2<f32>(chunk + 1) - start,
+        start - vec2<f32>(chunk),
+        d < vec2<f32>(0.0)
+    ) * dt;
+
+    var view: Ray;
+    view.o = world_ray.o - rayOrigin;
+    view.d = world_ray.d;
+    for (var visits = 0; visits < 2 * side + 2; visits++) {
+        let placed = pages[chunk.x * side + chunk.y];
+        if placed.page != 0u {
+            let result = trace_placed_chunk(world_ray, view, placed, through_water);
+            if result.hit {
+                let cell_length = f32(1u << result.level);
+                let voxel_local_pos = vec3<f32>(result.cell) * cell_length * placed.voxel_size;
+                let voxel_pos = placed.pos + placed.axes * voxel_local_pos;
+                // Normals scale inversely to the voxels
+                let normal = placed.axes * (result.normal / placed.voxel_size);
+                return chit(
+                    result.t,
+                    result.material,
+                    select(voxel_pos, world_ray.o + world_ray.d * result.t, result.surface),
+                    normalize(normal),
+                );
+            }
         }
 
-        if found_voxel != 0u {
-            var rayPayload: RayPayload;
-            rayPayload.hit = true;
-            rayPayload.objectIndex = found_voxel;
-            let voxel_world_pos = volume.pos + vec3<f32>(f32(x), f32(y), f32(z)) * volume.voxel_length;
-            rayPayload.worldPosition = voxel_world_pos;
-            rayPayload.worldNormal = normalize(normal);
-            return rayPayload;
+        if min(t_next.x, t_next.y) >= t_max {
+            break;
+        }
+        if t_next.x < t_next.y {
+            chunk.x += step.x;
+            t_next.x += dt.x;
+        } else {
+            chunk.y += step.y;
+            t_next.y += dt.y;
+        }
+        if any(chunk < vec2<i32>(0)) || any(chunk >= vec2<i32>(side)) {
+            break;
         }
     }
 
-    return miss(ray);
+    return miss(world_ray);
 }
 
-fn chit(ray: Ray, hitDistance: f32, objectIndex: u32) -> RayPayload {
+// Base color of each material, matching `default_materials` in the voxelizer
+fn material_albedo(material: u32) -> vec3<f32> {
+    var voxel_color: vec3<f32>;
+
+    switch material {
+        case 1u { // WATER, the color it scatters
+            voxel_color = vec3<f32>(0.02, 0.03, 0.1);
+        }
+        case 2u { // GRASS
+            voxel_color = vec3<f32>(0.1, 0.3, 0.05);
+        }
+        case 3u { // DIRT
+            voxel_color = vec3<f32>(0.25, 0.15, 0.08);
+        }
+        case 4u { // STONE
+            voxel_color = vec3<f32>(0.3, 0.3, 0.32);
+        }
+        case 5u { // SAND
+            voxel_color = vec3<f32>(0.76, 0.7, 0.5);
+        }
+        case 6u { // SNOW
+            voxel_color = vec3<f32>(0.9, 0.92, 0.95);
+        }
+        case 7u { // ICE
+            voxel_color = vec3<f32>(0.6, 0.75, 0.85);
+        }
+        case 8u { // CONCRETE
+            voxel_color = vec3<f32>(0.45, 0.45, 0.45);
+        }
+        case 9u { // MUD
+            voxel_color = vec3<f32>(0.2, 0.15, 0.1);
+        }
+        case 10u { // FOREST_FLOOR
+            voxel_color = vec3<f32>(0.05, 0.18, 0.04);
+        }
+        case 11u { // FARMLAND
+            voxel_color = vec3<f32>(0.35, 0.3, 0.12);
+        }
+        case 12u { // SHRUB
+            voxel_color = vec3<f32>(0.2, 0.25, 0.1);
+        }
+        case 13u { // WOOD
+            voxel_color = vec3<f32>(0.22, 0.14, 0.07);
+        }
+        case 14u { // LEAVES
+            voxel_color = vec3<f32>(0.08, 0.22, 0.06);
+        }
+        case 15u { // ASPHALT
+            voxel_color = vec3<f32>(0.06, 0.06, 0.07);
+        }
+        case 16u { // BRICK
+            voxel_color = vec3<f32>(0.4, 0.18, 0.12);
+        }
+        case default {
+            voxel_color = vec3<f32>(1.0, 0.0, 0.0);
+        }
+    }
+
+    return voxel_color;
+}
+
+const WATER = 1u;
+const WATER_IOR = 1.33;
+
+// Schlick's approximation of the light reflected off water at `cos_theta` from the normal
+fn schlick_fresnel(cos_theta: f32) -> f32 {
+    let r0 = pow((1.0 - WATER_IOR) / (1.0 + WATER_IOR), 2.0);
+    return r0 + (1.0 - r0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Normal of the water surface at a camera-relative `position`, tilted by a few travelling
+// waves on top faces. The waves are laid out in the window's frame so they stay put as the
+// camera moves, and sized in columns so they look the same at any column spacing
+fn water_normal(position: vec3<f32>, face_normal: vec3<f32>) -> vec3<f32> {
+    let up = volume_transform.axes[1];
+    if water.wave_strength <= 0.0 || dot(face_normal, up) < 0.5 {
+        return face_normal;
+    }
+
+    let p = (transpose(volume_transform.axes) * (position - volume_transform.pos)).xz
+        / volume_transform.voxel_length;
+    var slope = vec2<f32>(0.0);
+    for (var k = 0; k < 3; k++) {
+        let angle = f32(k) * 2.1 + 0.4;
+        let direction = vec2<f32>(cos(angle), sin(angle));
+        let frequency = 1.7 + f32(k) * 1.3;
+        let phase = dot(direction, p) * frequency + water.time * (1.0 + f32(k) * 0.5);
+        slope += direction * cos(phase) / frequency;
+    }
+
+    let local = normalize(vec3<f32>(-slope.x, 1.0, -slope.y) * vec3<f32>(water.wave_strength, 1.0, water.wave_strength));
+    return normalize(volume_transform.axes * local);
+}
+
+// Light coming up through the water surface hit at `position`: the refracted ray is traced to
+// the floor, which is lit and dimmed by Beer-Lambert absorption along the way, and the light
+// the water absorbed is made up by its own scattering color
+fn trace_underwater(position: vec3<f32>, direction: vec3<f32>, normal: vec3<f32>, face_normal: vec3<f32>) -> vec3<f32> {
+    let scatter = calculate_lighting(volume_transform.axes[1], material_albedo(WATER));
+
+    var refracted: Ray;
+    refracted.o = position - face_normal * 0.0001;
+    refracted.d = refract(direction, normal, 1.0 / WATER_IOR);
+    if all(refracted.d == vec3<f32>(0.0)) {
+        return scatter;
+    }
+
+    let floor = trace_ray(refracted, true);
+    if !floor.hit {
+        return scatter;
+    }
+
+    var shadow_ray: Ray;
+    shadow_ray.o = refracted.o + refracted.d * floor.hitDistance + floor.worldNormal * 0.0001;
+    shadow_ray.d = normalize(SUN_DIRECTION);
+    let lit = calculate_lighting(floor.worldNormal, material_albedo(floor.objectIndex));
+    let floor_light = select(lit, lit * 0.1, trace_ray(shadow_ray, true).hit);
+
+    // Render units are meters, like the absorption coefficients
+    let transmittance = exp(-water.absorption * floor.hitDistance);
+    return floor_light * transmittance + scatter * (1.0 - transmittance);
+}
+
+fn chit(hitDistance: f32, objectIndex: u32, worldPosition: vec3<f32>, worldNormal: vec3<f32>) -> RayPayload {
     var rayPayload: RayPayload;
     rayPayload.hitDistance = hitDistance;
     rayPayload.hit = true;
     rayPayload.objectIndex = objectIndex;
-
-    // let closestSphere = spheres[objectIndex];
-    // let origin: vec3<f32> = ray.o - closestSphere.pos;
-    // rayPayload.worldPosition = origin + ray.d * hitDistance;
-    // rayPayload.worldNormal = normalize(rayPayload.worldPosition);
-    // rayPayload.worldPosition += closestSphere.pos;
+    rayPayload.worldPosition = worldPosition;
+    rayPayload.worldNormal = worldNormal;
 
     return rayPayload;
 }
@@ -304,43 +687,39 @@ fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
         for (var i = 0u; i < u32(sceneProps.rayBounces); i++) {
             ray.o += (rand(global_id.x * i, random_seed) - 0.5) * sceneProps.rayOffset;
 
-            let rayPayload: RayPayload = trace_ray(ray);
+            let rayPayload: RayPayload = trace_ray(ray, false);
             if !rayPayload.hit { 
                 // light += vec3(0.53, 0.8, 0.92) * contribution; // hit background
                 break;
             }
 
+            if rayPayload.objectIndex == WATER {
+                // Light refracted down to the floor comes back through the surface, the rest
+                // of the path follows the reflection
+                let position = ray.o + ray.d * rayPayload.hitDistance;
+                let normal = water_normal(position, rayPayload.worldNormal);
+                let fresnel = schlick_fresnel(max(dot(-ray.d, normal), 0.0));
+                light += contribution * (1.0 - fresnel)
+                    * trace_underwater(position, ray.d, normal, rayPayload.worldNormal);
+
+                contribution *= fresnel;
+                ray.o = position + rayPayload.worldNormal * 0.0001;
+                ray.d = reflect(ray.d, normal);
+                continue;
+            }
+
             let reflectedDirection = reflect(ray.d, rayPayload.worldNormal);
             let randomVector = normalize(vec3(rand(global_id.x + i, random_seed) - 0.5,
                 rand(global_id.x * 2 + i, random_seed) - 0.5,
                 rand(global_id.x * 3 + i, random_seed) - 0.5));
 
             if rayPayload.hit {
-                var voxel_color: vec3<f32>;
-
-                switch rayPayload.objectIndex {
-
-                case 1u { // WATER
-                        voxel_color = vec3<f32>(0.02, 0.03, 0.1);
-            }
-                case 2u { // GRASS
-                        voxel_color = vec3<f32>(0.1, 0.3, 0.05);
-            }
-                case 3u { // DIRT
-                        voxel_color = vec3<f32>(0.25, 0.15, 0.08);
-            }
-                case 4u { // STONE
-                        voxel_color = vec3<f32>(0.3, 0.3, 0.32);
-            }
-                case default {
-                        voxel_color = vec3<f32>(1.0, 0.0, 0.0);
-                    }
-            }
+                let voxel_color = material_albedo(rayPayload.objectIndex);
 
                 var shadow_ray: Ray;
                 shadow_ray.o = rayPayload.worldPosition + rayPayload.worldNormal * 0.0001;
                 shadow_ray.d = normalize(SUN_DIRECTION);
-                let shadow_result = trace_ray(shadow_ray);
+                let shadow_result = trace_ray(shadow_ray, true);
 
                 let direct_light = calculate_lighting(rayPayload.worldNormal, voxel_color);
 
@@ -0,0 +1,215 @@
+use serde::Deserialize;
+
+use super::noise::Noise;
+
+/// Settings for the optional 3D density pass of the voxelizer, which carves caves below the
+/// heightfield and grows overhangs out of cliff faces above it.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DensityOptions {
+    pub seed: u64,
+    /// Wavelength in meters of the coarsest cave and overhang octave.
+    pub scale: f32,
+    /// Width of the cave tunnels, 0 disables caves and 1 riddles the depth range with them.
+    pub cave_density: f32,
+    /// Shallowest depth in meters below the DEM surface a cave can reach.
+    pub cave_min_depth: f32,
+    /// Deepest depth in meters below the DEM surface a cave can reach.
+    pub cave_max_depth: f32,
+    /// Never carve the surface and subsurface layers, so caves can't open onto the surface
+    /// and the DEM surface is kept exactly.
+    pub keep_surface_intact: bool,
+    /// How far rock lips reach out from cliff faces, 0 disables overhangs and 1 can bridge
+    /// the whole gap in front of a cliff.
+    pub overhang: f32,
+}
+
+impl Default for DensityOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            scale: 2_000.0,
+            cave_density: 0.5,
+            cave_min_depth: 50.0,
+            cave_max_depth: 1_000.0,
+            keep_surface_intact: true,
+            overhang: 0.5,
+        }
+    }
+}
+
+/// Evaluates the density field of a `DensityOptions` at points given in meters.
+#[derive(Debug, Clone)]
+pub struct Density {
+    options: DensityOptions,
+    tunnels: Noise,
+    tunnels_cross: Noise,
+    overhangs: Noise,
+}
+
+impl Density {
+    pub fn new(options: &DensityOptions) -> Self {
+        // Independent fields from one seed
+        Self {
+            options: *options,
+            tunnels: Noise::new(options.seed),
+            tunnels_cross: Noise::new(options.seed ^ 0x5bd1_e995),
+            overhangs: Noise::new(options.seed ^ 0x27d4_eb2f),
+        }
+    }
+
+    pub fn options(&self) -> &DensityOptions {
+        &self.options
+    }
+
+    fn noise_position(&self, position: [f32; 3]) -> [f32; 3] {
+        position.map(|v| v / self.options.scale)
+    }
+
+    /// Whether the point at `position`, `depth` meters below the DEM surface, is hollowed out
+    /// by a cave. Tunnels run where two independent noise fields both cross zero, which gives
+    /// long connected passages rather than isolated bubbles.
+    pub fn is_cave(&self, position: [f32; 3], depth: f32) -> bool {
+        let options = &self.options;
+        if options.cave_density <= 0.0
+            || depth < options.cave_min_depth
+            || depth > options.cave_max_depth
+        {
+            return false;
+        }
+
+        let [x, y, z] = self.noise_position(position);
+        let width = 0.15 * options.cave_density;
+        self.tunnels.fbm3(x, y, z, 3, 2.0, 0.5).abs() < width
+            && self.tunnels_cross.fbm3(x, y, z, 3, 2.0, 0.5).abs() < width
+    }
+
+    /// Whether rock fills the point at `position` in front of a cliff face. `height` is how
+    /// far up the gap between the foot and the top of the cliff the point is, from 0 to 1.
+    /// Lips grow from the top down and thin out towards the foot, leaving air beneath them.
+    pub fn is_overhang(&self, position: [f32; 3], height: f32) -> bool {
+        if self.options.overhang <= 0.0 {
+            return false;
+        }
+
+        let [x, y, z] = self.noise_position(position);
+        let value = self.overhangs.fbm3(x, y, z, 4, 2.0, 0.5) * 0.5 + 0.5;
+        value > 1.0 - self.options.overhang * height * height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor::{GeoBounds, Heightmap};
+    use crate::terrain::voxelizer::{
+        self, MaterialSources, AIR, HEIGHT, LENGTH, STONE, WATER, WIDTH,
+    };
+
+    const BOUNDS: GeoBounds = GeoBounds {
+        north: 46.02,
+        south: 46.0,
+        east: 7.02,
+        west: 7.0,
+    };
+
+    const NO_MATERIALS: MaterialSources = MaterialSources {
+        land_cover: None,
+        rules: None,
+    };
+
+    // Rolling slopes with a sheer step across the middle for overhangs to grow from
+    fn heightmap() -> Heightmap {
+        Heightmap::from_fn(32, 32, BOUNDS, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            let step = if x < 16.0 { 0.0 } else { 900.0 };
+            200.0 + 20.0 * y + 15.0 * (x * 0.7).sin() + step
+        })
+    }
+
+    #[test]
+    fn caves_keep_the_surface_intact() {
+        let heightmap = heightmap();
+        let options = DensityOptions {
+            scale: 300.0,
+            cave_density: 1.0,
+            cave_min_depth: 0.0,
+            cave_max_depth: 10_000.0,
+            keep_surface_intact: true,
+            overhang: 0.0,
+            ..Default::default()
+        };
+        let plain = voxelizer::heightmap_to_volume(&heightmap, &NO_MATERIALS, None, None);
+        let carved =
+            voxelizer::heightmap_to_volume(&heightmap, &NO_MATERIALS, None, Some(&options));
+
+        let mut caves = 0;
+        for x in 0..LENGTH {
+            for y in 0..HEIGHT {
+                for z in 0..WIDTH {
+                    // Surface and subsurface layers are never stone
+                    if plain[x][y][z] != STONE {
+                        assert_eq!(carved[x][y][z], plain[x][y][z], "carved at {x} {y} {z}");
+                    } else if carved[x][y][z] == AIR {
+                        caves += 1;
+                    }
+                }
+            }
+        }
+        assert!(caves > 0, "no caves were carved");
+
+        // Without the option the same field breaks through the surface
+        let exposed = voxelizer::heightmap_to_volume(
+            &heightmap,
+            &NO_MATERIALS,
+            None,
+            Some(&DensityOptions {
+                keep_surface_intact: false,
+                ..options
+            }),
+        );
+        let breaches = (0..LENGTH)
+            .flat_map(|x| (0..HEIGHT).flat_map(move |y| (0..WIDTH).map(move |z| (x, y, z))))
+            .filter(|&(x, y, z)| !matches!(plain[x][y][z], AIR | STONE) && exposed[x][y][z] == AIR)
+            .count();
+        assert!(breaches > 0);
+    }
+
+    #[test]
+    fn overhangs_hang_from_the_cliff() {
+        // Lone spires, whose diagonal neighbours would otherwise grow lips touching nothing
+        let heightmap = Heightmap::from_fn(32, 32, BOUNDS, |x, y| match (x % 5, y % 5) {
+            (0, 0) => 1500.0,
+            _ => 200.0,
+        });
+        let options = DensityOptions {
+            scale: 300.0,
+            cave_density: 0.0,
+            overhang: 1.0,
+            ..Default::default()
+        };
+        let plain = voxelizer::heightmap_to_volume(&heightmap, &NO_MATERIALS, None, None);
+        let grown = voxelizer::heightmap_to_volume(&heightmap, &NO_MATERIALS, None, Some(&options));
+        let solid = |x: usize, y: usize, z: usize| !matches!(grown[x][y][z], AIR | WATER);
+
+        let mut lips = 0;
+        for x in 0..LENGTH {
+            for y in 0..HEIGHT {
+                for z in 0..WIDTH {
+                    if plain[x][y][z] != AIR || grown[x][y][z] == AIR {
+                        continue;
+                    }
+                    lips += 1;
+
+                    let above = y + 1 < HEIGHT && solid(x, y + 1, z);
+                    let beside = (x > 0 && solid(x - 1, y, z))
+                        || (x + 1 < LENGTH && solid(x + 1, y, z))
+                        || (z > 0 && solid(x, y, z - 1))
+                        || (z + 1 < WIDTH && solid(x, y, z + 1));
+                    assert!(above || beside, "overhang floats at {x} {y} {z}");
+                }
+            }
+        }
+        assert!(lips > 0, "no overhangs grew");
+    }
+}
@@ -4,13 +4,18 @@ use std::path::{Path, PathBuf};
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
 
+use super::geoid::VerticalDatum;
+
 // struct EarthdataCredentials {
 //     username: String,
 //     password: String,
 // }
 
+/// Fetches the NASADEM tiles of the default region to `assets/terrain` and the EGM2008 geoid
+/// grid `Geoid::load` reads to `assets/geoid`, skipping files that are already there. Run it with
+/// `cargo run -- download`.
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn download() -> Result<(), Box<dyn std::error::Error>> {
     // let credentials = EarthdataCredentials {
     //     username: "your_username".to_string(),
     //     password: "your_password".to_string(),
@@ -32,6 +37,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let client = Client::builder().build()?;
     download_region(&client, &assets_dir, &bounds).await?;
 
+    // Geoid grid for converting between vertical datums, the smaller EGM96 one is bundled
+    if let Err(e) = download_geoid_if_needed(&client, VerticalDatum::Egm2008).await {
+        eprintln!("Failed to download the EGM2008 geoid grid: {}", e);
+    }
+
     Ok(())
 }
 
@@ -126,3 +136,41 @@ async fn download_tile_if_needed(
     println!("Successfully downloaded {}", tile_name);
     Ok(())
 }
+
+/// Fetches the undulation grid of `datum` to its asset path, unless it's already there.
+pub async fn download_geoid_if_needed(
+    client: &Client,
+    datum: VerticalDatum,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(file_path), Some(url)) = (datum.asset_path(), datum.grid_url()) else {
+        return Ok(());
+    };
+    if file_path.exists() {
+        println!(
+            "Geoid grid {} already exists, skipping download",
+            file_path.display()
+        );
+        return Ok(());
+    }
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+
+    println!("Downloading geoid grid {}", url);
+    let mut response = client.get(&url).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to download: {}", response.status()).into());
+    }
+
+    // Written under a temporary name so an interrupted download isn't mistaken for a grid
+    let partial = file_path.with_extension("part");
+    let mut file = File::create(&partial).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    fs::rename(&partial, file_path).await?;
+
+    println!("Successfully downloaded {}", file_path.display());
+    Ok(())
+}
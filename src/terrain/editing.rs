@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+use super::processor::Heightmap;
+use super::vegetation::Template;
+use super::voxelizer::{self, ChunkRegion, VoxelChunk, AIR, HEIGHT, LENGTH, WIDTH};
+
+/// A shape of voxels to change, in chunk voxel coordinates. Parts outside the chunk are
+/// clipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Brush {
+    /// A single voxel.
+    Set {
+        position: [i32; 3],
+    },
+    Sphere {
+        center: [i32; 3],
+        radius: f32,
+    },
+    /// Every voxel from `min` to `max`, inclusive.
+    Box {
+        min: [i32; 3],
+        max: [i32; 3],
+    },
+    /// Upright cylinder standing on `base`.
+    Cylinder {
+        base: [i32; 3],
+        radius: f32,
+        height: u32,
+    },
+    /// The face-connected region of voxels sharing the material at `start`, stopping after
+    /// `limit` voxels so filling open air can't run away.
+    FloodFill {
+        start: [i32; 3],
+        limit: usize,
+    },
+    /// Fills voxels mostly surrounded by solid ones and clears those mostly surrounded by
+    /// air, using the most common neighbouring material. Ignores the brush material.
+    Smooth {
+        center: [i32; 3],
+        radius: f32,
+    },
+    /// Changes the material of solid voxels, leaving air alone.
+    Paint {
+        center: [i32; 3],
+        radius: f32,
+    },
+    /// A template's voxels in their own materials, with its origin at `origin`, such as a prop
+    /// imported from a .vox file. Ignores the brush material.
+    Stamp {
+        origin: [i32; 3],
+        template: Template,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Change {
+    position: [usize; 3],
+    before: u32,
+    after: u32,
+}
+
+/// One undoable brush stroke.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Edit {
+    changes: Vec<Change>,
+}
+
+impl Edit {
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Box of voxels touched since the renderer last uploaded, `max` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirtyRegion {
+    pub min: [usize; 3],
+    pub max: [usize; 3],
+}
+
+impl DirtyRegion {
+    fn include(&mut self, position: [usize; 3]) {
+        self.min = std::array::from_fn(|i| self.min[i].min(position[i]));
+        self.max = std::array::from_fn(|i| self.max[i].max(position[i] + 1));
+    }
+
+    /// Ranges of flat voxel indices covering the region in the GPU buffer layout
+    /// `(x * height + y) * width + z`, one contiguous range per x slice.
+    pub fn spans(&self, size: [usize; 3]) -> impl Iterator<Item = Range<usize>> + '_ {
+        let [_, height, width] = size;
+        (self.min[0]..self.max[0]).map(move |x| {
+            let start = (x * height + self.min[1]) * width + self.min[2];
+            let end = (x * height + self.max[1] - 1) * width + self.max[2];
+            start..end
+        })
+    }
+
+    /// Ranges of column indices `x * width + z` the region covers, the layout of a chunk's
+    /// surface heights, one contiguous range per x slice.
+    pub fn columns(&self, width: usize) -> impl Iterator<Item = Range<usize>> + '_ {
+        (self.min[0]..self.max[0]).map(move |x| x * width + self.min[2]..x * width + self.max[2])
+    }
+}
+
+/// Editing layer over a chunk: applies brushes, keeps an undo/redo history of them and tracks
+/// which voxels changed since the last upload.
+#[derive(Debug, Clone)]
+pub struct Editor {
+    chunk: VoxelChunk,
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    /// Oldest edits are dropped past this many.
+    pub history_limit: usize,
+    dirty: Option<DirtyRegion>,
+}
+
+impl Editor {
+    pub fn new(chunk: VoxelChunk) -> Self {
+        Self {
+            chunk,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            history_limit: 256,
+            dirty: None,
+        }
+    }
+
+    pub fn chunk(&self) -> &VoxelChunk {
+        &self.chunk
+    }
+
+    pub fn into_chunk(self) -> VoxelChunk {
+        self.chunk
+    }
+
+    pub fn get(&self, position: [i32; 3]) -> Option<u32> {
+        self.in_bounds(position)
+            .then(|| self.chunk.voxels.get(position.map(|c| c as usize)))
+    }
+
+    fn in_bounds(&self, position: [i32; 3]) -> bool {
+        let size = self.chunk.voxels.size();
+        (0..3).all(|i| position[i] >= 0 && (position[i] as usize) < size[i])
+    }
+
+    /// Applies `brush` with `material`, AIR to carve, as one undoable edit. Returns the number
+    /// of voxels that changed.
+    pub fn apply(&mut self, brush: &Brush, material: u32) -> usize {
+        let targets = self.targets(brush, material);
+
+        let mut edit = Edit::default();
+        for (position, after) in targets {
+            let before = self.chunk.voxels.get(position);
+            if before != after {
+                edit.changes.push(Change {
+                    position,
+                    before,
+                    after,
+                });
+            }
+        }
+
+        for change in &edit.changes {
+            self.write(change.position, change.after);
+        }
+
+        let count = edit.len();
+        if !edit.is_empty() {
+            self.redo.clear();
+            self.undo.push_back(edit);
+            while self.undo.len() > self.history_limit {
+                self.undo.pop_front();
+            }
+        }
+        count
+    }
+
+    /// Reverts the latest edit. Returns false when there's nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some(edit) = self.undo.pop_back() else {
+            return false;
+        };
+        for change in edit.changes.iter().rev() {
+            self.write(change.position, change.before);
+        }
+        self.redo.push(edit);
+        true
+    }
+
+    /// Reapplies the latest undone edit. Returns false when there's nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let Some(edit) = self.redo.pop() else {
+            return false;
+        };
+        for change in &edit.changes {
+            self.write(change.position, change.after);
+        }
+        self.undo.push_back(edit);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Region changed since the last call, for the renderer to re-upload.
+    pub fn take_dirty(&mut self) -> Option<DirtyRegion> {
+        self.dirty.take()
+    }
+
+    /// Raw material ids of a range of flat voxel indices, as `DirtyRegion::spans` returns.
+    pub fn span_voxels(&self, span: Range<usize>) -> Vec<u32> {
+        let [_, height, width] = self.chunk.voxels.size();
+        span.map(|i| {
+            self.chunk
+                .voxels
+                .get([i / (height * width), i / width % height, i % width])
+        })
+        .collect()
+    }
+
+    /// Recomputes the fractional surface heights after edits exposed or buried columns.
+    /// `region` is the part of `heightmap` the chunk was voxelized from.
+    pub fn refresh_surface(&mut self, heightmap: &Heightmap, region: &ChunkRegion) {
+        let voxels = self.chunk.voxels.decode();
+        let mut volume = Box::new([[[AIR; WIDTH]; HEIGHT]; LENGTH]);
+        for (column, voxels) in volume.iter_mut().zip(voxels.chunks_exact(HEIGHT * WIDTH)) {
+            for (layer, voxels) in column.iter_mut().zip(voxels.chunks_exact(WIDTH)) {
+                layer.copy_from_slice(voxels);
+            }
+        }
+        self.chunk.surface = voxelizer::surface_heights(&volume, heightmap, region);
+    }
+
+    fn write(&mut self, position: [usize; 3], material: u32) {
+        self.chunk.voxels.set(position, material);
+        self.dirty
+            .get_or_insert(DirtyRegion {
+                min: position,
+                max: position.map(|c| c + 1),
+            })
+            .include(position);
+    }
+
+    /// Every voxel the brush covers, with the material it would get.
+    fn targets(&self, brush: &Brush, material: u32) -> Vec<([usize; 3], u32)> {
+        let within = |center: [i32; 3], radius: f32| {
+            let reach = radius.ceil() as i32;
+            let mut positions = Vec::new();
+            for x in -reach..=reach {
+                for y in -reach..=reach {
+                    for z in -reach..=reach {
+                        if ((x * x + y * y + z * z) as f32) <= radius * radius {
+                            positions.push([center[0] + x, center[1] + y, center[2] + z]);
+                        }
+                    }
+                }
+            }
+            positions
+        };
+
+        let positions: Vec<[i32; 3]> = match *brush {
+            Brush::Stamp {
+                origin,
+                ref template,
+            } => {
+                return template
+                    .voxels
+                    .iter()
+                    .map(|&(offset, material)| {
+                        let position: [i32; 3] = std::array::from_fn(|i| origin[i] + offset[i]);
+                        (position, material)
+                    })
+                    .filter(|&(position, _)| self.in_bounds(position))
+                    .map(|(position, material)| (position.map(|c| c as usize), material))
+                    .collect();
+            }
+            Brush::Set { position } => vec![position],
+            Brush::Sphere { center, radius } | Brush::Paint { center, radius } => {
+                within(center, radius)
+            }
+            Brush::Box { min, max } => {
+                let mut positions = Vec::new();
+                for x in min[0].min(max[0])..=min[0].max(max[0]) {
+                    for y in min[1].min(max[1])..=min[1].max(max[1]) {
+                        for z in min[2].min(max[2])..=min[2].max(max[2]) {
+                            positions.push([x, y, z]);
+                        }
+                    }
+                }
+                positions
+            }
+            Brush::Cylinder {
+                base,
+                radius,
+                height,
+            } => {
+                let reach = radius.ceil() as i32;
+                let mut positions = Vec::new();
+                for x in -reach..=reach {
+                    for z in -reach..=reach {
+                        if ((x * x + z * z) as f32) <= radius * radius {
+                            for y in 0..height as i32 {
+                                positions.push([base[0] + x, base[1] + y, base[2] + z]);
+                            }
+                        }
+                    }
+                }
+                positions
+            }
+            Brush::FloodFill { start, limit } => self.flood(start, limit),
+            Brush::Smooth { center, radius } => {
+                return self.smooth(within(center, radius));
+            }
+        };
+
+        positions
+            .into_iter()
+            .filter(|&position| self.in_bounds(position))
+            .map(|position| position.map(|c| c as usize))
+            .filter(|&position| {
+                !matches!(brush, Brush::Paint { .. }) || self.chunk.voxels.get(position) != AIR
+            })
+            .map(|position| (position, material))
+            .collect()
+    }
+
+    fn flood(&self, start: [i32; 3], limit: usize) -> Vec<[i32; 3]> {
+        let Some(target) = self.get(start) else {
+            return Vec::new();
+        };
+
+        let mut visited = vec![start];
+        let mut queue = VecDeque::from([start]);
+        let mut seen = HashSet::from([start]);
+
+        while let Some([x, y, z]) = queue.pop_front() {
+            for neighbour in [
+                [x - 1, y, z],
+                [x + 1, y, z],
+                [x, y - 1, z],
+                [x, y + 1, z],
+                [x, y, z - 1],
+                [x, y, z + 1],
+            ] {
+                if visited.len() >= limit {
+                    return visited;
+                }
+                if self.get(neighbour) == Some(target) && seen.insert(neighbour) {
+                    visited.push(neighbour);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        visited
+    }
+
+    fn smooth(&self, positions: Vec<[i32; 3]>) -> Vec<([usize; 3], u32)> {
+        // Decide everything from the voxels as they were, so the result doesn't depend on order
+        let mut results = Vec::new();
+        for position in positions {
+            if !self.in_bounds(position) {
+                continue;
+            }
+
+            let mut solid = 0;
+            let mut counts: HashMap<u32, usize> = HashMap::new();
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        if (dx, dy, dz) == (0, 0, 0) {
+                            continue;
+                        }
+                        let neighbour = [position[0] + dx, position[1] + dy, position[2] + dz];
+                        let material = self.get(neighbour).unwrap_or(AIR);
+                        if material != AIR {
+                            solid += 1;
+                            *counts.entry(material).or_default() += 1;
+                        }
+                    }
+                }
+            }
+
+            let current = self.get(position).unwrap_or(AIR);
+            let position = position.map(|c| c as usize);
+            if current == AIR && solid > 16 {
+                let common = counts
+                    .into_iter()
+                    .max_by_key(|&(material, count)| (count, std::cmp::Reverse(material)))
+                    .map_or(AIR, |(material, _)| material);
+                results.push((position, common));
+            } else if current != AIR && solid < 10 {
+                results.push((position, AIR));
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::jobs::{self, RegionSources};
+    use crate::terrain::palette::ChunkStorage;
+    use crate::terrain::processor;
+    use crate::terrain::vox::VoxFile;
+    use crate::terrain::voxelizer::{GeoCoord, MaterialSources, DIRT, GRASS, LEAVES, STONE, WOOD};
+
+    fn editor() -> Editor {
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            for (y, layer) in column.iter_mut().enumerate().take(8) {
+                layer.fill(if y == 7 { GRASS } else { STONE });
+            }
+        }
+        Editor::new(VoxelChunk::new(
+            GeoCoord { lat: 0.0, lon: 0.0 },
+            0.0,
+            &volume,
+        ))
+    }
+
+    #[test]
+    fn undo_and_redo_restore_voxels() {
+        let mut editor = editor();
+        let original = editor.chunk().clone();
+
+        editor.apply(
+            &Brush::Sphere {
+                center: [16, 8, 16],
+                radius: 4.0,
+            },
+            AIR,
+        );
+        editor.apply(
+            &Brush::Cylinder {
+                base: [4, 8, 4],
+                radius: 2.0,
+                height: 6,
+            },
+            DIRT,
+        );
+        let edited = editor.chunk().clone();
+        assert_ne!(edited, original);
+
+        assert!(editor.undo());
+        assert!(editor.undo());
+        assert!(!editor.undo());
+        assert_eq!(editor.chunk().voxels.decode(), original.voxels.decode());
+
+        assert!(editor.redo());
+        assert!(editor.redo());
+        assert!(!editor.redo());
+        assert_eq!(editor.chunk().voxels.decode(), edited.voxels.decode());
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut editor = editor();
+        editor.apply(
+            &Brush::Set {
+                position: [0, 8, 0],
+            },
+            DIRT,
+        );
+        editor.undo();
+        editor.apply(
+            &Brush::Set {
+                position: [1, 8, 0],
+            },
+            DIRT,
+        );
+        assert!(!editor.can_redo());
+    }
+
+    #[test]
+    fn stamps_imported_props() {
+        // A wooden post with a leaf on top and a stone beside it, through a .vox file
+        let materials = voxelizer::default_materials();
+        let size = [2, 3, 1];
+        let prop = ChunkStorage::encode(size, &[WOOD, WOOD, LEAVES, STONE, AIR, AIR]);
+        let file = VoxFile::from_region(&prop, [0; 3], size, &materials);
+        let template = VoxFile::from_bytes(&file.to_bytes())
+            .unwrap()
+            .to_template(&materials);
+
+        let mut editor = editor();
+        let changed = editor.apply(
+            &Brush::Stamp {
+                origin: [31, 8, 5],
+                template: template.clone(),
+            },
+            AIR,
+        );
+        assert_eq!(changed, 3);
+        assert_eq!(editor.get([31, 8, 5]), Some(WOOD));
+        assert_eq!(editor.get([31, 9, 5]), Some(WOOD));
+        assert_eq!(editor.get([31, 10, 5]), Some(LEAVES));
+
+        // Clipped at the chunk's edge, and undone as one edit
+        assert!(editor.undo());
+        assert_eq!(editor.get([31, 10, 5]), Some(AIR));
+        assert!(!editor.can_undo());
+    }
+
+    #[test]
+    fn paint_and_flood_fill_respect_materials() {
+        let mut editor = editor();
+
+        // Painting only recolors solid voxels
+        let changed = editor.apply(
+            &Brush::Paint {
+                center: [16, 10, 16],
+                radius: 3.0,
+            },
+            DIRT,
+        );
+        assert!(changed > 0);
+        assert_eq!(editor.get([16, 10, 16]), Some(AIR));
+        assert_eq!(editor.get([16, 7, 16]), Some(DIRT));
+
+        // The rest of the grass layer is one connected region
+        let grass = LENGTH * WIDTH - 1;
+        let changed = editor.apply(
+            &Brush::FloodFill {
+                start: [0, 7, 0],
+                limit: usize::MAX,
+            },
+            STONE,
+        );
+        assert_eq!(changed, grass);
+    }
+
+    #[test]
+    fn dirty_spans_cover_changes() {
+        let mut editor = editor();
+        editor.apply(
+            &Brush::Box {
+                min: [3, 9, 5],
+                max: [6, 12, 7],
+            },
+            DIRT,
+        );
+        let region = editor.take_dirty().unwrap();
+        assert_eq!(region.min, [3, 9, 5]);
+        assert_eq!(region.max, [7, 13, 8]);
+        assert!(editor.take_dirty().is_none());
+
+        let voxels = editor.chunk().voxels.decode();
+        let mut covered = 0;
+        for span in region.spans(editor.chunk().voxels.size()) {
+            assert_eq!(editor.span_voxels(span.clone()), voxels[span.clone()]);
+            covered += voxels[span].iter().filter(|&&m| m == DIRT).count();
+        }
+        assert_eq!(covered, 4 * 4 * 3);
+    }
+
+    #[test]
+    fn carving_the_surface_clears_its_height() {
+        // Rising 0.8 voxels per column east and 0.2 south
+        let heightmap =
+            processor::test_heightmap(LENGTH, WIDTH, |x, z| x as f32 * 10.0 + z as f32 * 2.5);
+        let sources = RegionSources {
+            heightmap: &heightmap,
+            materials: MaterialSources::default(),
+            water: None,
+            density: None,
+            lidar: None,
+            osm: None,
+            vegetation: None,
+        };
+        let chunk = jobs::voxelize_chunk(&sources, [1, 1], [0, 0]);
+        let original = chunk.surface.clone();
+        let top = original[3 * WIDTH + 4].unwrap().layer as i32;
+
+        let mut editor = Editor::new(chunk);
+        editor.apply(
+            &Brush::Set {
+                position: [3, top, 4],
+            },
+            AIR,
+        );
+        let region = editor.take_dirty().unwrap();
+        let chunk_region = jobs::chunk_region(&heightmap, None, [1, 1], [0, 0]);
+        editor.refresh_surface(&heightmap, &chunk_region);
+
+        let columns: Vec<_> = region.columns(WIDTH).collect();
+        assert_eq!(columns, vec![3 * WIDTH + 4..3 * WIDTH + 5]);
+        for (i, surface) in editor.chunk().surface.iter().enumerate() {
+            if i == 3 * WIDTH + 4 {
+                assert_eq!(*surface, None);
+            } else {
+                assert_eq!(*surface, original[i], "{i}");
+            }
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+
+use super::processor::Heightmap;
+
+// Height changes are summed as integers in this many steps per height range, which makes the
+// sum independent of the order droplets finish in on either implementation
+const FIXED_POINT: f32 = 16_777_216.0;
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Settings for `erode` and `erode_gpu`. The droplet constants act on heights normalized to
+/// the heightmap's elevation range, which is the scale their usual defaults are tuned for.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErosionOptions {
+    pub seed: u64,
+    /// Total number of water droplets to simulate.
+    pub droplets: u32,
+    /// Droplets simulated against the same heights before their changes are applied.
+    pub batch_size: u32,
+    /// Maximum steps of one cell a droplet takes.
+    pub lifetime: u32,
+    /// How much of its direction a droplet keeps each step, from 0 to 1.
+    pub inertia: f32,
+    /// Sediment a droplet can carry per unit of speed, water and slope.
+    pub capacity: f32,
+    /// Slope used for the capacity on flat ground, so droplets there still carry sediment.
+    pub min_slope: f32,
+    /// Fraction of excess sediment dropped each step.
+    pub deposition: f32,
+    /// Fraction of free capacity picked up each step.
+    pub erosion: f32,
+    /// Radius in cells that erosion is spread over. Eroding only the nearest cells digs pits
+    /// that droplets then bounce in and deepen without bound.
+    pub radius: i32,
+    /// Fraction of water lost each step.
+    pub evaporation: f32,
+    pub gravity: f32,
+    pub thermal_iterations: u32,
+    /// Tangent of the angle of repose in real meters, steeper material slides down.
+    pub talus: f32,
+    /// Fraction of the excess over the angle of repose that slides each iteration.
+    pub thermal_rate: f32,
+}
+
+impl Default for ErosionOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            droplets: 50_000,
+            batch_size: 1024,
+            lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_slope: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            radius: 3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            thermal_iterations: 50,
+            talus: 0.7,
+            thermal_rate: 0.5,
+        }
+    }
+}
+
+// Layout shared with erosion.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ErosionParams {
+    width: u32,
+    height: u32,
+    seed: u32,
+    first_droplet: u32,
+    droplet_count: u32,
+    lifetime: u32,
+    inertia: f32,
+    capacity: f32,
+    min_slope: f32,
+    deposition: f32,
+    erosion: f32,
+    evaporation: f32,
+    gravity: f32,
+    talus: f32,
+    thermal_rate: f32,
+    radius: i32,
+}
+
+impl ErosionParams {
+    fn new(heightmap: &Heightmap, scale: &HeightScale, options: &ErosionOptions) -> Self {
+        // Angle of repose as a normalized height difference between neighbouring cells
+        let (dx, dy) = heightmap.cell_size();
+        let talus = options.talus * (dx + dy) * 0.5 / scale.range;
+
+        Self {
+            width: heightmap.width as u32,
+            height: heightmap.height as u32,
+            seed: options.seed as u32 ^ (options.seed >> 32) as u32,
+            first_droplet: 0,
+            droplet_count: 0,
+            lifetime: options.lifetime,
+            inertia: options.inertia,
+            capacity: options.capacity,
+            min_slope: options.min_slope,
+            deposition: options.deposition,
+            erosion: options.erosion,
+            evaporation: options.evaporation,
+            gravity: options.gravity,
+            talus,
+            thermal_rate: options.thermal_rate,
+            radius: options.radius.max(1),
+        }
+    }
+}
+
+fn pcg(value: u32) -> u32 {
+    let state = value.wrapping_mul(747_796_405).wrapping_add(2_891_336_453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277_803_737);
+    (word >> 22) ^ word
+}
+
+struct HeightScale {
+    min: f32,
+    range: f32,
+}
+
+impl HeightScale {
+    fn new(heightmap: &Heightmap) -> Self {
+        let (min, max) = heightmap.min_max();
+        Self {
+            min,
+            range: (max - min).max(f32::EPSILON),
+        }
+    }
+
+    fn normalize(&self, heightmap: &Heightmap) -> Vec<f32> {
+        heightmap
+            .elevations
+            .iter()
+            .map(|h| (h - self.min) / self.range)
+            .collect()
+    }
+
+    fn restore(&self, heightmap: &Heightmap, heights: &[f32]) -> Heightmap {
+        let mut eroded = heightmap.clone();
+        eroded.elevations = heights.iter().map(|h| h * self.range + self.min).collect();
+        eroded
+    }
+}
+
+struct Simulation<'a> {
+    params: &'a ErosionParams,
+    heights: Vec<f32>,
+    deltas: Vec<i32>,
+}
+
+impl Simulation<'_> {
+    fn height_at(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.params.width as i32 - 1) as usize;
+        let y = y.clamp(0, self.params.height as i32 - 1) as usize;
+        self.heights[y * self.params.width as usize + x]
+    }
+
+    fn add_delta(&mut self, x: i32, y: i32, amount: f32) {
+        // WGSL's round() breaks ties to even
+        let index = y as usize * self.params.width as usize + x as usize;
+        self.deltas[index] += (amount * FIXED_POINT).round_ties_even() as i32;
+    }
+
+    fn height_and_gradient(&self, x: f32, y: f32) -> (f32, f32, f32) {
+        let (nx, ny) = (x.floor() as i32, y.floor() as i32);
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+
+        let nw = self.height_at(nx, ny);
+        let ne = self.height_at(nx + 1, ny);
+        let sw = self.height_at(nx, ny + 1);
+        let se = self.height_at(nx + 1, ny + 1);
+
+        let gradient_x = (ne - nw) * (1.0 - fy) + (se - sw) * fy;
+        let gradient_y = (sw - nw) * (1.0 - fx) + (se - ne) * fx;
+        let height = nw * (1.0 - fx) * (1.0 - fy)
+            + ne * fx * (1.0 - fy)
+            + sw * (1.0 - fx) * fy
+            + se * fx * fy;
+
+        (height, gradient_x, gradient_y)
+    }
+
+    fn add_bilinear(&mut self, x: f32, y: f32, amount: f32) {
+        let (nx, ny) = (x.floor() as i32, y.floor() as i32);
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+
+        self.add_delta(nx, ny, amount * (1.0 - fx) * (1.0 - fy));
+        self.add_delta(nx + 1, ny, amount * fx * (1.0 - fy));
+        self.add_delta(nx, ny + 1, amount * (1.0 - fx) * fy);
+        self.add_delta(nx + 1, ny + 1, amount * fx * fy);
+    }
+
+    // Spreads a height change over the cells within `radius`, weighted by closeness
+    fn add_brush(&mut self, x: f32, y: f32, amount: f32) {
+        let radius = self.params.radius;
+        let (width, height) = (self.params.width as i32, self.params.height as i32);
+        let (cx, cy) = (x.floor() as i32, y.floor() as i32);
+        let weight = |dx: i32, dy: i32| {
+            let (nx, ny) = (cx + dx, cy + dy);
+            if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                return 0.0;
+            }
+            let (ox, oy) = (nx as f32 - x, ny as f32 - y);
+            (radius as f32 - (ox * ox + oy * oy).sqrt()).max(0.0)
+        };
+
+        let mut total = 0.0;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                total += weight(dx, dy);
+            }
+        }
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let weight = weight(dx, dy);
+                if weight > 0.0 {
+                    self.add_delta(cx + dx, cy + dy, amount * weight / total);
+                }
+            }
+        }
+    }
+
+    fn droplet(&mut self, index: u32) {
+        let params = self.params;
+        let hash = pcg(params.seed ^ pcg(index));
+        let mut x = (hash & 0xffff) as f32 / 65536.0 * (params.width - 1) as f32;
+        let mut y = (hash >> 16) as f32 / 65536.0 * (params.height - 1) as f32;
+        let (mut direction_x, mut direction_y) = (0.0, 0.0);
+        let (mut speed, mut water, mut sediment) = (1.0f32, 1.0, 0.0);
+
+        for _ in 0..params.lifetime {
+            let (height, gradient_x, gradient_y) = self.height_and_gradient(x, y);
+
+            direction_x = direction_x * params.inertia - gradient_x * (1.0 - params.inertia);
+            direction_y = direction_y * params.inertia - gradient_y * (1.0 - params.inertia);
+            let length = (direction_x * direction_x + direction_y * direction_y).sqrt();
+            if length == 0.0 {
+                break;
+            }
+            direction_x /= length;
+            direction_y /= length;
+
+            let (next_x, next_y) = (x + direction_x, y + direction_y);
+            if next_x < 0.0
+                || next_y < 0.0
+                || next_x >= (params.width - 1) as f32
+                || next_y >= (params.height - 1) as f32
+            {
+                break;
+            }
+
+            let height_difference = self.height_and_gradient(next_x, next_y).0 - height;
+            let capacity =
+                (-height_difference).max(params.min_slope) * speed * water * params.capacity;
+
+            if height_difference > 0.0 {
+                // Fill the pit it's climbing out of and stop there. It can't see its own
+                // deposit until the batch is applied, so staying would fill the same pit again
+                self.add_bilinear(x, y, height_difference.min(sediment));
+                break;
+            } else if sediment > capacity {
+                let deposit = (sediment - capacity) * params.deposition;
+                sediment -= deposit;
+                self.add_bilinear(x, y, deposit);
+            } else {
+                let eroded = ((capacity - sediment) * params.erosion).min(-height_difference);
+                sediment += eroded;
+                self.add_brush(x, y, -eroded);
+            }
+
+            speed = (speed * speed - height_difference * params.gravity)
+                .max(0.0)
+                .sqrt();
+            water *= 1.0 - params.evaporation;
+            x = next_x;
+            y = next_y;
+        }
+    }
+
+    fn thermal(&mut self, index: usize) {
+        const OFFSETS: [(i32, i32); 8] = [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ];
+
+        let (width, height) = (self.params.width as i32, self.params.height as i32);
+        let x = (index % width as usize) as i32;
+        let y = (index / width as usize) as i32;
+        let center = self.heights[index];
+
+        let mut excess = [0.0f32; 8];
+        let (mut total, mut steepest) = (0.0f32, 0.0f32);
+        for (k, &(dx, dy)) in OFFSETS.iter().enumerate() {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                continue;
+            }
+
+            let distance = if dx != 0 && dy != 0 {
+                std::f32::consts::SQRT_2
+            } else {
+                1.0
+            };
+            let difference = center - self.height_at(nx, ny) - self.params.talus * distance;
+            if difference > 0.0 {
+                excess[k] = difference;
+                total += difference;
+                steepest = steepest.max(difference);
+            }
+        }
+
+        if total <= 0.0 {
+            return;
+        }
+
+        // Half the steepest excess settles, spread over every neighbour past the talus angle
+        let moved = self.params.thermal_rate * steepest * 0.5;
+        for (k, &(dx, dy)) in OFFSETS.iter().enumerate() {
+            if excess[k] > 0.0 {
+                let amount = moved * excess[k] / total;
+                self.add_delta(x + dx, y + dy, amount);
+                self.add_delta(x, y, -amount);
+            }
+        }
+    }
+
+    fn apply(&mut self) {
+        for (height, delta) in self.heights.iter_mut().zip(&mut self.deltas) {
+            *height += std::mem::take(delta) as f32 / FIXED_POINT;
+        }
+    }
+}
+
+/// Runs hydraulic then thermal erosion on the CPU. Droplets are simulated in batches that all
+/// see the same heights, which keeps the result identical for a seed and lets `erode_gpu`
+/// follow the same steps.
+pub fn erode(heightmap: &Heightmap, options: &ErosionOptions) -> Heightmap {
+    let scale = HeightScale::new(heightmap);
+    let params = ErosionParams::new(heightmap, &scale, options);
+    let mut simulation = Simulation {
+        params: &params,
+        heights: scale.normalize(heightmap),
+        deltas: vec![0; heightmap.elevations.len()],
+    };
+
+    let batch_size = options.batch_size.max(1);
+    for first in (0..options.droplets).step_by(batch_size as usize) {
+        for index in first..(first + batch_size).min(options.droplets) {
+            simulation.droplet(index);
+        }
+        simulation.apply();
+    }
+
+    for _ in 0..options.thermal_iterations {
+        for index in 0..simulation.heights.len() {
+            simulation.thermal(index);
+        }
+        simulation.apply();
+    }
+
+    scale.restore(heightmap, &simulation.heights)
+}
+
+/// Runs the same erosion as `erode` in compute shaders. Results match the CPU within float
+/// tolerance, as GPU square roots and divisions may round differently.
+pub fn erode_gpu(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    heightmap: &Heightmap,
+    options: &ErosionOptions,
+) -> Heightmap {
+    let scale = HeightScale::new(heightmap);
+    let mut params = ErosionParams::new(heightmap, &scale, options);
+    let heights = scale.normalize(heightmap);
+    let size = (heights.len() * std::mem::size_of::<f32>()) as wgpu::BufferAddress;
+
+    let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Erosion params"),
+        contents: bytemuck::cast_slice(&[params]),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+    let heights_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Erosion heights"),
+        contents: bytemuck::cast_slice(&heights),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+    });
+    let deltas_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Erosion deltas"),
+        contents: bytemuck::cast_slice(&vec![0i32; heights.len()]),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Erosion readback"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let storage = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Erosion bind group layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            storage(1),
+            storage(2),
+        ],
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Erosion bind group"),
+        layout: &bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: heights_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: deltas_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Erosion pipeline layout"),
+        bind_group_layouts: &[&bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Erosion"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("../erosion.wgsl").into()),
+    });
+    let pipeline = |entry_point| {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point,
+            compilation_options: Default::default(),
+            cache: None,
+        })
+    };
+    let droplets_pipeline = pipeline("droplets");
+    let thermal_pipeline = pipeline("thermal");
+    let apply_pipeline = pipeline("apply");
+
+    let cell_workgroups = (heights.len() as u32).div_ceil(WORKGROUP_SIZE);
+    let dispatch = |encoder: &mut wgpu::CommandEncoder, pipeline, workgroups| {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(workgroups, 1, 1);
+    };
+
+    // Each batch needs its own params, and buffer writes land before the whole submission
+    let batch_size = options.batch_size.max(1);
+    for first in (0..options.droplets).step_by(batch_size as usize) {
+        params.first_droplet = first;
+        params.droplet_count = batch_size.min(options.droplets - first);
+        queue.write_buffer(&params_buffer, 0, bytemuck::cast_slice(&[params]));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Erosion droplets"),
+        });
+        dispatch(
+            &mut encoder,
+            &droplets_pipeline,
+            params.droplet_count.div_ceil(WORKGROUP_SIZE),
+        );
+        dispatch(&mut encoder, &apply_pipeline, cell_workgroups);
+        queue.submit(Some(encoder.finish()));
+    }
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Erosion thermal"),
+    });
+    for _ in 0..options.thermal_iterations {
+        dispatch(&mut encoder, &thermal_pipeline, cell_workgroups);
+        dispatch(&mut encoder, &apply_pipeline, cell_workgroups);
+    }
+    encoder.copy_buffer_to_buffer(&heights_buffer, 0, &readback_buffer, 0, size);
+    queue.submit(Some(encoder.finish()));
+
+    let slice = readback_buffer.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |_| {});
+    device.poll(wgpu::Maintain::Wait);
+    let heights: Vec<f32> = bytemuck::cast_slice(&slice.get_mapped_range()).to_vec();
+    readback_buffer.unmap();
+
+    scale.restore(heightmap, &heights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor::GeoBounds;
+
+    fn cone() -> Heightmap {
+        let size = 64;
+        let bounds = GeoBounds {
+            north: 0.01,
+            south: 0.0,
+            east: 0.01,
+            west: 0.0,
+        };
+        Heightmap::from_fn(size, size, bounds, |x, y| {
+            let (x, y) = (x as f32 - 32.0, y as f32 - 32.0);
+            (1000.0 - 20.0 * (x * x + y * y).sqrt()).max(0.0)
+        })
+    }
+
+    #[test]
+    fn same_seed_same_terrain() {
+        let options = ErosionOptions {
+            droplets: 4000,
+            ..Default::default()
+        };
+        let heightmap = cone();
+        let eroded = erode(&heightmap, &options);
+
+        assert_ne!(eroded.elevations, heightmap.elevations);
+        assert_eq!(eroded.elevations, erode(&heightmap, &options).elevations);
+    }
+
+    #[test]
+    #[ignore = "needs a wgpu adapter"]
+    fn gpu_matches_cpu() {
+        let instance = wgpu::Instance::default();
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+                .expect("no GPU or software adapter to run the erosion shader on");
+        let (device, queue) =
+            pollster::block_on(adapter.request_device(&Default::default(), None)).unwrap();
+
+        let options = ErosionOptions {
+            droplets: 4000,
+            ..Default::default()
+        };
+        let heightmap = cone();
+        let cpu = erode(&heightmap, &options);
+        let gpu = erode_gpu(&device, &queue, &heightmap, &options);
+
+        // Rounding differences can nudge a few droplets onto other paths
+        let (min, max) = heightmap.min_max();
+        for (a, b) in cpu.elevations.iter().zip(&gpu.elevations) {
+            assert!((a - b).abs() < (max - min) * 0.005, "{a} vs {b}");
+        }
+    }
+
+    #[test]
+    fn thermal_erosion_keeps_volume_and_flattens() {
+        let options = ErosionOptions {
+            droplets: 0,
+            thermal_iterations: 20,
+            ..Default::default()
+        };
+        let heightmap = cone();
+        let eroded = erode(&heightmap, &options);
+
+        let volume = |h: &Heightmap| h.elevations.iter().map(|&e| e as f64).sum::<f64>();
+        let relative = (volume(&eroded) - volume(&heightmap)).abs() / volume(&heightmap);
+        assert!(relative < 1e-4, "volume changed by {relative}");
+        assert!(eroded.min_max().1 < heightmap.min_max().1);
+    }
+}
@@ -0,0 +1,207 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::noise::Noise;
+use super::processor::{GeoBounds, Heightmap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseKind {
+    /// Rolling hills from plain fractal Brownian motion.
+    Fbm,
+    /// Sharp mountain crests from inverted, sharpened octaves.
+    Ridged,
+    /// Fbm sampled through an fbm-displaced domain, giving eroded-looking folds.
+    DomainWarped,
+}
+
+/// Settings for a procedural heightmap, loadable from TOML like the material rules.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorOptions {
+    pub seed: u64,
+    pub kind: NoiseKind,
+    pub octaves: u32,
+    /// Wavelength in meters of the coarsest octave.
+    pub scale: f32,
+    /// Frequency multiplier between octaves.
+    pub lacunarity: f32,
+    /// Amplitude multiplier between octaves.
+    pub gain: f32,
+    /// Domain displacement in multiples of `scale`, only used by `DomainWarped`.
+    pub warp: f32,
+    /// Elevation in meters of the noise's zero level.
+    pub base_elevation: f32,
+    /// Elevation in meters that a noise value of 1 adds to the base.
+    pub relief: f32,
+}
+
+impl GeneratorOptions {
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            kind: NoiseKind::Ridged,
+            octaves: 6,
+            scale: 20_000.0,
+            lacunarity: 2.0,
+            gain: 0.5,
+            warp: 0.4,
+            base_elevation: -200.0,
+            relief: 1500.0,
+        }
+    }
+}
+
+/// Generates a `width` x `height` heightmap covering `bounds`, in the same form the DEM
+/// pipeline produces. The same options always give the same heightmap.
+pub fn generate(
+    options: &GeneratorOptions,
+    width: usize,
+    height: usize,
+    bounds: GeoBounds,
+) -> Heightmap {
+    let noise = Noise::new(options.seed);
+    let mut heightmap = Heightmap::new(width, height, bounds, vec![0.0; width * height]);
+    let (dx, dy) = heightmap.cell_size();
+
+    for y in 0..height {
+        for x in 0..width {
+            // Noise space is measured in coarsest-octave wavelengths
+            let px = x as f32 * dx / options.scale;
+            let py = y as f32 * dy / options.scale;
+
+            let value = match options.kind {
+                NoiseKind::Fbm => {
+                    noise.fbm2(px, py, options.octaves, options.lacunarity, options.gain)
+                }
+                NoiseKind::Ridged => ridged(&noise, px, py, options),
+                NoiseKind::DomainWarped => {
+                    let warp_x = noise.fbm2(px + 5.2, py + 1.3, 4, 2.0, 0.5);
+                    let warp_y = noise.fbm2(px - 1.7, py + 9.2, 4, 2.0, 0.5);
+                    noise.fbm2(
+                        px + options.warp * warp_x,
+                        py + options.warp * warp_y,
+                        options.octaves,
+                        options.lacunarity,
+                        options.gain,
+                    )
+                }
+            };
+
+            heightmap.elevations[y * width + x] = options.base_elevation + options.relief * value;
+        }
+    }
+
+    heightmap
+}
+
+// Musgrave's ridged multifractal, each octave is weighted by the sharpness of the one before
+// so detail gathers along the crests. Returns roughly 0..=1
+fn ridged(noise: &Noise, x: f32, y: f32, options: &GeneratorOptions) -> f32 {
+    let (mut sum, mut amplitude, mut frequency, mut total, mut weight) = (0.0, 1.0, 1.0, 0.0, 1.0);
+
+    for octave in 0..options.octaves {
+        let shift = octave as f32 * 17.31;
+        let ridge = 1.0
+            - noise
+                .get2(x * frequency + shift, y * frequency - shift)
+                .abs();
+        let ridge = ridge * ridge * weight;
+        weight = (ridge * 2.0).clamp(0.0, 1.0);
+
+        sum += ridge * amplitude;
+        total += amplitude;
+        amplitude *= options.gain;
+        frequency *= options.lacunarity;
+    }
+
+    sum / total.max(f32::EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: GeoBounds = GeoBounds {
+        north: 46.0,
+        south: 45.9,
+        east: 7.1,
+        west: 7.0,
+    };
+
+    #[test]
+    fn options_load_from_toml() {
+        let options = GeneratorOptions::from_toml(
+            r#"
+            seed = 42
+            kind = "domain_warped"
+            relief = 800.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.seed, 42);
+        assert_eq!(options.kind, NoiseKind::DomainWarped);
+        assert_eq!(options.relief, 800.0);
+        assert_eq!(options.octaves, GeneratorOptions::default().octaves);
+
+        assert!(GeneratorOptions::from_toml("kind = \"volcanic\"").is_err());
+        assert!(GeneratorOptions::from_toml("roughness = 1.0").is_err());
+    }
+
+    #[test]
+    fn seeds_are_deterministic() {
+        for kind in [NoiseKind::Fbm, NoiseKind::Ridged, NoiseKind::DomainWarped] {
+            let options = GeneratorOptions {
+                seed: 7,
+                kind,
+                ..Default::default()
+            };
+            let first = generate(&options, 32, 32, BOUNDS);
+            let second = generate(&options, 32, 32, BOUNDS);
+            assert_eq!(first.elevations, second.elevations);
+
+            let other = generate(&GeneratorOptions { seed: 8, ..options }, 32, 32, BOUNDS);
+            assert_ne!(first.elevations, other.elevations);
+        }
+    }
+
+    #[test]
+    fn elevations_stay_within_the_relief() {
+        for kind in [NoiseKind::Fbm, NoiseKind::Ridged, NoiseKind::DomainWarped] {
+            let options = GeneratorOptions {
+                seed: 3,
+                kind,
+                ..Default::default()
+            };
+            let heightmap = generate(&options, 64, 64, BOUNDS);
+            let (min, max) = heightmap
+                .elevations
+                .iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), &e| {
+                    (min.min(e), max.max(e))
+                });
+
+            let lowest = match kind {
+                NoiseKind::Ridged => options.base_elevation,
+                _ => options.base_elevation - options.relief,
+            };
+            assert!(min >= lowest, "{kind:?} dips to {min}");
+            assert!(
+                max <= options.base_elevation + options.relief,
+                "{kind:?} peaks at {max}"
+            );
+            assert!(max - min > 0.05 * options.relief, "{kind:?} is flat");
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+/// Reference surface that heightmap elevations are measured from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerticalDatum {
+    /// Height above the WGS84 ellipsoid, as reported by GNSS and some satellite DEMs.
+    Wgs84Ellipsoid,
+    /// Orthometric height above the EGM96 geoid, used by SRTM and NASADEM.
+    Egm96,
+    /// Orthometric height above the EGM2008 geoid, used by Copernicus DEM.
+    Egm2008,
+}
+
+impl VerticalDatum {
+    /// Maps an EPSG vertical CRS code, as found in a GeoTIFF's `VerticalCSTypeGeoKey`. The
+    /// ellipsoid has no vertical CRS code, heights above it come with a 3D geographic CRS.
+    pub fn from_epsg(code: u16) -> Option<Self> {
+        match code {
+            5773 => Some(Self::Egm96),
+            3855 => Some(Self::Egm2008),
+            _ => None,
+        }
+    }
+
+    /// Datum of a GeoTIFF key directory's `VerticalCSTypeGeoKey`. Without one, a 3D WGS84
+    /// `GeographicTypeGeoKey` means the heights are ellipsoidal.
+    pub fn from_geo_keys(vertical: Option<u16>, geographic: Option<u16>) -> Option<Self> {
+        const WGS84_3D: u16 = 4979;
+        match vertical {
+            Some(code) => Self::from_epsg(code),
+            None => (geographic == Some(WGS84_3D)).then_some(Self::Wgs84Ellipsoid),
+        }
+    }
+
+    /// Where the undulation grid for this datum is kept, see [`Geoid::load`]. The grids are
+    /// PROJ's GeoTIFFs, EGM96 every 15' and EGM2008 every 2.5'.
+    pub fn asset_path(&self) -> Option<&'static Path> {
+        match self {
+            Self::Wgs84Ellipsoid => None,
+            Self::Egm96 => Some(Path::new(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/assets/geoid/us_nga_egm96_15.tif"
+            ))),
+            Self::Egm2008 => Some(Path::new("assets/geoid/us_nga_egm08_25.tif")),
+        }
+    }
+
+    /// Whether the grid ships with the repository rather than being downloaded. EGM96 is
+    /// small enough to keep in `assets/geoid`, EGM2008 is tens of megabytes.
+    pub fn is_bundled(&self) -> bool {
+        *self == Self::Egm96
+    }
+
+    /// Where the downloader fetches the grid at `asset_path` from, `None` for bundled grids.
+    pub fn grid_url(&self) -> Option<String> {
+        if self.is_bundled() {
+            return None;
+        }
+        let name = self.asset_path()?.file_name()?.to_str()?;
+        Some(format!("https://cdn.proj.org/{name}"))
+    }
+}
+
+/// Geoid undulation grid, the height of the geoid above the WGS84 ellipsoid in meters.
+#[derive(Debug, Clone)]
+pub struct Geoid {
+    pub datum: VerticalDatum,
+    south: f64,
+    north: f64,
+    west: f64,
+    lat_spacing: f64,
+    lon_spacing: f64,
+    rows: usize,
+    columns: usize,
+    // Row-major from the north-west corner
+    undulations: Vec<f32>,
+}
+
+impl Geoid {
+    /// The grid for `datum` among `geoids`, or `None` for the ellipsoid, which needs none.
+    pub fn find<'a>(
+        geoids: &[&'a Geoid],
+        datum: VerticalDatum,
+    ) -> Result<Option<&'a Geoid>, Box<dyn std::error::Error>> {
+        if datum == VerticalDatum::Wgs84Ellipsoid {
+            return Ok(None);
+        }
+        geoids
+            .iter()
+            .find(|geoid| geoid.datum == datum)
+            .copied()
+            .map(Some)
+            .ok_or_else(|| format!("no geoid grid for {datum:?}").into())
+    }
+
+    /// Reads a single-band float GeoTIFF of undulations, like the PROJ grids. Samples may be
+    /// georeferenced by their corners or their centers.
+    pub fn from_geotiff<R: Read + Seek>(
+        reader: R,
+        datum: VerticalDatum,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        const RASTER_TYPE: u16 = 1025;
+        const PIXEL_IS_POINT: u16 = 2;
+
+        let mut decoder = Decoder::new(reader)?;
+        let (width, height) = decoder.dimensions()?;
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+        if tiepoint.len() < 6 || scale.len() < 2 {
+            return Err("geoid GeoTIFF is missing georeferencing tags".into());
+        }
+        let pixel_is_point = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)?
+            .unwrap_or_default()
+            .get(4..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .any(|key| key[0] == RASTER_TYPE && key[1] == 0 && key[3] == PIXEL_IS_POINT);
+
+        let undulations = match decoder.read_image()? {
+            DecodingResult::F32(data) => data,
+            DecodingResult::F64(data) => data.into_iter().map(|n| n as f32).collect(),
+            _ => return Err("geoid GeoTIFF samples aren't floats".into()),
+        };
+
+        // Center of the north-west sample
+        let offset = if pixel_is_point { 0.0 } else { 0.5 };
+        let (lon_spacing, lat_spacing) = (scale[0], scale[1]);
+        let west = tiepoint[3] + (offset - tiepoint[0]) * lon_spacing;
+        let north = tiepoint[4] - (offset - tiepoint[1]) * lat_spacing;
+        let (rows, columns) = (height as usize, width as usize);
+        Ok(Self {
+            datum,
+            south: north - (rows - 1) as f64 * lat_spacing,
+            north,
+            west,
+            lat_spacing,
+            lon_spacing,
+            rows,
+            columns,
+            undulations,
+        })
+    }
+
+    /// Loads the datum's grid from its asset path, either bundled or filled by
+    /// `cargo run -- download`. A missing grid is an error, there is no approximation to fall
+    /// back on.
+    pub fn load(datum: VerticalDatum) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = datum
+            .asset_path()
+            .ok_or("the ellipsoid has no geoid grid")?;
+        let file = File::open(path).map_err(|e| match datum.grid_url() {
+            Some(url) => format!(
+                "couldn't open the {datum:?} geoid grid {}: {e}, run `cargo run -- download` \
+                 or download it from {url}",
+                path.display(),
+            ),
+            None => format!(
+                "couldn't open the {datum:?} geoid grid {}: {e}, it ships with the repository",
+                path.display(),
+            ),
+        })?;
+        Self::from_geotiff(BufReader::new(file), datum)
+    }
+
+    /// Bilinearly interpolated undulation at a coordinate. Global grids wrap in longitude,
+    /// regional ones are clamped to their nearest edge like latitudes are.
+    pub fn undulation(&self, lat: f64, lon: f64) -> f32 {
+        let row = ((self.north - lat.clamp(self.south, self.north)) / self.lat_spacing)
+            .min((self.rows - 1) as f64);
+
+        // Global grids either end a step short of 360 degrees or repeat their first column
+        let span = (self.columns - 1) as f64 * self.lon_spacing;
+        let wraps = span + self.lon_spacing >= 360.0 - 1e-9;
+        let mut east_of_west = (lon - self.west).rem_euclid(360.0);
+        if !wraps && east_of_west > span {
+            // Outside a regional grid, whichever edge is closer around the globe
+            east_of_west = if east_of_west - span < 360.0 - east_of_west {
+                span
+            } else {
+                0.0
+            };
+        }
+        let column = east_of_west / self.lon_spacing;
+
+        let (r0, c0) = (row.floor() as usize, column.floor() as usize);
+        let r1 = (r0 + 1).min(self.rows - 1);
+        let c1 = if wraps {
+            (c0 + 1) % self.columns
+        } else {
+            (c0 + 1).min(self.columns - 1)
+        };
+        let (tr, tc) = (row.fract() as f32, column.fract() as f32);
+
+        let at = |r: usize, c: usize| self.undulations[r * self.columns + c];
+        let top = at(r0, c0) * (1.0 - tc) + at(r0, c1) * tc;
+        let bottom = at(r1, c0) * (1.0 - tc) + at(r1, c1) * tc;
+        top * (1.0 - tr) + bottom * tr
+    }
+}
+
+// Test grids are written in the NGA `.GRD` text format, which is shorter to write by hand
+#[cfg(test)]
+impl Geoid {
+    /// Parses the NGA `.GRD` text format: a `south north west east dlat dlon` header followed
+    /// by undulations row by row from north to south, west to east.
+    pub fn from_grd(
+        source: &str,
+        datum: VerticalDatum,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut values = source.split_whitespace().map(str::parse::<f64>);
+        let mut header = [0.0; 6];
+        for value in header.iter_mut() {
+            *value = values.next().ok_or("geoid grid header is truncated")??;
+        }
+        let [south, north, west, east, lat_spacing, lon_spacing] = header;
+
+        let rows = ((north - south) / lat_spacing).round() as usize + 1;
+        let columns = ((east - west) / lon_spacing).round() as usize + 1;
+        let undulations = values
+            .map(|value| value.map(|value| value as f32))
+            .collect::<Result<Vec<_>, _>>()?;
+        if undulations.len() != rows * columns {
+            return Err(format!(
+                "geoid grid has {} values, expected {}x{}",
+                undulations.len(),
+                rows,
+                columns
+            )
+            .into());
+        }
+
+        Ok(Self {
+            datum,
+            south,
+            north,
+            west,
+            lat_spacing,
+            lon_spacing,
+            rows,
+            columns,
+            undulations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 degree grid over two rows, the last column sits just west of the seam
+    const GRID: &str = "
+        0 10 0 350 10 10
+        0 10 20 30 40 50 60 70 80 90 100 110 120 130 140 150 160 170 180 190 200 210 220 230 240 250 260 270 280 290 300 310 320 330 340 350
+        1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1
+    ";
+
+    #[test]
+    fn parses_grd_grids() {
+        let geoid = Geoid::from_grd(GRID, VerticalDatum::Egm96).unwrap();
+        assert_eq!((geoid.rows, geoid.columns), (2, 36));
+        assert_eq!(geoid.undulation(10.0, 30.0), 30.0);
+        assert_eq!(geoid.undulation(0.0, 30.0), 1.0);
+
+        assert!(Geoid::from_grd("0 10 0 350 10", VerticalDatum::Egm96).is_err());
+        assert!(Geoid::from_grd("0 10 0 350 10 10 1 2 3", VerticalDatum::Egm96).is_err());
+        assert!(Geoid::from_grd("0 10 0 350 10 ten", VerticalDatum::Egm96).is_err());
+    }
+
+    #[test]
+    fn interpolates_bilinearly_across_the_seam() {
+        let geoid = Geoid::from_grd(GRID, VerticalDatum::Egm96).unwrap();
+        assert_eq!(geoid.undulation(10.0, 15.0), 15.0);
+        assert_eq!(geoid.undulation(5.0, 10.0), 5.5);
+        assert_eq!(geoid.undulation(5.0, 15.0), 8.0);
+        // Past the last column the grid wraps back to 0 degrees
+        assert_eq!(geoid.undulation(10.0, 355.0), 175.0);
+        assert_eq!(geoid.undulation(10.0, -5.0), 175.0);
+        assert_eq!(geoid.undulation(10.0, 370.0), 10.0);
+        // Latitudes are clamped to the grid
+        assert_eq!(geoid.undulation(80.0, 30.0), 30.0);
+    }
+
+    #[test]
+    fn regional_grids_clamp_outside_their_edges() {
+        // 2 to 4 degrees east, away from the seam
+        let geoid =
+            Geoid::from_grd("46 47 2 4 1 1  10 20 30  40 50 60", VerticalDatum::Egm96).unwrap();
+        assert_eq!(geoid.undulation(47.0, 3.0), 20.0);
+        assert_eq!(geoid.undulation(47.0, 3.5), 25.0);
+        assert_eq!(geoid.undulation(47.0, 5.0), 30.0);
+        assert_eq!(geoid.undulation(47.0, 90.0), 30.0);
+        assert_eq!(geoid.undulation(47.0, 1.0), 10.0);
+        assert_eq!(geoid.undulation(47.0, -90.0), 10.0);
+        assert_eq!(geoid.undulation(46.0, 362.5), 45.0);
+    }
+
+    // A PROJ-style grid: float samples, tiepoint on the north-west sample
+    fn geotiff(raster_type: u16, values: &[f32], width: u32, height: u32) -> Vec<u8> {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut bytes).unwrap();
+        let mut image = encoder
+            .new_image::<colortype::Gray32Float>(width, height)
+            .unwrap();
+        let tiepoint = [0.0, 0.0, 0.0, 2.0, 47.0, 0.0];
+        image
+            .encoder()
+            .write_tag(Tag::ModelTiepointTag, &tiepoint[..])
+            .unwrap();
+        image
+            .encoder()
+            .write_tag(Tag::ModelPixelScaleTag, &[1.0, 1.0, 0.0][..])
+            .unwrap();
+        let keys = [1, 1, 0, 1, 1025, 0, 1, raster_type];
+        image
+            .encoder()
+            .write_tag(Tag::GeoKeyDirectoryTag, &keys[..])
+            .unwrap();
+        image.write_data(values).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn reads_geotiff_grids() {
+        let values = [10.0, 20.0, 30.0, 40.0, 50.0, 60.0];
+
+        // Tiepoint at the sample itself
+        let points = geotiff(2, &values, 3, 2);
+        let geoid =
+            Geoid::from_geotiff(std::io::Cursor::new(points), VerticalDatum::Egm2008).unwrap();
+        assert_eq!((geoid.rows, geoid.columns), (2, 3));
+        assert_eq!(geoid.undulation(47.0, 2.0), 10.0);
+        assert_eq!(geoid.undulation(46.0, 4.0), 60.0);
+        assert_eq!(geoid.undulation(46.5, 2.5), 30.0);
+
+        // Tiepoint at the sample's corner, half a cell from its center
+        let areas = geotiff(1, &values, 3, 2);
+        let geoid = Geoid::from_geotiff(std::io::Cursor::new(areas), VerticalDatum::Egm96).unwrap();
+        assert_eq!(geoid.undulation(46.5, 2.5), 10.0);
+        assert_eq!(geoid.undulation(45.5, 4.5), 60.0);
+    }
+
+    #[test]
+    fn missing_grids_are_errors() {
+        if !VerticalDatum::Egm2008.asset_path().unwrap().exists() {
+            let error = Geoid::load(VerticalDatum::Egm2008).unwrap_err().to_string();
+            assert!(
+                error.contains("https://cdn.proj.org/us_nga_egm08_25.tif"),
+                "{error}"
+            );
+        }
+        assert!(Geoid::load(VerticalDatum::Wgs84Ellipsoid).is_err());
+    }
+
+    #[test]
+    fn only_unbundled_grids_are_downloaded() {
+        assert_eq!(VerticalDatum::Egm96.grid_url(), None);
+        assert!(VerticalDatum::Egm96.asset_path().unwrap().is_absolute());
+        assert_eq!(
+            VerticalDatum::Egm2008.grid_url().as_deref(),
+            Some("https://cdn.proj.org/us_nga_egm08_25.tif")
+        );
+    }
+}
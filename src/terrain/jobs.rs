@@ -0,0 +1,319 @@
+use rayon::prelude::*;
+
+use super::density::DensityOptions;
+use super::lidar::{self, ClassTable, LidarPoints};
+use super::osm::{self, OsmFeatures};
+use super::processor::Heightmap;
+use super::vegetation::{self, Neighbourhood, TemplateLibrary, VegetationOptions, VegetationTable};
+use super::voxelizer::{
+    self, ChunkRegion, GeoCoord, MaterialSources, VoxelChunk, VoxelWorld, HEIGHT, LENGTH, WIDTH,
+};
+use super::water::{WaterKind, WaterMap};
+
+/// Meters of air kept above a chunk's highest terrain or water for what's built or grows on
+/// it. Anything taller is cut off at the top layer.
+pub const FEATURE_HEADROOM: f32 = 40.0;
+
+/// Everything the chunks of a region are built from. Worker threads only read it.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionSources<'a> {
+    pub heightmap: &'a Heightmap,
+    pub materials: MaterialSources<'a>,
+    /// Water routing depends on the whole drainage basin, so it's computed for the region up
+    /// front rather than per chunk.
+    pub water: Option<&'a WaterMap>,
+    pub density: Option<&'a DensityOptions>,
+    /// Scanned points merged over the terrain, before any OpenStreetMap features.
+    pub lidar: Option<(&'a LidarPoints, &'a ClassTable)>,
+    /// Buildings, roads and water stamped over the terrain, before vegetation so trees don't
+    /// grow through roofs.
+    pub osm: Option<&'a OsmFeatures>,
+    pub vegetation: Option<(
+        &'a VegetationTable,
+        &'a TemplateLibrary,
+        &'a VegetationOptions,
+    )>,
+}
+
+/// Splits the heightmap into `chunks[0]` by `chunks[1]` chunks, east by south, and voxelizes
+/// them on `threads` worker threads, or one per core for `None`. Each chunk only depends on
+/// the sources and its own position, so the world is the same for any thread count.
+pub fn voxelize_chunks(
+    sources: &RegionSources,
+    chunks: [usize; 2],
+    threads: Option<usize>,
+) -> Result<VoxelWorld, Box<dyn std::error::Error>> {
+    let [columns, rows] = chunks;
+
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        pool = pool.num_threads(threads);
+    }
+    let built: Vec<VoxelChunk> = pool.build()?.install(|| {
+        (0..columns * rows)
+            .into_par_iter()
+            .map(|index| voxelize_chunk(sources, chunks, [index % columns, index / columns]))
+            .collect()
+    });
+
+    let heightmap = sources.heightmap;
+    let (dx, _) = heightmap.cell_size();
+    let last_x = (columns * LENGTH - 1).max(1) as f32;
+    let voxel_length = dx * (heightmap.width - 1) as f32 / last_x;
+    let mut world = VoxelWorld::new(heightmap.bounds, voxel_length);
+    for chunk in built {
+        world.insert(chunk);
+    }
+    Ok(world)
+}
+
+/// Voxelizes the chunk at `[column, row]` of the heightmap split into `chunks[0]` by
+/// `chunks[1]` chunks, the same way `voxelize_chunks` builds it.
+pub fn voxelize_chunk(
+    sources: &RegionSources,
+    chunks: [usize; 2],
+    [column, row]: [usize; 2],
+) -> VoxelChunk {
+    let heightmap = sources.heightmap;
+    let region = chunk_region(heightmap, sources.water, chunks, [column, row]);
+    let coord = chunk_coord(heightmap, &region);
+
+    let mut volume = terrain_volume(sources, &region);
+    if let Some((table, library, options)) = sources.vegetation {
+        // Features near an edge grow from the neighbouring chunks' sites, so rebuild their
+        // terrain to find where those stand
+        let neighbours = std::array::from_fn::<_, 3, _>(|dx| {
+            std::array::from_fn(|dz| {
+                let (column, row) = ((column + dx).wrapping_sub(1), (row + dz).wrapping_sub(1));
+                if column >= chunks[0] || row >= chunks[1] {
+                    return None;
+                }
+
+                // Rebuilt in this chunk's layers, so their sites stand at the right height here
+                let neighbour = ChunkRegion {
+                    min_height: region.min_height,
+                    max_height: region.max_height,
+                    ..chunk_region(heightmap, sources.water, chunks, [column, row])
+                };
+                let seed = vegetation::chunk_seed(options.seed, chunk_coord(heightmap, &neighbour));
+                let terrain = if (dx, dz) == (1, 1) {
+                    Box::new(volume)
+                } else {
+                    Box::new(terrain_volume(sources, &neighbour))
+                };
+                Some((terrain, seed))
+            })
+        });
+
+        let neighbourhood: Neighbourhood = neighbours.each_ref().map(|column| {
+            column
+                .each_ref()
+                .map(|chunk| chunk.as_ref().map(|(terrain, seed)| (&**terrain, *seed)))
+        });
+        vegetation::scatter(&mut volume, &neighbourhood, table, library, options);
+    }
+
+    let surface = voxelizer::surface_heights(&volume, heightmap, &region);
+    VoxelChunk::new(coord, region.min_height as f64, &volume)
+        .with_surface(surface)
+        .with_voxel_size(region.voxel_size(heightmap))
+}
+
+type Volume = [[[u32; WIDTH]; HEIGHT]; LENGTH];
+
+/// Heightmap area and elevation range of the chunk at `[column, row]`, as `voxelize_chunk`
+/// lays it out. The range runs from the chunk's lowest terrain to `FEATURE_HEADROOM` above
+/// its highest terrain or water, so flat chunks get layers thin enough for buildings.
+pub fn chunk_region(
+    heightmap: &Heightmap,
+    water: Option<&WaterMap>,
+    chunks: [usize; 2],
+    [column, row]: [usize; 2],
+) -> ChunkRegion {
+    // Voxel columns are spread evenly over the whole heightmap, so chunk edges don't repeat
+    let last_x = (chunks[0] * LENGTH - 1).max(1) as f32;
+    let last_z = (chunks[1] * WIDTH - 1).max(1) as f32;
+    let mut region = ChunkRegion {
+        u: [
+            (column * LENGTH) as f32 / last_x,
+            (column * LENGTH + LENGTH - 1) as f32 / last_x,
+        ],
+        v: [
+            (row * WIDTH) as f32 / last_z,
+            (row * WIDTH + WIDTH - 1) as f32 / last_z,
+        ],
+        min_height: f32::INFINITY,
+        max_height: f32::NEG_INFINITY,
+    };
+
+    for x in 0..LENGTH {
+        for z in 0..WIDTH {
+            let (u, v) = region.uv(x, z);
+            let elevation = heightmap.sample(u, v);
+            let top = match water.map(|water| water.sample(u, v)) {
+                Some((WaterKind::Ocean | WaterKind::Lake, level)) => elevation.max(level),
+                _ => elevation,
+            };
+            region.min_height = region.min_height.min(elevation);
+            region.max_height = region.max_height.max(top);
+        }
+    }
+    region.max_height += FEATURE_HEADROOM;
+    region
+}
+
+// Position of the chunk's north-west corner, which also seeds its vegetation
+fn chunk_coord(heightmap: &Heightmap, region: &ChunkRegion) -> GeoCoord {
+    let (u, v) = region.corner_uv();
+    let (lat, lon) = heightmap.bounds.from_uv(u as f64, v as f64);
+    GeoCoord { lat, lon }
+}
+
+// Everything but vegetation, which depends on the chunks around this one
+fn terrain_volume(sources: &RegionSources, region: &ChunkRegion) -> Volume {
+    let heightmap = sources.heightmap;
+    let mut volume = voxelizer::voxelize_region(
+        heightmap,
+        &sources.materials,
+        sources.water,
+        sources.density,
+        region,
+    );
+    if let Some((points, table)) = sources.lidar {
+        lidar::stamp(&mut volume, points, table, region);
+    }
+    if let Some(features) = sources.osm {
+        osm::stamp(&mut volume, features, heightmap, region);
+    }
+    volume
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geodesy;
+    use crate::terrain::generator::{self, GeneratorOptions};
+    use crate::terrain::processor::{self, TEST_BOUNDS};
+    use crate::terrain::voxelizer::{AIR, HEIGHT, WOOD};
+    use crate::terrain::water::WaterOptions;
+
+    #[test]
+    fn thread_count_does_not_change_output() {
+        let heightmap = generator::generate(&GeneratorOptions::default(), 96, 96, TEST_BOUNDS);
+        let water = WaterMap::compute(&heightmap, None, &WaterOptions::default());
+        let density = DensityOptions::default();
+        let table = VegetationTable::default();
+        let library = TemplateLibrary::default();
+        let options = VegetationOptions::default();
+        let sources = RegionSources {
+            heightmap: &heightmap,
+            materials: MaterialSources::default(),
+            water: Some(&water),
+            density: Some(&density),
+            lidar: None,
+            osm: None,
+            vegetation: Some((&table, &library, &options)),
+        };
+
+        let bytes = |threads| {
+            let world = voxelize_chunks(&sources, [3, 2], Some(threads)).unwrap();
+            assert_eq!(world.chunks.len(), 6);
+            let mut bytes = Vec::new();
+            world.write_to(&mut bytes).unwrap();
+            bytes
+        };
+
+        let single = bytes(1);
+        assert_eq!(bytes(4), single);
+        assert_eq!(bytes(7), single);
+    }
+
+    #[test]
+    fn surface_heights_follow_the_dem() {
+        // One heightmap pixel per column, rising four times faster east than south
+        let heightmap =
+            processor::test_heightmap(LENGTH, WIDTH, |x, z| x as f32 * 10.0 + z as f32 * 2.5);
+        let sources = RegionSources {
+            heightmap: &heightmap,
+            materials: MaterialSources::default(),
+            water: None,
+            density: None,
+            lidar: None,
+            osm: None,
+            vegetation: None,
+        };
+
+        let chunk = voxelize_chunk(&sources, [1, 1], [0, 0]);
+        let voxels = chunk.voxels.decode();
+        assert_eq!(chunk.surface.len(), LENGTH * WIDTH);
+        for x in 0..LENGTH {
+            for z in 0..WIDTH {
+                let height = (x as f32 * 10.0 + z as f32 * 2.5) / chunk.voxel_size[1];
+                let surface = chunk.surface[x * WIDTH + z].unwrap();
+                let layer = surface.layer as usize;
+                assert_eq!(layer, (height + 1e-3).floor() as usize);
+                assert_ne!(voxels[(x * HEIGHT + layer) * WIDTH + z], AIR);
+                if layer + 1 < HEIGHT {
+                    assert_eq!(voxels[(x * HEIGHT + layer + 1) * WIDTH + z], AIR);
+                }
+
+                let fill = (height + 1e-3).fract().max(1.0 / 16.0);
+                assert!((surface.fraction() - fill).abs() < 2e-3, "{x} {z}");
+            }
+        }
+
+        // Anything built on top of the surface keeps the voxel full
+        let region = chunk_region(&heightmap, None, [1, 1], [0, 0]);
+        let mut volume =
+            voxelizer::voxelize_region(&heightmap, &sources.materials, None, None, &region);
+        let layer = chunk.surface[3 * WIDTH + 4].unwrap().layer as usize;
+        volume[3][layer + 1][4] = WOOD;
+        let surface = voxelizer::surface_heights(&volume, &heightmap, &region);
+        assert_eq!(surface[3 * WIDTH + 4], None);
+        assert_eq!(surface[3 * WIDTH + 5], chunk.surface[3 * WIDTH + 5]);
+    }
+
+    #[test]
+    fn placed_voxels_stand_on_their_heightmap_samples() {
+        // One heightmap pixel per column over two by two chunks
+        let (width, height) = (2 * LENGTH, 2 * WIDTH);
+        let heightmap = processor::test_heightmap(width, height, |x, z| {
+            200.0 + x as f32 * 15.0 + z as f32 * 5.0
+        });
+        let bounds = heightmap.bounds;
+        let sources = RegionSources {
+            heightmap: &heightmap,
+            materials: MaterialSources::default(),
+            water: None,
+            density: None,
+            lidar: None,
+            osm: None,
+            vegetation: None,
+        };
+
+        // The renderer and the picker place every chunk in its own frame like this
+        let dlon = (bounds.east - bounds.west) / (width - 1) as f64;
+        let dlat = (bounds.north - bounds.south) / (height - 1) as f64;
+        for (column, row) in [(0, 0), (1, 0), (1, 1)] {
+            let chunk = voxelize_chunk(&sources, [2, 2], [column, row]);
+            let placement = chunk.placement();
+            let layer = chunk.voxel_size[1] as f64;
+
+            for (x, z) in [(0, 0), (5, 17), (LENGTH - 1, WIDTH - 1), (20, 3)] {
+                let top = chunk.surface[x * WIDTH + z].unwrap().layer as usize;
+                let center = [x as f64 + 0.5, top as f64 + 0.5, z as f64 + 0.5];
+                let (lat, lon, elevation) = geodesy::ecef_to_geodetic(placement.to_ecef(center));
+
+                let (x, z) = (column * LENGTH + x, row * WIDTH + z);
+                let cell_lat = bounds.north - z as f64 * dlat;
+                let cell_lon = bounds.west + x as f64 * dlon;
+                assert!((lat - cell_lat).abs() < dlat / 4.0, "{x} {z}: {lat}");
+                assert!((lon - cell_lon).abs() < dlon / 4.0, "{x} {z}: {lon}");
+
+                // The surface voxel's layer holds the elevation of the sample it was built from
+                let sample = heightmap.elevations[z * width + x] as f64;
+                assert!((elevation - sample).abs() < layer, "{x} {z}: {elevation}");
+            }
+        }
+    }
+}
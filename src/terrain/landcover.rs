@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use tiff::decoder::{Decoder, DecodingResult};
+
+use super::processor::{self, GeoBounds};
+use super::voxelizer;
+
+// ESA WorldCover class codes
+pub const TREE_COVER: u8 = 10;
+pub const SHRUBLAND: u8 = 20;
+pub const GRASSLAND: u8 = 30;
+pub const CROPLAND: u8 = 40;
+pub const BUILT_UP: u8 = 50;
+pub const BARE: u8 = 60;
+pub const SNOW_AND_ICE: u8 = 70;
+pub const PERMANENT_WATER: u8 = 80;
+pub const HERBACEOUS_WETLAND: u8 = 90;
+pub const MANGROVES: u8 = 95;
+pub const MOSS_AND_LICHEN: u8 = 100;
+
+/// Land-cover classes on a regular lat/lon grid, stored row-major from the north-west corner.
+#[derive(Debug, Clone)]
+pub struct LandCover {
+    pub width: usize,
+    pub height: usize,
+    pub bounds: GeoBounds,
+    pub classes: Vec<u8>,
+}
+
+impl LandCover {
+    /// Reads a headerless raster with one class byte per cell.
+    pub fn from_raw(
+        path: &Path,
+        width: usize,
+        height: usize,
+        bounds: GeoBounds,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let classes = std::fs::read(path)?;
+        if classes.len() != width * height {
+            return Err(format!(
+                "{} has {} cells, expected {}x{}",
+                path.display(),
+                classes.len(),
+                width,
+                height
+            )
+            .into());
+        }
+
+        Ok(Self {
+            width,
+            height,
+            bounds,
+            classes,
+        })
+    }
+
+    /// Reads a single-band 8-bit GeoTIFF in geographic coordinates, such as a WorldCover tile.
+    pub fn from_geotiff(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let (width, height) = decoder.dimensions()?;
+        let (width, height) = (width as usize, height as usize);
+        let bounds = processor::geotiff_bounds(&mut decoder)?;
+
+        let classes = match decoder.read_image()? {
+            DecodingResult::U8(classes) => classes,
+            _ => return Err(format!("{} is not an 8-bit raster", path.display()).into()),
+        };
+
+        Ok(Self {
+            width,
+            height,
+            bounds,
+            classes,
+        })
+    }
+
+    /// Nearest class at a coordinate, or `None` outside the raster.
+    pub fn class_at(&self, lat: f64, lon: f64) -> Option<u8> {
+        let (u, v) = self.bounds.to_uv(lat, lon);
+        if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+            return None;
+        }
+
+        let x = (u * self.width as f64) as usize;
+        let y = (v * self.height as f64) as usize;
+        Some(self.classes[y * self.width + x])
+    }
+}
+
+/// Maps land-cover classes to the material placed on top of a column.
+#[derive(Debug, Clone)]
+pub struct LandCoverTable {
+    surface: HashMap<u8, u32>,
+}
+
+impl LandCoverTable {
+    /// Parses a TOML table of `class = "material"` pairs, e.g. `10 = "forest_floor"`.
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let entries: HashMap<String, String> = toml::from_str(source)?;
+
+        let mut surface = HashMap::new();
+        for (class, material) in entries {
+            let class: u8 = class
+                .parse()
+                .map_err(|_| format!("invalid land-cover class {class:?}"))?;
+            let material = voxelizer::material_by_name(&material)
+                .ok_or_else(|| format!("unknown material {material:?} for class {class}"))?;
+            surface.insert(class, material);
+        }
+
+        Ok(Self { surface })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn surface_material(&self, class: u8) -> Option<u32> {
+        self.surface.get(&class).copied()
+    }
+}
+
+impl Default for LandCoverTable {
+    /// Table for the ESA WorldCover legend.
+    fn default() -> Self {
+        let surface = HashMap::from([
+            (TREE_COVER, voxelizer::FOREST_FLOOR),
+            (SHRUBLAND, voxelizer::SHRUB),
+            (GRASSLAND, voxelizer::GRASS),
+            (CROPLAND, voxelizer::FARMLAND),
+            (BUILT_UP, voxelizer::CONCRETE),
+            (BARE, voxelizer::SAND),
+            (SNOW_AND_ICE, voxelizer::SNOW),
+            (PERMANENT_WATER, voxelizer::WATER),
+            (HERBACEOUS_WETLAND, voxelizer::MUD),
+            (MANGROVES, voxelizer::MUD),
+            (MOSS_AND_LICHEN, voxelizer::SHRUB),
+        ]);
+
+        Self { surface }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor::Heightmap;
+    use crate::terrain::voxelizer::{MaterialSources, AIR, HEIGHT, LENGTH, WIDTH};
+
+    const BOUNDS: GeoBounds = GeoBounds {
+        north: 47.0,
+        south: 46.0,
+        east: 3.0,
+        west: 2.0,
+    };
+
+    #[test]
+    fn table_maps_classes_to_materials() {
+        let table = LandCoverTable::from_toml(
+            r#"
+            10 = "stone"
+            50 = "concrete"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(table.surface_material(TREE_COVER), Some(voxelizer::STONE));
+        assert_eq!(table.surface_material(BUILT_UP), Some(voxelizer::CONCRETE));
+        assert_eq!(table.surface_material(GRASSLAND), None);
+
+        assert!(LandCoverTable::from_toml(r#"10 = "marble""#).is_err());
+        assert!(LandCoverTable::from_toml(r#"forest = "stone""#).is_err());
+        assert_eq!(
+            LandCoverTable::default().surface_material(PERMANENT_WATER),
+            Some(voxelizer::WATER)
+        );
+    }
+
+    #[test]
+    fn raw_rasters_sample_nearest_cell() {
+        let path = std::env::temp_dir().join("landcover_raw_rasters_sample_nearest_cell.bin");
+        std::fs::write(
+            &path,
+            [
+                TREE_COVER,
+                SHRUBLAND,
+                GRASSLAND,
+                BARE,
+                SNOW_AND_ICE,
+                BUILT_UP,
+            ],
+        )
+        .unwrap();
+        let land_cover = LandCover::from_raw(&path, 3, 2, BOUNDS);
+        let wrong_size = LandCover::from_raw(&path, 2, 2, BOUNDS);
+        std::fs::remove_file(&path).unwrap();
+
+        let land_cover = land_cover.unwrap();
+        assert!(wrong_size.is_err());
+        assert_eq!(land_cover.class_at(46.9, 2.1), Some(TREE_COVER));
+        assert_eq!(land_cover.class_at(46.9, 2.5), Some(SHRUBLAND));
+        assert_eq!(land_cover.class_at(46.1, 2.9), Some(BUILT_UP));
+        assert_eq!(land_cover.class_at(47.5, 2.5), None);
+        assert_eq!(land_cover.class_at(46.5, 3.5), None);
+    }
+
+    #[test]
+    fn voxelized_columns_take_the_land_cover_material() {
+        // Western half forest, eastern half built up
+        let land_cover = LandCover {
+            width: 2,
+            height: 1,
+            bounds: BOUNDS,
+            classes: vec![TREE_COVER, BUILT_UP],
+        };
+        let table = LandCoverTable::default();
+        let heightmap = Heightmap::from_fn(16, 16, BOUNDS, |x, _| x as f32 * 10.0);
+        let sources = MaterialSources {
+            land_cover: Some((&land_cover, &table)),
+            rules: None,
+        };
+
+        let volume = voxelizer::heightmap_to_volume(&heightmap, &sources, None, None);
+        let top = |x: usize, z: usize| {
+            let y = (0..HEIGHT).rev().find(|&y| volume[x][y][z] != AIR).unwrap();
+            volume[x][y][z]
+        };
+        assert_eq!(top(2, WIDTH / 2), voxelizer::FOREST_FLOOR);
+        assert_eq!(top(LENGTH - 3, WIDTH / 2), voxelizer::CONCRETE);
+    }
+}
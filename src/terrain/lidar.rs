@@ -0,0 +1,644 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::geodesy;
+
+use super::geoid::{Geoid, VerticalDatum};
+use super::processor::Heightmap;
+use super::voxelizer::{self, ChunkRegion, AIR, HEIGHT, LENGTH, WIDTH};
+
+// ASPRS LAS classification codes
+pub const UNCLASSIFIED: u8 = 1;
+pub const GROUND: u8 = 2;
+pub const LOW_VEGETATION: u8 = 3;
+pub const MEDIUM_VEGETATION: u8 = 4;
+pub const HIGH_VEGETATION: u8 = 5;
+pub const BUILDING: u8 = 6;
+pub const LOW_NOISE: u8 = 7;
+pub const WATER: u8 = 9;
+pub const RAIL: u8 = 10;
+pub const ROAD_SURFACE: u8 = 11;
+pub const BRIDGE_DECK: u8 = 17;
+pub const HIGH_NOISE: u8 = 18;
+
+/// Classes describing the ground itself rather than something standing on it.
+const SURFACE_CLASSES: [u8; 3] = [GROUND, WATER, ROAD_SURFACE];
+
+// Public header sizes of LAS 1.0-1.2 and 1.4
+const HEADER_SIZE: usize = 227;
+const HEADER_SIZE_1_4: usize = 375;
+const VLR_HEADER_SIZE: usize = 54;
+
+// GeoTIFF keys in the LASF_Projection record
+const GEO_KEY_DIRECTORY: u16 = 34735;
+const GEOGRAPHIC_TYPE_KEY: u16 = 2048;
+const PROJECTED_TYPE_KEY: u16 = 3072;
+const VERTICAL_CS_TYPE_KEY: u16 = 4096;
+
+/// Coordinate system of a point cloud's x and y.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Longitude and latitude in degrees.
+    Geographic,
+    /// Easting and northing in meters.
+    Utm { zone: u8, north: bool },
+}
+
+impl Projection {
+    /// Recognizes WGS84, NAD83 and ETRS89 geographic and UTM systems, including 3D WGS84.
+    pub fn from_epsg(code: u16) -> Option<Self> {
+        let utm = |zone: u16, north| Self::Utm {
+            zone: zone as u8,
+            north,
+        };
+        match code {
+            4326 | 4979 | 4269 | 4258 => Some(Self::Geographic),
+            32601..=32660 => Some(utm(code - 32600, true)),
+            32701..=32760 => Some(utm(code - 32700, false)),
+            26901..=26923 => Some(utm(code - 26900, true)),
+            25828..=25838 => Some(utm(code - 25800, true)),
+            _ => None,
+        }
+    }
+
+    /// Picks the system out of GeoTIFF key codes, preferring the projected system over the
+    /// geographic one it's based on.
+    fn from_geo_keys(codes: &HashMap<u16, u16>) -> Option<Self> {
+        match codes.get(&PROJECTED_TYPE_KEY) {
+            Some(&code) => Self::from_epsg(code),
+            None => Self::from_epsg(*codes.get(&GEOGRAPHIC_TYPE_KEY)?),
+        }
+    }
+
+    /// `(lat, lon)` of a point's x and y.
+    pub fn to_geodetic(&self, x: f64, y: f64) -> (f64, f64) {
+        match *self {
+            Self::Geographic => (y, x),
+            Self::Utm { zone, north } => geodesy::utm_to_geodetic(zone, north, x, y),
+        }
+    }
+}
+
+// Codes of a GeoTIFF key directory by key: four shorts of header, then four per key, id,
+// location, count and value. Codes are stored inline, with location 0
+fn geo_key_codes(keys: &[u16]) -> HashMap<u16, u16> {
+    keys.get(4..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter(|key| key[1] == 0)
+        .map(|key| (key[0], key[3]))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LasPoint {
+    /// x, y and elevation in the cloud's coordinate system.
+    pub position: [f64; 3],
+    pub classification: u8,
+}
+
+/// Points of a LAS file, with the coordinate system named in its GeoTIFF keys when it's one
+/// `Projection` knows. Set `projection` by hand for anything else.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PointCloud {
+    pub projection: Option<Projection>,
+    /// Datum of the elevations, when the file names one.
+    pub vertical_datum: Option<VerticalDatum>,
+    pub points: Vec<LasPoint>,
+}
+
+fn bytes_at<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    bytes[offset..offset + N].try_into().unwrap()
+}
+
+impl PointCloud {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("laz"))
+        {
+            return Err(laz_error().into());
+        }
+        Self::from_las(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads LAS 1.0 to 1.4 with any of point formats 0 to 10.
+    pub fn from_las<R: Read>(mut reader: R) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut header = vec![0; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"LASF" {
+            return Err("not a LAS file".into());
+        }
+        let header_size = u16::from_le_bytes(bytes_at(&header, 94)) as usize;
+        if header_size < HEADER_SIZE {
+            return Err(format!("LAS header of {header_size} bytes").into());
+        }
+        header.resize(header_size, 0);
+        reader.read_exact(&mut header[HEADER_SIZE..])?;
+
+        let points_offset = u32::from_le_bytes(bytes_at(&header, 96)) as usize;
+        let vlr_count = u32::from_le_bytes(bytes_at(&header, 100));
+        let format = header[104];
+        let record_length = u16::from_le_bytes(bytes_at(&header, 105)) as usize;
+        // LAS 1.4 moved the count to 64 bits, leaving the legacy one 0 for new formats
+        let mut count = u32::from_le_bytes(bytes_at(&header, 107)) as u64;
+        if header_size >= HEADER_SIZE_1_4 && count == 0 {
+            count = u64::from_le_bytes(bytes_at(&header, 247));
+        }
+        let scale: [f64; 3] =
+            std::array::from_fn(|i| f64::from_le_bytes(bytes_at(&header, 131 + 8 * i)));
+        let offset: [f64; 3] =
+            std::array::from_fn(|i| f64::from_le_bytes(bytes_at(&header, 155 + 8 * i)));
+
+        // LASzip flags compressed point formats with the high bits
+        if format & 0xc0 != 0 {
+            return Err(laz_error().into());
+        }
+        let (classification_offset, minimum_length) = match format {
+            0..=5 => (15, 20),
+            6..=10 => (16, 30),
+            _ => return Err(format!("unknown LAS point format {format}").into()),
+        };
+        if record_length < minimum_length {
+            return Err(format!("{record_length} byte records for point format {format}").into());
+        }
+
+        let (mut projection, mut vertical_datum) = (None, None);
+        let mut position = header_size;
+        for _ in 0..vlr_count {
+            let mut vlr = [0; VLR_HEADER_SIZE];
+            reader.read_exact(&mut vlr)?;
+            let record_id = u16::from_le_bytes(bytes_at(&vlr, 18));
+            let mut data = vec![0; u16::from_le_bytes(bytes_at(&vlr, 20)) as usize];
+            reader.read_exact(&mut data)?;
+            position += VLR_HEADER_SIZE + data.len();
+
+            if vlr[2..].starts_with(b"LASF_Projection") && record_id == GEO_KEY_DIRECTORY {
+                let keys: Vec<u16> = data
+                    .chunks_exact(2)
+                    .map(|short| u16::from_le_bytes([short[0], short[1]]))
+                    .collect();
+                let codes = geo_key_codes(&keys);
+                projection = Projection::from_geo_keys(&codes);
+                vertical_datum = VerticalDatum::from_geo_keys(
+                    codes.get(&VERTICAL_CS_TYPE_KEY).copied(),
+                    codes.get(&GEOGRAPHIC_TYPE_KEY).copied(),
+                );
+            }
+        }
+        let padding = points_offset
+            .checked_sub(position)
+            .ok_or("LAS points overlap the header")?;
+        std::io::copy(
+            &mut (&mut reader).take(padding as u64),
+            &mut std::io::sink(),
+        )?;
+
+        let mut record = vec![0; record_length];
+        // Don't trust the header's count for the allocation
+        let mut points = Vec::with_capacity(count.min(1 << 20) as usize);
+        for _ in 0..count {
+            reader.read_exact(&mut record)?;
+            let position = std::array::from_fn(|i| {
+                offset[i] + scale[i] * i32::from_le_bytes(bytes_at(&record, 4 * i)) as f64
+            });
+            let mut classification = record[classification_offset];
+            if format <= 5 {
+                // The top bits are synthetic, key-point and withheld flags
+                classification &= 0x1f;
+            }
+            points.push(LasPoint {
+                position,
+                classification,
+            });
+        }
+
+        Ok(Self {
+            projection,
+            vertical_datum,
+            points,
+        })
+    }
+}
+
+fn laz_error() -> &'static str {
+    "LAZ point clouds aren't supported, decompress them to LAS with laszip first"
+}
+
+/// Maps ASPRS classes to the material their points become.
+#[derive(Debug, Clone)]
+pub struct ClassTable {
+    materials: HashMap<u8, u32>,
+}
+
+impl ClassTable {
+    /// Parses a TOML table of `class = "material"` pairs, e.g. `6 = "brick"`.
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let entries: HashMap<String, String> = toml::from_str(source)?;
+
+        let mut materials = HashMap::new();
+        for (class, material) in entries {
+            let class: u8 = class
+                .parse()
+                .map_err(|_| format!("invalid LiDAR class {class:?}"))?;
+            let material = voxelizer::material_by_name(&material)
+                .ok_or_else(|| format!("unknown material {material:?} for class {class}"))?;
+            materials.insert(class, material);
+        }
+
+        Ok(Self { materials })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn material(&self, class: u8) -> Option<u32> {
+        self.materials.get(&class).copied()
+    }
+}
+
+impl Default for ClassTable {
+    /// Table for the standard ASPRS classes. Ground is left out so it keeps the terrain's
+    /// surface material, unclassified and noise points are dropped.
+    fn default() -> Self {
+        let materials = HashMap::from([
+            (LOW_VEGETATION, voxelizer::SHRUB),
+            (MEDIUM_VEGETATION, voxelizer::SHRUB),
+            (HIGH_VEGETATION, voxelizer::LEAVES),
+            (BUILDING, voxelizer::BRICK),
+            (WATER, voxelizer::WATER),
+            (RAIL, voxelizer::STONE),
+            (ROAD_SURFACE, voxelizer::ASPHALT),
+            (BRIDGE_DECK, voxelizer::CONCRETE),
+        ]);
+
+        Self { materials }
+    }
+}
+
+/// A point placed on a heightmap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LidarPoint {
+    pub u: f32,
+    pub v: f32,
+    pub elevation: f32,
+    pub classification: u8,
+}
+
+/// The points of a cloud that fall on a heightmap, ready to be merged into chunks voxelized
+/// from it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LidarPoints {
+    /// Sorted by `u`, so each chunk can find its own.
+    pub points: Vec<LidarPoint>,
+}
+
+impl LidarPoints {
+    /// Places `cloud` on `heightmap`, converting its elevations to the heightmap's datum with
+    /// the grids in `geoids`. A cloud without a vertical datum is taken to share the
+    /// heightmap's.
+    pub fn from_cloud(
+        cloud: &PointCloud,
+        heightmap: &Heightmap,
+        geoids: &[&Geoid],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let projection = cloud
+            .projection
+            .ok_or("point cloud has no recognized coordinate system, set its projection")?;
+        let (source_geoid, target_geoid) = match cloud.vertical_datum {
+            Some(datum) if datum != heightmap.datum => (
+                Geoid::find(geoids, datum)?,
+                Geoid::find(geoids, heightmap.datum)?,
+            ),
+            _ => (None, None),
+        };
+
+        let mut points: Vec<LidarPoint> = cloud
+            .points
+            .iter()
+            .filter_map(|point| {
+                let [x, y, elevation] = point.position;
+                let (lat, lon) = projection.to_geodetic(x, y);
+                let (u, v) = heightmap.bounds.to_uv(lat, lon);
+                // Through the ellipsoid, h = H + N
+                let undulation = |geoid: Option<&Geoid>| {
+                    geoid.map_or(0.0, |geoid| geoid.undulation(lat, lon) as f64)
+                };
+                let elevation = elevation + undulation(source_geoid) - undulation(target_geoid);
+                ((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v)).then_some(LidarPoint {
+                    u: u as f32,
+                    v: v as f32,
+                    elevation: elevation as f32,
+                    classification: point.classification,
+                })
+            })
+            .collect();
+        points.sort_by(|a, b| a.u.total_cmp(&b.u));
+
+        Ok(Self { points })
+    }
+}
+
+/// Merges LiDAR points into a chunk voxelized from the same heightmap over `region`, at the
+/// chunk's own resolution. Ground, water and road points move their column's surface to the
+/// highest of them, keeping the terrain's surface material unless `table` maps their class.
+/// Points of other mapped classes fill the air voxel they fall in, and points of unmapped
+/// classes are ignored.
+///
+/// Points snap to the nearest voxel, so detail finer than a column or a layer of the chunk's
+/// elevation range is dropped, and points above or below that range are clamped into its top
+/// or bottom layer.
+pub fn stamp(
+    volume: &mut [[[u32; WIDTH]; HEIGHT]; LENGTH],
+    points: &LidarPoints,
+    table: &ClassTable,
+    region: &ChunkRegion,
+) {
+    // Half a column of margin, so edge columns get all their points
+    let margin = (region.u[1] - region.u[0]) / (LENGTH - 1) as f32 / 2.0;
+    let start = points
+        .points
+        .partition_point(|p| p.u < region.u[0] - margin);
+    let end = points
+        .points
+        .partition_point(|p| p.u <= region.u[1] + margin);
+    let placed: Vec<((usize, usize), &LidarPoint)> = points.points[start..end]
+        .iter()
+        .filter_map(|point| {
+            let [x, z] = region.to_voxel(point.u, point.v).map(f32::round);
+            let in_chunk = (0.0..LENGTH as f32).contains(&x) && (0.0..WIDTH as f32).contains(&z);
+            in_chunk.then_some(((x as usize, z as usize), point))
+        })
+        .collect();
+
+    let mut surfaces: HashMap<(usize, usize), &LidarPoint> = HashMap::new();
+    for &(column, point) in &placed {
+        if SURFACE_CLASSES.contains(&point.classification) {
+            let highest = surfaces.entry(column).or_insert(point);
+            if point.elevation > highest.elevation {
+                *highest = point;
+            }
+        }
+    }
+
+    for (&(x, z), point) in &surfaces {
+        let surface = region.layer(point.elevation);
+        let column = &mut volume[x];
+        let (top, ground) = match (0..HEIGHT).rev().find(|&y| column[y][z] != AIR) {
+            Some(top) => (top, column[top][z]),
+            None => (0, voxelizer::DIRT),
+        };
+        // Raised columns are filled with what was under the old surface
+        let fill = if top > 0 { column[top - 1][z] } else { ground };
+
+        for layer in column.iter_mut().take(top + 1).skip(surface + 1) {
+            layer[z] = AIR;
+        }
+        for layer in column.iter_mut().take(surface).skip(top) {
+            layer[z] = fill;
+        }
+        column[surface][z] = table.material(point.classification).unwrap_or(ground);
+    }
+
+    for ((x, z), point) in placed {
+        if SURFACE_CLASSES.contains(&point.classification) {
+            continue;
+        }
+        let Some(material) = table.material(point.classification) else {
+            continue;
+        };
+        let y = region.layer(point.elevation);
+        if volume[x][y][z] == AIR {
+            volume[x][y][z] = material;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::voxelizer::{GRASS, STONE};
+    use crate::terrain::{jobs, processor};
+
+    /// A LAS 1.2 file with point format 1, or 1.4 with format 6, in degrees when `epsg` is
+    /// geographic, 2D or 3D.
+    fn las(format: u8, epsg: Option<u16>, points: &[([f64; 3], u8)]) -> Vec<u8> {
+        let (header_size, record_length) = if format >= 6 { (375, 30) } else { (227, 28) };
+        let scale: [f64; 3] = [1e-7, 1e-7, 0.01];
+
+        let mut vlrs = Vec::new();
+        if let Some(epsg) = epsg {
+            let key = if matches!(epsg, 4326 | 4979) {
+                GEOGRAPHIC_TYPE_KEY
+            } else {
+                PROJECTED_TYPE_KEY
+            };
+            let keys = [1, 1, 0, 1, key, 0, 1, epsg];
+            vlrs.extend([0; 2]);
+            vlrs.extend(b"LASF_Projection\0");
+            vlrs.extend(GEO_KEY_DIRECTORY.to_le_bytes());
+            vlrs.extend((keys.len() as u16 * 2).to_le_bytes());
+            vlrs.extend([0; 32]);
+            vlrs.extend(keys.iter().flat_map(|key| key.to_le_bytes()));
+        }
+
+        let mut bytes = vec![0; header_size];
+        bytes[..4].copy_from_slice(b"LASF");
+        bytes[24..26].copy_from_slice(&[1, if format >= 6 { 4 } else { 2 }]);
+        bytes[94..96].copy_from_slice(&(header_size as u16).to_le_bytes());
+        bytes[96..100].copy_from_slice(&((header_size + vlrs.len()) as u32).to_le_bytes());
+        bytes[100..104].copy_from_slice(&(epsg.is_some() as u32).to_le_bytes());
+        bytes[104] = format;
+        bytes[105..107].copy_from_slice(&(record_length as u16).to_le_bytes());
+        if format >= 6 {
+            bytes[247..255].copy_from_slice(&(points.len() as u64).to_le_bytes());
+        } else {
+            bytes[107..111].copy_from_slice(&(points.len() as u32).to_le_bytes());
+        }
+        for (i, scale) in scale.iter().enumerate() {
+            bytes[131 + 8 * i..139 + 8 * i].copy_from_slice(&scale.to_le_bytes());
+        }
+        bytes.extend(vlrs);
+
+        for &(position, classification) in points {
+            let mut record = vec![0; record_length];
+            for i in 0..3 {
+                let value = (position[i] / scale[i]).round() as i32;
+                record[4 * i..4 * i + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            if format >= 6 {
+                record[16] = classification;
+            } else {
+                // With the synthetic flag set
+                record[15] = classification | 0x20;
+            }
+            bytes.extend(record);
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_las_point_formats() {
+        let points = [
+            ([2.55, 46.95, 123.45], GROUND),
+            ([2.56, 46.94, 140.0], BUILDING),
+        ];
+
+        let cloud = PointCloud::from_las(las(1, Some(4326), &points).as_slice()).unwrap();
+        assert_eq!(cloud.projection, Some(Projection::Geographic));
+        assert_eq!(cloud.points.len(), 2);
+        assert_eq!(cloud.points[1].classification, BUILDING);
+        let [x, y, z] = cloud.points[0].position;
+        assert!((x - 2.55).abs() < 1e-9 && (y - 46.95).abs() < 1e-9 && (z - 123.45).abs() < 1e-9);
+
+        let cloud = PointCloud::from_las(las(6, Some(32631), &points).as_slice()).unwrap();
+        let utm = Projection::Utm {
+            zone: 31,
+            north: true,
+        };
+        assert_eq!(cloud.projection, Some(utm));
+        assert_eq!(cloud.points[0].classification, GROUND);
+
+        let mut compressed = las(1, None, &points);
+        compressed[104] |= 0x80;
+        assert!(PointCloud::from_las(compressed.as_slice()).is_err());
+        assert!(PointCloud::load(Path::new("missing.laz")).is_err());
+    }
+
+    #[test]
+    fn utm_coordinates_follow_the_meridian_arc() {
+        // On the central meridian northing is the scaled meridian arc, 4984944.378 m at 45N
+        let (lat, lon) = Projection::Utm {
+            zone: 31,
+            north: true,
+        }
+        .to_geodetic(500_000.0, 0.9996 * 4_984_944.378);
+        assert!((lat - 45.0).abs() < 1e-7 && (lon - 3.0).abs() < 1e-9);
+
+        let (lat, _) = Projection::Utm {
+            zone: 31,
+            north: false,
+        }
+        .to_geodetic(500_000.0, 10_000_000.0 - 0.9996 * 4_984_944.378);
+        assert!((lat + 45.0).abs() < 1e-7);
+    }
+
+    #[test]
+    fn merges_points_with_terrain() {
+        let heightmap = processor::test_heightmap(64, 64, |x, y| match (x, y) {
+            (0, 0) => 900.0,
+            (1, 0) => 0.0,
+            _ => 100.0,
+        });
+        let region = ChunkRegion::whole(&heightmap);
+        let ground = region.layer(100.0);
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            for layer in &mut column[..ground] {
+                *layer = [STONE; WIDTH];
+            }
+            column[ground] = [GRASS; WIDTH];
+        }
+
+        // Points at the center of a column
+        let at = |x: usize, z: usize, elevation: f64, class: u8| {
+            let lon = 2.5 + 0.1 * x as f64 / (LENGTH - 1) as f64;
+            let lat = 47.0 - 0.1 * z as f64 / (WIDTH - 1) as f64;
+            ([lon, lat, elevation], class)
+        };
+        let points = [
+            at(10, 10, 250.0, GROUND),
+            at(10, 10, 300.0, GROUND),
+            at(12, 10, 30.0, GROUND),
+            at(14, 10, 100.0, WATER),
+            at(16, 10, 400.0, HIGH_VEGETATION),
+            at(16, 10, 500.0, UNCLASSIFIED),
+        ];
+        let cloud = PointCloud::from_las(las(1, Some(4326), &points).as_slice()).unwrap();
+        let points = LidarPoints::from_cloud(&cloud, &heightmap, &[]).unwrap();
+        stamp(&mut volume, &points, &ClassTable::default(), &region);
+
+        // Raised to the highest ground point, keeping grass on top of stone
+        let raised = region.layer(300.0);
+        assert_eq!(volume[10][raised][10], GRASS);
+        assert_eq!(volume[10][ground][10], STONE);
+        assert_eq!(volume[10][raised + 1][10], AIR);
+
+        let lowered = region.layer(30.0);
+        assert!(lowered < ground);
+        assert_eq!(volume[12][lowered][10], GRASS);
+        assert_eq!(volume[12][ground][10], AIR);
+
+        assert_eq!(volume[14][ground][10], voxelizer::WATER);
+
+        // Canopy floats above untouched ground, unclassified returns are dropped
+        assert_eq!(volume[16][ground][10], GRASS);
+        assert_eq!(volume[16][region.layer(400.0)][10], voxelizer::LEAVES);
+        assert_eq!(volume[16][region.layer(500.0)][10], AIR);
+    }
+
+    #[test]
+    fn snaps_points_to_the_chunk_grid() {
+        let heightmap = processor::test_heightmap(64, 64, |_, _| 250.0);
+        let region = jobs::chunk_region(&heightmap, None, [1, 1], [0, 0]);
+        let ground = region.layer(250.0);
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            column[ground] = [GRASS; WIDTH];
+        }
+
+        // Up to a third of a column off center, and a metre apart in height
+        let at = |x: f64, z: f64, elevation: f64, class: u8| {
+            let lon = 2.5 + 0.1 * x / (LENGTH - 1) as f64;
+            let lat = 47.0 - 0.1 * z / (WIDTH - 1) as f64;
+            ([lon, lat, elevation], class)
+        };
+        let points = [
+            at(10.3, 10.0, 260.0, HIGH_VEGETATION),
+            at(9.7, 10.0, 260.0, HIGH_VEGETATION),
+            at(20.0, 10.0, 261.7, HIGH_VEGETATION),
+            at(20.0, 10.0, 262.7, HIGH_VEGETATION),
+            at(24.0, 10.0, 900.0, HIGH_VEGETATION),
+        ];
+        let cloud = PointCloud::from_las(las(1, Some(4326), &points).as_slice()).unwrap();
+        let points = LidarPoints::from_cloud(&cloud, &heightmap, &[]).unwrap();
+        stamp(&mut volume, &points, &ClassTable::default(), &region);
+
+        // Both points land in one column, and in layers over a metre thick
+        let canopy = region.layer(260.0);
+        assert_eq!(volume[10][canopy][10], voxelizer::LEAVES);
+        assert_eq!(volume[9][canopy][10], AIR);
+        assert_eq!(volume[11][canopy][10], AIR);
+        assert!(region.voxel_size(&heightmap)[1] > 1.0);
+        let leaves = (0..HEIGHT).filter(|&y| volume[20][y][10] == voxelizer::LEAVES);
+        assert_eq!(leaves.count(), 1);
+
+        // Above the chunk's headroom, clamped into its top layer
+        assert_eq!(volume[24][HEIGHT - 1][10], voxelizer::LEAVES);
+    }
+
+    #[test]
+    fn converts_elevations_to_the_heightmap_datum() {
+        let heightmap = processor::test_heightmap(4, 4, |_, _| 100.0);
+        let geoid =
+            Geoid::from_grd("46 48 2 3 1 1  50 50  50 50  50 50", VerticalDatum::Egm96).unwrap();
+        let points = [([2.55, 46.95, 150.0], GROUND)];
+
+        // 3D WGS84 heights are ellipsoidal, 50 m above the orthometric ones here
+        let cloud = PointCloud::from_las(las(1, Some(4979), &points).as_slice()).unwrap();
+        assert_eq!(cloud.projection, Some(Projection::Geographic));
+        assert_eq!(cloud.vertical_datum, Some(VerticalDatum::Wgs84Ellipsoid));
+        let converted = LidarPoints::from_cloud(&cloud, &heightmap, &[&geoid]).unwrap();
+        assert!((converted.points[0].elevation - 100.0).abs() < 1e-3);
+        assert!(LidarPoints::from_cloud(&cloud, &heightmap, &[]).is_err());
+
+        // Without a vertical datum the heights are taken as they are
+        let cloud = PointCloud::from_las(las(1, Some(4326), &points).as_slice()).unwrap();
+        assert_eq!(cloud.vertical_datum, None);
+        let kept = LidarPoints::from_cloud(&cloud, &heightmap, &[]).unwrap();
+        assert_eq!(kept.points[0].elevation, 150.0);
+    }
+}
@@ -0,0 +1,221 @@
+use super::voxelizer::AIR;
+
+/// How a 2x2x2 block of voxels picks the material of its coarser voxel. Either way the block
+/// is solid when at least half of it is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LodSelection {
+    /// The most common solid material, ties going to the lower id.
+    Majority,
+    /// The material of the highest solid voxel, so distant slopes keep their surface color
+    /// instead of turning to the stone below it.
+    Representative,
+}
+
+/// A chunk's voxels and successively halved copies of them, down to a single voxel. Every
+/// level is laid out like the GPU voxel buffer, `(x * height + y) * width + z`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodChain {
+    pub selection: LodSelection,
+    /// `[length, height, width]` of each level.
+    pub sizes: Vec<[usize; 3]>,
+    pub levels: Vec<Vec<u32>>,
+}
+
+impl LodChain {
+    /// Levels in the chain of a chunk of `size`, down to a single voxel.
+    pub const fn level_count(size: [usize; 3]) -> usize {
+        let mut size = size;
+        let mut count = 1;
+        while size[0] > 1 || size[1] > 1 || size[2] > 1 {
+            size = half_size(size);
+            count += 1;
+        }
+        count
+    }
+
+    /// Voxels in every level of the chain of a chunk of `size`, the length of `flatten`.
+    pub const fn total_voxels(size: [usize; 3]) -> usize {
+        let mut size = size;
+        let mut total = size[0] * size[1] * size[2];
+        while size[0] > 1 || size[1] > 1 || size[2] > 1 {
+            size = half_size(size);
+            total += size[0] * size[1] * size[2];
+        }
+        total
+    }
+
+    /// Builds the chain from full-resolution voxels of `size`.
+    pub fn build(base: &[u32], size: [usize; 3], selection: LodSelection) -> Self {
+        let mut chain = Self {
+            selection,
+            sizes: vec![size],
+            levels: vec![base.to_vec()],
+        };
+        chain.rebuild();
+        chain
+    }
+
+    /// Replaces the full-resolution voxels and rebuilds every coarser level from them.
+    pub fn update(&mut self, base: &[u32]) {
+        self.levels[0].copy_from_slice(base);
+        self.rebuild();
+    }
+
+    fn rebuild(&mut self) {
+        self.sizes.truncate(1);
+        self.levels.truncate(1);
+
+        while self.sizes.last().unwrap().iter().any(|&n| n > 1) {
+            let size = *self.sizes.last().unwrap();
+            let next = downsample(self.levels.last().unwrap(), size, self.selection);
+            self.sizes.push(half_size(size));
+            self.levels.push(next);
+        }
+    }
+
+    /// Index of level `level`'s first voxel in `flatten`.
+    pub fn level_offset(&self, level: usize) -> usize {
+        self.levels[..level].iter().map(Vec::len).sum()
+    }
+
+    /// All levels back to back, finest first, for the GPU voxel buffer.
+    pub fn flatten(&self) -> Vec<u32> {
+        self.levels.concat()
+    }
+}
+
+/// Size of the level below one of `size`, halved along every axis longer than one voxel.
+const fn half_size(size: [usize; 3]) -> [usize; 3] {
+    const fn half(n: usize) -> usize {
+        if n > 1 {
+            n / 2
+        } else {
+            1
+        }
+    }
+    [half(size[0]), half(size[1]), half(size[2])]
+}
+
+/// Halves voxels of `size` along every axis longer than one voxel.
+pub fn downsample(voxels: &[u32], size: [usize; 3], selection: LodSelection) -> Vec<u32> {
+    let [length, height, width] = size;
+    let next = half_size(size);
+    let scale = std::array::from_fn::<usize, 3, _>(|i| if size[i] > 1 { 2 } else { 1 });
+    let block = scale.iter().product::<usize>();
+
+    let mut out = Vec::with_capacity(next.iter().product());
+    for x in 0..next[0] {
+        for y in 0..next[1] {
+            for z in 0..next[2] {
+                // Height and material of each solid voxel in the block
+                let mut solid: Vec<(usize, u32)> = Vec::with_capacity(block);
+                for dx in 0..scale[0] {
+                    for dy in 0..scale[1] {
+                        for dz in 0..scale[2] {
+                            let (vx, vy, vz) =
+                                (x * scale[0] + dx, y * scale[1] + dy, z * scale[2] + dz);
+                            if vx >= length || vy >= height || vz >= width {
+                                continue;
+                            }
+                            let material = voxels[(vx * height + vy) * width + vz];
+                            if material != AIR {
+                                solid.push((vy, material));
+                            }
+                        }
+                    }
+                }
+
+                out.push(if solid.len() * 2 < block {
+                    AIR
+                } else {
+                    select(&mut solid, selection)
+                });
+            }
+        }
+    }
+    out
+}
+
+fn select(solid: &mut [(usize, u32)], selection: LodSelection) -> u32 {
+    match selection {
+        LodSelection::Majority => {
+            solid.sort_unstable_by_key(|&(_, material)| material);
+            let mut best = (0, AIR);
+            for run in solid.chunk_by(|a, b| a.1 == b.1) {
+                if run.len() > best.0 {
+                    best = (run.len(), run[0].1);
+                }
+            }
+            best.1
+        }
+        LodSelection::Representative => {
+            // Highest voxel, and the lower id among voxels at the same height
+            solid
+                .iter()
+                .max_by_key(|&&(y, material)| (y, std::cmp::Reverse(material)))
+                .map_or(AIR, |&(_, material)| material)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::voxelizer::{DIRT, GRASS, STONE};
+
+    #[test]
+    fn chain_halves_down_to_one_voxel() {
+        let base = vec![STONE; 32 * 32 * 32];
+        let chain = LodChain::build(&base, [32, 32, 32], LodSelection::Majority);
+
+        assert_eq!(chain.levels.len(), 6);
+        assert_eq!(chain.sizes[5], [1, 1, 1]);
+        assert_eq!(chain.level_offset(1), 32 * 32 * 32);
+        assert_eq!(chain.level_offset(5), 37448);
+        assert_eq!(chain.flatten().len(), 37449);
+        assert!(chain.flatten().iter().all(|&m| m == STONE));
+    }
+
+    #[test]
+    fn chain_length_is_known_up_front() {
+        for size in [[32, 32, 32], [16, 4, 8], [5, 1, 3], [1, 1, 1]] {
+            let base = vec![STONE; size.iter().product()];
+            let chain = LodChain::build(&base, size, LodSelection::Majority);
+
+            assert_eq!(LodChain::level_count(size), chain.levels.len());
+            assert_eq!(LodChain::total_voxels(size), chain.flatten().len());
+        }
+    }
+
+    #[test]
+    fn selection_modes() {
+        // Half solid: grass above two stone voxels and one dirt voxel
+        let mut block = vec![AIR; 8];
+        let index = |x: usize, y: usize, z: usize| (x * 2 + y) * 2 + z;
+        block[index(0, 0, 0)] = STONE;
+        block[index(1, 0, 0)] = STONE;
+        block[index(0, 0, 1)] = DIRT;
+        block[index(1, 1, 1)] = GRASS;
+
+        let majority = downsample(&block, [2, 2, 2], LodSelection::Majority);
+        let representative = downsample(&block, [2, 2, 2], LodSelection::Representative);
+        assert_eq!(majority, [STONE]);
+        assert_eq!(representative, [GRASS]);
+
+        // Less than half solid thins out to air
+        block[index(1, 1, 1)] = AIR;
+        assert_eq!(downsample(&block, [2, 2, 2], LodSelection::Majority), [AIR]);
+    }
+
+    #[test]
+    fn update_rebuilds_coarse_levels() {
+        let mut base = vec![AIR; 4 * 4 * 4];
+        let mut chain = LodChain::build(&base, [4, 4, 4], LodSelection::Representative);
+        assert!(chain.flatten().iter().all(|&m| m == AIR));
+
+        base[..32].fill(DIRT);
+        chain.update(&base);
+        assert_eq!(chain.levels[1][..4], [DIRT; 4]);
+        assert_eq!(chain.levels[2], [DIRT]);
+    }
+}
@@ -0,0 +1,648 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use serde_json::json;
+
+use super::palette::ChunkStorage;
+use super::voxelizer::{VoxelChunk, VoxelMaterial, AIR};
+
+// Vertex brightness for 0 to 3 unoccluded neighbours around a corner
+const AO_BRIGHTNESS: [f32; 4] = [0.4, 0.6, 0.8, 1.0];
+
+#[derive(Debug, Clone)]
+pub struct MeshOptions {
+    /// Meters per voxel along x, y and z, like `VoxelChunk::voxel_size`.
+    pub voxel_size: [f32; 3],
+    /// Darken vertices in corners and crevices, written as vertex colors.
+    pub ambient_occlusion: bool,
+}
+
+impl Default for MeshOptions {
+    fn default() -> Self {
+        Self {
+            voxel_size: [1.0; 3],
+            ambient_occlusion: true,
+        }
+    }
+}
+
+/// Triangles of one material. Positions are in the chunk's tangent frame, x east, y up and
+/// z south, which is also glTF's right-handed y-up convention.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Primitive {
+    pub material: u32,
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    /// Ambient occlusion brightness per vertex, empty when it's turned off.
+    pub colors: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mesh {
+    /// One primitive per material, in ascending material id.
+    pub primitives: Vec<Primitive>,
+}
+
+/// Material and corner occlusion of one visible voxel face. Faces only merge when both match.
+#[derive(Clone, Copy, PartialEq)]
+struct Face {
+    material: u32,
+    ao: [u8; 4],
+}
+
+/// Meshes the whole chunk.
+pub fn mesh_chunk(chunk: &VoxelChunk, options: &MeshOptions) -> Mesh {
+    greedy_mesh(&chunk.voxels, [0; 3], chunk.voxels.size(), options)
+}
+
+/// Greedy-meshes voxels `min` up to `max` (exclusive) of `storage`. Voxels outside the region
+/// count as air, so the mesh is closed around the region.
+pub fn greedy_mesh(
+    storage: &ChunkStorage,
+    min: [usize; 3],
+    max: [usize; 3],
+    options: &MeshOptions,
+) -> Mesh {
+    let max: [usize; 3] = std::array::from_fn(|i| max[i].min(storage.size()[i]));
+    let size: [i32; 3] = std::array::from_fn(|i| max[i].saturating_sub(min[i]) as i32);
+
+    let solid = |p: [i32; 3]| -> bool {
+        (0..3).all(|i| p[i] >= 0 && p[i] < size[i])
+            && storage.get(std::array::from_fn(|i| min[i] + p[i] as usize)) != AIR
+    };
+
+    let mut primitives: BTreeMap<u32, Primitive> = BTreeMap::new();
+
+    for d in 0..3 {
+        let u = (d + 1) % 3;
+        let v = (d + 2) % 3;
+
+        for sign in [-1, 1] {
+            let mut mask = vec![None; (size[u] * size[v]) as usize];
+
+            for layer in 0..size[d] {
+                // Collect the faces of this layer that look out into air
+                for j in 0..size[v] {
+                    for i in 0..size[u] {
+                        let mut p = [0; 3];
+                        p[d] = layer;
+                        p[u] = i;
+                        p[v] = j;
+                        let mut outside = p;
+                        outside[d] += sign;
+
+                        mask[(j * size[u] + i) as usize] = if solid(p) && !solid(outside) {
+                            let material =
+                                storage.get(std::array::from_fn(|k| min[k] + p[k] as usize));
+                            let ao = if options.ambient_occlusion {
+                                corner_occlusion(&solid, outside, u, v)
+                            } else {
+                                [3; 4]
+                            };
+                            Some(Face { material, ao })
+                        } else {
+                            None
+                        };
+                    }
+                }
+
+                // Grow each unvisited face along u, then along v while whole rows match
+                for j in 0..size[v] {
+                    let mut i = 0;
+                    while i < size[u] {
+                        let Some(face) = mask[(j * size[u] + i) as usize] else {
+                            i += 1;
+                            continue;
+                        };
+
+                        let mut w = 1;
+                        while i + w < size[u] && mask[(j * size[u] + i + w) as usize] == Some(face)
+                        {
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        while j + h < size[v]
+                            && (i..i + w)
+                                .all(|k| mask[((j + h) * size[u] + k) as usize] == Some(face))
+                        {
+                            h += 1;
+                        }
+                        for row in j..j + h {
+                            for k in i..i + w {
+                                mask[(row * size[u] + k) as usize] = None;
+                            }
+                        }
+
+                        let mut corner = [0.0; 3];
+                        corner[d] = (layer + (sign > 0) as i32) as f32;
+                        corner[u] = i as f32;
+                        corner[v] = j as f32;
+                        let mut du = [0.0; 3];
+                        du[u] = w as f32;
+                        let mut dv = [0.0; 3];
+                        dv[v] = h as f32;
+                        let mut normal = [0.0; 3];
+                        normal[d] = sign as f32;
+
+                        let primitive =
+                            primitives
+                                .entry(face.material)
+                                .or_insert_with(|| Primitive {
+                                    material: face.material,
+                                    ..Default::default()
+                                });
+                        push_quad(primitive, corner, du, dv, normal, face.ao, options);
+
+                        i += w;
+                    }
+                }
+            }
+        }
+    }
+
+    Mesh {
+        primitives: primitives.into_values().collect(),
+    }
+}
+
+/// Occlusion of the four corners of a face, counting the solid voxels next to each corner in
+/// the layer the face looks into. Corners are in the order (-u, -v), (+u, -v), (+u, +v), (-u, +v).
+fn corner_occlusion(
+    solid: &impl Fn([i32; 3]) -> bool,
+    outside: [i32; 3],
+    u: usize,
+    v: usize,
+) -> [u8; 4] {
+    [(-1, -1), (1, -1), (1, 1), (-1, 1)].map(|(su, sv)| {
+        let offset = |a: i32, b: i32| {
+            let mut p = outside;
+            p[u] += a;
+            p[v] += b;
+            solid(p)
+        };
+        let side1 = offset(su, 0);
+        let side2 = offset(0, sv);
+        let corner = offset(su, sv);
+
+        if side1 && side2 {
+            0
+        } else {
+            3 - side1 as u8 - side2 as u8 - corner as u8
+        }
+    })
+}
+
+fn push_quad(
+    primitive: &mut Primitive,
+    corner: [f32; 3],
+    du: [f32; 3],
+    dv: [f32; 3],
+    normal: [f32; 3],
+    ao: [u8; 4],
+    options: &MeshOptions,
+) {
+    let first = primitive.positions.len() as u32;
+    let corners = [
+        corner,
+        std::array::from_fn(|k| corner[k] + du[k]),
+        std::array::from_fn(|k| corner[k] + du[k] + dv[k]),
+        std::array::from_fn(|k| corner[k] + dv[k]),
+    ];
+
+    for position in corners {
+        primitive
+            .positions
+            .push(std::array::from_fn(|k| position[k] * options.voxel_size[k]));
+        primitive.normals.push(normal);
+    }
+    if options.ambient_occlusion {
+        primitive
+            .colors
+            .extend(ao.map(|level| AO_BRIGHTNESS[level as usize]));
+    }
+
+    // u × v points along +d, so the corners run counter-clockwise seen from +d. Split along
+    // the brighter diagonal so occlusion interpolates without a visible crease
+    let flip = ao[0] + ao[2] < ao[1] + ao[3];
+    let mut triangles = if flip {
+        [1, 2, 3, 1, 3, 0]
+    } else {
+        [0, 1, 2, 0, 2, 3]
+    };
+    if normal.iter().sum::<f32>() < 0.0 {
+        triangles.reverse();
+    }
+    primitive
+        .indices
+        .extend(triangles.map(|index| first + index));
+}
+
+fn material_info(materials: &[VoxelMaterial], id: u32) -> (String, [f32; 3]) {
+    materials
+        .iter()
+        .find(|material| material.id == id)
+        .map_or((format!("material_{id}"), [0.5; 3]), |material| {
+            (material.name.clone(), material.albedo)
+        })
+}
+
+impl Mesh {
+    pub fn triangle_count(&self) -> usize {
+        self.primitives
+            .iter()
+            .map(|primitive| primitive.indices.len() / 3)
+            .sum()
+    }
+
+    /// Binary glTF 2.0 with one mesh, one primitive and one PBR material per voxel material.
+    /// Ambient occlusion is written as `COLOR_0`, which glTF multiplies into the base color.
+    /// Without any primitives it's an empty scene.
+    pub fn to_glb(&self, materials: &[VoxelMaterial]) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut gltf_primitives = Vec::new();
+        let mut gltf_materials = Vec::new();
+
+        let mut add_view = |buffer: &mut Vec<u8>, bytes: &[u8], target: u32| {
+            views.push(json!({
+                "buffer": 0,
+                "byteOffset": buffer.len(),
+                "byteLength": bytes.len(),
+                "target": target,
+            }));
+            buffer.extend_from_slice(bytes);
+            views.len() - 1
+        };
+
+        for (index, primitive) in self.primitives.iter().enumerate() {
+            let (name, albedo) = material_info(materials, primitive.material);
+            gltf_materials.push(json!({
+                "name": name,
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [albedo[0], albedo[1], albedo[2], 1.0],
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 1.0,
+                },
+            }));
+
+            let count = primitive.positions.len();
+            let mut min = [f32::INFINITY; 3];
+            let mut max = [f32::NEG_INFINITY; 3];
+            for position in &primitive.positions {
+                for k in 0..3 {
+                    min[k] = min[k].min(position[k]);
+                    max[k] = max[k].max(position[k]);
+                }
+            }
+
+            let view = add_view(
+                &mut buffer,
+                bytemuck::cast_slice(&primitive.positions),
+                34962,
+            );
+            accessors.push(json!({
+                "bufferView": view, "componentType": 5126, "count": count, "type": "VEC3",
+                "min": min, "max": max,
+            }));
+            let mut attributes = json!({ "POSITION": accessors.len() - 1 });
+
+            let view = add_view(&mut buffer, bytemuck::cast_slice(&primitive.normals), 34962);
+            accessors.push(json!({
+                "bufferView": view, "componentType": 5126, "count": count, "type": "VEC3",
+            }));
+            attributes["NORMAL"] = json!(accessors.len() - 1);
+
+            if !primitive.colors.is_empty() {
+                let colors: Vec<[f32; 3]> = primitive.colors.iter().map(|&c| [c; 3]).collect();
+                let view = add_view(&mut buffer, bytemuck::cast_slice(&colors), 34962);
+                accessors.push(json!({
+                    "bufferView": view, "componentType": 5126, "count": count, "type": "VEC3",
+                }));
+                attributes["COLOR_0"] = json!(accessors.len() - 1);
+            }
+
+            let view = add_view(&mut buffer, bytemuck::cast_slice(&primitive.indices), 34963);
+            accessors.push(json!({
+                "bufferView": view, "componentType": 5125, "count": primitive.indices.len(),
+                "type": "SCALAR",
+            }));
+
+            gltf_primitives.push(json!({
+                "attributes": attributes,
+                "indices": accessors.len() - 1,
+                "material": index,
+            }));
+        }
+
+        let mut document = json!({
+            "asset": { "version": "2.0", "generator": "voxel terrain mesher" },
+            "scene": 0,
+            "scenes": [{}],
+        });
+        // glTF doesn't allow empty arrays, so a mesh without primitives is left out entirely
+        if !gltf_primitives.is_empty() {
+            document["scenes"] = json!([{ "nodes": [0] }]);
+            document["nodes"] = json!([{ "mesh": 0 }]);
+            document["meshes"] = json!([{ "primitives": gltf_primitives }]);
+            document["materials"] = json!(gltf_materials);
+            document["accessors"] = json!(accessors);
+            document["bufferViews"] = json!(views);
+            document["buffers"] = json!([{ "byteLength": buffer.len() }]);
+        }
+
+        // Both chunks are padded to 4 bytes, JSON with spaces and the binary chunk with zeros
+        let mut json = document.to_string().into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+        let mut glb = Vec::with_capacity(28 + json.len() + buffer.len());
+        let length = 12
+            + 8
+            + json.len()
+            + if buffer.is_empty() {
+                0
+            } else {
+                8 + buffer.len()
+            };
+        glb.extend(b"glTF");
+        glb.extend(2u32.to_le_bytes());
+        glb.extend((length as u32).to_le_bytes());
+        glb.extend((json.len() as u32).to_le_bytes());
+        glb.extend(b"JSON");
+        glb.extend(json);
+        if !buffer.is_empty() {
+            glb.extend((buffer.len() as u32).to_le_bytes());
+            glb.extend(b"BIN\0");
+            glb.extend(buffer);
+        }
+        glb
+    }
+
+    pub fn save_glb(
+        &self,
+        path: &Path,
+        materials: &[VoxelMaterial],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.to_glb(materials))?;
+        Ok(())
+    }
+
+    /// Writes Wavefront OBJ geometry referencing the materials in `mtl_name`. Ambient occlusion
+    /// goes after each vertex as an `r g b` color, the extension Blender and MeshLab read.
+    pub fn write_obj(
+        &self,
+        out: &mut impl Write,
+        mtl_name: &str,
+        materials: &[VoxelMaterial],
+    ) -> std::io::Result<()> {
+        writeln!(out, "mtllib {mtl_name}")?;
+
+        // OBJ indices are 1-based and global across the file
+        let mut first = 1;
+        for primitive in &self.primitives {
+            let (name, _) = material_info(materials, primitive.material);
+            writeln!(out, "o {name}")?;
+            for (i, [x, y, z]) in primitive.positions.iter().enumerate() {
+                match primitive.colors.get(i) {
+                    Some(c) => writeln!(out, "v {x} {y} {z} {c} {c} {c}")?,
+                    None => writeln!(out, "v {x} {y} {z}")?,
+                }
+            }
+            for [x, y, z] in &primitive.normals {
+                writeln!(out, "vn {x} {y} {z}")?;
+            }
+            writeln!(out, "usemtl {name}")?;
+            for triangle in primitive.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|k| triangle[k] + first);
+                writeln!(out, "f {a}//{a} {b}//{b} {c}//{c}")?;
+            }
+            first += primitive.positions.len() as u32;
+        }
+        Ok(())
+    }
+
+    pub fn write_mtl(
+        &self,
+        out: &mut impl Write,
+        materials: &[VoxelMaterial],
+    ) -> std::io::Result<()> {
+        for primitive in &self.primitives {
+            let (name, [r, g, b]) = material_info(materials, primitive.material);
+            writeln!(out, "newmtl {name}")?;
+            writeln!(out, "Kd {r} {g} {b}")?;
+            writeln!(out, "Ka 0 0 0")?;
+            writeln!(out, "Ks 0 0 0")?;
+            writeln!(out, "illum 1")?;
+        }
+        Ok(())
+    }
+
+    /// Writes `path` and a material library next to it with the extension swapped to `.mtl`.
+    pub fn save_obj(
+        &self,
+        path: &Path,
+        materials: &[VoxelMaterial],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mtl_path = path.with_extension("mtl");
+        let mtl_name = mtl_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("invalid OBJ path")?;
+
+        let mut obj = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_obj(&mut obj, mtl_name, materials)?;
+        obj.flush()?;
+
+        let mut mtl = std::io::BufWriter::new(std::fs::File::create(&mtl_path)?);
+        self.write_mtl(&mut mtl, materials)?;
+        mtl.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::noise::hash_to_unit;
+    use crate::terrain::voxelizer::{self, GRASS, STONE};
+    use std::collections::HashMap;
+
+    fn storage(size: [usize; 3], material: impl Fn([usize; 3]) -> u32) -> ChunkStorage {
+        let mut voxels = Vec::with_capacity(size.iter().product());
+        for x in 0..size[0] {
+            for y in 0..size[1] {
+                for z in 0..size[2] {
+                    voxels.push(material([x, y, z]));
+                }
+            }
+        }
+        ChunkStorage::encode(size, &voxels)
+    }
+
+    fn mesh(storage: &ChunkStorage, ambient_occlusion: bool) -> Mesh {
+        let options = MeshOptions {
+            voxel_size: [1.0; 3],
+            ambient_occlusion,
+        };
+        greedy_mesh(storage, [0; 3], storage.size(), &options)
+    }
+
+    /// Checks the mesh is closed by cutting every triangle edge into unit steps, which splits
+    /// edges at T-junctions, and requiring each directed step to be matched by its reverse.
+    /// Also returns the enclosed volume.
+    fn assert_watertight(mesh: &Mesh) -> f64 {
+        let mut edges: HashMap<([i32; 3], [i32; 3]), i32> = HashMap::new();
+        let mut volume = 0.0;
+
+        for primitive in &mesh.primitives {
+            for triangle in primitive.indices.chunks_exact(3) {
+                let p = [0, 1, 2].map(|k| primitive.positions[triangle[k] as usize].map(f64::from));
+                let cross = [
+                    p[1][1] * p[2][2] - p[1][2] * p[2][1],
+                    p[1][2] * p[2][0] - p[1][0] * p[2][2],
+                    p[1][0] * p[2][1] - p[1][1] * p[2][0],
+                ];
+                volume += (0..3).map(|k| p[0][k] * cross[k]).sum::<f64>();
+
+                for k in 0..3 {
+                    let a = p[k].map(|c| c as i32);
+                    let b = p[(k + 1) % 3].map(|c| c as i32);
+                    let steps = (0..3).map(|i| (b[i] - a[i]).abs()).max().unwrap();
+                    // Only axis-aligned edges can meet other faces; diagonals are interior
+                    if (0..3).filter(|&i| a[i] != b[i]).count() != 1 {
+                        continue;
+                    }
+                    for s in 0..steps {
+                        let from: [i32; 3] =
+                            std::array::from_fn(|i| a[i] + (b[i] - a[i]).signum() * s);
+                        let to: [i32; 3] =
+                            std::array::from_fn(|i| from[i] + (b[i] - a[i]).signum());
+                        let (key, count) = if from < to {
+                            ((from, to), 1)
+                        } else {
+                            ((to, from), -1)
+                        };
+                        *edges.entry(key).or_default() += count;
+                    }
+                }
+            }
+        }
+
+        let open = edges.values().filter(|&&count| count != 0).count();
+        assert_eq!(open, 0, "{open} unmatched edges");
+        volume / 6.0
+    }
+
+    #[test]
+    fn single_voxel_is_a_cube() {
+        let storage = storage([3, 3, 3], |p| if p == [1, 1, 1] { STONE } else { AIR });
+        let mesh = mesh(&storage, true);
+
+        assert_eq!(mesh.triangle_count(), 12);
+        assert_eq!(assert_watertight(&mesh), 1.0);
+    }
+
+    #[test]
+    fn solid_chunk_merges_to_six_quads() {
+        let storage = storage([32, 32, 32], |_| STONE);
+        let mesh = mesh(&storage, true);
+
+        assert_eq!(mesh.triangle_count(), 12);
+        assert_eq!(assert_watertight(&mesh), 32.0 * 32.0 * 32.0);
+    }
+
+    #[test]
+    fn materials_get_separate_primitives() {
+        // Grass on stone: the faces between them are hidden, each layer is a slab of 5 quads
+        let storage = storage([8, 2, 8], |[_, y, _]| if y == 0 { STONE } else { GRASS });
+        let mesh = mesh(&storage, false);
+
+        assert_eq!(mesh.primitives.len(), 2);
+        for primitive in &mesh.primitives {
+            assert_eq!(primitive.indices.len() / 3, 10);
+        }
+        assert_eq!(assert_watertight(&mesh), 128.0);
+    }
+
+    #[test]
+    fn random_terrain_is_watertight() {
+        let storage = storage([16, 16, 16], |[x, y, z]| {
+            let height = 4.0 + 8.0 * hash_to_unit(7, x as i64 / 3, 0, z as i64 / 3);
+            let cave = hash_to_unit(11, x as i64, y as i64, z as i64) < 0.2;
+            if (y as f32) < height && !cave {
+                STONE
+            } else {
+                AIR
+            }
+        });
+        let count = storage.decode().iter().filter(|&&m| m != AIR).count();
+
+        for ambient_occlusion in [false, true] {
+            let mesh = mesh(&storage, ambient_occlusion);
+            assert_eq!(assert_watertight(&mesh), count as f64);
+        }
+    }
+
+    #[test]
+    fn glb_has_valid_layout() {
+        let storage = storage([4, 4, 4], |[_, y, _]| if y < 2 { STONE } else { AIR });
+        let glb = mesh(&storage, true).to_glb(&voxelizer::default_materials());
+
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(
+            u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize,
+            glb.len()
+        );
+        let json_length = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+        let document: serde_json::Value =
+            serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+        assert_eq!(document["materials"][0]["name"], "stone");
+
+        let bin = 20 + json_length;
+        let bin_length = u32::from_le_bytes(glb[bin..bin + 4].try_into().unwrap()) as u64;
+        assert_eq!(&glb[bin + 4..bin + 8], b"BIN\0");
+        assert!(document["buffers"][0]["byteLength"].as_u64().unwrap() <= bin_length);
+    }
+
+    #[test]
+    fn empty_glb_is_an_empty_scene() {
+        let storage = storage([4, 4, 4], |_| AIR);
+        let glb = mesh(&storage, true).to_glb(&voxelizer::default_materials());
+
+        // Only the JSON chunk, with no empty arrays anywhere
+        let json_length = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+        assert_eq!(glb.len(), 20 + json_length);
+        let document: serde_json::Value =
+            serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+        for key in [
+            "nodes",
+            "meshes",
+            "materials",
+            "accessors",
+            "bufferViews",
+            "buffers",
+        ] {
+            assert!(document.get(key).is_none(), "{key} present");
+        }
+        assert_eq!(document["scenes"], serde_json::json!([{}]));
+    }
+
+    #[test]
+    fn positions_scale_by_the_voxel_size() {
+        let storage = storage([1, 1, 1], |_| STONE);
+        let options = MeshOptions {
+            voxel_size: [74.0, 2.5, 108.0],
+            ambient_occlusion: false,
+        };
+        let mesh = greedy_mesh(&storage, [0; 3], [1; 3], &options);
+
+        for position in &mesh.primitives[0].positions {
+            assert!([0.0, 74.0].contains(&position[0]));
+            assert!([0.0, 2.5].contains(&position[1]));
+            assert!([0.0, 108.0].contains(&position[2]));
+        }
+    }
+}
@@ -1,3 +1,23 @@
+pub mod density;
 pub mod downloader;
+pub mod editing;
+pub mod erosion;
+pub mod generator;
+pub mod geoid;
+pub mod jobs;
+pub mod landcover;
+pub mod lidar;
+pub mod lod;
+pub mod mesh;
+pub mod noise;
+pub mod osm;
+pub mod overlay;
+pub mod palette;
 pub mod processor;
+pub mod rules;
+pub mod streaming;
+pub mod vegetation;
+pub mod vox;
 pub mod voxelizer;
+pub mod water;
+pub mod world_file;
@@ -0,0 +1,208 @@
+/// Seeded Perlin gradient noise in two and three dimensions. Values are roughly in `-1..=1`
+/// and identical for the same seed on every run.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    permutation: [u8; 512],
+}
+
+/// SplitMix64 step, a small deterministic generator for seeding.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Hashes integer coordinates and a seed to a uniform float in `0..1`.
+pub fn hash_to_unit(seed: u64, x: i64, y: i64, z: i64) -> f32 {
+    let mut state = seed
+        ^ (x as u64).wrapping_mul(0x8da6_b343)
+        ^ (y as u64).wrapping_mul(0xd816_3841)
+        ^ (z as u64).wrapping_mul(0xcb1a_b31f);
+    (splitmix64(&mut state) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn gradient2(hash: u8, x: f32, y: f32) -> f32 {
+    match hash & 7 {
+        0 => x + y,
+        1 => x - y,
+        2 => -x + y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
+
+fn gradient3(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    // The twelve cube edge directions from improved Perlin noise
+    match hash & 15 {
+        0 | 12 => x + y,
+        1 | 14 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 13 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+
+        // Fisher-Yates shuffle
+        let mut state = seed;
+        for i in (1..256).rev() {
+            let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        Self {
+            permutation: std::array::from_fn(|i| table[i % 256]),
+        }
+    }
+
+    fn hash(&self, x: i32) -> usize {
+        self.permutation[(x & 255) as usize] as usize
+    }
+
+    pub fn get2(&self, x: f32, y: f32) -> f32 {
+        let (xf, yf) = (x.floor(), y.floor());
+        let (xi, yi) = (xf as i32, yf as i32);
+        let (x, y) = (x - xf, y - yf);
+        let (u, v) = (fade(x), fade(y));
+
+        let a = self.hash(self.hash(xi) as i32 + yi);
+        let b = self.hash(self.hash(xi + 1) as i32 + yi);
+        let c = self.hash(self.hash(xi) as i32 + yi + 1);
+        let d = self.hash(self.hash(xi + 1) as i32 + yi + 1);
+
+        let p = &self.permutation;
+        lerp(
+            lerp(gradient2(p[a], x, y), gradient2(p[b], x - 1.0, y), u),
+            lerp(
+                gradient2(p[c], x, y - 1.0),
+                gradient2(p[d], x - 1.0, y - 1.0),
+                u,
+            ),
+            v,
+        )
+    }
+
+    pub fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (xf, yf, zf) = (x.floor(), y.floor(), z.floor());
+        let (xi, yi, zi) = (xf as i32, yf as i32, zf as i32);
+        let (x, y, z) = (x - xf, y - yf, z - zf);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let corner = |dx: i32, dy: i32, dz: i32| {
+            let hash = self.hash(self.hash(self.hash(xi + dx) as i32 + yi + dy) as i32 + zi + dz);
+            gradient3(
+                self.permutation[hash],
+                x - dx as f32,
+                y - dy as f32,
+                z - dz as f32,
+            )
+        };
+
+        lerp(
+            lerp(
+                lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+                lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+                v,
+            ),
+            lerp(
+                lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+                lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+                v,
+            ),
+            w,
+        )
+    }
+
+    /// Fractal sum of `octaves` layers of 2D noise, each `lacunarity` times finer and `gain`
+    /// times weaker than the last, normalized back to roughly `-1..=1`.
+    pub fn fbm2(&self, x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+        for octave in 0..octaves {
+            // Offset octaves so their lattices don't line up at the origin
+            let shift = octave as f32 * 17.31;
+            sum += amplitude * self.get2(x * frequency + shift, y * frequency - shift);
+            total += amplitude;
+            amplitude *= gain;
+            frequency *= lacunarity;
+        }
+        sum / total.max(f32::EPSILON)
+    }
+
+    pub fn fbm3(&self, x: f32, y: f32, z: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+        for octave in 0..octaves {
+            let shift = octave as f32 * 17.31;
+            sum += amplitude
+                * self.get3(
+                    x * frequency + shift,
+                    y * frequency - shift,
+                    z * frequency + shift,
+                );
+            total += amplitude;
+            amplitude *= gain;
+            frequency *= lacunarity;
+        }
+        sum / total.max(f32::EPSILON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_is_seeded() {
+        let (a, b) = (Noise::new(1), Noise::new(1));
+        let other = Noise::new(2);
+        let samples = |noise: &Noise| {
+            (0..100)
+                .map(|i| noise.get3(i as f32 * 0.37, i as f32 * 0.11, 1.5))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(samples(&a), samples(&b));
+        assert_ne!(samples(&a), samples(&other));
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let noise = Noise::new(5);
+        for i in 0..2000 {
+            let (x, y, z) = (i as f32 * 0.173, i as f32 * -0.291, i as f32 * 0.057);
+            assert!(noise.get2(x, y).abs() <= 1.0);
+            assert!(noise.get3(x, y, z).abs() <= 1.0);
+            assert!(noise.fbm2(x, y, 6, 2.0, 0.5).abs() <= 1.0);
+            assert!(noise.fbm3(x, y, z, 4, 2.0, 0.5).abs() <= 1.0);
+        }
+        // Gradient noise vanishes on the lattice
+        assert_eq!(noise.get2(3.0, -4.0), 0.0);
+        assert_eq!(noise.get3(3.0, -4.0, 2.0), 0.0);
+
+        for i in 0..1000 {
+            let value = hash_to_unit(9, i, -i, i * 3);
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+}
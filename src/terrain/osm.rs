@@ -0,0 +1,1167 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use serde::Deserialize;
+
+use super::processor::Heightmap;
+use super::voxelizer::{
+    ChunkRegion, AIR, ASPHALT, BRICK, CONCRETE, DIRT, HEIGHT, LENGTH, STONE, WATER, WIDTH,
+};
+
+// The PBF spec caps blob headers at 64 KiB and blobs at 32 MiB
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BLOB_SIZE: usize = 32 * 1024 * 1024;
+const SUPPORTED_FEATURES: [&str; 2] = ["OsmSchema-V0.6", "DenseNodes"];
+
+pub type Tags = HashMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmWay {
+    pub id: i64,
+    pub refs: Vec<i64>,
+    pub tags: Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberKind {
+    Node,
+    Way,
+    Relation,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmMember {
+    pub kind: MemberKind,
+    pub id: i64,
+    pub role: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmRelation {
+    pub id: i64,
+    pub members: Vec<OsmMember>,
+    pub tags: Tags,
+}
+
+/// The parts of an OpenStreetMap extract the importer uses. Node tags are dropped, only
+/// ways and relations describe buildings, roads and water.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsmData {
+    /// `[lat, lon]` of every node.
+    pub nodes: HashMap<i64, [f64; 2]>,
+    pub ways: Vec<OsmWay>,
+    pub relations: Vec<OsmRelation>,
+}
+
+/// A protobuf field's payload. Fixed-width values are never needed, so they're skipped.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.bytes.len() < n {
+            return Err("unexpected end of PBF data".into());
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint longer than 64 bits".into())
+    }
+
+    /// Next field number and payload, or `None` at the end of the message.
+    fn field(&mut self) -> Result<Option<(u64, Value<'a>)>, Box<dyn std::error::Error>> {
+        if self.bytes.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            2 => {
+                let length = self.varint()? as usize;
+                Value::Bytes(self.take(length)?)
+            }
+            5 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => return Err(format!("unsupported wire type {wire_type}").into()),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+fn zigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn packed(bytes: &[u8]) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let mut reader = Reader { bytes };
+    let mut values = Vec::new();
+    while !reader.bytes.is_empty() {
+        values.push(reader.varint()?);
+    }
+    Ok(values)
+}
+
+/// Packed, delta-coded signed values, like node ids and coordinates.
+fn packed_deltas(bytes: &[u8]) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+    let mut last = 0i64;
+    Ok(packed(bytes)?
+        .into_iter()
+        .map(|value| {
+            last += zigzag(value);
+            last
+        })
+        .collect())
+}
+
+fn tags(keys: &[u64], values: &[u64], strings: &[String]) -> Result<Tags, String> {
+    let string = |index: u64| {
+        strings
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| format!("string index {index} out of range"))
+    };
+    keys.iter()
+        .zip(values)
+        .map(|(&key, &value)| Ok((string(key)?, string(value)?)))
+        .collect()
+}
+
+/// Coordinates of a primitive block: nanodegrees are `offset + granularity * value`.
+struct Granularity {
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+}
+
+impl Granularity {
+    fn coord(&self, lat: i64, lon: i64) -> [f64; 2] {
+        [
+            (self.lat_offset + self.granularity * lat) as f64 * 1e-9,
+            (self.lon_offset + self.granularity * lon) as f64 * 1e-9,
+        ]
+    }
+}
+
+impl OsmData {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_pbf(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads an `.osm.pbf` stream: a sequence of length-prefixed blob headers and blobs,
+    /// each blob a raw or zlib-compressed header or data block.
+    pub fn from_pbf<R: Read>(mut reader: R) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut data = Self::default();
+
+        loop {
+            let mut length = [0; 4];
+            match reader.read_exact(&mut length) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let length = u32::from_be_bytes(length) as usize;
+            if length > MAX_HEADER_SIZE {
+                return Err(format!("blob header of {length} bytes").into());
+            }
+            let mut header = vec![0; length];
+            reader.read_exact(&mut header)?;
+
+            let (mut kind, mut size) = (String::new(), 0);
+            let mut fields = Reader { bytes: &header };
+            while let Some((field, value)) = fields.field()? {
+                match (field, value) {
+                    (1, Value::Bytes(bytes)) => kind = String::from_utf8(bytes.to_vec())?,
+                    (3, Value::Varint(value)) => size = value as usize,
+                    _ => {}
+                }
+            }
+            if size > MAX_BLOB_SIZE {
+                return Err(format!("blob of {size} bytes").into());
+            }
+            let mut blob = vec![0; size];
+            reader.read_exact(&mut blob)?;
+
+            match kind.as_str() {
+                "OSMHeader" => check_header(&decompress(&blob)?)?,
+                "OSMData" => data.read_block(&decompress(&blob)?)?,
+                // Unknown blob types are meant to be skipped
+                _ => {}
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn read_block(&mut self, block: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut strings = Vec::new();
+        let mut groups = Vec::new();
+        let mut granularity = Granularity {
+            granularity: 100,
+            lat_offset: 0,
+            lon_offset: 0,
+        };
+
+        let mut fields = Reader { bytes: block };
+        while let Some((field, value)) = fields.field()? {
+            match (field, value) {
+                (1, Value::Bytes(table)) => {
+                    let mut entries = Reader { bytes: table };
+                    while let Some((field, value)) = entries.field()? {
+                        if let (1, Value::Bytes(string)) = (field, value) {
+                            strings.push(String::from_utf8_lossy(string).into_owned());
+                        }
+                    }
+                }
+                (2, Value::Bytes(group)) => groups.push(group),
+                (17, Value::Varint(value)) => granularity.granularity = value as i64,
+                (19, Value::Varint(value)) => granularity.lat_offset = value as i64,
+                (20, Value::Varint(value)) => granularity.lon_offset = value as i64,
+                _ => {}
+            }
+        }
+
+        for group in groups {
+            let mut fields = Reader { bytes: group };
+            while let Some((field, value)) = fields.field()? {
+                let Value::Bytes(bytes) = value else {
+                    continue;
+                };
+                match field {
+                    1 => self.read_node(bytes, &granularity)?,
+                    2 => self.read_dense_nodes(bytes, &granularity)?,
+                    3 => self.read_way(bytes, &strings)?,
+                    4 => self.read_relation(bytes, &strings)?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_node(
+        &mut self,
+        node: &[u8],
+        granularity: &Granularity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut id, mut lat, mut lon) = (0, 0, 0);
+        let mut fields = Reader { bytes: node };
+        while let Some((field, value)) = fields.field()? {
+            match (field, value) {
+                (1, Value::Varint(value)) => id = zigzag(value),
+                (8, Value::Varint(value)) => lat = zigzag(value),
+                (9, Value::Varint(value)) => lon = zigzag(value),
+                _ => {}
+            }
+        }
+        self.nodes.insert(id, granularity.coord(lat, lon));
+        Ok(())
+    }
+
+    fn read_dense_nodes(
+        &mut self,
+        dense: &[u8],
+        granularity: &Granularity,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut ids, mut lats, mut lons) = (Vec::new(), Vec::new(), Vec::new());
+        let mut fields = Reader { bytes: dense };
+        while let Some((field, value)) = fields.field()? {
+            match (field, value) {
+                (1, Value::Bytes(bytes)) => ids = packed_deltas(bytes)?,
+                (8, Value::Bytes(bytes)) => lats = packed_deltas(bytes)?,
+                (9, Value::Bytes(bytes)) => lons = packed_deltas(bytes)?,
+                _ => {}
+            }
+        }
+        if ids.len() != lats.len() || ids.len() != lons.len() {
+            return Err("dense nodes with mismatched columns".into());
+        }
+
+        for ((id, lat), lon) in ids.into_iter().zip(lats).zip(lons) {
+            self.nodes.insert(id, granularity.coord(lat, lon));
+        }
+        Ok(())
+    }
+
+    fn read_way(
+        &mut self,
+        way: &[u8],
+        strings: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut id, mut keys, mut values, mut refs) = (0, Vec::new(), Vec::new(), Vec::new());
+        let mut fields = Reader { bytes: way };
+        while let Some((field, value)) = fields.field()? {
+            match (field, value) {
+                (1, Value::Varint(value)) => id = value as i64,
+                (2, Value::Bytes(bytes)) => keys = packed(bytes)?,
+                (3, Value::Bytes(bytes)) => values = packed(bytes)?,
+                (8, Value::Bytes(bytes)) => refs = packed_deltas(bytes)?,
+                _ => {}
+            }
+        }
+
+        self.ways.push(OsmWay {
+            id,
+            refs,
+            tags: tags(&keys, &values, strings)?,
+        });
+        Ok(())
+    }
+
+    fn read_relation(
+        &mut self,
+        relation: &[u8],
+        strings: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut id, mut keys, mut values) = (0, Vec::new(), Vec::new());
+        let (mut roles, mut member_ids, mut kinds) = (Vec::new(), Vec::new(), Vec::new());
+        let mut fields = Reader { bytes: relation };
+        while let Some((field, value)) = fields.field()? {
+            match (field, value) {
+                (1, Value::Varint(value)) => id = value as i64,
+                (2, Value::Bytes(bytes)) => keys = packed(bytes)?,
+                (3, Value::Bytes(bytes)) => values = packed(bytes)?,
+                (8, Value::Bytes(bytes)) => roles = packed(bytes)?,
+                (9, Value::Bytes(bytes)) => member_ids = packed_deltas(bytes)?,
+                (10, Value::Bytes(bytes)) => kinds = packed(bytes)?,
+                _ => {}
+            }
+        }
+
+        let members = member_ids
+            .into_iter()
+            .zip(kinds)
+            .zip(roles)
+            .map(|((id, kind), role)| {
+                let kind = match kind {
+                    0 => MemberKind::Node,
+                    1 => MemberKind::Way,
+                    2 => MemberKind::Relation,
+                    other => return Err(format!("unknown member type {other}")),
+                };
+                let role = strings
+                    .get(role as usize)
+                    .cloned()
+                    .ok_or_else(|| format!("string index {role} out of range"))?;
+                Ok(OsmMember { kind, id, role })
+            })
+            .collect::<Result<_, _>>()?;
+
+        self.relations.push(OsmRelation {
+            id,
+            members,
+            tags: tags(&keys, &values, strings)?,
+        });
+        Ok(())
+    }
+
+    fn coords(&self, refs: &[i64]) -> Vec<[f64; 2]> {
+        refs.iter()
+            .filter_map(|id| self.nodes.get(id).copied())
+            .collect()
+    }
+}
+
+fn decompress(blob: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut fields = Reader { bytes: blob };
+    while let Some((field, value)) = fields.field()? {
+        match (field, value) {
+            (1, Value::Bytes(raw)) => return Ok(raw.to_vec()),
+            (3, Value::Bytes(compressed)) => {
+                let mut bytes = Vec::new();
+                ZlibDecoder::new(compressed)
+                    .take(MAX_BLOB_SIZE as u64)
+                    .read_to_end(&mut bytes)?;
+                return Ok(bytes);
+            }
+            (4..=7, Value::Bytes(_)) => {
+                return Err("only raw and zlib-compressed PBF blobs are supported".into())
+            }
+            _ => {}
+        }
+    }
+    Err("blob without data".into())
+}
+
+fn check_header(block: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut fields = Reader { bytes: block };
+    while let Some((field, value)) = fields.field()? {
+        if let (4, Value::Bytes(feature)) = (field, value) {
+            let feature = String::from_utf8_lossy(feature);
+            if !SUPPORTED_FEATURES.contains(&feature.as_ref()) {
+                return Err(format!("unsupported PBF feature {feature:?}").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Settings for turning OpenStreetMap features into voxels, loadable from TOML like the
+/// generator options.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OsmOptions {
+    /// Height in meters of buildings without `height` or `building:levels` tags.
+    pub default_building_height: f32,
+    /// Meters per `building:levels` level.
+    pub level_height: f32,
+    /// Width in meters of railways without a `width` tag. Roads default by class.
+    pub rail_width: f32,
+}
+
+impl OsmOptions {
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl Default for OsmOptions {
+    fn default() -> Self {
+        Self {
+            default_building_height: 10.0,
+            level_height: 3.0,
+            rail_width: 4.0,
+        }
+    }
+}
+
+/// A building footprint. Rings are `[lat, lon]` and filled with the even-odd rule, so
+/// courtyards are just more rings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Building {
+    pub rings: Vec<Vec<[f64; 2]>>,
+    /// Lowest ground elevation under the footprint's corners.
+    pub base: f32,
+    pub height: f32,
+}
+
+/// A road or railway, draped onto the terrain as a strip of `material`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub points: Vec<[f64; 2]>,
+    /// Meters across.
+    pub width: f32,
+    pub material: u32,
+}
+
+/// A lake, reservoir or riverbank, filled flat at `level`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaterArea {
+    pub rings: Vec<Vec<[f64; 2]>>,
+    /// Lowest ground elevation along the shore.
+    pub level: f32,
+}
+
+/// OpenStreetMap features resolved to coordinates and elevations on a heightmap, ready to be
+/// stamped into chunks voxelized from it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsmFeatures {
+    pub buildings: Vec<Building>,
+    pub lines: Vec<Line>,
+    pub water: Vec<WaterArea>,
+}
+
+/// Parses a length like `12`, `12.5 m` or `40'` into meters.
+fn parse_length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let (number, feet) = match value.strip_suffix('\'').or(value.strip_suffix("ft")) {
+        Some(number) => (number, true),
+        None => (value.strip_suffix('m').unwrap_or(value), false),
+    };
+    let meters: f32 = number.trim().parse().ok()?;
+    (meters > 0.0).then_some(if feet { meters * 0.3048 } else { meters })
+}
+
+fn building_height(tags: &Tags, options: &OsmOptions) -> f32 {
+    tags.get("height")
+        .and_then(|height| parse_length(height))
+        .or_else(|| {
+            let levels: f32 = tags.get("building:levels")?.trim().parse().ok()?;
+            (levels > 0.0).then_some(levels * options.level_height)
+        })
+        .unwrap_or(options.default_building_height)
+}
+
+fn is_building(tags: &Tags) -> bool {
+    tags.get("building").is_some_and(|value| value != "no")
+}
+
+fn is_water(tags: &Tags) -> bool {
+    tags.get("natural").is_some_and(|value| value == "water")
+        || tags
+            .get("waterway")
+            .is_some_and(|value| value == "riverbank")
+        || tags
+            .get("landuse")
+            .is_some_and(|value| value == "reservoir" || value == "basin")
+}
+
+/// Width and material of a road or railway way, or `None` for anything else or anything
+/// underground.
+fn line_style(tags: &Tags, options: &OsmOptions) -> Option<(f32, u32)> {
+    if tags.get("tunnel").is_some_and(|value| value != "no") {
+        return None;
+    }
+    let tagged_width = tags.get("width").and_then(|width| parse_length(width));
+
+    if let Some(highway) = tags.get("highway") {
+        let (width, material) = match highway.as_str() {
+            "motorway" | "trunk" => (12.0, ASPHALT),
+            "primary" | "motorway_link" | "trunk_link" => (10.0, ASPHALT),
+            "secondary" | "primary_link" => (8.0, ASPHALT),
+            "tertiary" | "secondary_link" | "tertiary_link" | "residential" | "unclassified"
+            | "living_street" => (6.0, ASPHALT),
+            "service" | "pedestrian" => (4.0, CONCRETE),
+            "footway" | "path" | "cycleway" | "bridleway" | "track" | "steps" => (2.0, DIRT),
+            _ => return None,
+        };
+        let lanes = tags
+            .get("lanes")
+            .and_then(|lanes| lanes.trim().parse::<f32>().ok())
+            .map(|lanes| lanes * 3.5);
+        return Some((tagged_width.or(lanes).unwrap_or(width), material));
+    }
+
+    match tags.get("railway").map(String::as_str) {
+        // Ballast under the tracks
+        Some(
+            "rail" | "light_rail" | "narrow_gauge" | "tram" | "subway" | "monorail" | "funicular",
+        ) => Some((tagged_width.unwrap_or(options.rail_width), STONE)),
+        _ => None,
+    }
+}
+
+/// Joins a multipolygon's member ways into closed rings, dropping whatever can't be closed.
+fn assemble_rings(ways: Vec<Vec<i64>>) -> Vec<Vec<i64>> {
+    let closed = |way: &Vec<i64>| way.len() >= 4 && way.first() == way.last();
+    let (mut rings, mut open): (Vec<_>, Vec<_>) = ways
+        .into_iter()
+        .filter(|way| way.len() >= 2)
+        .partition(closed);
+
+    while let Some(mut ring) = open.pop() {
+        while !closed(&ring) {
+            let end = *ring.last().unwrap();
+            let Some(next) = open
+                .iter()
+                .position(|way| way.first() == Some(&end) || way.last() == Some(&end))
+            else {
+                break;
+            };
+            let mut way = open.swap_remove(next);
+            if way.first() != Some(&end) {
+                way.reverse();
+            }
+            ring.extend(&way[1..]);
+        }
+        if closed(&ring) {
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+/// Lowest ground elevation at the points that fall on the heightmap, or `None` when none do.
+fn lowest_ground(heightmap: &Heightmap, rings: &[Vec<[f64; 2]>]) -> Option<f32> {
+    rings
+        .iter()
+        .flatten()
+        .filter_map(|&[lat, lon]| heightmap.sample_geo(lat, lon))
+        .min_by(f32::total_cmp)
+}
+
+impl OsmFeatures {
+    /// Picks buildings, roads, railways and water areas out of `data`, keeping those that
+    /// touch `heightmap` and taking their ground elevations from it.
+    pub fn from_data(data: &OsmData, heightmap: &Heightmap, options: &OsmOptions) -> Self {
+        let mut features = Self::default();
+        let mut add_area = |rings: Vec<Vec<[f64; 2]>>, tags: &Tags| {
+            let Some(ground) = lowest_ground(heightmap, &rings) else {
+                return;
+            };
+            if is_building(tags) {
+                features.buildings.push(Building {
+                    rings,
+                    base: ground,
+                    height: building_height(tags, options),
+                });
+            } else if is_water(tags) {
+                features.water.push(WaterArea {
+                    rings,
+                    level: ground,
+                });
+            }
+        };
+
+        for way in &data.ways {
+            let closed = way.refs.len() >= 4 && way.refs.first() == way.refs.last();
+            if closed && (is_building(&way.tags) || is_water(&way.tags)) {
+                add_area(vec![data.coords(&way.refs)], &way.tags);
+            }
+        }
+
+        let ways: HashMap<i64, &OsmWay> = data.ways.iter().map(|way| (way.id, way)).collect();
+        for relation in &data.relations {
+            if relation.tags.get("type").map(String::as_str) != Some("multipolygon") {
+                continue;
+            }
+            let members = relation
+                .members
+                .iter()
+                .filter(|member| member.kind == MemberKind::Way)
+                .filter_map(|member| ways.get(&member.id))
+                .map(|way| way.refs.clone())
+                .collect();
+            let rings = assemble_rings(members)
+                .iter()
+                .map(|ring| data.coords(ring))
+                .collect();
+            add_area(rings, &relation.tags);
+        }
+
+        for way in &data.ways {
+            let Some((width, material)) = line_style(&way.tags, options) else {
+                continue;
+            };
+            let points = data.coords(&way.refs);
+            let touches = points
+                .iter()
+                .any(|&[lat, lon]| heightmap.sample_geo(lat, lon).is_some());
+            if points.len() >= 2 && touches {
+                features.lines.push(Line {
+                    points,
+                    width,
+                    material,
+                });
+            }
+        }
+
+        features
+    }
+}
+
+/// Whether `point` is inside `rings` by the even-odd rule.
+fn inside(rings: &[Vec<[f32; 2]>], [x, z]: [f32; 2]) -> bool {
+    let mut crossings = 0;
+    for ring in rings {
+        for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+            if (a[1] > z) != (b[1] > z) && x < a[0] + (z - a[1]) / (b[1] - a[1]) * (b[0] - a[0]) {
+                crossings += 1;
+            }
+        }
+    }
+    crossings % 2 == 1
+}
+
+fn distance_to_segment(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+    let (dx, dz) = (b[0] - a[0], b[1] - a[1]);
+    let length = dx * dx + dz * dz;
+    let t = if length > 0.0 {
+        (((point[0] - a[0]) * dx + (point[1] - a[1]) * dz) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (px, pz) = (a[0] + t * dx - point[0], a[1] + t * dz - point[1]);
+    (px * px + pz * pz).sqrt()
+}
+
+/// Columns of the chunk whose centers fall within `[min, max]` in voxel units.
+fn columns(min: [f32; 2], max: [f32; 2]) -> impl Iterator<Item = (usize, usize)> {
+    let range = |min: f32, max: f32, size: usize| {
+        let start = min.round().max(0.0) as usize;
+        let end = (max.round() + 1.0).clamp(0.0, size as f32) as usize;
+        start..end
+    };
+    let zs = range(min[1], max[1], WIDTH);
+    range(min[0], max[0], LENGTH).flat_map(move |x| zs.clone().map(move |z| (x, z)))
+}
+
+fn bounds(points: impl Iterator<Item = [f32; 2]>) -> ([f32; 2], [f32; 2]) {
+    points.fold(
+        ([f32::INFINITY; 2], [f32::NEG_INFINITY; 2]),
+        |(min, max), [x, z]| {
+            (
+                [min[0].min(x), min[1].min(z)],
+                [max[0].max(x), max[1].max(z)],
+            )
+        },
+    )
+}
+
+/// Columns of the chunk whose centers are inside `rings`. Areas too small to cover any column
+/// center still take the column under the first ring's centroid.
+fn footprint(rings: &[Vec<[f32; 2]>]) -> Vec<(usize, usize)> {
+    let (min, max) = bounds(rings.iter().flatten().copied());
+    let covered: Vec<_> = columns(min, max)
+        .filter(|&(x, z)| inside(rings, [x as f32, z as f32]))
+        .collect();
+    if !covered.is_empty() {
+        return covered;
+    }
+
+    let Some(ring) = rings.first().filter(|ring| !ring.is_empty()) else {
+        return covered;
+    };
+    let count = ring.len() as f32;
+    let centroid = ring.iter().fold([0.0; 2], |sum, point| {
+        [sum[0] + point[0] / count, sum[1] + point[1] / count]
+    });
+    columns(centroid, centroid).collect()
+}
+
+fn top_solid(volume: &[[[u32; WIDTH]; HEIGHT]; LENGTH], x: usize, z: usize) -> Option<usize> {
+    (0..HEIGHT).rev().find(|&y| volume[x][y][z] != AIR)
+}
+
+/// Stamps OpenStreetMap features into a chunk voxelized from `heightmap` over `region`, going
+/// through the same lat/lon to voxel mapping as the terrain. Water areas are filled flat,
+/// roads and railways replace the surface voxel, and buildings rise as brick with a concrete
+/// roof.
+pub fn stamp(
+    volume: &mut [[[u32; WIDTH]; HEIGHT]; LENGTH],
+    features: &OsmFeatures,
+    heightmap: &Heightmap,
+    region: &ChunkRegion,
+) {
+    let to_voxel = |&[lat, lon]: &[f64; 2]| {
+        let (u, v) = heightmap.bounds.to_uv(lat, lon);
+        region.to_voxel(u as f32, v as f32)
+    };
+    let voxel_rings = |rings: &[Vec<[f64; 2]>]| -> Vec<Vec<[f32; 2]>> {
+        rings
+            .iter()
+            .map(|ring| ring.iter().map(to_voxel).collect())
+            .collect()
+    };
+    for area in &features.water {
+        let level = region.layer(area.level);
+        for (x, z) in footprint(&voxel_rings(&area.rings)) {
+            let top = top_solid(volume, x, z).unwrap_or(0);
+            let column = &mut volume[x];
+            for layer in column.iter_mut().take(top + 1).skip(level + 1) {
+                layer[z] = AIR;
+            }
+            for layer in &mut column[top.min(level)..level] {
+                if layer[z] == AIR {
+                    layer[z] = WATER;
+                }
+            }
+            column[level][z] = WATER;
+        }
+    }
+
+    // Meters per voxel column, to size strips
+    let (dx, _) = heightmap.cell_size();
+    let column_length =
+        dx * (heightmap.width - 1) as f32 * (region.u[1] - region.u[0]) / (LENGTH - 1) as f32;
+    for line in &features.lines {
+        let points: Vec<[f32; 2]> = line.points.iter().map(to_voxel).collect();
+        let half_width = (line.width / 2.0 / column_length).max(0.5);
+
+        for segment in points.windows(2) {
+            let (min, max) = bounds(segment.iter().copied());
+            let min = min.map(|c| c - half_width);
+            let max = max.map(|c| c + half_width);
+
+            for (x, z) in columns(min, max) {
+                if distance_to_segment([x as f32, z as f32], segment[0], segment[1]) > half_width {
+                    continue;
+                }
+                // Roads over water are bridges, which aren't modelled
+                if let Some(y) = top_solid(volume, x, z).filter(|&y| volume[x][y][z] != WATER) {
+                    volume[x][y][z] = line.material;
+                }
+            }
+        }
+    }
+
+    // Buildings are sized in the chunk's layers from their base, so their heights keep their
+    // ratios wherever the base falls within a layer
+    let layer_height = region.voxel_size(heightmap)[1];
+    for building in &features.buildings {
+        let base = region.layer(building.base);
+        let floors = ((building.height / layer_height).round() as usize).max(1);
+        let roof = (base + floors).min(HEIGHT - 1);
+        for (x, z) in footprint(&voxel_rings(&building.rings)) {
+            let ground = top_solid(volume, x, z).unwrap_or(0).min(base);
+            for (y, layer) in volume[x]
+                .iter_mut()
+                .enumerate()
+                .take(roof + 1)
+                .skip(ground + 1)
+            {
+                layer[z] = if y == roof { CONCRETE } else { BRICK };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::terrain::voxelizer::GRASS;
+    use crate::terrain::{jobs, processor};
+
+    /// Just enough of a protobuf writer to build PBF fixtures.
+    #[derive(Default)]
+    struct Message {
+        bytes: Vec<u8>,
+    }
+
+    impl Message {
+        fn varint(&mut self, mut value: u64) -> &mut Self {
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    self.bytes.push(byte);
+                    return self;
+                }
+                self.bytes.push(byte | 0x80);
+            }
+        }
+
+        fn uint(&mut self, field: u64, value: u64) -> &mut Self {
+            self.varint(field << 3).varint(value)
+        }
+
+        fn bytes(&mut self, field: u64, bytes: &[u8]) -> &mut Self {
+            self.varint(field << 3 | 2).varint(bytes.len() as u64);
+            self.bytes.extend(bytes);
+            self
+        }
+
+        fn packed(&mut self, field: u64, values: &[u64]) -> &mut Self {
+            let mut inner = Message::default();
+            for &value in values {
+                inner.varint(value);
+            }
+            self.bytes(field, &inner.bytes)
+        }
+
+        fn deltas(&mut self, field: u64, values: &[i64]) -> &mut Self {
+            let mut last = 0;
+            let encoded: Vec<u64> = values
+                .iter()
+                .map(|&value| {
+                    let delta = value - last;
+                    last = value;
+                    ((delta << 1) ^ (delta >> 63)) as u64
+                })
+                .collect();
+            self.packed(field, &encoded)
+        }
+    }
+
+    fn blob(kind: &str, block: &[u8], compress: bool) -> Vec<u8> {
+        let mut blob = Message::default();
+        if compress {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(block).unwrap();
+            blob.uint(2, block.len() as u64)
+                .bytes(3, &encoder.finish().unwrap());
+        } else {
+            blob.bytes(1, block);
+        }
+
+        let mut header = Message::default();
+        header
+            .bytes(1, kind.as_bytes())
+            .uint(3, blob.bytes.len() as u64);
+        let mut bytes = (header.bytes.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(header.bytes);
+        bytes.extend(blob.bytes);
+        bytes
+    }
+
+    /// A square house with a courtyard, a road across the map and a pond, around 47N 2.5E.
+    fn extract() -> Vec<u8> {
+        let strings = [
+            "",
+            "building",
+            "yes",
+            "building:levels",
+            "4",
+            "highway",
+            "residential",
+            "natural",
+            "water",
+            "type",
+            "multipolygon",
+            "outer",
+            "inner",
+        ];
+        let mut table = Message::default();
+        for string in strings {
+            table.bytes(1, string.as_bytes());
+        }
+
+        // Degrees in units of the default 100 nanodegree granularity
+        let unit = |degrees: f64| (degrees * 1e7).round() as i64;
+        let nodes: Vec<(i64, f64, f64)> = vec![
+            // Outer walls
+            (1, 46.99, 2.51),
+            (2, 46.99, 2.53),
+            (3, 46.97, 2.53),
+            (4, 46.97, 2.51),
+            // Courtyard
+            (5, 46.985, 2.515),
+            (6, 46.985, 2.525),
+            (7, 46.975, 2.525),
+            (8, 46.975, 2.515),
+            // Road
+            (9, 46.95, 2.5),
+            (10, 46.95, 2.6),
+            // Pond
+            (11, 46.93, 2.56),
+            (12, 46.93, 2.58),
+            (13, 46.91, 2.58),
+            (14, 46.91, 2.56),
+        ];
+        let mut dense = Message::default();
+        dense
+            .deltas(1, &nodes.iter().map(|node| node.0).collect::<Vec<_>>())
+            .deltas(
+                8,
+                &nodes.iter().map(|node| unit(node.1)).collect::<Vec<_>>(),
+            )
+            .deltas(
+                9,
+                &nodes.iter().map(|node| unit(node.2)).collect::<Vec<_>>(),
+            );
+
+        let way = |id: u64, tags: &[u64], refs: &[i64]| {
+            let mut way = Message::default();
+            way.uint(1, id);
+            if !tags.is_empty() {
+                let keys: Vec<u64> = tags.iter().step_by(2).copied().collect();
+                let values: Vec<u64> = tags.iter().skip(1).step_by(2).copied().collect();
+                way.packed(2, &keys).packed(3, &values);
+            }
+            way.deltas(8, refs);
+            way.bytes
+        };
+        // The house is a multipolygon whose outer ring comes in two halves
+        let mut relation = Message::default();
+        relation
+            .uint(1, 1)
+            .packed(2, &[9, 1, 3])
+            .packed(3, &[10, 2, 4])
+            .packed(8, &[11, 11, 12])
+            .deltas(9, &[100, 102, 101])
+            .packed(10, &[1, 1, 1]);
+
+        let mut group = Message::default();
+        group
+            .bytes(2, &dense.bytes)
+            .bytes(3, &way(100, &[], &[1, 2, 3]))
+            .bytes(3, &way(101, &[], &[5, 6, 7, 8, 5]))
+            .bytes(3, &way(102, &[], &[3, 4, 1]))
+            .bytes(3, &way(103, &[5, 6], &[9, 10]))
+            .bytes(3, &way(104, &[7, 8], &[11, 12, 13, 14, 11]))
+            .bytes(4, &relation.bytes);
+
+        let mut block = Message::default();
+        block.bytes(1, &table.bytes).bytes(2, &group.bytes);
+
+        let mut header = Message::default();
+        header.bytes(4, b"OsmSchema-V0.6").bytes(4, b"DenseNodes");
+
+        let mut bytes = blob("OSMHeader", &header.bytes, false);
+        bytes.extend(blob("OSMData", &block.bytes, true));
+        bytes
+    }
+
+    fn flat_heightmap() -> Heightmap {
+        // One high corner so the flat ground sits low in the chunk
+        processor::test_heightmap(64, 64, |x, y| if (x, y) == (0, 0) { 900.0 } else { 100.0 })
+    }
+
+    #[test]
+    fn reads_pbf_extracts() {
+        let data = OsmData::from_pbf(extract().as_slice()).unwrap();
+
+        assert_eq!(data.nodes.len(), 14);
+        let [lat, lon] = data.nodes[&6];
+        assert!((lat - 46.985).abs() < 1e-9 && (lon - 2.525).abs() < 1e-9);
+        assert_eq!(data.ways.len(), 5);
+        assert_eq!(data.ways[3].tags["highway"], "residential");
+        assert_eq!(data.ways[1].refs, [5, 6, 7, 8, 5]);
+        assert_eq!(data.relations.len(), 1);
+        assert_eq!(data.relations[0].members[2].role, "inner");
+    }
+
+    #[test]
+    fn rejects_unsupported_features() {
+        let mut header = Message::default();
+        header.bytes(4, b"HistoricalInformation");
+        assert!(OsmData::from_pbf(blob("OSMHeader", &header.bytes, false).as_slice()).is_err());
+    }
+
+    #[test]
+    fn resolves_tags_and_multipolygons() {
+        let data = OsmData::from_pbf(extract().as_slice()).unwrap();
+        let features = OsmFeatures::from_data(&data, &flat_heightmap(), &OsmOptions::default());
+
+        assert_eq!(features.buildings.len(), 1);
+        let house = &features.buildings[0];
+        assert_eq!(house.rings.len(), 2);
+        assert_eq!(house.height, 12.0);
+        assert_eq!(house.base, 100.0);
+
+        assert_eq!(features.lines.len(), 1);
+        assert_eq!(features.lines[0].material, ASPHALT);
+        assert_eq!(features.water.len(), 1);
+
+        assert_eq!(parse_length("12.5 m"), Some(12.5));
+        assert_eq!(parse_length("10'"), Some(3.048));
+        assert_eq!(parse_length("tall"), None);
+    }
+
+    #[test]
+    fn stamps_features_into_chunks() {
+        let heightmap = flat_heightmap();
+        let region = ChunkRegion::whole(&heightmap);
+        let ground = region.layer(100.0);
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            for layer in &mut column[..ground] {
+                *layer = [STONE; WIDTH];
+            }
+            column[ground] = [GRASS; WIDTH];
+        }
+
+        let data = OsmData::from_pbf(extract().as_slice()).unwrap();
+        let mut features = OsmFeatures::from_data(&data, &heightmap, &OsmOptions::default());
+        // Tall enough to span a few of the chunk's 26 meter layers
+        features.buildings[0].height = 80.0;
+        stamp(&mut volume, &features, &heightmap, &region);
+
+        let column = |lon: f64, lat: f64| {
+            let [x, z] = region.to_voxel(((lon - 2.5) / 0.1) as f32, ((47.0 - lat) / 0.1) as f32);
+            (x.round() as usize, z.round() as usize)
+        };
+
+        // Walls rise from the ground to a concrete roof, the courtyard stays open
+        let (x, z) = column(2.512, 46.988);
+        let roof = region.layer(180.0);
+        assert_eq!(volume[x][ground + 1][z], BRICK);
+        assert_eq!(volume[x][roof][z], CONCRETE);
+        assert_eq!(volume[x][roof + 1][z], AIR);
+        let (x, z) = column(2.52, 46.98);
+        assert_eq!(volume[x][ground][z], GRASS);
+        assert_eq!(volume[x][ground + 1][z], AIR);
+
+        // The road replaces the grass along its whole length
+        let road = column(2.5, 46.95).1;
+        assert!((0..LENGTH).all(|x| volume[x][ground][road] == ASPHALT));
+
+        let (x, z) = column(2.57, 46.92);
+        assert_eq!(volume[x][ground][z], WATER);
+    }
+
+    #[test]
+    fn keeps_building_heights_apart() {
+        // A town on a gentle slope, with a mountain in the far chunk
+        let heightmap = processor::test_heightmap(64, 64, |x, y| match (x, y) {
+            (63, 63) => 1500.0,
+            _ => 180.0 + 40.0 * x as f32 / 63.0,
+        });
+        let region = jobs::chunk_region(&heightmap, None, [2, 2], [0, 0]);
+        let ground = region.layer(195.0);
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            for layer in &mut column[..ground] {
+                *layer = [STONE; WIDTH];
+            }
+            column[ground] = [GRASS; WIDTH];
+        }
+
+        let heights = [3.0, 6.0, 9.0, 15.0];
+        let square = |lon: f64| {
+            vec![vec![
+                [46.97, lon],
+                [46.97, lon + 0.005],
+                [46.975, lon + 0.005],
+                [46.975, lon],
+                [46.97, lon],
+            ]]
+        };
+        let features = OsmFeatures {
+            buildings: heights
+                .iter()
+                .enumerate()
+                .map(|(i, &height)| Building {
+                    rings: square(2.505 + 0.01 * i as f64),
+                    base: 195.0,
+                    height,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        stamp(&mut volume, &features, &heightmap, &region);
+
+        let layer_height = region.voxel_size(&heightmap)[1];
+        assert!(layer_height < 3.0);
+        let mut last = 0;
+        for (i, height) in heights.into_iter().enumerate() {
+            let lon = 2.5075 + 0.01 * i as f64;
+            let [x, z] =
+                region.to_voxel(((lon - 2.5) / 0.1) as f32, ((47.0 - 46.9725) / 0.1) as f32);
+            let column = &volume[x.round() as usize];
+            let floors = (ground + 1..HEIGHT)
+                .filter(|&y| column[y][z.round() as usize] != AIR)
+                .count();
+
+            // Within half a layer of the real height, and taller than the one before
+            assert!((floors as f32 * layer_height - height).abs() <= layer_height / 2.0);
+            assert!(floors > last);
+            last = floors;
+        }
+    }
+
+    #[test]
+    fn options_load_from_toml() {
+        let options = OsmOptions::from_toml("level_height = 3.5").unwrap();
+        assert_eq!(options.level_height, 3.5);
+        assert_eq!(
+            options.default_building_height,
+            OsmOptions::default().default_building_height
+        );
+        assert!(OsmOptions::from_toml("roof_pitch = 30.0").is_err());
+    }
+}
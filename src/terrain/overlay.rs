@@ -0,0 +1,410 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+use super::processor::Heightmap;
+use super::voxelizer::{LENGTH, WIDTH};
+
+/// Colors given to layers in the order they're loaded.
+const LAYER_COLORS: [[f32; 3]; 6] = [
+    [1.0, 0.4, 0.1],
+    [0.1, 0.6, 1.0],
+    [1.0, 0.9, 0.1],
+    [0.9, 0.2, 0.7],
+    [0.2, 0.9, 0.4],
+    [1.0, 1.0, 1.0],
+];
+
+/// A shape in `[lat, lon]` degrees.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverlayFeature {
+    Point([f64; 2]),
+    Line(Vec<[f64; 2]>),
+    /// Outer ring and holes, each closed.
+    Polygon(Vec<Vec<[f64; 2]>>),
+}
+
+/// Meters draped features float above the ground, so the terrain doesn't hide them.
+const DRAPE_LIFT: f64 = 2.0;
+
+/// Tracks, boundaries or points of interest shown over the terrain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayLayer {
+    pub name: String,
+    pub color: [f32; 3],
+    pub visible: bool,
+    pub features: Vec<OverlayFeature>,
+}
+
+/// A layer's features laid on the terrain, as `[lat, lon, height]` with heights in the
+/// heightmap's datum.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DrapedLayer {
+    pub lines: Vec<Vec<[f64; 3]>>,
+    pub points: Vec<[f64; 3]>,
+}
+
+/// Value of attribute `name` in the inside of an XML tag after its name.
+fn attribute<'a>(mut attributes: &'a str, name: &str) -> Option<&'a str> {
+    loop {
+        let (key, rest) = attributes.split_once('=')?;
+        let rest = rest.trim_start();
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let rest = &rest[1..];
+        let end = rest.find(quote)?;
+        if key.trim() == name {
+            return Some(&rest[..end]);
+        }
+        attributes = &rest[end + 1..];
+    }
+}
+
+fn gpx_coord(attributes: &str) -> Result<[f64; 2], Box<dyn std::error::Error>> {
+    let value = |name| -> Result<f64, Box<dyn std::error::Error>> {
+        let value =
+            attribute(attributes, name).ok_or_else(|| format!("GPX point without {name}"))?;
+        Ok(value.trim().parse()?)
+    };
+    Ok([value("lat")?, value("lon")?])
+}
+
+fn geojson_position(value: &Value) -> Result<[f64; 2], Box<dyn std::error::Error>> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([lon, lat, ..]) => Ok([
+            lat.as_f64().ok_or("GeoJSON latitude isn't a number")?,
+            lon.as_f64().ok_or("GeoJSON longitude isn't a number")?,
+        ]),
+        _ => Err("GeoJSON position isn't an array of coordinates".into()),
+    }
+}
+
+fn geojson_array(value: &Value) -> Result<&Vec<Value>, Box<dyn std::error::Error>> {
+    value
+        .as_array()
+        .ok_or_else(|| "GeoJSON coordinates aren't an array".into())
+}
+
+fn geojson_positions(value: &Value) -> Result<Vec<[f64; 2]>, Box<dyn std::error::Error>> {
+    geojson_array(value)?.iter().map(geojson_position).collect()
+}
+
+fn geojson_rings(value: &Value) -> Result<Vec<Vec<[f64; 2]>>, Box<dyn std::error::Error>> {
+    geojson_array(value)?
+        .iter()
+        .map(geojson_positions)
+        .collect()
+}
+
+fn geojson_features(
+    value: &Value,
+    features: &mut Vec<OverlayFeature>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let kind = value["type"]
+        .as_str()
+        .ok_or("GeoJSON object without a type")?;
+    let coordinates = &value["coordinates"];
+
+    match kind {
+        "FeatureCollection" => {
+            for feature in value["features"]
+                .as_array()
+                .ok_or("GeoJSON feature collection without features")?
+            {
+                geojson_features(feature, features)?;
+            }
+        }
+        // Features may have a null geometry
+        "Feature" if value["geometry"].is_null() => {}
+        "Feature" => geojson_features(&value["geometry"], features)?,
+        "GeometryCollection" => {
+            for geometry in value["geometries"]
+                .as_array()
+                .ok_or("GeoJSON geometry collection without geometries")?
+            {
+                geojson_features(geometry, features)?;
+            }
+        }
+        "Point" => features.push(OverlayFeature::Point(geojson_position(coordinates)?)),
+        "MultiPoint" => features.extend(
+            geojson_positions(coordinates)?
+                .into_iter()
+                .map(OverlayFeature::Point),
+        ),
+        "LineString" => features.push(OverlayFeature::Line(geojson_positions(coordinates)?)),
+        "MultiLineString" => features.extend(
+            geojson_rings(coordinates)?
+                .into_iter()
+                .map(OverlayFeature::Line),
+        ),
+        "Polygon" => features.push(OverlayFeature::Polygon(geojson_rings(coordinates)?)),
+        "MultiPolygon" => {
+            for polygon in geojson_array(coordinates)? {
+                features.push(OverlayFeature::Polygon(geojson_rings(polygon)?));
+            }
+        }
+        other => return Err(format!("unknown GeoJSON type {other:?}").into()),
+    }
+    Ok(())
+}
+
+impl OverlayLayer {
+    fn new(name: &str, features: Vec<OverlayFeature>) -> Self {
+        Self {
+            name: name.to_string(),
+            color: LAYER_COLORS[0],
+            visible: true,
+            features,
+        }
+    }
+
+    /// Reads a `.gpx`, `.geojson` or `.json` file, naming the layer after it.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        let source = std::fs::read_to_string(path)?;
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("gpx") => Self::from_gpx(&name, &source),
+            Some("geojson" | "json") => Self::from_geojson(&name, &source),
+            _ => Err(format!("{} isn't a GPX or GeoJSON file", path.display()).into()),
+        }
+    }
+
+    /// Loads every GPX and GeoJSON file in `dir`, by file name, giving each its own color.
+    /// A missing directory has no layers.
+    pub fn load_dir(dir: &Path) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        paths.retain(|path| {
+            path.extension().is_some_and(|extension| {
+                ["gpx", "geojson", "json"]
+                    .iter()
+                    .any(|known| extension.eq_ignore_ascii_case(known))
+            })
+        });
+        paths.sort();
+
+        paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let mut layer = Self::load(path)?;
+                layer.color = LAYER_COLORS[i % LAYER_COLORS.len()];
+                Ok(layer)
+            })
+            .collect()
+    }
+
+    /// Reads the tracks, routes and waypoints of a GPX document. Each track segment and route
+    /// becomes a line.
+    pub fn from_gpx(name: &str, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut features = Vec::new();
+        let mut line: Option<Vec<[f64; 2]>> = None;
+
+        let mut rest = source;
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start + 1..];
+            if let Some(comment) = rest.strip_prefix("!--") {
+                let end = comment.find("-->").ok_or("unterminated GPX comment")?;
+                rest = &comment[end + 3..];
+                continue;
+            }
+            let end = rest.find('>').ok_or("unterminated GPX tag")?;
+            let tag = &rest[..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with(['?', '!']) {
+                continue;
+            }
+
+            let closing = tag.starts_with('/');
+            let tag = tag.trim_start_matches('/').trim_end_matches('/');
+            let (element, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            match (element, closing) {
+                ("trkseg" | "rte", false) => line = Some(Vec::new()),
+                ("trkseg" | "rte", true) => {
+                    if let Some(points) = line.take().filter(|points| points.len() >= 2) {
+                        features.push(OverlayFeature::Line(points));
+                    }
+                }
+                ("trkpt" | "rtept", false) => {
+                    if let Some(points) = &mut line {
+                        points.push(gpx_coord(attributes)?);
+                    }
+                }
+                ("wpt", false) => features.push(OverlayFeature::Point(gpx_coord(attributes)?)),
+                _ => {}
+            }
+        }
+
+        Ok(Self::new(name, features))
+    }
+
+    /// Reads the points, lines and polygons of a GeoJSON feature collection, feature or
+    /// geometry.
+    pub fn from_geojson(name: &str, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut features = Vec::new();
+        geojson_features(&serde_json::from_str(source)?, &mut features)?;
+        Ok(Self::new(name, features))
+    }
+
+    /// Lays the layer on a heightmap voxelized as `chunks[0]` by `chunks[1]` chunks, just
+    /// above the ground. Lines get a point at every column so they follow the ground, and are
+    /// split where they leave the heightmap.
+    pub fn drape(&self, heightmap: &Heightmap, chunks: [usize; 2]) -> DrapedLayer {
+        let last = [
+            (chunks[0] * LENGTH - 1) as f64,
+            (chunks[1] * WIDTH - 1) as f64,
+        ];
+        let place = |[lat, lon]: [f64; 2]| {
+            let elevation = heightmap.sample_geo(lat, lon)?;
+            Some([lat, lon, elevation as f64 + DRAPE_LIFT])
+        };
+
+        let mut draped = DrapedLayer::default();
+        let mut drape_line = |points: &[[f64; 2]]| {
+            let mut line = Vec::new();
+            for (i, pair) in points.windows(2).enumerate() {
+                let ([lat0, lon0], [lat1, lon1]) = (pair[0], pair[1]);
+                let (u0, v0) = heightmap.bounds.to_uv(lat0, lon0);
+                let (u1, v1) = heightmap.bounds.to_uv(lat1, lon1);
+                let columns = ((u1 - u0) * last[0]).hypot((v1 - v0) * last[1]);
+                let steps = columns.ceil().max(1.0) as usize;
+
+                // The first segment includes its start, the rest start where the last ended
+                for step in (if i == 0 { 0 } else { 1 })..=steps {
+                    let t = step as f64 / steps as f64;
+                    let point = [lat0 + (lat1 - lat0) * t, lon0 + (lon1 - lon0) * t];
+                    match place(point) {
+                        Some(point) => line.push(point),
+                        None if line.len() >= 2 => draped.lines.push(std::mem::take(&mut line)),
+                        None => line.clear(),
+                    }
+                }
+            }
+            if line.len() >= 2 {
+                draped.lines.push(line);
+            }
+        };
+
+        let mut points = Vec::new();
+        for feature in &self.features {
+            match feature {
+                OverlayFeature::Point(point) => points.extend(place(*point)),
+                OverlayFeature::Line(line) => drape_line(line),
+                OverlayFeature::Polygon(rings) => {
+                    for ring in rings {
+                        drape_line(ring);
+                    }
+                }
+            }
+        }
+        draped.points = points;
+        draped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <!-- <wpt lat="0" lon="0"/> -->
+  <wpt lat="46.95" lon="2.55"><name>Summit</name></wpt>
+  <trk>
+    <name>Loop</name>
+    <trkseg>
+      <trkpt lat="46.99" lon="2.51"><ele>120</ele></trkpt>
+      <trkpt lon='2.59' lat='46.99'/>
+    </trkseg>
+    <trkseg>
+      <trkpt lat="46.91" lon="2.51"/>
+    </trkseg>
+  </trk>
+  <rte><rtept lat="46.92" lon="2.52"/><rtept lat="46.93" lon="2.53"/></rte>
+</gpx>"#;
+
+    #[test]
+    fn reads_gpx_tracks_and_waypoints() {
+        let layer = OverlayLayer::from_gpx("hike", GPX).unwrap();
+        assert_eq!(
+            layer.features,
+            [
+                OverlayFeature::Point([46.95, 2.55]),
+                OverlayFeature::Line(vec![[46.99, 2.51], [46.99, 2.59]]),
+                OverlayFeature::Line(vec![[46.92, 2.52], [46.93, 2.53]]),
+            ]
+        );
+        assert!(OverlayLayer::from_gpx("bad", r#"<wpt lat="46.9"/>"#).is_err());
+    }
+
+    #[test]
+    fn reads_geojson_geometries() {
+        let source = r#"{
+            "type": "FeatureCollection",
+            "features": [
+                {"type": "Feature", "properties": {}, "geometry": {"type": "Point", "coordinates": [2.55, 46.95, 300]}},
+                {"type": "Feature", "properties": {}, "geometry": null},
+                {"type": "Feature", "properties": {}, "geometry": {
+                    "type": "MultiPolygon",
+                    "coordinates": [[[[2.51, 46.99], [2.52, 46.99], [2.52, 46.98], [2.51, 46.99]]]]
+                }},
+                {"type": "Feature", "properties": {}, "geometry": {
+                    "type": "GeometryCollection",
+                    "geometries": [{"type": "LineString", "coordinates": [[2.5, 46.9], [2.6, 47.0]]}]
+                }}
+            ]
+        }"#;
+        let layer = OverlayLayer::from_geojson("parks", source).unwrap();
+
+        assert_eq!(layer.features.len(), 3);
+        assert_eq!(layer.features[0], OverlayFeature::Point([46.95, 2.55]));
+        let OverlayFeature::Polygon(rings) = &layer.features[1] else {
+            panic!("expected a polygon, got {:?}", layer.features[1]);
+        };
+        assert_eq!(rings[0].len(), 4);
+        assert_eq!(
+            layer.features[2],
+            OverlayFeature::Line(vec![[46.9, 2.5], [47.0, 2.6]])
+        );
+
+        let unknown = r#"{"type": "Circle", "coordinates": [0, 0]}"#;
+        assert!(OverlayLayer::from_geojson("bad", unknown).is_err());
+    }
+
+    #[test]
+    fn drapes_features_over_the_terrain() {
+        // Rising eastwards
+        let heightmap = processor::test_heightmap(2, 2, |x, _| x as f32 * 100.0);
+        let layer = OverlayLayer::new(
+            "test",
+            vec![
+                OverlayFeature::Point([46.95, 2.5]),
+                OverlayFeature::Point([48.0, 2.5]),
+                // Runs off the map to the east
+                OverlayFeature::Line(vec![[46.92, 2.5], [46.92, 2.75]]),
+            ],
+        );
+        let draped = layer.drape(&heightmap, [2, 1]);
+
+        assert_eq!(draped.points, vec![[46.95, 2.5, DRAPE_LIFT]]);
+
+        // One point per column across the two chunks, then cut off at the edge
+        assert_eq!(draped.lines.len(), 1);
+        let line = &draped.lines[0];
+        assert_eq!(line.len(), 64);
+        assert_eq!(line[0], [46.92, 2.5, DRAPE_LIFT]);
+        assert!(line[63][1] > 2.59 && line[63][2] > 99.0);
+        assert!(line.windows(2).all(|pair| pair[0][2] <= pair[1][2]));
+    }
+}
@@ -0,0 +1,489 @@
+/// Chunk voxels stored as indices into a per-chunk palette of material ids. Voxels are
+/// addressed by flat index `(x * height + y) * width + z`, the layout the GPU buffer uses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkStorage {
+    /// Bit-packed palette indices, one per voxel.
+    Paletted(PalettedVoxels),
+    /// Runs of one palette entry up each column, for the usual stone-below-air-above terrain.
+    Columns(RleColumns),
+}
+
+impl ChunkStorage {
+    /// Encodes `voxels` of `size` as `[length, height, width]` in whichever form is smaller.
+    pub fn encode(size: [usize; 3], voxels: &[u32]) -> Self {
+        let paletted = PalettedVoxels::encode(size, voxels);
+        let columns = RleColumns::encode(size, voxels);
+
+        if columns.memory_bytes() < paletted.memory_bytes() {
+            Self::Columns(columns)
+        } else {
+            Self::Paletted(paletted)
+        }
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        match self {
+            Self::Paletted(voxels) => voxels.size,
+            Self::Columns(voxels) => voxels.size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size().iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn flat_index(&self, [x, y, z]: [usize; 3]) -> usize {
+        let [_, height, width] = self.size();
+        (x * height + y) * width + z
+    }
+
+    pub fn get(&self, position: [usize; 3]) -> u32 {
+        match self {
+            Self::Paletted(voxels) => voxels.get(self.flat_index(position)),
+            Self::Columns(voxels) => voxels.get(position),
+        }
+    }
+
+    pub fn set(&mut self, position: [usize; 3], material: u32) {
+        let index = self.flat_index(position);
+        match self {
+            Self::Paletted(voxels) => voxels.set(index, material),
+            Self::Columns(voxels) => voxels.set(position, material),
+        }
+    }
+
+    /// Writes every voxel as a raw material id, in the layout of the GPU voxel buffer.
+    pub fn decode_into(&self, out: &mut [u32]) {
+        match self {
+            Self::Paletted(voxels) => voxels.decode_into(out),
+            Self::Columns(voxels) => voxels.decode_into(out),
+        }
+    }
+
+    pub fn decode(&self) -> Vec<u32> {
+        let mut voxels = vec![0; self.len()];
+        self.decode_into(&mut voxels);
+        voxels
+    }
+
+    /// Heap and inline bytes used by the encoded voxels.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Paletted(voxels) => voxels.memory_bytes(),
+            Self::Columns(voxels) => voxels.memory_bytes(),
+        }
+    }
+
+    /// Bytes the same voxels take as raw `u32` ids.
+    pub fn raw_bytes(&self) -> usize {
+        self.len() * std::mem::size_of::<u32>()
+    }
+}
+
+fn build_palette(voxels: &[u32]) -> Vec<u32> {
+    let mut palette: Vec<u32> = Vec::new();
+    for &material in voxels {
+        if !palette.contains(&material) {
+            palette.push(material);
+        }
+    }
+    palette
+}
+
+/// Palette indices packed `bits` wide into u64 words. Indices never straddle two words, which
+/// wastes a few bits per word but keeps lookups to one shift and mask.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PalettedVoxels {
+    size: [usize; 3],
+    palette: Vec<u32>,
+    bits: u32,
+    words: Vec<u64>,
+}
+
+impl PalettedVoxels {
+    pub fn encode(size: [usize; 3], voxels: &[u32]) -> Self {
+        let palette = build_palette(voxels);
+        let mut paletted = Self {
+            size,
+            bits: Self::bits_for(palette.len()),
+            palette,
+            words: Vec::new(),
+        };
+
+        paletted.words = vec![0; paletted.word_count(voxels.len())];
+        for (i, material) in voxels.iter().enumerate() {
+            let index = paletted.palette.iter().position(|m| m == material).unwrap();
+            paletted.write(i, index);
+        }
+        paletted
+    }
+
+    // A single-entry palette needs no indices at all
+    fn bits_for(palette_len: usize) -> u32 {
+        match palette_len {
+            0 | 1 => 0,
+            len => usize::BITS - (len - 1).leading_zeros(),
+        }
+    }
+
+    fn per_word(&self) -> usize {
+        64 / self.bits as usize
+    }
+
+    fn word_count(&self, len: usize) -> usize {
+        if self.bits == 0 {
+            0
+        } else {
+            len.div_ceil(self.per_word())
+        }
+    }
+
+    fn read(&self, i: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let word = self.words[i / self.per_word()];
+        let shift = (i % self.per_word()) as u32 * self.bits;
+        ((word >> shift) & ((1 << self.bits) - 1)) as usize
+    }
+
+    fn write(&mut self, i: usize, index: usize) {
+        if self.bits == 0 {
+            return;
+        }
+        let per_word = self.per_word();
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.words[i / per_word];
+        *word = (*word & !mask) | ((index as u64) << shift);
+    }
+
+    pub fn palette(&self) -> &[u32] {
+        &self.palette
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Rebuilds packed voxels from their parts, such as ones read from a world file, checking
+    /// that they describe `size` voxels.
+    pub fn from_parts(
+        size: [usize; 3],
+        palette: Vec<u32>,
+        bits: u32,
+        words: Vec<u64>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if bits > 32 || bits < Self::bits_for(palette.len()) {
+            return Err(format!("{bits} bits can't index {} entries", palette.len()).into());
+        }
+
+        let voxels = Self {
+            size,
+            palette,
+            bits,
+            words,
+        };
+        let len = size.iter().product();
+        if voxels.words.len() != voxels.word_count(len) {
+            return Err(format!("{} words can't hold {len} voxels", voxels.words.len()).into());
+        }
+        if (0..len).any(|i| voxels.read(i) >= voxels.palette.len()) {
+            return Err("palette index out of range".into());
+        }
+
+        Ok(voxels)
+    }
+
+    pub fn get(&self, i: usize) -> u32 {
+        self.palette[self.read(i)]
+    }
+
+    /// Sets one voxel, growing the palette and repacking the indices wider when it runs out
+    /// of room. Entries that fall out of use stay in the palette until the chunk is re-encoded.
+    pub fn set(&mut self, i: usize, material: u32) {
+        let index = match self.palette.iter().position(|&m| m == material) {
+            Some(index) => index,
+            None => {
+                self.palette.push(material);
+                let bits = Self::bits_for(self.palette.len());
+                if bits != self.bits {
+                    self.repack(bits);
+                }
+                self.palette.len() - 1
+            }
+        };
+        self.write(i, index);
+    }
+
+    fn repack(&mut self, bits: u32) {
+        let len = self.size.iter().product();
+        let indices: Vec<usize> = (0..len).map(|i| self.read(i)).collect();
+
+        self.bits = bits;
+        self.words = vec![0; self.word_count(len)];
+        for (i, index) in indices.into_iter().enumerate() {
+            self.write(i, index);
+        }
+    }
+
+    pub fn decode_into(&self, out: &mut [u32]) {
+        for (i, voxel) in out.iter_mut().enumerate() {
+            *voxel = self.get(i);
+        }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.palette.len() * std::mem::size_of::<u32>()
+            + self.words.len() * std::mem::size_of::<u64>()
+    }
+}
+
+/// One palette entry repeated `length` voxels up a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub index: u16,
+    pub length: u16,
+}
+
+/// Run-length-encoded columns, bottom to top. Runs of every column share one buffer, column
+/// `x * width + z` owning `runs[offsets[c]..offsets[c + 1]]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RleColumns {
+    size: [usize; 3],
+    palette: Vec<u32>,
+    offsets: Vec<u32>,
+    runs: Vec<Run>,
+}
+
+impl RleColumns {
+    pub fn encode(size: [usize; 3], voxels: &[u32]) -> Self {
+        let [length, height, width] = size;
+        let palette = build_palette(voxels);
+        let mut columns = Self {
+            size,
+            palette,
+            offsets: Vec::with_capacity(length * width + 1),
+            runs: Vec::new(),
+        };
+
+        columns.offsets.push(0);
+        for x in 0..length {
+            for z in 0..width {
+                let column = (0..height).map(|y| voxels[(x * height + y) * width + z]);
+                let runs = columns.encode_column(column);
+                columns.runs.extend(runs);
+                columns.offsets.push(columns.runs.len() as u32);
+            }
+        }
+        columns
+    }
+
+    fn encode_column(&mut self, column: impl Iterator<Item = u32>) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        for material in column {
+            let index = match self.palette.iter().position(|&m| m == material) {
+                Some(index) => index,
+                None => {
+                    self.palette.push(material);
+                    self.palette.len() - 1
+                }
+            } as u16;
+
+            match runs.last_mut() {
+                Some(run) if run.index == index => run.length += 1,
+                _ => runs.push(Run { index, length: 1 }),
+            }
+        }
+        runs
+    }
+
+    fn column_runs(&self, x: usize, z: usize) -> &[Run] {
+        let column = x * self.size[2] + z;
+        &self.runs[self.offsets[column] as usize..self.offsets[column + 1] as usize]
+    }
+
+    pub fn palette(&self) -> &[u32] {
+        &self.palette
+    }
+
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+
+    pub fn runs(&self) -> &[Run] {
+        &self.runs
+    }
+
+    /// Rebuilds columns from their parts, checking every column is exactly `size[1]` tall.
+    pub fn from_parts(
+        size: [usize; 3],
+        palette: Vec<u32>,
+        offsets: Vec<u32>,
+        runs: Vec<Run>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let [length, height, width] = size;
+        if offsets.len() != length * width + 1
+            || offsets.first() != Some(&0)
+            || offsets.last() != Some(&(runs.len() as u32))
+            || offsets.windows(2).any(|pair| pair[0] > pair[1])
+        {
+            return Err("column offsets don't match the runs".into());
+        }
+        if runs.iter().any(|run| run.index as usize >= palette.len()) {
+            return Err("palette index out of range".into());
+        }
+
+        let columns = Self {
+            size,
+            palette,
+            offsets,
+            runs,
+        };
+        for x in 0..length {
+            for z in 0..width {
+                let total: usize = columns
+                    .column_runs(x, z)
+                    .iter()
+                    .map(|run| run.length as usize)
+                    .sum();
+                if total != height {
+                    return Err(format!("column {x}, {z} is {total} voxels tall").into());
+                }
+            }
+        }
+
+        Ok(columns)
+    }
+
+    pub fn get(&self, [x, y, z]: [usize; 3]) -> u32 {
+        let mut top = 0;
+        for run in self.column_runs(x, z) {
+            top += run.length as usize;
+            if y < top {
+                return self.palette[run.index as usize];
+            }
+        }
+        unreachable!("y {y} is above the column")
+    }
+
+    /// Sets one voxel by re-encoding its column and splicing it into the run buffer.
+    pub fn set(&mut self, [x, y, z]: [usize; 3], material: u32) {
+        let height = self.size[1];
+        let mut column: Vec<u32> = (0..height).map(|y| self.get([x, y, z])).collect();
+        column[y] = material;
+
+        let runs = self.encode_column(column.into_iter());
+        let index = x * self.size[2] + z;
+        let (start, end) = (self.offsets[index], self.offsets[index + 1]);
+        let shift = runs.len() as i64 - (end - start) as i64;
+
+        self.runs.splice(start as usize..end as usize, runs);
+        for offset in &mut self.offsets[index + 1..] {
+            *offset = (*offset as i64 + shift) as u32;
+        }
+    }
+
+    pub fn decode_into(&self, out: &mut [u32]) {
+        let [length, height, width] = self.size;
+        for x in 0..length {
+            for z in 0..width {
+                let mut y = 0;
+                for run in self.column_runs(x, z) {
+                    for _ in 0..run.length {
+                        out[(x * height + y) * width + z] = self.palette[run.index as usize];
+                        y += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.palette.len() * std::mem::size_of::<u32>()
+            + self.offsets.len() * std::mem::size_of::<u32>()
+            + self.runs.len() * std::mem::size_of::<Run>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::generator::{self, GeneratorOptions};
+    use crate::terrain::processor::GeoBounds;
+    use crate::terrain::voxelizer::{self, AIR, HEIGHT, LENGTH, STONE, WIDTH};
+
+    const SIZE: [usize; 3] = [LENGTH, HEIGHT, WIDTH];
+
+    fn terrain() -> Vec<u32> {
+        let bounds = GeoBounds {
+            north: 47.0,
+            south: 46.75,
+            east: 2.75,
+            west: 2.5,
+        };
+        let heightmap = generator::generate(&GeneratorOptions::default(), 128, 128, bounds);
+        let volume = voxelizer::heightmap_to_volume(&heightmap, &Default::default(), None, None);
+        volume.iter().flatten().flatten().copied().collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let voxels = terrain();
+        let paletted = ChunkStorage::Paletted(PalettedVoxels::encode(SIZE, &voxels));
+        let columns = ChunkStorage::Columns(RleColumns::encode(SIZE, &voxels));
+
+        assert_eq!(paletted.decode(), voxels);
+        assert_eq!(columns.decode(), voxels);
+        assert_eq!(columns.get([3, 0, 7]), voxels[(3 * HEIGHT) * WIDTH + 7]);
+    }
+
+    #[test]
+    fn set_grows_palette() {
+        let mut voxels = vec![AIR; LENGTH * HEIGHT * WIDTH];
+        voxels[0] = STONE;
+        let mut paletted = ChunkStorage::Paletted(PalettedVoxels::encode(SIZE, &voxels));
+        let mut columns = ChunkStorage::Columns(RleColumns::encode(SIZE, &voxels));
+
+        for material in 2..20 {
+            let position = [material as usize, 5, 9];
+            paletted.set(position, material);
+            columns.set(position, material);
+            voxels[(material as usize * HEIGHT + 5) * WIDTH + 9] = material;
+        }
+
+        assert_eq!(paletted.decode(), voxels);
+        assert_eq!(columns.decode(), voxels);
+        let ChunkStorage::Paletted(paletted) = paletted else {
+            unreachable!()
+        };
+        assert_eq!(paletted.bits(), 5);
+    }
+
+    #[test]
+    fn measured_savings() {
+        let voxels = terrain();
+        let raw_bytes = voxels.len() * std::mem::size_of::<u32>();
+
+        // A handful of materials packs into a few bits per voxel
+        let paletted = PalettedVoxels::encode(SIZE, &voxels);
+        assert!(paletted.memory_bytes() < raw_bytes / 4);
+
+        // Plain terrain has a handful of materials in a few runs per column, so whichever
+        // encoding is picked does better still
+        let storage = ChunkStorage::encode(SIZE, &voxels);
+        assert_eq!(storage.raw_bytes(), raw_bytes);
+        assert!(storage.memory_bytes() <= paletted.memory_bytes());
+        assert!((storage.memory_bytes() as f32) < 0.2 * raw_bytes as f32);
+    }
+}
@@ -1,3 +1,321 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+use super::geoid::{Geoid, VerticalDatum};
+use super::water;
+
+// NASADEM and SRTM mark missing samples with this value
+pub const VOID: f32 = -32768.0;
+
+/// Geographic extent in degrees, negative for west and south.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoBounds {
+    pub north: f64,
+    pub south: f64,
+    pub east: f64,
+    pub west: f64,
+}
+
+impl GeoBounds {
+    /// Maps a coordinate inside the bounds to `(u, v)` in `0..=1`, with `v` growing southwards
+    /// to match row order.
+    pub fn to_uv(&self, lat: f64, lon: f64) -> (f64, f64) {
+        (
+            (lon - self.west) / (self.east - self.west),
+            (self.north - lat) / (self.north - self.south),
+        )
+    }
+
+    pub fn from_uv(&self, u: f64, v: f64) -> (f64, f64) {
+        (
+            self.north - v * (self.north - self.south),
+            self.west + u * (self.east - self.west),
+        )
+    }
+}
+
+/// Elevation grid in meters, stored row-major starting at the north-west corner.
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    pub width: usize,
+    pub height: usize,
+    pub bounds: GeoBounds,
+    /// Surface the elevations are measured from.
+    pub datum: VerticalDatum,
+    pub elevations: Vec<f32>,
+}
+
+impl Heightmap {
+    /// Creates a heightmap in NASADEM's EGM96 datum, set `datum` for other sources.
+    pub fn new(width: usize, height: usize, bounds: GeoBounds, elevations: Vec<f32>) -> Self {
+        assert_eq!(elevations.len(), width * height, "heightmap size mismatch");
+        Self {
+            width,
+            height,
+            bounds,
+            datum: VerticalDatum::Egm96,
+            elevations,
+        }
+    }
+
+    /// A heightmap with `elevation(x, y)` at each cell, `y` counting rows south.
+    pub fn from_fn(
+        width: usize,
+        height: usize,
+        bounds: GeoBounds,
+        elevation: impl Fn(usize, usize) -> f32,
+    ) -> Self {
+        let elevations = (0..width * height)
+            .map(|i| elevation(i % width, i / width))
+            .collect();
+        Self::new(width, height, bounds, elevations)
+    }
+
+    /// Elevation at a grid cell, clamped to the edges.
+    pub fn get(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.elevations[y * self.width + x]
+    }
+
+    /// Bilinearly interpolated elevation at normalized `(u, v)`.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let fx = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let fy = v.clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let (x, y) = (fx.floor() as isize, fy.floor() as isize);
+        let (tx, ty) = (fx.fract(), fy.fract());
+
+        let top = self.get(x, y) * (1.0 - tx) + self.get(x + 1, y) * tx;
+        let bottom = self.get(x, y + 1) * (1.0 - tx) + self.get(x + 1, y + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    /// Bilinearly interpolated elevation at a coordinate, or `None` outside the bounds.
+    pub fn sample_geo(&self, lat: f64, lon: f64) -> Option<f32> {
+        let (u, v) = self.bounds.to_uv(lat, lon);
+        ((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v))
+            .then(|| self.sample(u as f32, v as f32))
+    }
+
+    /// Approximate `(east-west, north-south)` size of one cell in meters at the center latitude.
+    pub fn cell_size(&self) -> (f32, f32) {
+        const METERS_PER_DEGREE: f64 = 111_320.0;
+        let center_lat = ((self.bounds.north + self.bounds.south) / 2.0).to_radians();
+        let dlon = (self.bounds.east - self.bounds.west) / (self.width - 1).max(1) as f64;
+        let dlat = (self.bounds.north - self.bounds.south) / (self.height - 1).max(1) as f64;
+        (
+            (dlon * METERS_PER_DEGREE * center_lat.cos()) as f32,
+            (dlat * METERS_PER_DEGREE) as f32,
+        )
+    }
+
+    /// Lowest and highest elevations, leaving out voids.
+    pub fn min_max(&self) -> (f32, f32) {
+        self.elevations
+            .iter()
+            .filter(|&&h| h != VOID)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &h| {
+                (min.min(h), max.max(h))
+            })
+    }
+}
+
+/// Extent of the heightmaps tests build, a tenth of a degree in central France.
+#[cfg(test)]
+pub const TEST_BOUNDS: GeoBounds = GeoBounds {
+    north: 47.0,
+    south: 46.9,
+    east: 2.6,
+    west: 2.5,
+};
+
+/// A `width` by `height` heightmap over `TEST_BOUNDS` with `elevation(x, y)` at each cell.
+#[cfg(test)]
+pub fn test_heightmap(
+    width: usize,
+    height: usize,
+    elevation: impl Fn(usize, usize) -> f32,
+) -> Heightmap {
+    Heightmap::from_fn(width, height, TEST_BOUNDS, elevation)
+}
+
+/// Reads the extent of a GeoTIFF in geographic coordinates from its tiepoint and pixel scale.
+pub fn geotiff_bounds<R: std::io::Read + std::io::Seek>(
+    decoder: &mut Decoder<R>,
+) -> Result<GeoBounds, Box<dyn std::error::Error>> {
+    let (width, height) = decoder.dimensions()?;
+
+    // Tiepoint is (i, j, k, lon, lat, z) of the raster origin, pixel scale is (dlon, dlat, dz)
+    let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+    let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+    if tiepoint.len() < 6 || scale.len() < 2 {
+        return Err("GeoTIFF is missing georeferencing tags".into());
+    }
+
+    let west = tiepoint[3] - tiepoint[0] * scale[0];
+    let north = tiepoint[4] + tiepoint[1] * scale[1];
+    Ok(GeoBounds {
+        north,
+        south: north - height as f64 * scale[1],
+        east: west + width as f64 * scale[0],
+        west,
+    })
+}
+
+/// Reads a single-band elevation GeoTIFF, such as a GEBCO bathymetry tile. Depths below sea
+/// level are negative.
+pub fn read_elevation_geotiff(path: &Path) -> Result<Heightmap, Box<dyn std::error::Error>> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let bounds = geotiff_bounds(&mut decoder)?;
+
+    let datum = geotiff_vertical_datum(&mut decoder)?.unwrap_or(VerticalDatum::Egm96);
+
+    let elevations = match decoder.read_image()? {
+        DecodingResult::I16(data) => data.into_iter().map(f32::from).collect(),
+        DecodingResult::I32(data) => data.into_iter().map(|h| h as f32).collect(),
+        DecodingResult::F32(data) => data,
+        _ => return Err(format!("{} has an unsupported sample format", path.display()).into()),
+    };
+
+    let mut heightmap = Heightmap::new(width as usize, height as usize, bounds, elevations);
+    heightmap.datum = datum;
+    Ok(heightmap)
+}
+
+// Looks up the vertical and geographic CRS in the GeoKey directory, a header of four shorts
+// followed by (key, location, count, value) entries
+fn geotiff_vertical_datum<R: std::io::Read + std::io::Seek>(
+    decoder: &mut Decoder<R>,
+) -> Result<Option<VerticalDatum>, Box<dyn std::error::Error>> {
+    const GEOGRAPHIC_TYPE: u16 = 2048;
+    const VERTICAL_CS_TYPE: u16 = 4096;
+
+    let Some(directory) = decoder.find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)? else {
+        return Ok(None);
+    };
+    let key = |key: u16| {
+        directory
+            .get(4..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .find(|entry| entry[0] == key && entry[1] == 0)
+            .map(|entry| entry[3])
+    };
+
+    Ok(VerticalDatum::from_geo_keys(
+        key(VERTICAL_CS_TYPE),
+        key(GEOGRAPHIC_TYPE),
+    ))
+}
+
+/// Converts a heightmap to another vertical datum through the ellipsoid, using the undulation
+/// grids of the source and target datums from `geoids`.
+pub fn convert_datum(
+    heightmap: &Heightmap,
+    target: VerticalDatum,
+    geoids: &[&Geoid],
+) -> Result<Heightmap, Box<dyn std::error::Error>> {
+    let mut converted = heightmap.clone();
+    converted.datum = target;
+    if heightmap.datum == target {
+        return Ok(converted);
+    }
+
+    let source_geoid = Geoid::find(geoids, heightmap.datum)?;
+    let target_geoid = Geoid::find(geoids, target)?;
+
+    for y in 0..heightmap.height {
+        for x in 0..heightmap.width {
+            let i = y * heightmap.width + x;
+            if heightmap.elevations[i] == VOID {
+                continue;
+            }
+
+            let u = x as f64 / (heightmap.width - 1).max(1) as f64;
+            let v = y as f64 / (heightmap.height - 1).max(1) as f64;
+            let (lat, lon) = heightmap.bounds.from_uv(u, v);
+
+            // Orthometric height H relates to ellipsoidal height h by h = H + N
+            let ellipsoidal = heightmap.elevations[i]
+                + source_geoid.map_or(0.0, |geoid| geoid.undulation(lat, lon));
+            converted.elevations[i] =
+                ellipsoidal - target_geoid.map_or(0.0, |geoid| geoid.undulation(lat, lon));
+        }
+    }
+
+    Ok(converted)
+}
+
+/// Fills void cells with the elevation of the nearest cell that has one, spreading outwards a
+/// cell at a time. A heightmap that is all voids comes back at sea level.
+pub fn fill_voids(heightmap: &Heightmap) -> Heightmap {
+    let (width, height) = (heightmap.width, heightmap.height);
+    let mut filled = heightmap.clone();
+    let mut queue: VecDeque<usize> = (0..width * height)
+        .filter(|&i| heightmap.elevations[i] != VOID)
+        .collect();
+    if queue.is_empty() {
+        filled.elevations.fill(0.0);
+        return filled;
+    }
+
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = (i % width, i / width);
+        let neighbours = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < width).then(|| i + 1),
+            (y > 0).then(|| i - width),
+            (y + 1 < height).then(|| i + width),
+        ];
+        for n in neighbours.into_iter().flatten() {
+            if filled.elevations[n] == VOID {
+                filled.elevations[n] = filled.elevations[i];
+                queue.push_back(n);
+            }
+        }
+    }
+    filled
+}
+
+/// Replaces sea and void cells of a land DEM with bathymetry. Land DEMs report the sea surface
+/// as 0, so cells at or below 0 that connect to the map edge take the seafloor depth when the
+/// bathymetry has one there. Inland depressions below 0, like polders, keep their elevation.
+/// Both grids should share a vertical datum, see [`convert_datum`].
+pub fn merge_bathymetry(land: &Heightmap, bathymetry: &Heightmap) -> Heightmap {
+    let mut merged = land.clone();
+    let sea = water::flood_from_edges(land.width, land.height, |i| {
+        land.elevations[i] <= 0.0 || land.elevations[i] == VOID
+    });
+
+    for y in 0..land.height {
+        for x in 0..land.width {
+            let i = y * land.width + x;
+            let elevation = land.elevations[i];
+            if !sea[i] && elevation != VOID {
+                continue;
+            }
+
+            let u = x as f64 / (land.width - 1).max(1) as f64;
+            let v = y as f64 / (land.height - 1).max(1) as f64;
+            let (lat, lon) = land.bounds.from_uv(u, v);
+
+            merged.elevations[i] = match bathymetry.sample_geo(lat, lon) {
+                Some(depth) if depth < 0.0 || elevation == VOID => depth,
+                _ if elevation == VOID => 0.0,
+                _ => elevation,
+            };
+        }
+    }
+
+    merged
+}
+
 // use reqwest::Client;
 // use std::path::{Path, PathBuf};
 // use tokio::fs;
@@ -57,3 +375,96 @@
 //
 //     Ok(voxels)
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bathymetry_replaces_sea_and_void_cells_only() {
+        // Sea along the west edge, a void and a polder inland
+        let (sea, void, polder) = (3 * 8, 3 * 8 + 4, 5 * 8 + 5);
+        let land = test_heightmap(8, 8, |x, y| match y * 8 + x {
+            i if i == void => VOID,
+            i if i == polder => -5.0,
+            _ if x == 0 => 0.0,
+            _ => 20.0,
+        });
+        let bathymetry = test_heightmap(8, 8, |_, _| -30.0);
+
+        let merged = merge_bathymetry(&land, &bathymetry);
+        assert_eq!(merged.elevations[sea], -30.0);
+        assert_eq!(merged.elevations[void], -30.0);
+        assert_eq!(merged.elevations[polder], -5.0);
+        assert_eq!(merged.elevations[sea + 1], 20.0);
+
+        // Bathymetry above sea level only fills voids
+        let shoal = test_heightmap(8, 8, |_, _| 3.0);
+        let merged = merge_bathymetry(&land, &shoal);
+        assert_eq!(merged.elevations[sea], 0.0);
+        assert_eq!(merged.elevations[void], 3.0);
+    }
+
+    #[test]
+    fn datum_conversion_round_trips() {
+        let geoid =
+            Geoid::from_grd("46 48 2 3 1 1  40 41  44 45  48 49", VerticalDatum::Egm96).unwrap();
+        let orthometric = test_heightmap(4, 4, |x, y| if (x, y) == (1, 1) { VOID } else { 100.0 });
+
+        let ellipsoidal =
+            convert_datum(&orthometric, VerticalDatum::Wgs84Ellipsoid, &[&geoid]).unwrap();
+        assert_eq!(ellipsoidal.datum, VerticalDatum::Wgs84Ellipsoid);
+        assert_eq!(ellipsoidal.elevations[5], VOID);
+        // h = H + N, with N halfway between 44 and 45 at the north west corner
+        assert!((ellipsoidal.elevations[0] - 144.5).abs() < 1e-3);
+
+        let back = convert_datum(&ellipsoidal, VerticalDatum::Egm96, &[&geoid]).unwrap();
+        for (a, b) in back.elevations.iter().zip(&orthometric.elevations) {
+            assert!((a - b).abs() < 1e-3, "{a} != {b}");
+        }
+
+        assert!(convert_datum(&orthometric, VerticalDatum::Egm2008, &[&geoid]).is_err());
+    }
+
+    #[test]
+    fn dem_voids_are_filled_without_bathymetry() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        // A 4x3 NASADEM-style tile with a void in the middle and one in a corner
+        let mut samples = [120i16; 12];
+        samples[0] = -32768;
+        samples[5] = -32768;
+        samples[6] = 80;
+        let path = std::env::temp_dir().join("processor_dem_voids_are_filled.tif");
+        {
+            let mut encoder = TiffEncoder::new(File::create(&path).unwrap()).unwrap();
+            let mut image = encoder.new_image::<colortype::GrayI16>(4, 3).unwrap();
+            let tiepoint = [0.0, 0.0, 0.0, 2.5, 47.0, 0.0];
+            image
+                .encoder()
+                .write_tag(Tag::ModelTiepointTag, &tiepoint[..])
+                .unwrap();
+            image
+                .encoder()
+                .write_tag(Tag::ModelPixelScaleTag, &[0.025, 0.025, 0.0][..])
+                .unwrap();
+            image.write_data(&samples).unwrap();
+        }
+        let dem = read_elevation_geotiff(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let dem = dem.unwrap();
+        assert_eq!(dem.elevations[5], VOID);
+        assert_eq!(dem.min_max(), (80.0, 120.0));
+
+        let filled = fill_voids(&dem);
+        assert!(!filled.elevations.contains(&VOID));
+        assert_eq!(filled.elevations[0], 120.0);
+        // Ties between equally near cells go to the first in row order, here the one north
+        assert_eq!(filled.elevations[5], 120.0);
+        assert_eq!(filled.min_max(), (80.0, 120.0));
+
+        let all_void = test_heightmap(2, 2, |_, _| VOID);
+        assert_eq!(fill_voids(&all_void).elevations, vec![0.0; 4]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use super::processor::Heightmap;
+use super::voxelizer;
+
+/// Terrain attributes of a single heightmap cell that rules can match against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainAttributes {
+    /// Meters above the heightmap's vertical datum.
+    pub altitude: f32,
+    /// Degrees from horizontal.
+    pub slope: f32,
+    /// Compass bearing of the downslope direction in degrees, 0 for north-facing slopes.
+    pub aspect: f32,
+    /// Laplacian of the surface in 1/m, positive in valleys and negative on ridges.
+    pub curvature: f32,
+    /// Meters to the nearest water cell, infinite without a water mask.
+    pub water_distance: f32,
+}
+
+/// Per-cell terrain attributes derived from a heightmap.
+#[derive(Debug, Clone)]
+pub struct AttributeMap {
+    pub width: usize,
+    pub height: usize,
+    pub attributes: Vec<TerrainAttributes>,
+}
+
+impl AttributeMap {
+    /// Derives attributes with central differences. `water` marks water cells row-major like
+    /// the heightmap and drives `water_distance`.
+    pub fn compute(heightmap: &Heightmap, water: Option<&[bool]>) -> Self {
+        let (width, height) = (heightmap.width, heightmap.height);
+        let (dx, dy) = heightmap.cell_size();
+        let water_distance = water.map(|water| distance_field(water, width, height, dx, dy));
+
+        let mut attributes = Vec::with_capacity(width * height);
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let center = heightmap.get(x, y);
+                let (west, east) = (heightmap.get(x - 1, y), heightmap.get(x + 1, y));
+                let (north, south) = (heightmap.get(x, y - 1), heightmap.get(x, y + 1));
+
+                let d_east = (east - west) / (2.0 * dx);
+                let d_north = (north - south) / (2.0 * dy);
+
+                let slope = d_east.hypot(d_north).atan().to_degrees();
+                let aspect = (-d_east).atan2(-d_north).to_degrees().rem_euclid(360.0);
+                let curvature = (east - 2.0 * center + west) / (dx * dx)
+                    + (north - 2.0 * center + south) / (dy * dy);
+
+                let index = y as usize * width + x as usize;
+                attributes.push(TerrainAttributes {
+                    altitude: center,
+                    slope,
+                    aspect,
+                    curvature,
+                    water_distance: water_distance
+                        .as_ref()
+                        .map_or(f32::INFINITY, |distance| distance[index]),
+                });
+            }
+        }
+
+        Self {
+            width,
+            height,
+            attributes,
+        }
+    }
+
+    /// Attributes of the cell nearest to normalized `(u, v)`.
+    pub fn sample(&self, u: f32, v: f32) -> TerrainAttributes {
+        let x = (u.clamp(0.0, 1.0) * (self.width - 1) as f32).round() as usize;
+        let y = (v.clamp(0.0, 1.0) * (self.height - 1) as f32).round() as usize;
+        self.attributes[y * self.width + x]
+    }
+}
+
+// Two-pass chamfer distance transform, exact along axes and diagonals
+fn distance_field(water: &[bool], width: usize, height: usize, dx: f32, dy: f32) -> Vec<f32> {
+    let diagonal = dx.hypot(dy);
+    let mut distance: Vec<f32> = water
+        .iter()
+        .map(|&w| if w { 0.0 } else { f32::INFINITY })
+        .collect();
+
+    let relax = |distance: &mut Vec<f32>, x: usize, y: usize, nx: isize, ny: isize, d: f32| {
+        if nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height {
+            let candidate = distance[ny as usize * width + nx as usize] + d;
+            let current = &mut distance[y * width + x];
+            *current = current.min(candidate);
+        }
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let (ix, iy) = (x as isize, y as isize);
+            relax(&mut distance, x, y, ix - 1, iy, dx);
+            relax(&mut distance, x, y, ix, iy - 1, dy);
+            relax(&mut distance, x, y, ix - 1, iy - 1, diagonal);
+            relax(&mut distance, x, y, ix + 1, iy - 1, diagonal);
+        }
+    }
+    for y in (0..height).rev() {
+        for x in (0..width).rev() {
+            let (ix, iy) = (x as isize, y as isize);
+            relax(&mut distance, x, y, ix + 1, iy, dx);
+            relax(&mut distance, x, y, ix, iy + 1, dy);
+            relax(&mut distance, x, y, ix + 1, iy + 1, diagonal);
+            relax(&mut distance, x, y, ix - 1, iy + 1, diagonal);
+        }
+    }
+
+    distance
+}
+
+/// Inclusive bounds on an attribute, either side may be left open.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+impl Bounds {
+    fn contains(&self, value: f32) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+
+    // Compass ranges wrap through north when min > max, e.g. 315..45
+    fn contains_bearing(&self, bearing: f32) -> bool {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min > max => bearing >= min || bearing <= max,
+            _ => self.contains(bearing),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    /// The top voxel of a column.
+    #[default]
+    Surface,
+    /// The voxels directly below the surface, `depth` deep.
+    Subsurface,
+}
+
+/// Places `material` on `layer` wherever every given condition holds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: u32,
+    #[serde(default)]
+    pub layer: Layer,
+    #[serde(default = "default_depth")]
+    pub depth: usize,
+    pub altitude: Option<Bounds>,
+    pub slope: Option<Bounds>,
+    pub aspect: Option<Bounds>,
+    pub curvature: Option<Bounds>,
+    pub water_distance: Option<Bounds>,
+    /// Land-cover classes the rule is limited to.
+    pub land_cover: Option<Vec<u8>>,
+}
+
+fn default_depth() -> usize {
+    3
+}
+
+fn deserialize_material<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    voxelizer::material_by_name(&name)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown material {name:?}")))
+}
+
+impl Rule {
+    pub fn matches(&self, attributes: &TerrainAttributes, land_cover: Option<u8>) -> bool {
+        let within = |bounds: &Option<Bounds>, value: f32| {
+            bounds.as_ref().is_none_or(|bounds| bounds.contains(value))
+        };
+
+        within(&self.altitude, attributes.altitude)
+            && within(&self.slope, attributes.slope)
+            && within(&self.curvature, attributes.curvature)
+            && within(&self.water_distance, attributes.water_distance)
+            && self
+                .aspect
+                .as_ref()
+                .is_none_or(|aspect| aspect.contains_bearing(attributes.aspect))
+            && self
+                .land_cover
+                .as_ref()
+                .is_none_or(|classes| land_cover.is_some_and(|class| classes.contains(&class)))
+    }
+}
+
+/// Materials picked for one column. `None` keeps the voxelizer's default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColumnMaterials {
+    pub surface: Option<u32>,
+    pub subsurface: Option<(u32, usize)>,
+}
+
+/// Ordered material rules, the first matching rule of each layer wins.
+///
+/// ```toml
+/// [[rule]]
+/// material = "stone"
+/// slope = { min = 35.0 }
+///
+/// [[rule]]
+/// material = "snow"
+/// altitude = { min = 2500.0 }
+/// aspect = { min = 270.0, max = 90.0 }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn evaluate(
+        &self,
+        attributes: &TerrainAttributes,
+        land_cover: Option<u8>,
+    ) -> ColumnMaterials {
+        let first = |layer: Layer| {
+            self.rules
+                .iter()
+                .find(|rule| rule.layer == layer && rule.matches(attributes, land_cover))
+        };
+
+        ColumnMaterials {
+            surface: first(Layer::Surface).map(|rule| rule.material),
+            subsurface: first(Layer::Subsurface).map(|rule| (rule.material, rule.depth)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor::GeoBounds;
+
+    const BOUNDS: GeoBounds = GeoBounds {
+        north: 0.01,
+        south: 0.0,
+        east: 0.01,
+        west: 0.0,
+    };
+
+    // Elevations as a function of meters east and south, on a map small enough to be metric
+    fn heightmap(size: usize, elevation: impl Fn(f32, f32) -> f32) -> Heightmap {
+        let (dx, dy) = Heightmap::from_fn(size, size, BOUNDS, |_, _| 0.0).cell_size();
+        Heightmap::from_fn(size, size, BOUNDS, |x, y| {
+            elevation(x as f32 * dx, y as f32 * dy)
+        })
+    }
+
+    #[test]
+    fn slope_and_aspect_of_tilted_plane() {
+        // Rises towards the south by 1 m per meter, so it faces north at 45 degrees
+        let attributes = AttributeMap::compute(&heightmap(16, |_, south| south), None);
+        let center = attributes.sample(0.5, 0.5);
+
+        assert!((center.slope - 45.0).abs() < 0.01);
+        assert!(center.aspect < 0.01 || center.aspect > 359.99);
+        assert!(center.curvature.abs() < 1e-4);
+    }
+
+    #[test]
+    fn curvature_sign_of_valley_and_ridge() {
+        let valley = AttributeMap::compute(
+            &heightmap(16, |east, _| (east - 500.0).powi(2) * 0.001),
+            None,
+        );
+        let ridge = AttributeMap::compute(
+            &heightmap(16, |east, _| -(east - 500.0).powi(2) * 0.001),
+            None,
+        );
+
+        assert!(valley.sample(0.5, 0.5).curvature > 0.0);
+        assert!(ridge.sample(0.5, 0.5).curvature < 0.0);
+    }
+
+    #[test]
+    fn water_distance_grows_away_from_water() {
+        let map = heightmap(8, |_, _| 0.0);
+        let water: Vec<bool> = (0..64).map(|i| i % 8 == 0).collect();
+        let attributes = AttributeMap::compute(&map, Some(&water));
+        let (dx, _) = map.cell_size();
+
+        assert_eq!(attributes.attributes[0].water_distance, 0.0);
+        assert!((attributes.attributes[3].water_distance - 3.0 * dx).abs() < 0.01);
+    }
+
+    #[test]
+    fn steep_slopes_become_rock_and_high_north_faces_snow() {
+        let rules = RuleSet::from_toml(
+            r#"
+            [[rule]]
+            material = "snow"
+            altitude = { min = 2500.0 }
+            aspect = { min = 270.0, max = 90.0 }
+
+            [[rule]]
+            material = "stone"
+            slope = { min = 35.0 }
+
+            [[rule]]
+            material = "sand"
+            layer = "subsurface"
+            depth = 2
+            "#,
+        )
+        .unwrap();
+
+        // Pyramid peaking at 2800 m with 45 degree faces
+        let attributes = AttributeMap::compute(
+            &heightmap(32, |east, south| {
+                2800.0 - (east - 500.0).abs().max((south - 500.0).abs())
+            }),
+            None,
+        );
+
+        let north_face = attributes.sample(15.0 / 31.0, 8.0 / 31.0);
+        let south_face = attributes.sample(15.0 / 31.0, 22.0 / 31.0);
+        let low_north_face = attributes.sample(15.0 / 31.0, 3.0 / 31.0);
+
+        assert_eq!(
+            rules.evaluate(&north_face, None).surface,
+            Some(voxelizer::SNOW)
+        );
+        assert_eq!(
+            rules.evaluate(&south_face, None).surface,
+            Some(voxelizer::STONE)
+        );
+        assert!(low_north_face.altitude < 2500.0);
+        assert_eq!(
+            rules.evaluate(&low_north_face, None).surface,
+            Some(voxelizer::STONE)
+        );
+        assert_eq!(
+            rules.evaluate(&south_face, None).subsurface,
+            Some((voxelizer::SAND, 2))
+        );
+    }
+
+    #[test]
+    fn land_cover_condition_and_unknown_material() {
+        let rules = RuleSet::from_toml(
+            r#"
+            [[rule]]
+            material = "mud"
+            land_cover = [90]
+            "#,
+        )
+        .unwrap();
+        let flat = AttributeMap::compute(&heightmap(4, |_, _| 10.0), None).sample(0.5, 0.5);
+
+        assert_eq!(
+            rules.evaluate(&flat, Some(90)).surface,
+            Some(voxelizer::MUD)
+        );
+        assert_eq!(rules.evaluate(&flat, Some(10)).surface, None);
+        assert_eq!(rules.evaluate(&flat, None).surface, None);
+
+        assert!(RuleSet::from_toml("[[rule]]\nmaterial = \"lava\"").is_err());
+    }
+}
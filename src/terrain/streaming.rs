@@ -0,0 +1,541 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use super::density::DensityOptions;
+use super::jobs::{self, RegionSources};
+use super::landcover::{LandCover, LandCoverTable};
+use super::lidar::{ClassTable, LidarPoints};
+use super::lod::{LodChain, LodSelection};
+use super::osm::OsmFeatures;
+use super::processor::Heightmap;
+use super::rules::{AttributeMap, RuleSet};
+use super::vegetation::{TemplateLibrary, VegetationOptions, VegetationTable};
+use super::voxelizer::{GeoCoord, MaterialSources, VoxelChunk, HEIGHT, LENGTH, WIDTH};
+use super::water::WaterMap;
+use super::world_file::WorldReader;
+
+/// Words in one pool slot: a chunk's LOD chain, 32^3 + 16^3 + ... + 1, followed by the packed
+/// surface height of each of its 32^2 columns.
+pub const SLOT_VOXELS: usize = LodChain::total_voxels([LENGTH, HEIGHT, WIDTH]) + LENGTH * WIDTH;
+
+/// Where streamed chunks come from. Chunk coordinates count chunks east and south from the
+/// world's north-west chunk, and are called from worker threads.
+pub trait ChunkSource: Send + Sync {
+    /// The chunk at `coord`, or `None` where the world has no data.
+    fn load(&self, coord: [i32; 2]) -> Result<Option<VoxelChunk>, Box<dyn std::error::Error>>;
+}
+
+/// Voxelizes chunks on demand from a heightmap split into `chunks[0]` by `chunks[1]` chunks,
+/// the same way `jobs::voxelize_chunks` builds the whole grid.
+#[derive(Debug, Clone)]
+pub struct GeneratedSource {
+    pub heightmap: Heightmap,
+    pub chunks: [usize; 2],
+    pub land_cover: Option<(LandCover, LandCoverTable)>,
+    pub rules: Option<(RuleSet, AttributeMap)>,
+    pub water: Option<WaterMap>,
+    pub density: Option<DensityOptions>,
+    pub lidar: Option<(LidarPoints, ClassTable)>,
+    pub osm: Option<OsmFeatures>,
+    pub vegetation: Option<(VegetationTable, TemplateLibrary, VegetationOptions)>,
+}
+
+impl GeneratedSource {
+    /// Inputs of every chunk, for voxelizing the whole grid at once with `jobs::voxelize_chunks`.
+    pub fn sources(&self) -> RegionSources<'_> {
+        RegionSources {
+            heightmap: &self.heightmap,
+            materials: MaterialSources {
+                land_cover: self
+                    .land_cover
+                    .as_ref()
+                    .map(|(cover, table)| (cover, table)),
+                rules: self
+                    .rules
+                    .as_ref()
+                    .map(|(rules, attributes)| (rules, attributes)),
+            },
+            water: self.water.as_ref(),
+            density: self.density.as_ref(),
+            lidar: self.lidar.as_ref().map(|(points, table)| (points, table)),
+            osm: self.osm.as_ref(),
+            vegetation: self
+                .vegetation
+                .as_ref()
+                .map(|(table, library, options)| (table, library, options)),
+        }
+    }
+}
+
+impl ChunkSource for GeneratedSource {
+    fn load(&self, coord: [i32; 2]) -> Result<Option<VoxelChunk>, Box<dyn std::error::Error>> {
+        let in_grid = (0..2).all(|i| coord[i] >= 0 && (coord[i] as usize) < self.chunks[i]);
+        if !in_grid {
+            return Ok(None);
+        }
+
+        let position = coord.map(|c| c as usize);
+        Ok(Some(jobs::voxelize_chunk(
+            &self.sources(),
+            self.chunks,
+            position,
+        )))
+    }
+}
+
+/// Reads chunks from a world file as they're needed. Chunk coordinates follow the grid the
+/// file's chunk corners form.
+pub struct WorldFileSource<R> {
+    reader: Mutex<WorldReader<R>>,
+    coords: HashMap<[i32; 2], GeoCoord>,
+}
+
+impl WorldFileSource<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(WorldReader::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> WorldFileSource<R> {
+    pub fn new(reader: WorldReader<R>) -> Result<Self, Box<dyn std::error::Error>> {
+        if reader.header.chunk_size != [LENGTH as u32, HEIGHT as u32, WIDTH as u32] {
+            return Err(format!(
+                "streamed chunks must be {LENGTH}x{HEIGHT}x{WIDTH} voxels, not {:?}",
+                reader.header.chunk_size
+            )
+            .into());
+        }
+
+        // Columns run east with longitude, rows south against latitude
+        let mut lons: Vec<f64> = reader.chunk_coords().map(|coord| coord.lon).collect();
+        let mut lats: Vec<f64> = reader.chunk_coords().map(|coord| coord.lat).collect();
+        lons.sort_by(f64::total_cmp);
+        lons.dedup();
+        lats.sort_by(|a, b| b.total_cmp(a));
+        lats.dedup();
+
+        let position =
+            |values: &[f64], value: f64| values.iter().position(|&v| v == value).unwrap() as i32;
+        let coords = reader
+            .chunk_coords()
+            .map(|&coord| {
+                (
+                    [position(&lons, coord.lon), position(&lats, coord.lat)],
+                    coord,
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            reader: Mutex::new(reader),
+            coords,
+        })
+    }
+}
+
+impl<R: Read + Seek + Send> ChunkSource for WorldFileSource<R> {
+    fn load(&self, coord: [i32; 2]) -> Result<Option<VoxelChunk>, Box<dyn std::error::Error>> {
+        let Some(&coord) = self.coords.get(&coord) else {
+            return Ok(None);
+        };
+        let mut reader = self
+            .reader
+            .lock()
+            .map_err(|_| "world file reader poisoned")?;
+        reader.read_chunk(coord)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingOptions {
+    /// Chunks kept loaded on each side of the camera's chunk.
+    pub radius: u32,
+    /// Worker threads loading chunks, or one per core for `None`.
+    pub threads: Option<usize>,
+    pub selection: LodSelection,
+}
+
+impl Default for StreamingOptions {
+    fn default() -> Self {
+        Self {
+            radius: 3,
+            threads: None,
+            selection: LodSelection::Representative,
+        }
+    }
+}
+
+/// A loaded chunk's LOD chain and surface heights, to be written at `slot * SLOT_VOXELS` in the
+/// GPU chunk pool, along with the chunk itself for where it's drawn and picking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkUpload {
+    pub slot: usize,
+    pub voxels: Vec<u32>,
+    pub chunk: VoxelChunk,
+}
+
+type Loaded = ([i32; 2], Option<(Vec<u32>, VoxelChunk)>);
+
+/// Keeps the square of chunks within `radius` of the camera's chunk loaded into a fixed pool
+/// of GPU slots. Chunks load on worker threads nearest first, and chunks that fall out of
+/// range give their slot back.
+pub struct ChunkStreamer {
+    options: StreamingOptions,
+    source: Arc<dyn ChunkSource>,
+    workers: rayon::ThreadPool,
+    sender: Sender<Loaded>,
+    receiver: Receiver<Loaded>,
+
+    center: [i32; 2],
+    capacity: usize,
+    free: Vec<usize>,
+    resident: HashMap<[i32; 2], usize>,
+    pinned: HashMap<[i32; 2], usize>,
+    /// Chunks the source has no data for, so they aren't asked for again while in range.
+    empty: HashSet<[i32; 2]>,
+    pending: HashSet<[i32; 2]>,
+}
+
+impl ChunkStreamer {
+    pub fn new(
+        source: Arc<dyn ChunkSource>,
+        options: StreamingOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut workers = rayon::ThreadPoolBuilder::new();
+        if let Some(threads) = options.threads {
+            workers = workers.num_threads(threads);
+        }
+        let (sender, receiver) = mpsc::channel();
+
+        let side = 2 * options.radius as usize + 1;
+        let capacity = side * side;
+        Ok(Self {
+            options,
+            source,
+            workers: workers.build()?,
+            sender,
+            receiver,
+            center: [0, 0],
+            capacity,
+            free: (0..capacity).rev().collect(),
+            resident: HashMap::new(),
+            pinned: HashMap::new(),
+            empty: HashSet::new(),
+            pending: HashSet::new(),
+        })
+    }
+
+    /// Chunks along each side of the page table.
+    pub fn side(&self) -> usize {
+        2 * self.options.radius as usize + 1
+    }
+
+    /// Slots the GPU chunk pool needs.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Chunk at the page table's first entry.
+    pub fn origin(&self) -> [i32; 2] {
+        let radius = self.options.radius as i32;
+        self.center.map(|c| c - radius)
+    }
+
+    /// Chunks in the pool, pinned ones included.
+    pub fn loaded(&self) -> usize {
+        self.resident.len() + self.pinned.len()
+    }
+
+    /// Chunks waiting on a worker.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Reserves an extra slot for the chunk at `coord`, which is then never loaded or evicted
+    /// so the caller can fill it, like a chunk being edited. Pin before sizing the GPU pool.
+    pub fn pin(&mut self, coord: [i32; 2]) -> usize {
+        if let Some(&slot) = self.pinned.get(&coord) {
+            return slot;
+        }
+        if let Some(slot) = self.resident.remove(&coord) {
+            self.free.push(slot);
+        }
+
+        let slot = self.capacity;
+        self.capacity += 1;
+        self.pinned.insert(coord, slot);
+        slot
+    }
+
+    /// Moves the window to `center`, evicting chunks that left it and queueing the ones that
+    /// entered, and returns the chunks that finished loading since the last call.
+    pub fn update(&mut self, center: [i32; 2]) -> Vec<ChunkUpload> {
+        self.center = center;
+        let radius = self.options.radius as i32;
+        let in_range = |coord: [i32; 2]| (0..2).all(|i| (coord[i] - center[i]).abs() <= radius);
+
+        let free = &mut self.free;
+        self.resident.retain(|&coord, &mut slot| {
+            in_range(coord) || {
+                free.push(slot);
+                false
+            }
+        });
+        self.empty.retain(|&coord| in_range(coord));
+
+        let mut uploads = Vec::new();
+        for (coord, loaded) in self.receiver.try_iter() {
+            self.pending.remove(&coord);
+            if !in_range(coord) || self.pinned.contains_key(&coord) {
+                continue;
+            }
+            match loaded {
+                Some((voxels, chunk)) => {
+                    // The window never holds more chunks than the pool has slots
+                    let slot = self.free.pop().expect("chunk pool is full");
+                    self.resident.insert(coord, slot);
+                    uploads.push(ChunkUpload {
+                        slot,
+                        voxels,
+                        chunk,
+                    });
+                }
+                None => {
+                    self.empty.insert(coord);
+                }
+            }
+        }
+
+        // Nearest first, and only a few at a time so the queue keeps up with the camera
+        let mut wanted: Vec<[i32; 2]> = (-radius..=radius)
+            .flat_map(|dx| (-radius..=radius).map(move |dz| [center[0] + dx, center[1] + dz]))
+            .filter(|coord| {
+                !self.resident.contains_key(coord)
+                    && !self.pinned.contains_key(coord)
+                    && !self.empty.contains(coord)
+                    && !self.pending.contains(coord)
+            })
+            .collect();
+        wanted.sort_by_key(|coord| {
+            let (dx, dz) = (coord[0] - center[0], coord[1] - center[1]);
+            dx * dx + dz * dz
+        });
+
+        let in_flight = 2 * self.workers.current_num_threads();
+        for coord in wanted {
+            if self.pending.len() >= in_flight {
+                break;
+            }
+            self.request(coord);
+        }
+
+        uploads
+    }
+
+    fn request(&mut self, coord: [i32; 2]) {
+        self.pending.insert(coord);
+
+        let source = Arc::clone(&self.source);
+        let sender = self.sender.clone();
+        let selection = self.options.selection;
+        self.workers.spawn(move || {
+            let loaded = match source.load(coord) {
+                Ok(Some(chunk)) if chunk.voxels.size() != [LENGTH, HEIGHT, WIDTH] => {
+                    eprintln!(
+                        "Rejected chunk {coord:?}: expected {:?} voxels to fit a pool slot, got {:?}",
+                        [LENGTH, HEIGHT, WIDTH],
+                        chunk.voxels.size()
+                    );
+                    None
+                }
+                Ok(chunk) => chunk.map(|chunk| {
+                    let mut voxels =
+                        LodChain::build(&chunk.voxels.decode(), chunk.voxels.size(), selection)
+                            .flatten();
+                    voxels.extend(chunk.surface_words());
+                    (voxels, chunk)
+                }),
+                Err(e) => {
+                    eprintln!("Failed to load chunk {coord:?}: {e}");
+                    None
+                }
+            };
+            // The streamer may already be gone
+            let _ = sender.send((coord, loaded));
+        });
+    }
+
+    /// One entry per chunk of the window, column-major from `origin` like the voxels: the
+    /// chunk's slot plus one, or zero when it isn't loaded.
+    pub fn page_table(&self) -> Vec<u32> {
+        let side = self.side() as i32;
+        let origin = self.origin();
+        let mut table = Vec::with_capacity((side * side) as usize);
+        for i in 0..side {
+            for j in 0..side {
+                let coord = [origin[0] + i, origin[1] + j];
+                let slot = self.resident.get(&coord).or(self.pinned.get(&coord));
+                table.push(slot.map_or(0, |&slot| slot as u32 + 1));
+            }
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::terrain::generator::{self, GeneratorOptions};
+    use crate::terrain::processor::TEST_BOUNDS;
+    use crate::terrain::voxelizer::{AIR, STONE};
+
+    /// A flat slab whose height encodes the chunk, with nothing west of column zero.
+    struct Slabs;
+
+    impl ChunkSource for Slabs {
+        fn load(&self, coord: [i32; 2]) -> Result<Option<VoxelChunk>, Box<dyn std::error::Error>> {
+            if coord[0] < 0 {
+                return Ok(None);
+            }
+            let top = (coord[0] + 2 * coord[1]).rem_euclid(HEIGHT as i32) as usize;
+            let mut volume = Box::new([[[AIR; WIDTH]; HEIGHT]; LENGTH]);
+            for column in volume.iter_mut() {
+                for layer in &mut column[..=top] {
+                    *layer = [STONE; WIDTH];
+                }
+            }
+            let coord = GeoCoord {
+                lat: -(coord[1] as f64),
+                lon: coord[0] as f64,
+            };
+            Ok(Some(VoxelChunk::new(coord, 0.0, &volume)))
+        }
+    }
+
+    /// Updates until every chunk in range has been loaded, collecting the uploads by slot.
+    fn settle(streamer: &mut ChunkStreamer, center: [i32; 2]) -> HashMap<usize, Vec<u32>> {
+        let start = Instant::now();
+        let mut uploads = HashMap::new();
+        loop {
+            for upload in streamer.update(center) {
+                uploads.insert(upload.slot, upload.voxels);
+            }
+            if streamer.pending() == 0 {
+                return uploads;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "streaming stalled"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn slab_top(voxels: &[u32]) -> usize {
+        (0..HEIGHT)
+            .rev()
+            .find(|&y| voxels[y * WIDTH] != AIR)
+            .unwrap()
+    }
+
+    #[test]
+    fn streams_the_window_around_the_camera() {
+        let options = StreamingOptions {
+            radius: 1,
+            threads: Some(2),
+            ..Default::default()
+        };
+        let mut streamer = ChunkStreamer::new(Arc::new(Slabs), options).unwrap();
+        assert_eq!(streamer.capacity(), 9);
+
+        let mut pool = vec![vec![]; 9];
+        for (slot, voxels) in settle(&mut streamer, [0, 0]) {
+            assert_eq!(voxels.len(), SLOT_VOXELS);
+            pool[slot] = voxels;
+        }
+        // Column -1 has no data
+        assert_eq!(streamer.loaded(), 6);
+
+        let check = |streamer: &ChunkStreamer, pool: &[Vec<u32>]| {
+            let origin = streamer.origin();
+            for (index, &page) in streamer.page_table().iter().enumerate() {
+                let coord = [origin[0] + index as i32 / 3, origin[1] + index as i32 % 3];
+                if coord[0] < 0 {
+                    assert_eq!(page, 0);
+                } else {
+                    let top = (coord[0] + 2 * coord[1]).rem_euclid(HEIGHT as i32) as usize;
+                    assert_eq!(slab_top(&pool[page as usize - 1]), top);
+                }
+            }
+        };
+        check(&streamer, &pool);
+
+        // Moving east drops the western column and loads a new eastern one into its slots
+        for (slot, voxels) in settle(&mut streamer, [2, 0]) {
+            pool[slot] = voxels;
+        }
+        assert_eq!(streamer.origin(), [1, -1]);
+        assert_eq!(streamer.loaded(), 9);
+        check(&streamer, &pool);
+    }
+
+    #[test]
+    fn pinned_chunks_are_left_to_the_caller() {
+        let options = StreamingOptions {
+            radius: 1,
+            threads: Some(1),
+            ..Default::default()
+        };
+        let mut streamer = ChunkStreamer::new(Arc::new(Slabs), options).unwrap();
+        let slot = streamer.pin([1, 1]);
+        assert_eq!(slot, 9);
+        assert_eq!(streamer.capacity(), 10);
+
+        let uploads = settle(&mut streamer, [1, 1]);
+        assert_eq!(uploads.len(), 8);
+        assert!(!uploads.contains_key(&slot));
+        assert_eq!(streamer.page_table()[4], slot as u32 + 1);
+
+        // Out of range it stays in its slot, but off the page table
+        settle(&mut streamer, [10, 10]);
+        assert_eq!(streamer.loaded(), 10);
+        assert!(!streamer.page_table().contains(&(slot as u32 + 1)));
+    }
+
+    #[test]
+    fn world_files_stream_by_grid_position() {
+        let heightmap = generator::generate(&GeneratorOptions::default(), 64, 64, TEST_BOUNDS);
+        let sources = RegionSources {
+            heightmap: &heightmap,
+            materials: MaterialSources::default(),
+            water: None,
+            density: None,
+            lidar: None,
+            osm: None,
+            vegetation: None,
+        };
+        let world = jobs::voxelize_chunks(&sources, [3, 2], Some(1)).unwrap();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        let source = WorldFileSource::new(WorldReader::new(Cursor::new(bytes)).unwrap()).unwrap();
+        let chunk = source.load([2, 1]).unwrap().unwrap();
+        assert_eq!(chunk, jobs::voxelize_chunk(&sources, [3, 2], [2, 1]));
+        assert!(source.load([3, 0]).unwrap().is_none());
+        assert!(source.load([0, -1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn slot_size_matches_the_lod_chain() {
+        let voxels = vec![STONE; LENGTH * HEIGHT * WIDTH];
+        let chain = LodChain::build(&voxels, [LENGTH, HEIGHT, WIDTH], LodSelection::Majority);
+        assert_eq!(chain.flatten().len() + LENGTH * WIDTH, SLOT_VOXELS);
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::f32::consts::{SQRT_2, TAU};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::noise::{hash_to_unit, splitmix64};
+use super::voxelizer::{
+    self, GeoCoord, AIR, HEIGHT, LEAVES, LENGTH, SHRUB, STONE, WATER, WIDTH, WOOD,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeatureKind {
+    Tree,
+    Shrub,
+    Rock,
+}
+
+/// Chance of each kind of feature taking a Poisson-disk site, each from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VegetationDensity {
+    pub trees: f32,
+    pub shrubs: f32,
+    pub rocks: f32,
+}
+
+/// Maps surface materials to feature densities. Surfaces come from the land cover and material
+/// rules when the world has them, falling back to grass, so whichever is loaded decides where
+/// forests grow.
+#[derive(Debug, Clone)]
+pub struct VegetationTable {
+    densities: HashMap<u32, VegetationDensity>,
+}
+
+impl VegetationTable {
+    /// Parses a TOML table keyed by material name, e.g.
+    /// `forest_floor = { trees = 0.8, shrubs = 0.1 }`.
+    pub fn from_toml(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let entries: HashMap<String, VegetationDensity> = toml::from_str(source)?;
+
+        let mut densities = HashMap::new();
+        for (material, density) in entries {
+            let material = voxelizer::material_by_name(&material)
+                .ok_or_else(|| format!("unknown material {material:?}"))?;
+            densities.insert(material, density);
+        }
+
+        Ok(Self { densities })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn density(&self, surface: u32) -> VegetationDensity {
+        self.densities.get(&surface).copied().unwrap_or_default()
+    }
+}
+
+impl Default for VegetationTable {
+    fn default() -> Self {
+        let density = |trees, shrubs, rocks| VegetationDensity {
+            trees,
+            shrubs,
+            rocks,
+        };
+        let densities = HashMap::from([
+            (voxelizer::FOREST_FLOOR, density(0.8, 0.15, 0.0)),
+            (voxelizer::SHRUB, density(0.05, 0.6, 0.05)),
+            (voxelizer::GRASS, density(0.05, 0.1, 0.02)),
+            (voxelizer::FARMLAND, density(0.01, 0.0, 0.0)),
+            (voxelizer::MUD, density(0.0, 0.2, 0.0)),
+            (voxelizer::STONE, density(0.0, 0.0, 0.15)),
+            (voxelizer::SAND, density(0.0, 0.0, 0.03)),
+            (voxelizer::SNOW, density(0.0, 0.0, 0.02)),
+        ]);
+
+        Self { densities }
+    }
+}
+
+/// Voxel model stamped with its origin on the first air voxel above a surface.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Template {
+    pub voxels: Vec<([i32; 3], u32)>,
+}
+
+impl Template {
+    /// Round crown of leaves on a straight trunk.
+    pub fn broadleaf(trunk_height: i32, crown_radius: i32) -> Self {
+        let mut voxels: Vec<_> = (0..trunk_height).map(|y| ([0, y, 0], WOOD)).collect();
+
+        let center = trunk_height + crown_radius - 1;
+        let radius_squared = crown_radius * crown_radius + crown_radius;
+        for dx in -crown_radius..=crown_radius {
+            for dy in -crown_radius..=crown_radius {
+                for dz in -crown_radius..=crown_radius {
+                    let inside = dx * dx + dy * dy + dz * dz <= radius_squared;
+                    let trunk = dx == 0 && dz == 0 && center + dy < trunk_height;
+                    if inside && !trunk {
+                        voxels.push(([dx, center + dy, dz], LEAVES));
+                    }
+                }
+            }
+        }
+
+        Self { voxels }
+    }
+
+    /// Cone of leaves narrowing to the top of the trunk.
+    pub fn conifer(height: i32, radius: i32) -> Self {
+        let mut voxels: Vec<_> = (0..height).map(|y| ([0, y, 0], WOOD)).collect();
+
+        for y in 2..=height {
+            let layer = radius * (height - y) / (height - 2).max(1);
+            for dx in -layer..=layer {
+                for dz in -layer..=layer {
+                    let trunk = dx == 0 && dz == 0 && y < height;
+                    if dx * dx + dz * dz <= layer * layer + layer && !trunk {
+                        voxels.push(([dx, y, dz], LEAVES));
+                    }
+                }
+            }
+        }
+
+        Self { voxels }
+    }
+
+    /// Writes the template into `volume` with its origin at `origin`, clipped at the chunk
+    /// bounds. With `only_air` it grows around whatever is already there.
+    pub fn stamp(
+        &self,
+        volume: &mut [[[u32; WIDTH]; HEIGHT]; LENGTH],
+        origin: [i32; 3],
+        only_air: bool,
+    ) {
+        for &([dx, dy, dz], material) in &self.voxels {
+            let (x, y, z) = (origin[0] + dx, origin[1] + dy, origin[2] + dz);
+            if !(0..LENGTH as i32).contains(&x)
+                || !(0..HEIGHT as i32).contains(&y)
+                || !(0..WIDTH as i32).contains(&z)
+            {
+                continue;
+            }
+
+            let voxel = &mut volume[x as usize][y as usize][z as usize];
+            if !only_air || *voxel == AIR {
+                *voxel = material;
+            }
+        }
+    }
+
+    pub fn shrub(radius: i32) -> Self {
+        Self::dome(radius, radius, SHRUB)
+    }
+
+    pub fn rock(radius: i32) -> Self {
+        Self::dome(radius, (radius + 1) / 2, STONE)
+    }
+
+    fn dome(radius: i32, height: i32, material: u32) -> Self {
+        let mut voxels = Vec::new();
+        for dx in -radius..=radius {
+            for dy in 0..height {
+                for dz in -radius..=radius {
+                    // Stretch the vertical axis so the dome is `height` tall
+                    let scaled = dy * radius / height.max(1);
+                    if dx * dx + scaled * scaled + dz * dz <= radius * radius {
+                        voxels.push(([dx, dy, dz], material));
+                    }
+                }
+            }
+        }
+
+        Self { voxels }
+    }
+}
+
+/// Templates to pick from for each feature kind. Prefabs can be added next to the procedural
+/// defaults.
+#[derive(Debug, Clone)]
+pub struct TemplateLibrary {
+    templates: HashMap<FeatureKind, Vec<Template>>,
+}
+
+impl TemplateLibrary {
+    pub fn empty() -> Self {
+        Self {
+            templates: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, kind: FeatureKind, template: Template) {
+        self.templates.entry(kind).or_default().push(template);
+    }
+
+    pub fn templates(&self, kind: FeatureKind) -> &[Template] {
+        self.templates.get(&kind).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl Default for TemplateLibrary {
+    fn default() -> Self {
+        let mut library = Self::empty();
+        library.add(FeatureKind::Tree, Template::broadleaf(3, 2));
+        library.add(FeatureKind::Tree, Template::broadleaf(4, 3));
+        library.add(FeatureKind::Tree, Template::conifer(7, 2));
+        library.add(FeatureKind::Tree, Template::conifer(9, 3));
+        library.add(FeatureKind::Shrub, Template::shrub(1));
+        library.add(FeatureKind::Shrub, Template::shrub(2));
+        library.add(FeatureKind::Rock, Template::rock(1));
+        library.add(FeatureKind::Rock, Template::rock(2));
+        library
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VegetationOptions {
+    pub seed: u64,
+    /// Minimum distance in voxels between any two features.
+    pub spacing: f32,
+}
+
+impl Default for VegetationOptions {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            spacing: 4.0,
+        }
+    }
+}
+
+/// Seed for the chunk whose north-west corner is at `coord`, so every chunk gets its own
+/// layout that doesn't depend on the order chunks are generated in.
+pub fn chunk_seed(seed: u64, coord: GeoCoord) -> u64 {
+    let mut state = seed ^ coord.lat.to_bits().rotate_left(29) ^ coord.lon.to_bits();
+    splitmix64(&mut state)
+}
+
+fn unit(state: &mut u64) -> f32 {
+    (splitmix64(state) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// Bridson's algorithm, sites no closer than `spacing` in a `width` x `length` area
+fn poisson_disk(seed: u64, width: f32, length: f32, spacing: f32) -> Vec<[f32; 2]> {
+    const ATTEMPTS: usize = 30;
+
+    let cell = spacing / SQRT_2;
+    let columns = (width / cell).ceil() as usize;
+    let rows = (length / cell).ceil() as usize;
+    let grid_index = |point: [f32; 2]| {
+        let column = ((point[0] / cell) as usize).min(columns - 1);
+        let row = ((point[1] / cell) as usize).min(rows - 1);
+        (column, row)
+    };
+
+    let mut state = seed;
+    let mut grid: Vec<Option<usize>> = vec![None; columns * rows];
+    let mut points = vec![[unit(&mut state) * width, unit(&mut state) * length]];
+    let (column, row) = grid_index(points[0]);
+    grid[row * columns + column] = Some(0);
+    let mut active = vec![0];
+
+    while !active.is_empty() {
+        let slot = ((unit(&mut state) * active.len() as f32) as usize).min(active.len() - 1);
+        let origin = points[active[slot]];
+
+        let mut found = false;
+        for _ in 0..ATTEMPTS {
+            let angle = unit(&mut state) * TAU;
+            let distance = spacing * (1.0 + unit(&mut state));
+            let candidate = [
+                origin[0] + distance * angle.cos(),
+                origin[1] + distance * angle.sin(),
+            ];
+            if !(0.0..width).contains(&candidate[0]) || !(0.0..length).contains(&candidate[1]) {
+                continue;
+            }
+
+            // A cell is small enough to hold one point, so only neighbours two cells out matter
+            let (column, row) = grid_index(candidate);
+            let crowded = (row.saturating_sub(2)..(row + 3).min(rows)).any(|r| {
+                (column.saturating_sub(2)..(column + 3).min(columns)).any(|c| {
+                    grid[r * columns + c].is_some_and(|other| {
+                        let [x, y] = points[other];
+                        let (dx, dy) = (x - candidate[0], y - candidate[1]);
+                        dx * dx + dy * dy < spacing * spacing
+                    })
+                })
+            });
+
+            if !crowded {
+                grid[row * columns + column] = Some(points.len());
+                active.push(points.len());
+                points.push(candidate);
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            active.swap_remove(slot);
+        }
+    }
+
+    points
+}
+
+/// The chunk being scattered and the eight around it, indexed `[dx + 1][dz + 1]` for the chunk
+/// `dx` columns east and `dz` rows south. Each holds that chunk's volume before vegetation and
+/// its chunk seed, or `None` past the edge of the region.
+pub type Neighbourhood<'a> = [[Option<(&'a [[[u32; WIDTH]; HEIGHT]; LENGTH], u64)>; 3]; 3];
+
+// Poisson-disk sites of one chunk, kept `spacing / 2` away from its edges so sites on either
+// side of a seam are still `spacing` apart
+fn chunk_sites(chunk_seed: u64, spacing: f32) -> Vec<[f32; 2]> {
+    let (width, length) = (LENGTH as f32 - spacing, WIDTH as f32 - spacing);
+    if width <= 0.0 || length <= 0.0 {
+        return Vec::new();
+    }
+
+    let margin = spacing / 2.0;
+    poisson_disk(chunk_seed, width, length, spacing)
+        .into_iter()
+        .map(|[x, z]| [x + margin, z + margin])
+        .collect()
+}
+
+/// Scatters trees, shrubs and rocks over the surface of a voxelized chunk. Each chunk samples
+/// its own Poisson-disk sites, kept or dropped by the density of the surface they land on, and
+/// features from the neighbouring chunks' sites are stamped too so the ones crossing a seam
+/// are whole on both sides. Features only fill air, and the same neighbourhood always gives
+/// the same result.
+pub fn scatter(
+    volume: &mut [[[u32; WIDTH]; HEIGHT]; LENGTH],
+    neighbourhood: &Neighbourhood,
+    table: &VegetationTable,
+    library: &TemplateLibrary,
+    options: &VegetationOptions,
+) {
+    let spacing = options.spacing.max(1.0);
+
+    // Row by row, then column by column, so overlapping features resolve the same way in
+    // every chunk they reach
+    for dz in -1..=1 {
+        for dx in -1..=1 {
+            let Some((source, chunk_seed)) = neighbourhood[(dx + 1) as usize][(dz + 1) as usize]
+            else {
+                continue;
+            };
+
+            for [x, z] in chunk_sites(chunk_seed, spacing) {
+                let (x, z) = (x as usize, z as usize);
+                let Some(y) = (0..HEIGHT).rev().find(|&y| source[x][y][z] != AIR) else {
+                    continue;
+                };
+                let surface = source[x][y][z];
+                if surface == WATER {
+                    continue;
+                }
+
+                let density = table.density(surface);
+                let roll = hash_to_unit(chunk_seed, x as i64, z as i64, 0);
+                let kind = if roll < density.trees {
+                    FeatureKind::Tree
+                } else if roll < density.trees + density.shrubs {
+                    FeatureKind::Shrub
+                } else if roll < density.trees + density.shrubs + density.rocks {
+                    FeatureKind::Rock
+                } else {
+                    continue;
+                };
+
+                let templates = library.templates(kind);
+                if templates.is_empty() {
+                    continue;
+                }
+                let pick = hash_to_unit(chunk_seed, x as i64, z as i64, 1) * templates.len() as f32;
+                let template = &templates[(pick as usize).min(templates.len() - 1)];
+
+                let origin = [
+                    x as i32 + dx * LENGTH as i32,
+                    y as i32 + 1,
+                    z as i32 + dz * WIDTH as i32,
+                ];
+                template.stamp(volume, origin, true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::voxelizer::FOREST_FLOOR;
+
+    fn forest() -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
+        let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+        for column in volume.iter_mut() {
+            column[0] = [STONE; WIDTH];
+            column[1] = [FOREST_FLOOR; WIDTH];
+        }
+        volume
+    }
+
+    fn scattered(coord: GeoCoord) -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
+        let mut volume = forest();
+        let options = VegetationOptions::default();
+        let source = forest();
+        let mut neighbourhood: Neighbourhood = Default::default();
+        neighbourhood[1][1] = Some((&source, chunk_seed(options.seed, coord)));
+        scatter(
+            &mut volume,
+            &neighbourhood,
+            &VegetationTable::default(),
+            &TemplateLibrary::default(),
+            &options,
+        );
+        volume
+    }
+
+    #[test]
+    fn same_chunk_same_forest() {
+        let coord = GeoCoord {
+            lat: 47.0,
+            lon: 2.5,
+        };
+        let volume = scattered(coord);
+
+        assert!(volume.iter().flatten().flatten().any(|&m| m == WOOD));
+        assert_eq!(volume, scattered(coord));
+        assert_ne!(
+            volume,
+            scattered(GeoCoord {
+                lat: 47.0,
+                lon: 2.6
+            })
+        );
+    }
+
+    #[test]
+    fn sites_respect_spacing() {
+        let sites = poisson_disk(7, 32.0, 32.0, 4.0);
+        assert!(sites.len() > 20);
+
+        for (i, a) in sites.iter().enumerate() {
+            for b in &sites[i + 1..] {
+                let (dx, dy) = (a[0] - b[0], a[1] - b[1]);
+                assert!(dx * dx + dy * dy >= 16.0);
+            }
+        }
+    }
+
+    #[test]
+    fn spacing_holds_across_seams() {
+        let west = chunk_sites(7, 4.0);
+        let east = chunk_sites(8, 4.0);
+        assert!(west.len() > 20 && east.len() > 20);
+
+        for a in &west {
+            for b in &east {
+                let (dx, dy) = (a[0] - (b[0] + LENGTH as f32), a[1] - b[1]);
+                assert!(dx * dx + dy * dy >= 16.0);
+            }
+        }
+    }
+
+    #[test]
+    fn trees_are_whole_across_seams() {
+        let tree = Template::broadleaf(4, 3);
+        let mut library = TemplateLibrary::empty();
+        library.add(FeatureKind::Tree, tree.clone());
+        let table = VegetationTable::from_toml("forest_floor = { trees = 1.0 }").unwrap();
+        let options = VegetationOptions::default();
+
+        // Two chunks side by side, each scattered with the other as its neighbour
+        let source = forest();
+        let seeds = [11, 12];
+        let chunks: Vec<_> = (0..2)
+            .map(|chunk| {
+                let mut neighbourhood: Neighbourhood = Default::default();
+                neighbourhood[1][1] = Some((&source, seeds[chunk]));
+                neighbourhood[2 - 2 * chunk][1] = Some((&source, seeds[1 - chunk]));
+
+                let mut volume = forest();
+                scatter(&mut volume, &neighbourhood, &table, &library, &options);
+                volume
+            })
+            .collect();
+        let voxel = |x: i32, y: i32, z: i32| {
+            chunks[x as usize / LENGTH][x as usize % LENGTH][y as usize][z as usize]
+        };
+
+        let mut crossing = 0;
+        for x in 0..2 * LENGTH as i32 {
+            for z in 0..WIDTH as i32 {
+                if voxel(x, 2, z) != WOOD {
+                    continue;
+                }
+                for &([dx, dy, dz], _) in &tree.voxels {
+                    let (x, y, z) = (x + dx, 2 + dy, z + dz);
+                    if (0..2 * LENGTH as i32).contains(&x) && (0..WIDTH as i32).contains(&z) {
+                        assert_ne!(voxel(x, y, z), AIR, "{x} {y} {z}");
+                    }
+                }
+                // The crown reaches three voxels out from the trunk
+                if (LENGTH as i32 - 3..LENGTH as i32 + 3).contains(&x) {
+                    crossing += 1;
+                }
+            }
+        }
+        assert!(crossing > 0);
+    }
+}
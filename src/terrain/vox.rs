@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::palette::ChunkStorage;
+use super::vegetation::Template;
+use super::voxelizer::{VoxelMaterial, AIR};
+
+const VERSION: i32 = 200;
+// Rotation byte of the identity matrix: row 0 picks x, row 1 picks y, no signs flipped
+const IDENTITY: u8 = 0b0000100;
+
+// MagicaVoxel's default palette from the file format description, 0xAABBGGRR by color index,
+// used by files without an RGBA chunk
+const DEFAULT_PALETTE: [u32; 256] = [
+    0x00000000, 0xffffffff, 0xffccffff, 0xff99ffff, 0xff66ffff, 0xff33ffff, 0xff00ffff, 0xffffccff,
+    0xffccccff, 0xff99ccff, 0xff66ccff, 0xff33ccff, 0xff00ccff, 0xffff99ff, 0xffcc99ff, 0xff9999ff,
+    0xff6699ff, 0xff3399ff, 0xff0099ff, 0xffff66ff, 0xffcc66ff, 0xff9966ff, 0xff6666ff, 0xff3366ff,
+    0xff0066ff, 0xffff33ff, 0xffcc33ff, 0xff9933ff, 0xff6633ff, 0xff3333ff, 0xff0033ff, 0xffff00ff,
+    0xffcc00ff, 0xff9900ff, 0xff6600ff, 0xff3300ff, 0xff0000ff, 0xffffffcc, 0xffccffcc, 0xff99ffcc,
+    0xff66ffcc, 0xff33ffcc, 0xff00ffcc, 0xffffcccc, 0xffcccccc, 0xff99cccc, 0xff66cccc, 0xff33cccc,
+    0xff00cccc, 0xffff99cc, 0xffcc99cc, 0xff9999cc, 0xff6699cc, 0xff3399cc, 0xff0099cc, 0xffff66cc,
+    0xffcc66cc, 0xff9966cc, 0xff6666cc, 0xff3366cc, 0xff0066cc, 0xffff33cc, 0xffcc33cc, 0xff9933cc,
+    0xff6633cc, 0xff3333cc, 0xff0033cc, 0xffff00cc, 0xffcc00cc, 0xff9900cc, 0xff6600cc, 0xff3300cc,
+    0xff0000cc, 0xffffff99, 0xffccff99, 0xff99ff99, 0xff66ff99, 0xff33ff99, 0xff00ff99, 0xffffcc99,
+    0xffcccc99, 0xff99cc99, 0xff66cc99, 0xff33cc99, 0xff00cc99, 0xffff9999, 0xffcc9999, 0xff999999,
+    0xff669999, 0xff339999, 0xff009999, 0xffff6699, 0xffcc6699, 0xff996699, 0xff666699, 0xff336699,
+    0xff006699, 0xffff3399, 0xffcc3399, 0xff993399, 0xff663399, 0xff333399, 0xff003399, 0xffff0099,
+    0xffcc0099, 0xff990099, 0xff660099, 0xff330099, 0xff000099, 0xffffff66, 0xffccff66, 0xff99ff66,
+    0xff66ff66, 0xff33ff66, 0xff00ff66, 0xffffcc66, 0xffcccc66, 0xff99cc66, 0xff66cc66, 0xff33cc66,
+    0xff00cc66, 0xffff9966, 0xffcc9966, 0xff999966, 0xff669966, 0xff339966, 0xff009966, 0xffff6666,
+    0xffcc6666, 0xff996666, 0xff666666, 0xff336666, 0xff006666, 0xffff3366, 0xffcc3366, 0xff993366,
+    0xff663366, 0xff333366, 0xff003366, 0xffff0066, 0xffcc0066, 0xff990066, 0xff660066, 0xff330066,
+    0xff000066, 0xffffff33, 0xffccff33, 0xff99ff33, 0xff66ff33, 0xff33ff33, 0xff00ff33, 0xffffcc33,
+    0xffcccc33, 0xff99cc33, 0xff66cc33, 0xff33cc33, 0xff00cc33, 0xffff9933, 0xffcc9933, 0xff999933,
+    0xff669933, 0xff339933, 0xff009933, 0xffff6633, 0xffcc6633, 0xff996633, 0xff666633, 0xff336633,
+    0xff006633, 0xffff3333, 0xffcc3333, 0xff993333, 0xff663333, 0xff333333, 0xff003333, 0xffff0033,
+    0xffcc0033, 0xff990033, 0xff660033, 0xff330033, 0xff000033, 0xffffff00, 0xffccff00, 0xff99ff00,
+    0xff66ff00, 0xff33ff00, 0xff00ff00, 0xffffcc00, 0xffcccc00, 0xff99cc00, 0xff66cc00, 0xff33cc00,
+    0xff00cc00, 0xffff9900, 0xffcc9900, 0xff999900, 0xff669900, 0xff339900, 0xff009900, 0xffff6600,
+    0xffcc6600, 0xff996600, 0xff666600, 0xff336600, 0xff006600, 0xffff3300, 0xffcc3300, 0xff993300,
+    0xff663300, 0xff333300, 0xff003300, 0xffff0000, 0xffcc0000, 0xff990000, 0xff660000, 0xff330000,
+    0xff0000ee, 0xff0000dd, 0xff0000bb, 0xff0000aa, 0xff000088, 0xff000077, 0xff000055, 0xff000044,
+    0xff000022, 0xff000011, 0xff00ee00, 0xff00dd00, 0xff00bb00, 0xff00aa00, 0xff008800, 0xff007700,
+    0xff005500, 0xff004400, 0xff002200, 0xff001100, 0xffee0000, 0xffdd0000, 0xffbb0000, 0xffaa0000,
+    0xff880000, 0xff770000, 0xff550000, 0xff440000, 0xff220000, 0xff110000, 0xffeeeeee, 0xffdddddd,
+    0xffbbbbbb, 0xffaaaaaa, 0xff888888, 0xff777777, 0xff555555, 0xff444444, 0xff222222, 0xff111111,
+];
+
+/// One model of a MagicaVoxel file, in MagicaVoxel's z-up coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxModel {
+    pub size: [u32; 3],
+    /// `[x, y, z, color index]`, color indices run from 1 to 255.
+    pub voxels: Vec<[u8; 4]>,
+}
+
+/// A model placed in the scene, with the transforms of the scene graph above it combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxInstance {
+    pub model: usize,
+    /// Position of the model's center.
+    pub translation: [i32; 3],
+    /// MagicaVoxel's packed rotation byte.
+    pub rotation: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    pub instances: Vec<VoxInstance>,
+    /// RGBA of color indices 1 to 255, entry 0 is unused.
+    pub palette: [[u8; 4]; 256],
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Transform {
+        child: i32,
+        translation: [i32; 3],
+        rotation: u8,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        model: usize,
+    },
+}
+
+fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(channel: f32) -> u8 {
+    let c = channel.clamp(0.0, 1.0);
+    let c = if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+// The first two rows must pick distinct columns for the byte to encode a rotation
+fn is_rotation(rotation: u8) -> bool {
+    let (first, second) = (rotation & 3, (rotation >> 2) & 3);
+    first < 3 && second < 3 && first != second
+}
+
+fn rotation_matrix(rotation: u8) -> [[i32; 3]; 3] {
+    let first = (rotation & 3) as usize;
+    let second = ((rotation >> 2) & 3) as usize;
+    let third = 3 - first - second;
+    let sign = |bit: u8| if rotation & bit != 0 { -1 } else { 1 };
+
+    let mut matrix = [[0; 3]; 3];
+    matrix[0][first] = sign(1 << 4);
+    matrix[1][second] = sign(1 << 5);
+    matrix[2][third] = sign(1 << 6);
+    matrix
+}
+
+fn rotation_byte(matrix: [[i32; 3]; 3]) -> u8 {
+    let column = |row: [i32; 3]| row.iter().position(|&v| v != 0).unwrap_or(0) as u8;
+    let negative = |row: [i32; 3]| row.iter().any(|&v| v < 0) as u8;
+
+    column(matrix[0])
+        | column(matrix[1]) << 2
+        | negative(matrix[0]) << 4
+        | negative(matrix[1]) << 5
+        | negative(matrix[2]) << 6
+}
+
+fn multiply(a: [[i32; 3]; 3], b: [[i32; 3]; 3]) -> [[i32; 3]; 3] {
+    std::array::from_fn(|row| {
+        std::array::from_fn(|col| (0..3).map(|k| a[row][k] * b[k][col]).sum())
+    })
+}
+
+fn transform(matrix: [[i32; 3]; 3], v: [i32; 3]) -> [i32; 3] {
+    std::array::from_fn(|row| (0..3).map(|k| matrix[row][k] * v[k]).sum())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.bytes.len() < n {
+            return Err("unexpected end of .vox data".into());
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn i32(&mut self) -> Result<i32, Box<dyn std::error::Error>> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    // Lengths, counts and indices are stored as i32 but never negative
+    fn count(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        let value = self.i32()?;
+        Ok(usize::try_from(value).map_err(|_| format!("negative count {value} in .vox data"))?)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let length = self.count()?;
+        Ok(String::from_utf8(self.take(length)?.to_vec())?)
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        (0..self.i32()?)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn i32(&mut self, value: i32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.i32(value.len() as i32);
+        self.bytes.extend(value.as_bytes());
+    }
+
+    fn dict(&mut self, entries: &[(&str, String)]) {
+        self.i32(entries.len() as i32);
+        for (key, value) in entries {
+            self.string(key);
+            self.string(value);
+        }
+    }
+
+    fn chunk(&mut self, id: &[u8; 4], content: impl FnOnce(&mut Writer)) {
+        let mut inner = Writer { bytes: Vec::new() };
+        content(&mut inner);
+        self.bytes.extend(id);
+        self.i32(inner.bytes.len() as i32);
+        self.i32(0);
+        self.bytes.extend(inner.bytes);
+    }
+}
+
+impl VoxFile {
+    /// Every .vox file in `dir` as a template named after the file, with colors mapped to
+    /// `materials`, sorted by name. A missing directory has none.
+    pub fn load_templates(
+        dir: &Path,
+        materials: &[VoxelMaterial],
+    ) -> Result<Vec<(String, Template)>, Box<dyn std::error::Error>> {
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("vox"))
+        });
+        paths.sort();
+
+        paths
+            .iter()
+            .map(|path| {
+                let name = path
+                    .file_stem()
+                    .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+                Ok((name, Self::load(path)?.to_template(materials)))
+            })
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = Reader { bytes };
+        if reader.take(4)? != b"VOX " {
+            return Err("not a .vox file".into());
+        }
+        reader.i32()?;
+
+        if reader.take(4)? != b"MAIN" {
+            return Err("missing MAIN chunk".into());
+        }
+        let content = reader.count()?;
+        reader.i32()?;
+        reader.take(content)?;
+
+        let mut models = Vec::new();
+        let mut size = None;
+        let mut nodes = HashMap::new();
+        let mut palette = DEFAULT_PALETTE.map(u32::to_le_bytes);
+
+        // Every other chunk is a child of MAIN, one after another
+        while !reader.bytes.is_empty() {
+            let id: [u8; 4] = reader.take(4)?.try_into()?;
+            let content_length = reader.count()?;
+            let children_length = reader.count()?;
+            let mut content = Reader {
+                bytes: reader.take(content_length)?,
+            };
+            reader.take(children_length)?;
+
+            match &id {
+                b"SIZE" => {
+                    let [x, y, z] = [content.count()?, content.count()?, content.count()?];
+                    size = Some([u32::try_from(x)?, u32::try_from(y)?, u32::try_from(z)?]);
+                }
+                b"XYZI" => {
+                    let count = content.count()?;
+                    let length = count.checked_mul(4).ok_or("XYZI voxel count overflows")?;
+                    let voxels = content
+                        .take(length)?
+                        .chunks_exact(4)
+                        .map(|v| [v[0], v[1], v[2], v[3]])
+                        .collect();
+                    models.push(VoxModel {
+                        size: size.take().ok_or("XYZI chunk without SIZE")?,
+                        voxels,
+                    });
+                }
+                b"RGBA" => {
+                    // Entry i holds color index i + 1
+                    for i in 0..255 {
+                        palette[i + 1] = content.take(4)?.try_into()?;
+                    }
+                }
+                b"nTRN" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    let child = content.i32()?;
+                    content.i32()?;
+                    content.i32()?;
+                    let frames = content.i32()?;
+
+                    let (mut translation, mut rotation) = ([0; 3], IDENTITY);
+                    if frames > 0 {
+                        let frame = content.dict()?;
+                        if let Some(t) = frame.get("_t") {
+                            let values: Vec<i32> = t
+                                .split_whitespace()
+                                .map(str::parse)
+                                .collect::<Result<_, _>>()?;
+                            translation = values
+                                .try_into()
+                                .map_err(|_| format!("invalid translation {t:?}"))?;
+                        }
+                        if let Some(r) = frame.get("_r") {
+                            rotation = r.parse()?;
+                            if !is_rotation(rotation) {
+                                return Err(format!("invalid rotation {r:?}").into());
+                            }
+                        }
+                    }
+                    nodes.insert(
+                        id,
+                        Node::Transform {
+                            child,
+                            translation,
+                            rotation,
+                        },
+                    );
+                }
+                b"nGRP" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    let children = (0..content.i32()?)
+                        .map(|_| content.i32())
+                        .collect::<Result<_, _>>()?;
+                    nodes.insert(id, Node::Group { children });
+                }
+                b"nSHP" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    // Later entries are animation frames
+                    content.i32()?;
+                    let model = content.count()?;
+                    nodes.insert(id, Node::Shape { model });
+                }
+                _ => {}
+            }
+        }
+
+        let mut instances = Vec::new();
+        if nodes.is_empty() {
+            // Files from before the scene graph stack every model at the origin
+            instances.extend((0..models.len()).map(|model| VoxInstance {
+                model,
+                translation: [0; 3],
+                rotation: IDENTITY,
+            }));
+        } else {
+            let identity = rotation_matrix(IDENTITY);
+            collect_instances(&nodes, 0, [0; 3], identity, &mut instances, 0)?;
+        }
+
+        if let Some(instance) = instances.iter().find(|i| i.model >= models.len()) {
+            return Err(format!("shape refers to missing model {}", instance.model).into());
+        }
+
+        Ok(Self {
+            models,
+            instances,
+            palette,
+        })
+    }
+
+    /// Writes every model with a flat scene graph of one transform per instance.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut children = Writer { bytes: Vec::new() };
+
+        for model in &self.models {
+            children.chunk(b"SIZE", |w| {
+                for n in model.size {
+                    w.i32(n as i32);
+                }
+            });
+            children.chunk(b"XYZI", |w| {
+                w.i32(model.voxels.len() as i32);
+                for voxel in &model.voxels {
+                    w.bytes.extend(voxel);
+                }
+            });
+        }
+
+        // Root transform, a group, then a transform and shape node per instance
+        children.chunk(b"nTRN", |w| {
+            w.i32(0);
+            w.dict(&[]);
+            w.i32(1);
+            w.i32(-1);
+            w.i32(-1);
+            w.i32(1);
+            w.dict(&[]);
+        });
+        children.chunk(b"nGRP", |w| {
+            w.i32(1);
+            w.dict(&[]);
+            w.i32(self.instances.len() as i32);
+            for i in 0..self.instances.len() {
+                w.i32(2 + 2 * i as i32);
+            }
+        });
+        for (i, instance) in self.instances.iter().enumerate() {
+            let id = 2 + 2 * i as i32;
+            let [x, y, z] = instance.translation;
+            children.chunk(b"nTRN", |w| {
+                w.i32(id);
+                w.dict(&[]);
+                w.i32(id + 1);
+                w.i32(-1);
+                w.i32(0);
+                w.i32(1);
+                let mut frame = vec![("_t", format!("{x} {y} {z}"))];
+                if instance.rotation != IDENTITY {
+                    frame.push(("_r", instance.rotation.to_string()));
+                }
+                w.dict(&frame);
+            });
+            children.chunk(b"nSHP", |w| {
+                w.i32(id + 1);
+                w.dict(&[]);
+                w.i32(1);
+                w.i32(instance.model as i32);
+                w.dict(&[]);
+            });
+        }
+
+        children.chunk(b"RGBA", |w| {
+            for color in &self.palette[1..] {
+                w.bytes.extend(color);
+            }
+            w.bytes.extend([0; 4]);
+        });
+
+        let mut file = Writer {
+            bytes: b"VOX ".to_vec(),
+        };
+        file.i32(VERSION);
+        file.bytes.extend(b"MAIN");
+        file.i32(0);
+        file.i32(children.bytes.len() as i32);
+        file.bytes.extend(children.bytes);
+        file.bytes
+    }
+
+    /// Material id for every color index, the material whose albedo is closest to the color.
+    pub fn material_map(&self, materials: &[VoxelMaterial]) -> [u32; 256] {
+        std::array::from_fn(|index| {
+            let [r, g, b, _] = self.palette[index];
+            let color = [r, g, b].map(srgb_to_linear);
+            materials
+                .iter()
+                .filter(|material| material.id != AIR)
+                .min_by(|a, b| {
+                    let distance = |m: &VoxelMaterial| -> f32 {
+                        (0..3).map(|c| (m.albedo[c] - color[c]).powi(2)).sum()
+                    };
+                    distance(a).total_cmp(&distance(b))
+                })
+                .map_or(AIR, |material| material.id)
+        })
+    }
+
+    /// Every instance's voxels as material ids in the renderer's y-up coordinates, ready to
+    /// stamp into a chunk. MagicaVoxel's y axis points north, so it becomes our -z.
+    pub fn to_template(&self, materials: &[VoxelMaterial]) -> Template {
+        let map = self.material_map(materials);
+        let mut voxels = Vec::new();
+
+        for instance in &self.instances {
+            let model = &self.models[instance.model];
+            let matrix = rotation_matrix(instance.rotation);
+            let half = model.size.map(|n| (n / 2) as i32);
+
+            for &[x, y, z, index] in &model.voxels {
+                let local = [x as i32 - half[0], y as i32 - half[1], z as i32 - half[2]];
+                let rotated = transform(matrix, local);
+                let [wx, wy, wz] = std::array::from_fn(|i| rotated[i] + instance.translation[i]);
+                voxels.push(([wx, wz, -wy], map[index as usize]));
+            }
+        }
+
+        Template { voxels }
+    }
+
+    /// Palette with one entry per material, and the color index each material id maps to.
+    pub fn palette_for(materials: &[VoxelMaterial]) -> ([[u8; 4]; 256], HashMap<u32, u8>) {
+        let mut palette = [[0, 0, 0, 255]; 256];
+        // Index 0 is empty space, and files can't store it
+        palette[0] = DEFAULT_PALETTE[0].to_le_bytes();
+        let mut indices = HashMap::new();
+
+        for (slot, material) in materials
+            .iter()
+            .filter(|material| material.id != AIR)
+            .take(255)
+            .enumerate()
+        {
+            let [r, g, b] = material.albedo.map(linear_to_srgb);
+            palette[slot + 1] = [r, g, b, 255];
+            indices.insert(material.id, slot as u8 + 1);
+        }
+
+        (palette, indices)
+    }
+
+    /// Exports the voxels of `storage` between `min` and `max` (exclusive) as a single model.
+    /// Regions larger than MagicaVoxel's 256 voxel limit should be split by the caller and
+    /// added with `add_region` at their offsets.
+    pub fn from_region(
+        storage: &ChunkStorage,
+        min: [usize; 3],
+        max: [usize; 3],
+        materials: &[VoxelMaterial],
+    ) -> Self {
+        let (palette, _) = Self::palette_for(materials);
+        let mut file = Self {
+            models: Vec::new(),
+            instances: Vec::new(),
+            palette,
+        };
+        file.add_region(storage, min, max, materials, [0; 3]);
+        file
+    }
+
+    /// Adds a region of `storage` as another model whose minimum corner sits at `offset`
+    /// voxels in the renderer's coordinates.
+    pub fn add_region(
+        &mut self,
+        storage: &ChunkStorage,
+        min: [usize; 3],
+        max: [usize; 3],
+        materials: &[VoxelMaterial],
+        offset: [i32; 3],
+    ) {
+        let (_, indices) = Self::palette_for(materials);
+        let [sx, sy, sz] = std::array::from_fn(|i| max[i].saturating_sub(min[i]).min(256));
+
+        // Our x, y, z become MagicaVoxel's x, -y, z so the export stays right-handed
+        let mut voxels = Vec::new();
+        for x in 0..sx {
+            for y in 0..sy {
+                for z in 0..sz {
+                    let material = storage.get([min[0] + x, min[1] + y, min[2] + z]);
+                    if let Some(&index) = indices.get(&material) {
+                        voxels.push([x as u8, (sz - 1 - z) as u8, y as u8, index]);
+                    }
+                }
+            }
+        }
+
+        let size = [sx as u32, sz as u32, sy as u32];
+        let half = size.map(|n| (n / 2) as i32);
+        self.instances.push(VoxInstance {
+            model: self.models.len(),
+            translation: [
+                offset[0] + half[0],
+                -offset[2] - (sz as i32 - 1) + half[1],
+                offset[1] + half[2],
+            ],
+            rotation: IDENTITY,
+        });
+        self.models.push(VoxModel { size, voxels });
+    }
+}
+
+fn collect_instances(
+    nodes: &HashMap<i32, Node>,
+    id: i32,
+    translation: [i32; 3],
+    rotation: [[i32; 3]; 3],
+    instances: &mut Vec<VoxInstance>,
+    depth: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if depth > 64 {
+        return Err("scene graph is cyclic or too deep".into());
+    }
+
+    match nodes.get(&id).ok_or(format!("missing scene node {id}"))? {
+        Node::Transform {
+            child,
+            translation: local_translation,
+            rotation: local_rotation,
+        } => {
+            let moved = transform(rotation, *local_translation);
+            let translation = std::array::from_fn(|i| translation[i] + moved[i]);
+            let rotation = multiply(rotation, rotation_matrix(*local_rotation));
+            collect_instances(nodes, *child, translation, rotation, instances, depth + 1)
+        }
+        Node::Group { children } => children.iter().try_for_each(|&child| {
+            collect_instances(nodes, child, translation, rotation, instances, depth + 1)
+        }),
+        Node::Shape { model } => {
+            instances.push(VoxInstance {
+                model: *model,
+                translation,
+                rotation: rotation_byte(rotation),
+            });
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::voxelizer::{self, GRASS, HEIGHT, LENGTH, STONE, WIDTH, WOOD};
+
+    fn region() -> ChunkStorage {
+        let mut voxels = vec![AIR; LENGTH * HEIGHT * WIDTH];
+        let index = |x: usize, y: usize, z: usize| (x * HEIGHT + y) * WIDTH + z;
+        for x in 0..4 {
+            for z in 0..3 {
+                voxels[index(x, 0, z)] = STONE;
+                voxels[index(x, 1, z)] = GRASS;
+            }
+        }
+        voxels[index(1, 2, 2)] = WOOD;
+        ChunkStorage::encode([LENGTH, HEIGHT, WIDTH], &voxels)
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let materials = voxelizer::default_materials();
+        let mut file = VoxFile::from_region(&region(), [0; 3], [4, 3, 3], &materials);
+        file.add_region(&region(), [0; 3], [2, 2, 2], &materials, [10, 0, -5]);
+        file.instances[1].rotation = 0b0010001;
+
+        let read = VoxFile::from_bytes(&file.to_bytes()).unwrap();
+        assert_eq!(read, file);
+    }
+
+    #[test]
+    fn files_without_rgba_use_the_default_palette() {
+        let mut file = Writer {
+            bytes: b"VOX ".to_vec(),
+        };
+        file.i32(VERSION);
+        let mut children = Writer { bytes: Vec::new() };
+        children.chunk(b"SIZE", |w| [1, 1, 1].into_iter().for_each(|n| w.i32(n)));
+        children.chunk(b"XYZI", |w| {
+            w.i32(1);
+            w.bytes.extend([0, 0, 0, 1]);
+        });
+        file.bytes.extend(b"MAIN");
+        file.i32(0);
+        file.i32(children.bytes.len() as i32);
+        file.bytes.extend(children.bytes);
+
+        let palette = VoxFile::from_bytes(&file.bytes).unwrap().palette;
+        assert_eq!(palette[1], [255, 255, 255, 255]);
+        assert_eq!(palette[6], [255, 255, 0, 255]);
+        assert_eq!(palette[36], [255, 0, 0, 255]);
+        assert_eq!(palette[215], [0, 0, 0x33, 255]);
+        assert_eq!(palette[216], [0xee, 0, 0, 255]);
+        assert_eq!(palette[255], [0x11, 0x11, 0x11, 255]);
+    }
+
+    #[test]
+    fn regions_import_where_they_were_exported() {
+        let materials = voxelizer::default_materials();
+        let storage = region();
+        let file = VoxFile::from_region(&storage, [0; 3], [4, 3, 3], &materials);
+        let template = VoxFile::from_bytes(&file.to_bytes())
+            .unwrap()
+            .to_template(&materials);
+
+        assert_eq!(template.voxels.len(), 4 * 3 * 2 + 1);
+        for ([x, y, z], material) in template.voxels {
+            assert_eq!(storage.get([x as usize, y as usize, z as usize]), material);
+        }
+    }
+
+    #[test]
+    fn templates_load_from_a_directory() {
+        let materials = voxelizer::default_materials();
+        let dir = std::env::temp_dir().join("vox_templates_load_from_a_directory");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = VoxFile::from_region(&region(), [0; 3], [4, 3, 3], &materials);
+        file.save(&dir.join("b_hut.vox")).unwrap();
+        file.save(&dir.join("a_wall.VOX")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a model").unwrap();
+
+        let templates = VoxFile::load_templates(&dir, &materials).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = templates.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a_wall", "b_hut"]);
+        assert_eq!(templates[1].1, file.to_template(&materials));
+        assert!(VoxFile::load_templates(&dir, &materials)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn nested_transforms_combine() {
+        let mut nodes = HashMap::new();
+        nodes.insert(
+            0,
+            Node::Transform {
+                child: 1,
+                translation: [10, 0, 0],
+                // 90 degrees about z: x becomes y
+                rotation: 0b0010001,
+            },
+        );
+        nodes.insert(
+            1,
+            Node::Transform {
+                child: 2,
+                translation: [5, 0, 0],
+                rotation: IDENTITY,
+            },
+        );
+        nodes.insert(2, Node::Shape { model: 0 });
+
+        let mut instances = Vec::new();
+        collect_instances(
+            &nodes,
+            0,
+            [0; 3],
+            rotation_matrix(IDENTITY),
+            &mut instances,
+            0,
+        )
+        .unwrap();
+        assert_eq!(instances[0].translation, [10, 5, 0]);
+        assert_eq!(instances[0].rotation, 0b0010001);
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let file = |children: &dyn Fn(&mut Writer)| {
+            let mut inner = Writer { bytes: Vec::new() };
+            children(&mut inner);
+            let mut file = Writer {
+                bytes: b"VOX ".to_vec(),
+            };
+            file.i32(VERSION);
+            file.bytes.extend(b"MAIN");
+            file.i32(0);
+            file.i32(inner.bytes.len() as i32);
+            file.bytes.extend(inner.bytes);
+            VoxFile::from_bytes(&file.bytes)
+        };
+        let sized = |[x, y, z]: [i32; 3]| {
+            move |w: &mut Writer| {
+                w.chunk(b"SIZE", |w| {
+                    w.i32(x);
+                    w.i32(y);
+                    w.i32(z);
+                })
+            }
+        };
+        let size = sized([2, 2, 2]);
+        let xyzi = |count: i32| {
+            move |w: &mut Writer| {
+                size(w);
+                w.chunk(b"XYZI", |w| {
+                    w.i32(count);
+                    w.bytes.extend([0, 0, 0, 1]);
+                });
+            }
+        };
+
+        assert!(file(&xyzi(1)).is_ok());
+        assert!(file(&xyzi(-1)).is_err());
+        assert!(file(&xyzi(2)).is_err());
+        assert!(file(&xyzi(i32::MAX)).is_err());
+        assert!(file(&sized([2, -2, 2])).is_err());
+
+        // A chunk whose lengths point outside the file
+        let negative_length = |w: &mut Writer| {
+            w.bytes.extend(b"XYZI");
+            w.i32(-4);
+            w.i32(0);
+        };
+        assert!(file(&negative_length).is_err());
+
+        // Negative model indices and rotations with a repeated column
+        let shape = |model: i32, rotation: &'static str| {
+            move |w: &mut Writer| {
+                xyzi(1)(w);
+                w.chunk(b"nTRN", |w| {
+                    w.i32(0);
+                    w.dict(&[]);
+                    w.i32(1);
+                    w.i32(-1);
+                    w.i32(0);
+                    w.i32(1);
+                    w.dict(&[("_r", rotation.to_string())]);
+                });
+                w.chunk(b"nSHP", |w| {
+                    w.i32(1);
+                    w.dict(&[]);
+                    w.i32(1);
+                    w.i32(model);
+                    w.dict(&[]);
+                });
+            }
+        };
+        assert!(file(&shape(0, "4")).is_ok());
+        assert!(file(&shape(-1, "4")).is_err());
+        assert!(file(&shape(0, "0")).is_err());
+        assert!(file(&shape(0, "15")).is_err());
+
+        let bytes = VoxFile::from_region(
+            &region(),
+            [0; 3],
+            [4, 3, 3],
+            &voxelizer::default_materials(),
+        )
+        .to_bytes();
+        // Cuts between chunks leave a valid file, anywhere else is an error, never a panic
+        let cut = (0..bytes.len())
+            .filter(|&length| VoxFile::from_bytes(&bytes[..length]).is_err())
+            .count();
+        assert!(cut > bytes.len() / 2);
+    }
+}
@@ -1,36 +1,192 @@
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use cgmath::Vector3;
+
+use crate::geodesy::LocalFrame;
+use crate::world_pos::WorldPos;
+
+use super::density::{Density, DensityOptions};
+use super::landcover::{LandCover, LandCoverTable};
+use super::palette::ChunkStorage;
+use super::processor::{GeoBounds, Heightmap};
+use super::rules::{AttributeMap, ColumnMaterials, RuleSet};
+use super::water::{WaterKind, WaterMap};
 
 // negative for west and south
-#[derive(Debug)]
-struct GeoCoord {
-    lat: f32,
-    lon: f32,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoCoord {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+// Coordinates are never NaN, so bitwise equality is a total equivalence
+impl Eq for GeoCoord {}
+
+impl Hash for GeoCoord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.lat.to_bits().hash(state);
+        self.lon.to_bits().hash(state);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelMaterial {
+    pub id: u32,
+    pub name: String,
+    pub albedo: [f32; 3],
 }
 
-#[derive(Debug, Clone)]
-struct VoxelMaterial {
-    id: u32,
-    albedo: [f32; 3],
+/// How far a column's top terrain voxel is filled, so slopes can be traced smoother than the
+/// voxel grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SurfaceHeight {
+    /// Layer of the partly filled voxel.
+    pub layer: u16,
+    /// Filled part of the voxel from its bottom, in 65535ths. Never zero.
+    pub fill: u16,
 }
 
-#[derive(Debug, Clone)]
-struct Voxel {
-    height: u32,
-    material: VoxelMaterial,
+impl SurfaceHeight {
+    /// Thinnest fill, so a surface voxel never collapses to nothing.
+    const MIN_FILL: u16 = 65535 / 16;
+
+    pub fn fraction(&self) -> f32 {
+        self.fill as f32 / 65535.0
+    }
+
+    /// Layer in the low and fill in the high 16 bits, zero for a full voxel, as the ray
+    /// tracer reads it.
+    pub fn pack(surface: Option<Self>) -> u32 {
+        surface.map_or(0, |surface| {
+            (surface.fill as u32) << 16 | surface.layer as u32
+        })
+    }
+
+    pub fn unpack(bits: u32) -> Option<Self> {
+        (bits >> 16 != 0).then_some(Self {
+            layer: bits as u16,
+            fill: (bits >> 16) as u16,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelChunk {
+    /// North-west corner of the chunk.
+    pub coord: GeoCoord,
+    /// Height in meters of the bottom voxel layer in the vertical datum of the heightmap it was
+    /// built from, usually the EGM96 geoid rather than the ellipsoid.
+    pub base_height: f64,
+    pub voxels: ChunkStorage,
+    /// Fill of each column's surface voxel, at `x * size[2] + z`. Empty when the chunk only
+    /// has full voxels, `None` for columns whose top isn't DEM terrain.
+    pub surface: Vec<Option<SurfaceHeight>>,
+    /// Meters per voxel along x, y and z: the column spacing east and south and the height of
+    /// a layer.
+    pub voxel_size: [f32; 3],
+}
+
+/// Where a chunk's voxels lie on the globe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkPlacement {
+    pub frame: LocalFrame,
+    pub voxel_size: [f32; 3],
+}
+
+impl ChunkPlacement {
+    /// ECEF position of a point in voxels from the chunk's corner, `[0.5; 3]` being the
+    /// center of voxel `[0][0][0]`.
+    pub fn to_ecef(&self, voxel: [f64; 3]) -> Vector3<f64> {
+        let [x, y, z] = std::array::from_fn(|i| voxel[i] * self.voxel_size[i] as f64);
+        self.frame.to_ecef(Vector3::new(x, y, z))
+    }
+}
+
+impl VoxelChunk {
+    pub fn new(
+        coord: GeoCoord,
+        base_height: f64,
+        volume: &[[[u32; WIDTH]; HEIGHT]; LENGTH],
+    ) -> Self {
+        let flat: Vec<u32> = volume.iter().flatten().flatten().copied().collect();
+        Self {
+            coord,
+            base_height,
+            voxels: ChunkStorage::encode([LENGTH, HEIGHT, WIDTH], &flat),
+            surface: Vec::new(),
+            voxel_size: [1.0; 3],
+        }
+    }
+
+    pub fn with_surface(mut self, surface: Vec<Option<SurfaceHeight>>) -> Self {
+        self.surface = surface;
+        self
+    }
+
+    pub fn with_voxel_size(mut self, voxel_size: [f32; 3]) -> Self {
+        self.voxel_size = voxel_size;
+        self
+    }
+
+    /// Packed surface heights of every column, zeros if the chunk has none.
+    pub fn surface_words(&self) -> Vec<u32> {
+        let [length, _, width] = self.voxels.size();
+        if self.surface.is_empty() {
+            return vec![0; length * width];
+        }
+        self.surface
+            .iter()
+            .copied()
+            .map(SurfaceHeight::pack)
+            .collect()
+    }
+
+    /// The chunk's own tangent frame, with voxel `[x][y][z]` spanning `(x, y, z)` to
+    /// `(x + 1, y + 1, z + 1)` times `voxel_size` along its east, up and south axes.
+    /// `base_height` is taken as ellipsoidal, so for a geoid datum the frame sits off by the
+    /// undulation, tens of meters at most.
+    pub fn frame(&self) -> LocalFrame {
+        LocalFrame::new(self.coord.lat, self.coord.lon, self.base_height)
+    }
+
+    pub fn placement(&self) -> ChunkPlacement {
+        ChunkPlacement {
+            frame: self.frame(),
+            voxel_size: self.voxel_size,
+        }
+    }
+
+    /// Split ECEF position of voxel `[0][0][0]`, for camera-relative rendering.
+    pub fn origin(&self) -> WorldPos {
+        WorldPos::from_f64(self.frame().origin)
+    }
 }
 
-#[derive(Debug)]
-struct VoxelChunk {
-    coord: GeoCoord,
-    voxels: Vec<Vec<Vec<u32>>>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxelWorld {
+    pub bounds: GeoBounds,
+    pub materials: Vec<VoxelMaterial>,
+    pub voxel_length: f32,
+    /// Voxels per chunk along x, y and z.
+    pub chunk_size: [u32; 3],
+    pub chunks: HashMap<GeoCoord, VoxelChunk>,
 }
 
-#[derive(Debug)]
-struct VoxelWorld {
-    materials: Vec<VoxelMaterial>,
-    voxel_length: f32,
-    chunk_size: Vec<u32>,
-    chunks: HashMap<GeoCoord, VoxelChunk>,
+impl VoxelWorld {
+    pub fn new(bounds: GeoBounds, voxel_length: f32) -> Self {
+        Self {
+            bounds,
+            materials: default_materials(),
+            voxel_length,
+            chunk_size: [LENGTH as u32, HEIGHT as u32, WIDTH as u32],
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, chunk: VoxelChunk) {
+        self.chunks.insert(chunk.coord, chunk);
+    }
 }
 
 // const AIR: VoxelMaterial = VoxelMaterial {
@@ -112,15 +268,80 @@ struct VoxelWorld {
 //     voxels
 // }
 
-const LENGTH: usize = 32;
-const HEIGHT: usize = 32;
-const WIDTH: usize = 32;
+pub(crate) const LENGTH: usize = 32;
+pub(crate) const HEIGHT: usize = 32;
+pub(crate) const WIDTH: usize = 32;
+
+pub const AIR: u32 = 0;
+pub const WATER: u32 = 1;
+pub const GRASS: u32 = 2;
+pub const DIRT: u32 = 3;
+pub const STONE: u32 = 4;
+pub const SAND: u32 = 5;
+pub const SNOW: u32 = 6;
+pub const ICE: u32 = 7;
+pub const CONCRETE: u32 = 8;
+pub const MUD: u32 = 9;
+pub const FOREST_FLOOR: u32 = 10;
+pub const FARMLAND: u32 = 11;
+pub const SHRUB: u32 = 12;
+pub const WOOD: u32 = 13;
+pub const LEAVES: u32 = 14;
+pub const ASPHALT: u32 = 15;
+pub const BRICK: u32 = 16;
 
-const AIR: u32 = 0;
-const WATER: u32 = 1;
-const GRASS: u32 = 2;
-const DIRT: u32 = 3;
-const STONE: u32 = 4;
+/// Material table matching the colors in raygen.wgsl.
+pub fn default_materials() -> Vec<VoxelMaterial> {
+    [
+        (AIR, "air", [0.0, 0.0, 0.0]),
+        (WATER, "water", [0.02, 0.03, 0.1]),
+        (GRASS, "grass", [0.1, 0.3, 0.05]),
+        (DIRT, "dirt", [0.25, 0.15, 0.08]),
+        (STONE, "stone", [0.3, 0.3, 0.32]),
+        (SAND, "sand", [0.76, 0.7, 0.5]),
+        (SNOW, "snow", [0.9, 0.92, 0.95]),
+        (ICE, "ice", [0.6, 0.75, 0.85]),
+        (CONCRETE, "concrete", [0.45, 0.45, 0.45]),
+        (MUD, "mud", [0.2, 0.15, 0.1]),
+        (FOREST_FLOOR, "forest_floor", [0.05, 0.18, 0.04]),
+        (FARMLAND, "farmland", [0.35, 0.3, 0.12]),
+        (SHRUB, "shrub", [0.2, 0.25, 0.1]),
+        (WOOD, "wood", [0.22, 0.14, 0.07]),
+        (LEAVES, "leaves", [0.08, 0.22, 0.06]),
+        (ASPHALT, "asphalt", [0.06, 0.06, 0.07]),
+        (BRICK, "brick", [0.4, 0.18, 0.12]),
+    ]
+    .into_iter()
+    .map(|(id, name, albedo)| VoxelMaterial {
+        id,
+        name: name.to_string(),
+        albedo,
+    })
+    .collect()
+}
+
+pub fn material_by_name(name: &str) -> Option<u32> {
+    match name {
+        "air" => Some(AIR),
+        "water" => Some(WATER),
+        "grass" => Some(GRASS),
+        "dirt" => Some(DIRT),
+        "stone" => Some(STONE),
+        "sand" => Some(SAND),
+        "snow" => Some(SNOW),
+        "ice" => Some(ICE),
+        "concrete" => Some(CONCRETE),
+        "mud" => Some(MUD),
+        "forest_floor" => Some(FOREST_FLOOR),
+        "farmland" => Some(FARMLAND),
+        "shrub" => Some(SHRUB),
+        "wood" => Some(WOOD),
+        "leaves" => Some(LEAVES),
+        "asphalt" => Some(ASPHALT),
+        "brick" => Some(BRICK),
+        _ => None,
+    }
+}
 
 pub fn generate_volume() -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
     // Changed array dimension order
@@ -161,3 +382,331 @@ pub fn generate_volume() -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
 
     volume
 }
+
+/// Optional inputs that decide which materials a column is built from. Rules take precedence
+/// over land cover, which takes precedence over the grass/dirt/stone default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaterialSources<'a> {
+    pub land_cover: Option<(&'a LandCover, &'a LandCoverTable)>,
+    pub rules: Option<(&'a RuleSet, &'a AttributeMap)>,
+}
+
+impl MaterialSources<'_> {
+    fn column(&self, heightmap: &Heightmap, u: f32, v: f32) -> ColumnMaterials {
+        let (lat, lon) = heightmap.bounds.from_uv(u as f64, v as f64);
+        let class = self
+            .land_cover
+            .and_then(|(land_cover, _)| land_cover.class_at(lat, lon));
+
+        let mut materials = self
+            .rules
+            .map(|(rules, attributes)| rules.evaluate(&attributes.sample(u, v), class))
+            .unwrap_or_default();
+
+        if materials.surface.is_none() {
+            materials.surface = self
+                .land_cover
+                .zip(class)
+                .and_then(|((_, table), class)| table.surface_material(class));
+        }
+
+        materials
+    }
+}
+
+/// Part of a heightmap voxelized into one chunk. Each chunk of a region has its own elevation
+/// range, so its layers are as thin as its terrain allows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkRegion {
+    /// Heightmap `u` of the first and last voxel columns along x.
+    pub u: [f32; 2],
+    /// Heightmap `v` of the first and last voxel columns along z.
+    pub v: [f32; 2],
+    /// Elevations of the bottom and top voxel layers.
+    pub min_height: f32,
+    pub max_height: f32,
+}
+
+impl ChunkRegion {
+    /// The whole heightmap in one chunk, its elevation range scaled to the chunk height.
+    pub fn whole(heightmap: &Heightmap) -> Self {
+        let (min_height, max_height) = heightmap.min_max();
+        Self {
+            u: [0.0, 1.0],
+            v: [0.0, 1.0],
+            min_height,
+            max_height,
+        }
+    }
+
+    /// Heightmap `(u, v)` of column `[x, z]`.
+    pub fn uv(&self, x: usize, z: usize) -> (f32, f32) {
+        (
+            self.u[0] + (self.u[1] - self.u[0]) * x as f32 / (LENGTH - 1) as f32,
+            self.v[0] + (self.v[1] - self.v[0]) * z as f32 / (WIDTH - 1) as f32,
+        )
+    }
+
+    /// Inverse of the column layout: fractional `[x, z]` of heightmap `(u, v)`, where column
+    /// `x` covers `x - 0.5..x + 0.5`.
+    pub fn to_voxel(&self, u: f32, v: f32) -> [f32; 2] {
+        [
+            (u - self.u[0]) / (self.u[1] - self.u[0]) * (LENGTH - 1) as f32,
+            (v - self.v[0]) / (self.v[1] - self.v[0]) * (WIDTH - 1) as f32,
+        ]
+    }
+
+    /// Meters per voxel along x, y and z. Columns stand on their heightmap samples, so the
+    /// column spacing follows the heightmap's cell size, and layer `y` spans heights `y..y + 1`
+    /// of the elevation range.
+    pub fn voxel_size(&self, heightmap: &Heightmap) -> [f32; 3] {
+        let (dx, dz) = heightmap.cell_size();
+        let height_range = (self.max_height - self.min_height).max(f32::EPSILON);
+        [
+            dx * (heightmap.width - 1) as f32 * (self.u[1] - self.u[0]) / (LENGTH - 1) as f32,
+            height_range / (HEIGHT - 1) as f32,
+            dz * (heightmap.height - 1) as f32 * (self.v[1] - self.v[0]) / (WIDTH - 1) as f32,
+        ]
+    }
+
+    /// Heightmap `(u, v)` of the chunk's north-west corner, half a column outside its first
+    /// sample.
+    pub fn corner_uv(&self) -> (f32, f32) {
+        (
+            self.u[0] - (self.u[1] - self.u[0]) / (LENGTH - 1) as f32 / 2.0,
+            self.v[0] - (self.v[1] - self.v[0]) / (WIDTH - 1) as f32 / 2.0,
+        )
+    }
+
+    /// Voxel layer an elevation falls in, clamped to the chunk.
+    pub fn layer(&self, elevation: f32) -> usize {
+        self.height(elevation).clamp(0.0, (HEIGHT - 1) as f32) as usize
+    }
+
+    /// Elevation in voxels above the bottom of the chunk, unclamped.
+    fn height(&self, elevation: f32) -> f32 {
+        let height_range = (self.max_height - self.min_height).max(f32::EPSILON);
+        (elevation - self.min_height) / height_range * (HEIGHT - 1) as f32
+    }
+}
+
+/// Voxelizes a heightmap into the render volume, scaling its elevation range to the volume
+/// height. Water fills each wet column up to its surface level, rivers are carved one voxel
+/// into the terrain. With `density` set, caves are carved below the surface and overhangs
+/// grown out of cliffs on dry land.
+pub fn heightmap_to_volume(
+    heightmap: &Heightmap,
+    sources: &MaterialSources,
+    water: Option<&WaterMap>,
+    density: Option<&DensityOptions>,
+) -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
+    voxelize_region(
+        heightmap,
+        sources,
+        water,
+        density,
+        &ChunkRegion::whole(heightmap),
+    )
+}
+
+/// Voxelizes the part of a heightmap covered by `region`, like `heightmap_to_volume`. Only
+/// reads its inputs, so chunks of a region can be voxelized on any number of threads.
+pub fn voxelize_region(
+    heightmap: &Heightmap,
+    sources: &MaterialSources,
+    water: Option<&WaterMap>,
+    density: Option<&DensityOptions>,
+    region: &ChunkRegion,
+) -> [[[u32; WIDTH]; HEIGHT]; LENGTH] {
+    let mut volume = [[[AIR; WIDTH]; HEIGHT]; LENGTH];
+
+    let height_range = (region.max_height - region.min_height).max(f32::EPSILON);
+
+    let surface_heights: [[usize; WIDTH]; LENGTH] = std::array::from_fn(|x| {
+        std::array::from_fn(|z| {
+            let (u, v) = region.uv(x, z);
+            region.layer(heightmap.sample(u, v))
+        })
+    });
+
+    for x in 0..LENGTH {
+        for z in 0..WIDTH {
+            let (u, v) = region.uv(x, z);
+            let mut column_height = surface_heights[x][z];
+            let (water_kind, water_level) = water
+                .map(|water| water.sample(u, v))
+                .unwrap_or((WaterKind::Dry, 0.0));
+            let water_top = match water_kind {
+                WaterKind::Dry => None,
+                WaterKind::River => {
+                    let surface = column_height;
+                    column_height = column_height.saturating_sub(1);
+                    Some(surface)
+                }
+                WaterKind::Ocean | WaterKind::Lake => Some(region.layer(water_level)),
+            };
+
+            let materials = sources.column(heightmap, u, v);
+            let submerged = matches!(water_kind, WaterKind::Ocean | WaterKind::Lake)
+                && water_top.is_some_and(|top| top > column_height);
+            // Seafloor and lake beds default to sand, water is never a solid surface
+            let surface = match materials.surface {
+                Some(WATER) | None if submerged => SAND,
+                Some(WATER) | None => GRASS,
+                Some(surface) => surface,
+            };
+            let (subsurface, depth) = materials.subsurface.unwrap_or((DIRT, 3));
+
+            let column = &mut volume[x];
+            for (y, layer) in column[..=column_height].iter_mut().enumerate() {
+                layer[z] = if y == column_height {
+                    surface
+                } else if y + depth > column_height {
+                    subsurface
+                } else {
+                    STONE
+                };
+            }
+
+            if let Some(water_top) = water_top {
+                // A water level below the top of the column leaves it dry
+                for layer in column
+                    .iter_mut()
+                    .take(water_top + 1)
+                    .skip(column_height + 1)
+                {
+                    layer[z] = WATER;
+                }
+            }
+        }
+    }
+
+    if let Some(options) = density {
+        apply_density(
+            &mut volume,
+            heightmap,
+            region,
+            &surface_heights,
+            height_range,
+            &Density::new(options),
+        );
+    }
+
+    volume
+}
+
+/// Fractional fill of each column's surface voxel from the DEM elevation, for columns whose
+/// top voxel is still the terrain surface `voxelize_region` put there, with air above it.
+/// Water, carved riverbeds and whatever was stamped on top keep full voxels.
+pub fn surface_heights(
+    volume: &[[[u32; WIDTH]; HEIGHT]; LENGTH],
+    heightmap: &Heightmap,
+    region: &ChunkRegion,
+) -> Vec<Option<SurfaceHeight>> {
+    let mut surface = Vec::with_capacity(LENGTH * WIDTH);
+    for (x, column) in volume.iter().enumerate() {
+        for z in 0..WIDTH {
+            let (u, v) = region.uv(x, z);
+            let height = region.height(heightmap.sample(u, v));
+            let layer = height.floor() as usize;
+
+            let exposed = layer < HEIGHT
+                && !matches!(column[layer][z], AIR | WATER)
+                && column.get(layer + 1).is_none_or(|above| above[z] == AIR);
+            surface.push((height >= 0.0 && exposed).then(|| SurfaceHeight {
+                layer: layer as u16,
+                fill: ((height.fract() * 65535.0).round() as u16).max(SurfaceHeight::MIN_FILL),
+            }));
+        }
+    }
+    surface
+}
+
+// Carves caves below and grows overhangs above the heightfield. Runs after the columns are
+// built so it can tell the surface layers and water apart from plain stone
+fn apply_density(
+    volume: &mut [[[u32; WIDTH]; HEIGHT]; LENGTH],
+    heightmap: &Heightmap,
+    region: &ChunkRegion,
+    surface_heights: &[[usize; WIDTH]; LENGTH],
+    height_range: f32,
+    density: &Density,
+) {
+    // Meters per voxel along each axis, so the noise isn't stretched with the volume. Positions
+    // are measured from the heightmap's corner so neighbouring chunks share one noise field
+    let (dx, dz) = heightmap.cell_size();
+    let extent_x = dx * (heightmap.width - 1) as f32;
+    let extent_z = dz * (heightmap.height - 1) as f32;
+    let voxel_x = extent_x * (region.u[1] - region.u[0]) / (LENGTH - 1) as f32;
+    let voxel_y = height_range / (HEIGHT - 1) as f32;
+    let voxel_z = extent_z * (region.v[1] - region.v[0]) / (WIDTH - 1) as f32;
+    let (start_x, start_y, start_z) = (
+        extent_x * region.u[0],
+        region.min_height - heightmap.min_max().0,
+        extent_z * region.v[0],
+    );
+    let position = |x: usize, y: usize, z: usize| {
+        [
+            start_x + x as f32 * voxel_x,
+            start_y + y as f32 * voxel_y,
+            start_z + z as f32 * voxel_z,
+        ]
+    };
+
+    for x in 0..LENGTH {
+        for z in 0..WIDTH {
+            let surface = surface_heights[x][z];
+            for (y, layer) in volume[x][..surface].iter_mut().enumerate() {
+                let voxel = &mut layer[z];
+                let depth = (surface - y) as f32 * voxel_y;
+                let carvable = if density.options().keep_surface_intact {
+                    *voxel == STONE
+                } else {
+                    *voxel != AIR && *voxel != WATER
+                };
+
+                if carvable && density.is_cave(position(x, y, z), depth) {
+                    *voxel = AIR;
+                }
+            }
+        }
+    }
+
+    // Overhangs grow once the caves are carved, so the rock they hang from is final
+    for x in 0..LENGTH {
+        for z in 0..WIDTH {
+            // Only the foot of a cliff gets overhangs, from the tallest neighbouring column
+            let surface = surface_heights[x][z];
+            let cliff_top = (x.saturating_sub(1)..(x + 2).min(LENGTH))
+                .flat_map(|nx| (z.saturating_sub(1)..(z + 2).min(WIDTH)).map(move |nz| (nx, nz)))
+                .map(|(nx, nz)| surface_heights[nx][nz])
+                .max()
+                .unwrap_or(surface);
+            if cliff_top <= surface + 1 {
+                continue;
+            }
+
+            // Lips grow from the top down, each voxel must share a face with the rock above
+            // or beside it so nothing floats free of the cliff
+            for y in (surface + 1..cliff_top).rev() {
+                if volume[x][y][z] != AIR {
+                    continue;
+                }
+
+                let solid = |voxel: u32| voxel != AIR && voxel != WATER;
+                let attached = solid(volume[x][y + 1][z])
+                    || [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|&(dx, dz)| {
+                        let (nx, nz) = (x as isize + dx, z as isize + dz);
+                        (0..LENGTH as isize).contains(&nx)
+                            && (0..WIDTH as isize).contains(&nz)
+                            && solid(volume[nx as usize][y][nz as usize])
+                    });
+
+                let height = (y - surface) as f32 / (cliff_top - surface) as f32;
+                if attached && density.is_overhang(position(x, y, z), height) {
+                    volume[x][y][z] = STONE;
+                }
+            }
+        }
+    }
+}
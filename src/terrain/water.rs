@@ -0,0 +1,361 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+
+use super::landcover::LandCover;
+use super::processor::Heightmap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaterKind {
+    Dry,
+    Ocean,
+    Lake,
+    River,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WaterOptions {
+    /// Geodetic sea level in meters. Cells below it that connect to the map edge become ocean.
+    pub sea_level: f32,
+    /// Upstream area in square meters a cell needs to drain before it carries a river.
+    pub river_catchment: f32,
+}
+
+impl Default for WaterOptions {
+    fn default() -> Self {
+        Self {
+            sea_level: 0.0,
+            river_catchment: 5_000_000.0,
+        }
+    }
+}
+
+/// Water surface per heightmap cell, stored row-major like the heightmap.
+#[derive(Debug, Clone)]
+pub struct WaterMap {
+    pub width: usize,
+    pub height: usize,
+    pub kinds: Vec<WaterKind>,
+    /// Elevation of the water surface in meters, meaningless for dry cells.
+    pub levels: Vec<f32>,
+}
+
+// Raise each filled cell at least this much above the cell it drains into so flats still drain
+const FILL_EPSILON: f32 = 1e-3;
+
+const NEIGHBORS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+#[derive(PartialEq)]
+struct Elevation(f32);
+
+impl Eq for Elevation {}
+
+impl PartialOrd for Elevation {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Elevation {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl WaterMap {
+    /// Places ocean below the sea level, lakes from `lakes` (a row-major mask on the heightmap
+    /// grid), and rivers wherever the flow accumulation exceeds the river catchment.
+    pub fn compute(heightmap: &Heightmap, lakes: Option<&[bool]>, options: &WaterOptions) -> Self {
+        let (width, height) = (heightmap.width, heightmap.height);
+        let mut kinds = vec![WaterKind::Dry; width * height];
+        let mut levels = heightmap.elevations.clone();
+
+        // Ocean floods inwards from the edges so inland depressions stay dry
+        let ocean = flood_from_edges(width, height, |i| {
+            heightmap.elevations[i] < options.sea_level
+        });
+        for i in (0..width * height).filter(|&i| ocean[i]) {
+            kinds[i] = WaterKind::Ocean;
+            levels[i] = options.sea_level;
+        }
+
+        // Each connected lake is flat at its spill level, the lowest cell on its rim, so it fills
+        // the basin up to where it would overflow
+        if let Some(lakes) = lakes {
+            let mut visited = vec![false; width * height];
+            for start in 0..width * height {
+                if !lakes[start] || visited[start] || kinds[start] != WaterKind::Dry {
+                    continue;
+                }
+
+                let mut component = vec![start];
+                visited[start] = true;
+                let mut next = 0;
+                while next < component.len() {
+                    for n in neighbors(component[next], width, height) {
+                        if lakes[n] && !visited[n] && kinds[n] == WaterKind::Dry {
+                            visited[n] = true;
+                            component.push(n);
+                        }
+                    }
+                    next += 1;
+                }
+
+                let level = spill_level(heightmap, &component, &visited, lakes);
+                for i in component {
+                    kinds[i] = WaterKind::Lake;
+                    levels[i] = level;
+                }
+            }
+        }
+
+        let (dx, dy) = heightmap.cell_size();
+        let accumulation = flow_accumulation(heightmap);
+        for i in 0..width * height {
+            if kinds[i] == WaterKind::Dry && accumulation[i] * dx * dy >= options.river_catchment {
+                kinds[i] = WaterKind::River;
+            }
+        }
+
+        Self {
+            width,
+            height,
+            kinds,
+            levels,
+        }
+    }
+
+    /// Resamples a water-body raster onto the heightmap grid, e.g. an SWBD mask with
+    /// `|class| class != 0` or WorldCover with `|class| class == PERMANENT_WATER`.
+    pub fn lake_mask(
+        heightmap: &Heightmap,
+        raster: &LandCover,
+        is_water: impl Fn(u8) -> bool,
+    ) -> Vec<bool> {
+        let mut mask = Vec::with_capacity(heightmap.width * heightmap.height);
+        for y in 0..heightmap.height {
+            for x in 0..heightmap.width {
+                let u = x as f64 / (heightmap.width - 1).max(1) as f64;
+                let v = y as f64 / (heightmap.height - 1).max(1) as f64;
+                let (lat, lon) = heightmap.bounds.from_uv(u, v);
+                mask.push(raster.class_at(lat, lon).is_some_and(&is_water));
+            }
+        }
+        mask
+    }
+
+    /// Row-major mask of every wet cell, for `AttributeMap::compute`.
+    pub fn mask(&self) -> Vec<bool> {
+        self.kinds
+            .iter()
+            .map(|&kind| kind != WaterKind::Dry)
+            .collect()
+    }
+
+    /// Nearest cell to normalized `(u, v)`.
+    pub fn sample(&self, u: f32, v: f32) -> (WaterKind, f32) {
+        let x = (u.clamp(0.0, 1.0) * (self.width - 1) as f32).round() as usize;
+        let y = (v.clamp(0.0, 1.0) * (self.height - 1) as f32).round() as usize;
+        let i = y * self.width + x;
+        (self.kinds[i], self.levels[i])
+    }
+}
+
+/// Cells for which `wet` holds that connect to the edge of a `width` by `height` grid through
+/// other such cells, row-major.
+pub fn flood_from_edges(width: usize, height: usize, wet: impl Fn(usize) -> bool) -> Vec<bool> {
+    let mut flooded = vec![false; width * height];
+    let mut queue: VecDeque<usize> = (0..width * height)
+        .filter(|&i| {
+            let (x, y) = (i % width, i / width);
+            (x == 0 || y == 0 || x == width - 1 || y == height - 1) && wet(i)
+        })
+        .collect();
+    for &i in &queue {
+        flooded[i] = true;
+    }
+    while let Some(i) = queue.pop_front() {
+        for n in neighbors(i, width, height) {
+            if !flooded[n] && wet(n) {
+                flooded[n] = true;
+                queue.push_back(n);
+            }
+        }
+    }
+    flooded
+}
+
+// Lowest elevation just outside a lake component. Falls back to the highest cell inside it
+// when the rim isn't above the lake floor, as on a masked river reach, or there's no rim at all
+fn spill_level(
+    heightmap: &Heightmap,
+    component: &[usize],
+    visited: &[bool],
+    lakes: &[bool],
+) -> f32 {
+    let (width, height) = (heightmap.width, heightmap.height);
+    let (floor, top) =
+        component
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &i| {
+                let elevation = heightmap.elevations[i];
+                (min.min(elevation), max.max(elevation))
+            });
+    let rim = component
+        .iter()
+        .flat_map(|&i| neighbors(i, width, height))
+        .filter(|&n| !(lakes[n] && visited[n]))
+        .map(|n| heightmap.elevations[n])
+        .fold(f32::INFINITY, f32::min);
+
+    if rim.is_finite() && rim > floor {
+        rim
+    } else {
+        top
+    }
+}
+
+fn neighbors(i: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % width) as isize, (i / width) as isize);
+    NEIGHBORS.iter().filter_map(move |&(dx, dy)| {
+        let (nx, ny) = (x + dx, y + dy);
+        (nx >= 0 && ny >= 0 && nx < width as isize && ny < height as isize)
+            .then(|| ny as usize * width + nx as usize)
+    })
+}
+
+/// Number of cells draining through each cell, itself included. Depressions are filled with a
+/// priority flood from the edges first, so every cell drains off the map along D8 directions.
+fn flow_accumulation(heightmap: &Heightmap) -> Vec<f32> {
+    let (width, height) = (heightmap.width, heightmap.height);
+    let mut filled = heightmap.elevations.clone();
+    let mut visited = vec![false; width * height];
+    let mut order = Vec::with_capacity(width * height);
+    let mut open = BinaryHeap::new();
+
+    for i in 0..width * height {
+        let (x, y) = (i % width, i / width);
+        if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+            visited[i] = true;
+            open.push(Reverse((Elevation(filled[i]), i)));
+        }
+    }
+
+    while let Some(Reverse((_, i))) = open.pop() {
+        order.push(i);
+        for n in neighbors(i, width, height) {
+            if !visited[n] {
+                visited[n] = true;
+                filled[n] = filled[n].max(filled[i] + FILL_EPSILON);
+                open.push(Reverse((Elevation(filled[n]), n)));
+            }
+        }
+    }
+
+    // Highest cells first, so every cell has received its upstream flow before passing it on
+    let mut accumulation = vec![1.0; width * height];
+    for &i in order.iter().rev() {
+        let downstream = neighbors(i, width, height)
+            .filter(|&n| filled[n] < filled[i])
+            .min_by(|&a, &b| filled[a].total_cmp(&filled[b]));
+        if let Some(n) = downstream {
+            accumulation[n] += accumulation[i];
+        }
+    }
+
+    accumulation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::processor;
+    use crate::terrain::voxelizer::{self, WATER};
+
+    const SIZE: usize = 16;
+
+    fn heightmap(elevation: impl Fn(usize, usize) -> f32) -> Heightmap {
+        processor::test_heightmap(SIZE, SIZE, elevation)
+    }
+
+    // Rivers would need a huge catchment on these small maps, keep them out of the way
+    const NO_RIVERS: WaterOptions = WaterOptions {
+        sea_level: 0.0,
+        river_catchment: f32::INFINITY,
+    };
+
+    #[test]
+    fn sea_floods_in_from_the_edges() {
+        let map = heightmap(|x, _| if x < SIZE / 2 { -10.0 } else { 50.0 });
+        let water = WaterMap::compute(&map, None, &NO_RIVERS);
+
+        for i in 0..SIZE * SIZE {
+            if i % SIZE < SIZE / 2 {
+                assert_eq!(water.kinds[i], WaterKind::Ocean);
+                assert_eq!(water.levels[i], 0.0);
+            } else {
+                assert_eq!(water.kinds[i], WaterKind::Dry);
+            }
+        }
+    }
+
+    #[test]
+    fn enclosed_basins_below_sea_level_stay_dry() {
+        let inside = |x: usize, y: usize| (5..11).contains(&x) && (5..11).contains(&y);
+        let map = heightmap(|x, y| if inside(x, y) { -20.0 } else { 50.0 });
+        let water = WaterMap::compute(&map, None, &NO_RIVERS);
+
+        assert!(water.kinds.iter().all(|&kind| kind == WaterKind::Dry));
+    }
+
+    #[test]
+    fn masked_lakes_fill_to_their_rim() {
+        // A 100 m deep basin whose rim dips to 60 m on one side
+        let inside = |x: usize, y: usize| (4..12).contains(&x) && (4..12).contains(&y);
+        let map = heightmap(|x, y| match (inside(x, y), x) {
+            (true, _) => 0.0,
+            (false, 12) if y == 8 => 60.0,
+            _ => 100.0,
+        });
+        let lakes: Vec<bool> = (0..SIZE * SIZE)
+            .map(|i| inside(i % SIZE, i / SIZE))
+            .collect();
+        let water = WaterMap::compute(&map, Some(&lakes), &NO_RIVERS);
+
+        let center = 8 * SIZE + 8;
+        assert_eq!(water.kinds[center], WaterKind::Lake);
+        assert_eq!(water.levels[center], 60.0);
+
+        let volume = voxelizer::heightmap_to_volume(&map, &Default::default(), Some(&water), None);
+        let water_voxels = volume
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|&&m| m == WATER)
+            .count();
+        assert!(water_voxels > 0);
+    }
+
+    #[test]
+    fn flow_gathers_down_a_valley() {
+        // Sides fall towards the middle row, which falls east
+        let middle = SIZE / 2;
+        let map = heightmap(|x, y| y.abs_diff(middle) as f32 * 10.0 + (SIZE - x) as f32);
+        let accumulation = flow_accumulation(&map);
+
+        assert_eq!(accumulation[0], 1.0);
+        assert_eq!(accumulation[middle * SIZE + SIZE - 1], (SIZE * SIZE) as f32);
+        for x in 1..SIZE {
+            let row = middle * SIZE;
+            assert!(accumulation[row + x] > accumulation[row + x - 1]);
+        }
+    }
+}
@@ -0,0 +1,696 @@
+//! Chunked world files.
+//!
+//! All numbers are little-endian. A file starts with an 8 byte magic, the format version that
+//! wrote it, the oldest version able to read it and the length of the header that follows:
+//!
+//! ```text
+//! magic "PEWORLD\0" | version u16 | min_reader_version u16 | header_length u32
+//! header:      north, south, east, west f64 | voxel_length f32 | chunk_size 3 x u32
+//!              material_count u32 | materials | chunk_count u32 | index_entry_length u16
+//! chunk index: chunk_count entries of index_entry_length bytes,
+//!              lat, lon, base_height f64 | offset u64 | length u32 | voxel_size 3 x f32
+//! payloads:    one deflate stream per chunk at its offset from the start of the file,
+//!              encoded voxels | column_count u32 | column_count x packed surface height u32
+//! ```
+//!
+//! The surface heights are optional, a payload that ends after the voxels only has full ones.
+//! Version 1 index entries end before `voxel_size`, their chunks have cubic voxels of the
+//! header's `voxel_length`.
+//!
+//! Newer writers may append fields to the header and to index entries. Readers skip what they
+//! don't know using the stored lengths, and only refuse files whose `min_reader_version` is
+//! above their own version.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::palette::{ChunkStorage, PalettedVoxels, RleColumns, Run};
+use super::processor::GeoBounds;
+use super::voxelizer::{GeoCoord, SurfaceHeight, VoxelChunk, VoxelMaterial, VoxelWorld};
+
+const MAGIC: &[u8; 8] = b"PEWORLD\0";
+pub const FORMAT_VERSION: u16 = 2;
+// Bump only when older readers would misread files, not for appended fields
+const MIN_READER_VERSION: u16 = 1;
+const INDEX_ENTRY_LENGTH: u16 = 48;
+// Entries written by version 1, without the voxel size
+const V1_INDEX_ENTRY_LENGTH: u16 = 36;
+
+// Counts read from a file only size allocations up to here, the rest grows as data arrives so
+// a corrupt count fails on the missing data instead of allocating gigabytes
+const MAX_PREALLOCATED: usize = 1024;
+
+const PALETTED: u8 = 0;
+const COLUMNS: u8 = 1;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    base_height: f64,
+    offset: u64,
+    length: u32,
+    voxel_size: [f32; 3],
+}
+
+/// Everything in a world file except the chunk payloads.
+#[derive(Debug, Clone)]
+pub struct WorldHeader {
+    pub version: u16,
+    pub bounds: GeoBounds,
+    pub voxel_length: f32,
+    pub chunk_size: [u32; 3],
+    pub materials: Vec<VoxelMaterial>,
+}
+
+/// Open world file that reads chunks on demand through its index.
+pub struct WorldReader<R> {
+    reader: R,
+    pub header: WorldHeader,
+    index: HashMap<GeoCoord, IndexEntry>,
+}
+
+impl WorldReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> WorldReader<R> {
+    /// Reads the header and chunk index, leaving the payloads on disk.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a world file".into());
+        }
+
+        let version = read_u16(&mut reader)?;
+        let min_reader_version = read_u16(&mut reader)?;
+        if min_reader_version > FORMAT_VERSION {
+            return Err(format!(
+                "world file version {version} needs a reader of at least version \
+                 {min_reader_version}, this is version {FORMAT_VERSION}"
+            )
+            .into());
+        }
+
+        let header_length = read_u32(&mut reader)? as u64;
+        let header_start = reader.stream_position()?;
+
+        let bounds = GeoBounds {
+            north: read_f64(&mut reader)?,
+            south: read_f64(&mut reader)?,
+            east: read_f64(&mut reader)?,
+            west: read_f64(&mut reader)?,
+        };
+        let voxel_length = read_f32(&mut reader)?;
+        let chunk_size = [
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+            read_u32(&mut reader)?,
+        ];
+        let voxel_count = chunk_size
+            .iter()
+            .try_fold(1usize, |count, &n| count.checked_mul(n as usize));
+        if voxel_count.is_none_or(|count| count == 0 || count > u32::MAX as usize) {
+            return Err(format!("invalid chunk size {chunk_size:?}").into());
+        }
+
+        let material_count = read_u32(&mut reader)?;
+        let mut materials = Vec::with_capacity((material_count as usize).min(MAX_PREALLOCATED));
+        for _ in 0..material_count {
+            let id = read_u32(&mut reader)?;
+            let name_length = read_u16(&mut reader)?;
+            let mut name = vec![0; name_length as usize];
+            reader.read_exact(&mut name)?;
+            let albedo = [
+                read_f32(&mut reader)?,
+                read_f32(&mut reader)?,
+                read_f32(&mut reader)?,
+            ];
+            materials.push(VoxelMaterial {
+                id,
+                name: String::from_utf8(name)?,
+                albedo,
+            });
+        }
+
+        let chunk_count = read_u32(&mut reader)?;
+        let entry_length = read_u16(&mut reader)? as i64;
+        if entry_length < V1_INDEX_ENTRY_LENGTH as i64 {
+            return Err(format!("index entries of {entry_length} bytes are too short").into());
+        }
+
+        // Skip header fields added by newer versions
+        reader.seek(SeekFrom::Start(header_start + header_length))?;
+
+        let mut index = HashMap::with_capacity((chunk_count as usize).min(MAX_PREALLOCATED));
+        for _ in 0..chunk_count {
+            let coord = GeoCoord {
+                lat: read_f64(&mut reader)?,
+                lon: read_f64(&mut reader)?,
+            };
+            let base_height = read_f64(&mut reader)?;
+            let offset = read_u64(&mut reader)?;
+            let length = read_u32(&mut reader)?;
+            let voxel_size = if entry_length >= INDEX_ENTRY_LENGTH as i64 {
+                [
+                    read_f32(&mut reader)?,
+                    read_f32(&mut reader)?,
+                    read_f32(&mut reader)?,
+                ]
+            } else {
+                [voxel_length; 3]
+            };
+            let entry = IndexEntry {
+                base_height,
+                offset,
+                length,
+                voxel_size,
+            };
+            let read = (INDEX_ENTRY_LENGTH as i64).min(entry_length);
+            reader.seek(SeekFrom::Current(entry_length - read))?;
+            index.insert(coord, entry);
+        }
+
+        Ok(Self {
+            reader,
+            header: WorldHeader {
+                version,
+                bounds,
+                voxel_length,
+                chunk_size,
+                materials,
+            },
+            index,
+        })
+    }
+
+    pub fn chunk_coords(&self) -> impl Iterator<Item = &GeoCoord> {
+        self.index.keys()
+    }
+
+    /// Reads and decompresses one chunk, or `None` if the world has no chunk at `coord`.
+    pub fn read_chunk(&mut self, coord: GeoCoord) -> Result<Option<VoxelChunk>> {
+        let Some(entry) = self.index.get(&coord).copied() else {
+            return Ok(None);
+        };
+
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        let mut compressed = Vec::new();
+        (&mut self.reader)
+            .take(entry.length as u64)
+            .read_to_end(&mut compressed)?;
+        if compressed.len() != entry.length as usize {
+            return Err(format!("chunk at {coord:?} is truncated").into());
+        }
+
+        let mut payload = Vec::new();
+        DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut payload)?;
+        let size = self.header.chunk_size.map(|n| n as usize);
+        let mut bytes = payload.as_slice();
+
+        Ok(Some(VoxelChunk {
+            coord,
+            base_height: entry.base_height,
+            voxels: decode_storage(size, &mut bytes)?,
+            surface: decode_surface(size, &mut bytes)?,
+            voxel_size: entry.voxel_size,
+        }))
+    }
+
+    /// Reads every chunk into memory.
+    pub fn into_world(mut self) -> Result<VoxelWorld> {
+        let coords: Vec<GeoCoord> = self.index.keys().copied().collect();
+        let mut chunks = HashMap::with_capacity(coords.len());
+        for coord in coords {
+            if let Some(chunk) = self.read_chunk(coord)? {
+                chunks.insert(coord, chunk);
+            }
+        }
+
+        Ok(VoxelWorld {
+            bounds: self.header.bounds,
+            materials: self.header.materials,
+            voxel_length: self.header.voxel_length,
+            chunk_size: self.header.chunk_size,
+            chunks,
+        })
+    }
+}
+
+impl VoxelWorld {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        WorldReader::open(path)?.into_world()
+    }
+
+    pub fn read_from<R: Read + Seek>(reader: R) -> Result<Self> {
+        WorldReader::new(reader)?.into_world()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut header = Vec::new();
+        for value in [
+            self.bounds.north,
+            self.bounds.south,
+            self.bounds.east,
+            self.bounds.west,
+        ] {
+            header.extend(value.to_le_bytes());
+        }
+        header.extend(self.voxel_length.to_le_bytes());
+        for n in self.chunk_size {
+            header.extend(n.to_le_bytes());
+        }
+
+        header.extend((self.materials.len() as u32).to_le_bytes());
+        for material in &self.materials {
+            header.extend(material.id.to_le_bytes());
+            header.extend((material.name.len() as u16).to_le_bytes());
+            header.extend(material.name.as_bytes());
+            for channel in material.albedo {
+                header.extend(channel.to_le_bytes());
+            }
+        }
+
+        // Sorted so the same world always writes the same bytes
+        let mut chunks: Vec<&VoxelChunk> = self.chunks.values().collect();
+        chunks.sort_by(|a, b| {
+            (a.coord.lat, a.coord.lon)
+                .partial_cmp(&(b.coord.lat, b.coord.lon))
+                .unwrap()
+        });
+
+        header.extend((chunks.len() as u32).to_le_bytes());
+        header.extend(INDEX_ENTRY_LENGTH.to_le_bytes());
+
+        let payloads = chunks
+            .iter()
+            .map(|chunk| {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&encode_storage(&chunk.voxels))?;
+                encoder.write_all(&encode_surface(&chunk.surface))?;
+                Ok(encoder.finish()?)
+            })
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+
+        let mut offset = (MAGIC.len() + 2 + 2 + 4 + header.len()) as u64
+            + chunks.len() as u64 * INDEX_ENTRY_LENGTH as u64;
+        let mut index = Vec::with_capacity(chunks.len() * INDEX_ENTRY_LENGTH as usize);
+        for (chunk, payload) in chunks.iter().zip(&payloads) {
+            index.extend(chunk.coord.lat.to_le_bytes());
+            index.extend(chunk.coord.lon.to_le_bytes());
+            index.extend(chunk.base_height.to_le_bytes());
+            index.extend(offset.to_le_bytes());
+            index.extend((payload.len() as u32).to_le_bytes());
+            for length in chunk.voxel_size {
+                index.extend(length.to_le_bytes());
+            }
+            offset += payload.len() as u64;
+        }
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&MIN_READER_VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        writer.write_all(&index)?;
+        for payload in payloads {
+            writer.write_all(&payload)?;
+        }
+        Ok(())
+    }
+}
+
+// Payloads keep whichever encoding the chunk is stored in, tagged by its first byte
+fn encode_storage(storage: &ChunkStorage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match storage {
+        ChunkStorage::Paletted(voxels) => {
+            bytes.push(PALETTED);
+            write_palette(&mut bytes, voxels.palette());
+            bytes.push(voxels.bits() as u8);
+            bytes.extend((voxels.words().len() as u32).to_le_bytes());
+            for word in voxels.words() {
+                bytes.extend(word.to_le_bytes());
+            }
+        }
+        ChunkStorage::Columns(columns) => {
+            bytes.push(COLUMNS);
+            write_palette(&mut bytes, columns.palette());
+            bytes.extend((columns.runs().len() as u32).to_le_bytes());
+            for run in columns.runs() {
+                bytes.extend(run.index.to_le_bytes());
+                bytes.extend(run.length.to_le_bytes());
+            }
+            // Offsets follow from the run lengths, so only the runs per column are stored
+            for pair in columns.offsets().windows(2) {
+                bytes.extend(((pair[1] - pair[0]) as u16).to_le_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+fn decode_storage(size: [usize; 3], bytes: &mut &[u8]) -> Result<ChunkStorage> {
+    let mut tag = [0];
+    bytes.read_exact(&mut tag)?;
+
+    match tag[0] {
+        PALETTED => {
+            let palette = read_palette(bytes)?;
+            let mut bits = [0];
+            bytes.read_exact(&mut bits)?;
+            let words = (0..read_u32(bytes)?)
+                .map(|_| read_u64(bytes))
+                .collect::<Result<Vec<u64>>>()?;
+            Ok(ChunkStorage::Paletted(PalettedVoxels::from_parts(
+                size,
+                palette,
+                bits[0] as u32,
+                words,
+            )?))
+        }
+        COLUMNS => {
+            let palette = read_palette(bytes)?;
+            let runs = (0..read_u32(bytes)?)
+                .map(|_| {
+                    Ok(Run {
+                        index: read_u16(bytes)?,
+                        length: read_u16(bytes)?,
+                    })
+                })
+                .collect::<Result<Vec<Run>>>()?;
+
+            let mut offsets = vec![0];
+            for _ in 0..size[0] * size[2] {
+                let count = read_u16(bytes)? as u32;
+                offsets.push(offsets.last().unwrap() + count);
+            }
+            Ok(ChunkStorage::Columns(RleColumns::from_parts(
+                size, palette, offsets, runs,
+            )?))
+        }
+        tag => Err(format!("unknown chunk encoding {tag}").into()),
+    }
+}
+
+fn encode_surface(surface: &[Option<SurfaceHeight>]) -> Vec<u8> {
+    if surface.is_empty() {
+        return Vec::new();
+    }
+    let mut bytes = Vec::with_capacity(4 + surface.len() * 4);
+    bytes.extend((surface.len() as u32).to_le_bytes());
+    for &column in surface {
+        bytes.extend(SurfaceHeight::pack(column).to_le_bytes());
+    }
+    bytes
+}
+
+// Either no surface heights or one per column
+fn decode_surface(size: [usize; 3], bytes: &mut &[u8]) -> Result<Vec<Option<SurfaceHeight>>> {
+    if bytes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let count = read_u32(bytes)? as usize;
+    if count != size[0] * size[2] {
+        return Err(format!(
+            "{count} surface heights for {}x{} columns",
+            size[0], size[2]
+        )
+        .into());
+    }
+    (0..count)
+        .map(|_| Ok(SurfaceHeight::unpack(read_u32(bytes)?)))
+        .collect()
+}
+
+fn write_palette(bytes: &mut Vec<u8>, palette: &[u32]) {
+    bytes.extend((palette.len() as u16).to_le_bytes());
+    for material in palette {
+        bytes.extend(material.to_le_bytes());
+    }
+}
+
+fn read_palette(bytes: &mut &[u8]) -> Result<Vec<u32>> {
+    (0..read_u16(bytes)?).map(|_| read_u32(bytes)).collect()
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+fn read_f32(reader: &mut impl Read) -> Result<f32> {
+    Ok(f32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_f64(reader: &mut impl Read) -> Result<f64> {
+    Ok(f64::from_le_bytes(read_array(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::terrain::generator::{self, GeneratorOptions};
+    use crate::terrain::palette::{PalettedVoxels, RleColumns};
+    use crate::terrain::voxelizer::{self, ChunkRegion, HEIGHT, LENGTH, WIDTH};
+
+    fn world() -> VoxelWorld {
+        let bounds = GeoBounds {
+            north: 47.0,
+            south: 46.5,
+            east: 3.0,
+            west: 2.5,
+        };
+        let mut world = VoxelWorld::new(bounds, 30.0);
+
+        for (i, (lat, lon)) in [(47.0, 2.5), (47.0, 2.75), (46.75, 2.5)]
+            .into_iter()
+            .enumerate()
+        {
+            let options = GeneratorOptions {
+                seed: i as u64,
+                ..Default::default()
+            };
+            let chunk_bounds = GeoBounds {
+                north: lat,
+                south: lat - 0.25,
+                east: lon + 0.25,
+                west: lon,
+            };
+            let heightmap = generator::generate(&options, 64, 64, chunk_bounds);
+            let volume =
+                voxelizer::heightmap_to_volume(&heightmap, &Default::default(), None, None);
+            let mut chunk = VoxelChunk::new(GeoCoord { lat, lon }, 120.0 * i as f64, &volume)
+                .with_voxel_size([74.0, 2.5 + i as f32, 108.0]);
+            if i == 0 {
+                let region = ChunkRegion::whole(&heightmap);
+                chunk.surface = voxelizer::surface_heights(&volume, &heightmap, &region);
+            }
+
+            // Cover both payload encodings
+            let flat = chunk.voxels.decode();
+            let size = [LENGTH, HEIGHT, WIDTH];
+            chunk.voxels = if i % 2 == 0 {
+                ChunkStorage::Columns(RleColumns::encode(size, &flat))
+            } else {
+                ChunkStorage::Paletted(PalettedVoxels::encode(size, &flat))
+            };
+            world.insert(chunk);
+        }
+
+        world
+    }
+
+    #[test]
+    fn round_trips() {
+        let world = world();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        let read = VoxelWorld::read_from(Cursor::new(&bytes)).unwrap();
+        assert_eq!(read, world);
+
+        // Writing is deterministic
+        let mut again = Vec::new();
+        read.write_to(&mut again).unwrap();
+        assert_eq!(again, bytes);
+    }
+
+    #[test]
+    fn reads_single_chunks() {
+        let world = world();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        let mut reader = WorldReader::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(reader.chunk_coords().count(), 3);
+
+        let coord = GeoCoord {
+            lat: 47.0,
+            lon: 2.75,
+        };
+        assert_eq!(
+            reader.read_chunk(coord).unwrap().as_ref(),
+            world.chunks.get(&coord)
+        );
+        assert!(reader
+            .read_chunk(GeoCoord { lat: 0.0, lon: 0.0 })
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn skips_fields_from_newer_versions() {
+        let world = world();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        // Pretend a newer writer appended a header field and 4 bytes to each index entry
+        let header_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let header_end = 16 + header_length;
+        let index_end = header_end + 3 * INDEX_ENTRY_LENGTH as usize;
+        let longer_entry = INDEX_ENTRY_LENGTH + 4;
+        let shift = 8 + 3 * 4;
+
+        let mut newer = bytes[..header_end].to_vec();
+        newer[8..10].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        newer[12..16].copy_from_slice(&(header_length as u32 + 8).to_le_bytes());
+        let entry_length_at = header_end - 2;
+        newer[entry_length_at..header_end].copy_from_slice(&longer_entry.to_le_bytes());
+        newer.extend(0xdead_beef_u64.to_le_bytes());
+
+        for entry in bytes[header_end..index_end].chunks(INDEX_ENTRY_LENGTH as usize) {
+            let offset = u64::from_le_bytes(entry[24..32].try_into().unwrap()) + shift;
+            newer.extend(&entry[..24]);
+            newer.extend(offset.to_le_bytes());
+            newer.extend(&entry[32..]);
+            newer.extend([0xff; 4]);
+        }
+        newer.extend(&bytes[index_end..]);
+
+        let read = VoxelWorld::read_from(Cursor::new(&newer)).unwrap();
+        assert_eq!(read.chunks, world.chunks);
+
+        // A file that older readers can't understand is refused rather than misread
+        newer[10..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(VoxelWorld::read_from(Cursor::new(&newer)).is_err());
+    }
+
+    #[test]
+    fn version_1_chunks_have_cubic_voxels() {
+        let world = world();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        // Drop the voxel sizes from the end of each index entry, like a version 1 writer
+        let header_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let header_end = 16 + header_length;
+        let index_end = header_end + 3 * INDEX_ENTRY_LENGTH as usize;
+        let shift = 3 * (INDEX_ENTRY_LENGTH - V1_INDEX_ENTRY_LENGTH) as u64;
+
+        let mut older = bytes[..header_end].to_vec();
+        older[8..10].copy_from_slice(&1u16.to_le_bytes());
+        let entry_length_at = header_end - 2;
+        older[entry_length_at..header_end].copy_from_slice(&V1_INDEX_ENTRY_LENGTH.to_le_bytes());
+        for entry in bytes[header_end..index_end].chunks(INDEX_ENTRY_LENGTH as usize) {
+            let offset = u64::from_le_bytes(entry[24..32].try_into().unwrap()) - shift;
+            older.extend(&entry[..24]);
+            older.extend(offset.to_le_bytes());
+            older.extend(&entry[32..V1_INDEX_ENTRY_LENGTH as usize]);
+        }
+        older.extend(&bytes[index_end..]);
+
+        let read = VoxelWorld::read_from(Cursor::new(&older)).unwrap();
+        assert_eq!(read.chunks.len(), 3);
+        for (coord, chunk) in &world.chunks {
+            let read = &read.chunks[coord];
+            assert_eq!(read.voxel_size, [30.0; 3]);
+            assert_eq!(read.voxels, chunk.voxels);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_files() {
+        let world = world();
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+
+        // Cut anywhere, a file either fails to open or fails to read its chunks
+        for length in (0..bytes.len()).step_by(97) {
+            assert!(VoxelWorld::read_from(Cursor::new(&bytes[..length])).is_err());
+        }
+
+        // Absurd counts fail on the missing data rather than allocating for them
+        let header_length = u32::from_le_bytes(bytes[12..16].try_into().unwrap()) as usize;
+        let chunk_count_at = 16 + header_length - 6;
+        for at in [64, chunk_count_at] {
+            let mut corrupt = bytes.clone();
+            corrupt[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            assert!(VoxelWorld::read_from(Cursor::new(&corrupt)).is_err());
+        }
+
+        let mut corrupt = bytes.clone();
+        corrupt[52..64].copy_from_slice(&[0xff; 12]);
+        assert!(WorldReader::new(Cursor::new(&corrupt)).is_err());
+
+        // A chunk whose index entry claims more bytes than the file holds
+        let mut corrupt = bytes.clone();
+        let length_at = 16 + header_length + 32;
+        corrupt[length_at..length_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(VoxelWorld::read_from(Cursor::new(&corrupt)).is_err());
+    }
+
+    #[test]
+    fn rejects_surface_heights_of_the_wrong_size() {
+        let mut world = world();
+        let coord = GeoCoord {
+            lat: 47.0,
+            lon: 2.5,
+        };
+        let chunk = world.chunks.get_mut(&coord).unwrap();
+        assert_eq!(chunk.surface.len(), LENGTH * WIDTH);
+        chunk.surface.pop();
+
+        let mut bytes = Vec::new();
+        world.write_to(&mut bytes).unwrap();
+        let mut reader = WorldReader::new(Cursor::new(&bytes)).unwrap();
+        assert!(reader.read_chunk(coord).is_err());
+        assert!(reader
+            .read_chunk(GeoCoord {
+                lat: 47.0,
+                lon: 2.75,
+            })
+            .is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use cgmath::*;
+
+/// Edge length in world units of the cells positions are split into. Offsets stay below this,
+/// so f32 keeps sub-millimeter precision however far from the world origin a position is.
+pub const CELL_SIZE: f64 = 4096.0;
+
+/// World position split into an integer cell and an f32 offset inside it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldPos {
+    pub cell: Vector3<i64>,
+    pub offset: Vector3<f32>,
+}
+
+impl WorldPos {
+    pub fn from_f64(position: Vector3<f64>) -> Self {
+        let cell = position.map(|v| (v / CELL_SIZE).floor() as i64);
+        let offset = position - cell.map(|c| c as f64 * CELL_SIZE);
+        Self {
+            cell,
+            offset: offset.map(|v| v as f32),
+        }
+    }
+
+    pub fn to_f64(self) -> Vector3<f64> {
+        self.cell.map(|c| c as f64 * CELL_SIZE) + self.offset.map(|v| v as f64)
+    }
+
+    pub fn translate(&mut self, delta: Vector3<f32>) {
+        self.offset += delta;
+
+        // Carry whole cells out of the offset so it stays small
+        for axis in 0..3 {
+            let carry = (self.offset[axis] as f64 / CELL_SIZE).floor();
+            if carry != 0.0 {
+                self.cell[axis] += carry as i64;
+                self.offset[axis] = (self.offset[axis] as f64 - carry * CELL_SIZE) as f32;
+            }
+        }
+    }
+
+    /// Small f32 vector from `origin` to this position, what the GPU sees after rebasing.
+    pub fn relative_to(&self, origin: &WorldPos) -> Vector3<f32> {
+        let cells = (self.cell - origin.cell).map(|c| c as f64 * CELL_SIZE);
+        let offsets = (self.offset - origin.offset).map(|v| v as f64);
+        (cells + offsets).map(|v| v as f32)
+    }
+}
+
+impl From<Point3<f64>> for WorldPos {
+    fn from(position: Point3<f64>) -> Self {
+        Self::from_f64(position.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translation_carries_across_cells() {
+        let mut position = WorldPos::from_f64(Vector3::new(4090.0, 10.0, 0.5));
+        assert_eq!(position.cell, Vector3::new(0, 0, 0));
+
+        position.translate(Vector3::new(10.0, -20.0, -1.0));
+        assert_eq!(position.cell, Vector3::new(1, -1, -1));
+        assert_eq!(position.offset, Vector3::new(4.0, 4086.0, 4095.5));
+
+        // Several cells in one step
+        position.translate(Vector3::new(
+            -3.0 * CELL_SIZE as f32,
+            2.0 * CELL_SIZE as f32,
+            0.5,
+        ));
+        assert_eq!(position.cell, Vector3::new(-2, 1, 0));
+        assert_eq!(position.offset, Vector3::new(4.0, 4086.0, 0.0));
+        assert_eq!(position.to_f64(), Vector3::new(-8188.0, 8182.0, 0.0));
+    }
+
+    #[test]
+    fn relative_positions_keep_precision_far_from_the_origin() {
+        let far = Vector3::new(1.0e7, -1.0e7, 6.4e6);
+        let origin = WorldPos::from_f64(far);
+        let position = WorldPos::from_f64(far + Vector3::new(0.125, -0.003, 1.5));
+        let relative = position.relative_to(&origin);
+        assert!((relative - Vector3::new(0.125, -0.003, 1.5)).magnitude() < 1e-3);
+
+        // A plain f32 loses this to rounding at 1e7
+        assert_ne!((far.x as f32 + 0.125) - far.x as f32, 0.125);
+
+        let mut moved = origin;
+        moved.translate(Vector3::new(5000.0, 0.001, 0.0));
+        let relative = moved.relative_to(&origin);
+        assert!((relative - Vector3::new(5000.0, 0.001, 0.0)).magnitude() < 1e-3);
+    }
+}